@@ -0,0 +1,111 @@
+// A frontend of its own around a headless machine: the picture is drawn as
+// text in the terminal, the sound is written to a WAV file and the input
+// comes from a script of button presses.
+//
+//     cargo run --example custom_frontend -- game.nes sound.wav
+//
+// A frontend using another toolkit for window, sound and input would do the
+// same three things each frame.
+
+use std::env;
+use std::fs;
+use std::process;
+
+use nesemu::nes;
+use nesemu::nes::screen::{HEIGHT, WIDTH};
+
+const FRAMES: u32 = 60 * 20;
+
+// Buttons held during frames start..end
+const SCRIPT: &[(&str, u32, u32)] = &[
+    ("start", 120, 130),
+    ("start", 240, 250),
+    ("right", 300, 900),
+    ("a", 400, 430),
+    ("a", 600, 630),
+];
+
+fn buttons_for_frame(frame: u32) -> [bool; 8] {
+    let mut buttons = [false; 8];
+    for (name, start, end) in SCRIPT {
+        if (*start..*end).contains(&frame) {
+            let index = nes::BUTTON_NAMES.iter().position(|b| b == name).unwrap();
+            buttons[index] = true;
+        }
+    }
+    buttons
+}
+
+// Draws the frame with one character per 4x8 pixel block, by brightness
+fn draw_frame(frame_buffer: &[u8]) {
+    const SHADES: &[u8] = b" .:-=+*#%@";
+    let mut text = String::from("\x1b[H"); // cursor to the top left corner
+    for row in 0..HEIGHT / 8 {
+        for column in 0..WIDTH / 4 {
+            let mut sum = 0;
+            for y in row * 8..row * 8 + 8 {
+                for x in column * 4..column * 4 + 4 {
+                    let offset = ((y * WIDTH + x) * 3) as usize;
+                    sum += frame_buffer[offset..offset + 3].iter().map(|c| *c as u32).sum::<u32>();
+                }
+            }
+            let brightness = sum / (4 * 8 * 3);
+            text.push(SHADES[brightness as usize * SHADES.len() / 256] as char);
+        }
+        text.push('\n');
+    }
+    print!("{}", text);
+}
+
+// 16 bit mono WAV
+fn wav_file(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+    let data_size = samples.len() as u32 * 2;
+    let mut wav = Vec::new();
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // channels
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_size.to_le_bytes());
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        wav.extend_from_slice(&value.to_le_bytes());
+    }
+    wav
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: custom_frontend <rom> <sound.wav>");
+        process::exit(1);
+    }
+    let rom = fs::read(&args[1]).expect("Unable to read the ROM");
+
+    let mut machine = nes::Machine::new_headless();
+    let mut cpu = nes::cpu::Cpu::new();
+    let cartridge = nes::cartridge::Cartridge::from_bytes(&rom).expect("Unable to load the ROM");
+    machine.load_cartridge(cartridge);
+    cpu.reset(&mut machine);
+
+    print!("\x1b[2J"); // clear the terminal
+    let mut sound = Vec::new();
+    for frame in 0..FRAMES {
+        machine.set_buttons(buttons_for_frame(frame));
+        let output = nes::run_frame(&mut cpu, &mut machine);
+        // Drawing every frame is more than most terminals keep up with
+        if frame % 4 == 0 {
+            draw_frame(output.picture);
+        }
+        sound.extend(output.samples);
+    }
+
+    fs::write(&args[2], wav_file(&sound, machine.audio_sample_rate()))
+        .expect("Unable to write the sound");
+}
@@ -0,0 +1,50 @@
+// Runs a ROM without window or sound for a number of frames and saves the
+// last frame as a PPM image, e.g. to check that a game boots:
+//
+//     cargo run --example headless_run -- game.nes 600 last_frame.ppm
+//
+// Start is pressed now and then to get past title screens.
+
+use std::env;
+use std::fs;
+use std::process;
+
+use nesemu::nes;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 4 {
+        eprintln!("Usage: headless_run <rom> <frames> <image.ppm>");
+        process::exit(1);
+    }
+    let rom = fs::read(&args[1]).expect("Unable to read the ROM");
+    let frames: u32 = args[2].parse().expect("The number of frames must be a number");
+
+    // The ROM is loaded from memory, so it could as well be embedded in the
+    // program with include_bytes!
+    let mut machine = nes::Machine::new_headless();
+    let mut cpu = nes::cpu::Cpu::new();
+    let cartridge = nes::cartridge::Cartridge::from_bytes(&rom).expect("Unable to load the ROM");
+    machine.load_cartridge(cartridge);
+    cpu.reset(&mut machine);
+
+    let start = nes::BUTTON_NAMES.iter().position(|b| *b == "start").unwrap();
+    let mut sample_count = 0;
+    let mut peak_level: f32 = 0.0;
+    for frame in 0..frames {
+        let mut buttons = [false; 8];
+        buttons[start] = frame % 120 < 10;
+        machine.set_buttons(buttons);
+
+        let samples = nes::run_frame(&mut cpu, &mut machine).samples;
+        sample_count += samples.len();
+        peak_level = samples.iter().fold(peak_level, |peak, s| peak.max(s.abs()));
+    }
+    println!("{} frames, {:.1} seconds of sound with peak level {:.3}",
+             frames, sample_count as f64 / machine.audio_sample_rate() as f64, peak_level);
+
+    let mut image = format!("P6\n{} {}\n255\n", nes::screen::WIDTH, nes::screen::HEIGHT)
+        .into_bytes();
+    image.extend_from_slice(machine.frame_buffer());
+    fs::write(&args[3], image).expect("Unable to write the image");
+}
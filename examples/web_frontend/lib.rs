@@ -0,0 +1,162 @@
+// A frontend for web browsers: the machine runs as WebAssembly, draws on a
+// canvas and plays its sound with WebAudio, and the keyboard is controller 1.
+// Build it without SDL:
+//
+//     cargo build --release --example web_frontend --no-default-features \
+//         --target wasm32-unknown-unknown
+//
+// and serve index.html from this directory together with
+// target/wasm32-unknown-unknown/release/examples/web_frontend.wasm.
+//
+// There are no bindings to the browser here. The page calls the exported
+// functions and reads the picture and the sound out of the memory of the
+// module, which the sinks of the machine write them to.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use nesemu::nes;
+use nesemu::nes::screen::{HEIGHT, WIDTH};
+
+// Shared by the sinks and the exported functions
+#[derive(Default)]
+struct Output {
+    // RGBA, as the canvas wants it
+    picture: Vec<u8>,
+    // Mono, played by the page after each frame
+    samples: Vec<f32>,
+    // The buttons held, A in bit 0, in the order of BUTTON_NAMES
+    buttons: u8,
+}
+
+struct Canvas(Rc<RefCell<Output>>);
+
+impl nes::VideoSink for Canvas {
+    fn present_frame(&mut self, picture: &[u8]) {
+        let mut output = self.0.borrow_mut();
+        output.picture.clear();
+        for pixel in picture.chunks(3) {
+            output.picture.extend_from_slice(&[pixel[0], pixel[1], pixel[2], 0xFF]);
+        }
+    }
+}
+
+struct WebAudio {
+    output: Rc<RefCell<Output>>,
+    sample_rate: u32,
+}
+
+impl nes::AudioSink for WebAudio {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn queue(&mut self, samples: &[f32]) {
+        self.output.borrow_mut().samples.extend_from_slice(samples);
+    }
+}
+
+struct Keyboard(Rc<RefCell<Output>>);
+
+impl nes::InputSource for Keyboard {
+    fn buttons(&mut self, player: usize) -> Option<[bool; 8]> {
+        if player != 1 {
+            return None;
+        }
+        let bits = self.0.borrow().buttons;
+        Some([0, 1, 2, 3, 4, 5, 6, 7].map(|button| bits & (1 << button) != 0))
+    }
+}
+
+struct Emulator {
+    cpu: nes::cpu::Cpu,
+    machine: nes::Machine,
+    output: Rc<RefCell<Output>>,
+}
+
+thread_local! {
+    static ROM: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    static EMULATOR: RefCell<Option<Emulator>> = const { RefCell::new(None) };
+}
+
+// Room for a .nes file of the given size, which the page copies it to
+// before calling start
+#[no_mangle]
+pub extern "C" fn rom_buffer(size: usize) -> *mut u8 {
+    ROM.with(|rom| {
+        let mut rom = rom.borrow_mut();
+        *rom = vec![0; size];
+        rom.as_mut_ptr()
+    })
+}
+
+// Inserts the ROM and switches the console on, with the sound at the sample
+// rate of the AudioContext of the page. Returns false, keeping the game that
+// was running, if it is not a ROM that nesemu can run.
+#[no_mangle]
+pub extern "C" fn start(sample_rate: u32) -> bool {
+    let rom = ROM.with(|rom| std::mem::take(&mut *rom.borrow_mut()));
+    let cartridge = match nes::cartridge::Cartridge::from_bytes(&rom) {
+        Ok(cartridge) => cartridge,
+        Err(_) => return false,
+    };
+    let output = Rc::new(RefCell::new(Output::default()));
+    let mut machine = nes::Machine::new_headless();
+    if let Some(region) = cartridge.region() {
+        machine.set_region(region);
+    }
+    machine.load_cartridge(cartridge);
+    machine.set_video_sink(Box::new(Canvas(output.clone())));
+    machine.set_audio_sink(Box::new(WebAudio { output: output.clone(), sample_rate }));
+    machine.set_input_source(Box::new(Keyboard(output.clone())));
+    let mut cpu = nes::cpu::Cpu::new();
+    cpu.reset(&mut machine);
+    EMULATOR.with(|emulator| *emulator.borrow_mut() = Some(Emulator { cpu, machine, output }));
+    true
+}
+
+// Runs a frame with the buttons held, after which picture and samples point
+// at what it produced
+#[no_mangle]
+pub extern "C" fn run_frame(buttons: u8) {
+    EMULATOR.with(|emulator| {
+        if let Some(Emulator { cpu, machine, output }) = emulator.borrow_mut().as_mut() {
+            {
+                let mut output = output.borrow_mut();
+                output.buttons = buttons;
+                output.samples.clear();
+            }
+            machine.handle_events();
+            nes::run_frame(cpu, machine);
+            machine.present();
+        }
+    })
+}
+
+// WIDTH x HEIGHT pixels of 4 bytes
+#[no_mangle]
+pub extern "C" fn picture() -> *const u8 {
+    with_output(|output| {
+        output.picture.resize((WIDTH * HEIGHT * 4) as usize, 0);
+        output.picture.as_ptr()
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn samples() -> *const f32 {
+    with_output(|output| output.samples.as_ptr())
+}
+
+#[no_mangle]
+pub extern "C" fn sample_count() -> usize {
+    with_output(|output| output.samples.len())
+}
+
+fn with_output<T>(f: impl FnOnce(&mut Output) -> T) -> T {
+    EMULATOR.with(|emulator| {
+        let emulator = emulator.borrow();
+        let emulator = emulator.as_ref().expect("No ROM has been started");
+        let mut output = emulator.output.borrow_mut();
+        f(&mut output)
+    })
+}
@@ -0,0 +1,71 @@
+/*
+ * The C API of nesemu, built with the ffi feature, see src/ffi.rs. All
+ * functions take the handle from nesemu_create and do nothing, or return 0,
+ * false or NULL, when it is NULL.
+ */
+
+#ifndef NESEMU_H
+#define NESEMU_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define NESEMU_WIDTH 256
+#define NESEMU_HEIGHT 240
+
+/* The bits of the buttons in nesemu_set_input */
+#define NESEMU_BUTTON_A      0x01
+#define NESEMU_BUTTON_B      0x02
+#define NESEMU_BUTTON_SELECT 0x04
+#define NESEMU_BUTTON_START  0x08
+#define NESEMU_BUTTON_UP     0x10
+#define NESEMU_BUTTON_DOWN   0x20
+#define NESEMU_BUTTON_LEFT   0x40
+#define NESEMU_BUTTON_RIGHT  0x80
+
+typedef struct Nesemu Nesemu;
+
+/* A console without a cartridge */
+Nesemu *nesemu_create(void);
+void nesemu_destroy(Nesemu *nesemu);
+
+/* Inserts a cartridge from the contents of a .nes file into a console just
+   switched on. Returns false if it is not a ROM that nesemu can run. */
+bool nesemu_load_rom(Nesemu *nesemu, const uint8_t *data, size_t size);
+
+/* Runs the console until the next picture is complete. Returns false
+   without a ROM or if the CPU has stopped on an opcode it can not execute. */
+bool nesemu_run_frame(Nesemu *nesemu);
+
+/* The last complete picture, NESEMU_WIDTH x NESEMU_HEIGHT pixels of 3 bytes
+   (RGB) each, valid until the next nesemu_run_frame */
+const uint8_t *nesemu_get_framebuffer(Nesemu *nesemu);
+
+/* The sound of the last frame, mono samples from -1.0 to 1.0, valid until
+   the next nesemu_run_frame. The number of samples is written to count. */
+const float *nesemu_get_audio(Nesemu *nesemu, size_t *count);
+uint32_t nesemu_audio_sample_rate(Nesemu *nesemu);
+
+/* Sets the NESEMU_BUTTON_ bits a player, 1 to 4, holds from now on. Players
+   3 and 4 connect the Four Score. Returns false for other players. */
+bool nesemu_set_input(Nesemu *nesemu, uint32_t player, uint8_t buttons);
+
+/* Writes the state to buffer if it holds size bytes or more, and returns
+   the size of the state either way; a NULL buffer asks for the size.
+   Returns 0 without a ROM. */
+size_t nesemu_save_state(Nesemu *nesemu, uint8_t *buffer, size_t size);
+
+/* Restores a state from nesemu_save_state of the same ROM. Returns false,
+   leaving the console as it was, if it is not one. */
+bool nesemu_load_state(Nesemu *nesemu, const uint8_t *data, size_t size);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,477 @@
+// A libretro core around the headless machine, so that nesemu can run in
+// RetroArch and other libretro frontends, which then provide the shaders,
+// netplay, input mapping and save files. Build it with
+//
+//     cargo build --release -p nesemu-libretro
+//
+// and load target/release/libnesemu_libretro.so (.dll, .dylib) as a core.
+
+use std::cell::RefCell;
+use std::ffi::{c_void, CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+
+use nesemu::nes;
+use nesemu::nes::screen::{HEIGHT, WIDTH};
+
+const API_VERSION: u32 = 1;
+
+const ENVIRONMENT_SET_PIXEL_FORMAT: u32 = 10;
+const ENVIRONMENT_GET_LOG_INTERFACE: u32 = 27;
+const LOG_WARN: u32 = 2;
+const PIXEL_FORMAT_XRGB8888: u32 = 1;
+const DEVICE_JOYPAD: u32 = 1;
+const REGION_NTSC: u32 = 0;
+const REGION_PAL: u32 = 1;
+const MEMORY_SAVE_RAM: u32 = 0;
+
+// The joypad buttons in the order of nes::BUTTON_NAMES, with B and A on the
+// lower and right face buttons like on the NES controller
+const JOYPAD_IDS: [u32; 8] = [8, 0, 2, 3, 4, 5, 6, 7];
+
+#[repr(C)]
+pub struct SystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+pub struct GameGeometry {
+    base_width: u32,
+    base_height: u32,
+    max_width: u32,
+    max_height: u32,
+    aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct SystemTiming {
+    fps: f64,
+    sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct SystemAvInfo {
+    geometry: GameGeometry,
+    timing: SystemTiming,
+}
+
+#[repr(C)]
+pub struct GameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+type EnvironmentFn = unsafe extern "C" fn(u32, *mut c_void) -> bool;
+type VideoRefreshFn = unsafe extern "C" fn(*const c_void, u32, u32, usize);
+type AudioSampleFn = unsafe extern "C" fn(i16, i16);
+type AudioSampleBatchFn = unsafe extern "C" fn(*const i16, usize) -> usize;
+type InputPollFn = unsafe extern "C" fn();
+type InputStateFn = unsafe extern "C" fn(u32, u32, u32, u32) -> i16;
+type LogPrintfFn = unsafe extern "C" fn(u32, *const c_char, ...);
+
+#[repr(C)]
+struct LogCallback {
+    log: Option<LogPrintfFn>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+
+fn callbacks() -> Callbacks {
+    *CALLBACKS.lock().unwrap()
+}
+
+// Through the log interface of the frontend, if it has one
+fn log_warning(message: &str) {
+    let environment = match callbacks().environment {
+        Some(environment) => environment,
+        None => return,
+    };
+    let mut callback = LogCallback { log: None };
+    unsafe {
+        let data = &mut callback as *mut LogCallback as *mut c_void;
+        if !environment(ENVIRONMENT_GET_LOG_INTERFACE, data) {
+            return;
+        }
+        if let (Some(log), Ok(message)) = (callback.log, CString::new(message)) {
+            log(LOG_WARN, b"%s\n\0".as_ptr() as *const c_char, message.as_ptr());
+        }
+    }
+}
+
+struct Core {
+    cpu: nes::cpu::Cpu,
+    machine: nes::Machine,
+    video: Vec<u32>,
+}
+
+// The frontend calls the core from one thread at a time, so the machine,
+// which is not Send, can be kept in a static
+struct CoreCell(RefCell<Option<Core>>);
+
+// SAFETY: the libretro API has the frontend make its calls into the core one
+// at a time, never from two threads at once, so the RefCell is never
+// borrowed concurrently. The Machine is only not Send because of the video,
+// audio and input trait objects a frontend can give it, which this core
+// never sets, so nothing in the Core is tied to the thread that made it.
+unsafe impl Sync for CoreCell {}
+
+static CORE: CoreCell = CoreCell(RefCell::new(None));
+
+fn with_core<T>(f: impl FnOnce(&mut Core) -> T) -> Option<T> {
+    CORE.0.borrow_mut().as_mut().map(f)
+}
+
+fn frames_per_second(region: nes::Region) -> f64 {
+    // CPU cycles per frame, with the skipped dot of every other NTSC frame
+    match region {
+        nes::Region::Ntsc => region.cpu_frequency() / 29780.5,
+        nes::Region::Pal => region.cpu_frequency() / 33247.5,
+    }
+}
+
+fn to_xrgb8888(picture: &[u8], video: &mut Vec<u32>) {
+    video.clear();
+    video.extend(picture.chunks(3).map(|p| (p[0] as u32) << 16 | (p[1] as u32) << 8 | p[2] as u32));
+}
+
+// Mono samples from -1.0 to 1.0 as interleaved 16 bit stereo
+fn to_stereo_i16(samples: &[f32]) -> Vec<i16> {
+    samples.iter()
+        .flat_map(|s| {
+            let sample = (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            [sample, sample]
+        })
+        .collect()
+}
+
+// Codes in the raw format of RetroArch's cheat files, "AAAA:VV" or
+// "AAAA?CC:VV" with a compare value, several joined with '+'
+fn parse_cheats(codes: &str) -> Result<Vec<nes::Cheat>, String> {
+    let hex = |s: &str| u16::from_str_radix(s, 16).map_err(|_| format!("Invalid cheat code: {}", codes));
+    codes.split('+').map(|code| {
+        let (target, value) = code.trim().split_once(':')
+            .ok_or_else(|| format!("Invalid cheat code: {}", codes))?;
+        let (address, compare) = match target.split_once('?') {
+            Some((address, compare)) => (address, Some(hex(compare)? as u8)),
+            None => (target, None),
+        };
+        Ok(nes::Cheat { address: hex(address)?, value: hex(value)? as u8, compare })
+    }).collect()
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> u32 {
+    API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(environment: EnvironmentFn) {
+    CALLBACKS.lock().unwrap().environment = Some(environment);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(video_refresh: VideoRefreshFn) {
+    CALLBACKS.lock().unwrap().video_refresh = Some(video_refresh);
+}
+
+// The sound is always given a frame at a time, through the batch callback
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_audio_sample: AudioSampleFn) {
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(audio_sample_batch: AudioSampleBatchFn) {
+    CALLBACKS.lock().unwrap().audio_sample_batch = Some(audio_sample_batch);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(input_poll: InputPollFn) {
+    CALLBACKS.lock().unwrap().input_poll = Some(input_poll);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(input_state: InputStateFn) {
+    CALLBACKS.lock().unwrap().input_state = Some(input_state);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {
+}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *CORE.0.borrow_mut() = None;
+}
+
+/// # Safety
+/// `info` must point to a system info struct to fill in.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    *info = SystemInfo {
+        library_name: b"nesemu\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"nes\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+/// `info` must point to an AV info struct to fill in.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    let region = with_core(|core| core.machine.region()).unwrap_or(nes::Region::Ntsc);
+    let sample_rate = with_core(|core| core.machine.audio_sample_rate()).unwrap_or(44100);
+    *info = SystemAvInfo {
+        geometry: GameGeometry {
+            base_width: WIDTH,
+            base_height: HEIGHT,
+            max_width: WIDTH,
+            max_height: HEIGHT,
+            // The pixels are a little wider than high
+            aspect_ratio: (WIDTH as f32 * 8.0 / 7.0) / HEIGHT as f32,
+        },
+        timing: SystemTiming { fps: frames_per_second(region), sample_rate: sample_rate as f64 },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: u32, _device: u32) {
+}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    with_core(|core| nes::reset(&mut core.cpu, &mut core.machine));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let callbacks = callbacks();
+    // A panic must not unwind into the frontend. It leaves the machine in an
+    // unknown state, so the game is unloaded and the frontend gets no more
+    // frames from it.
+    let frame = panic::catch_unwind(AssertUnwindSafe(|| with_core(|core| unsafe {
+        if let Some(input_poll) = callbacks.input_poll {
+            input_poll();
+        }
+        if let Some(input_state) = callbacks.input_state {
+            for port in 0..2 {
+                let mut buttons = [false; 8];
+                for (button, id) in buttons.iter_mut().zip(JOYPAD_IDS.iter()) {
+                    *button = input_state(port, DEVICE_JOYPAD, 0, *id) != 0;
+                }
+                core.machine.controller.set_player_buttons(port as usize + 1, buttons);
+            }
+        }
+        // A game that stops the CPU keeps showing its last picture, without
+        // sound, until it is reset
+        let samples = match nes::try_run_frame(&mut core.cpu, &mut core.machine) {
+            Ok(frame) => frame.samples,
+            Err(_) => Vec::new(),
+        };
+        to_xrgb8888(core.machine.frame_buffer(), &mut core.video);
+        if let Some(video_refresh) = callbacks.video_refresh {
+            video_refresh(core.video.as_ptr() as *const c_void, WIDTH, HEIGHT, WIDTH as usize * 4);
+        }
+        if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+            let audio = to_stereo_i16(&samples);
+            let mut frames_left = &audio[..];
+            while !frames_left.is_empty() {
+                let written = audio_sample_batch(frames_left.as_ptr(), frames_left.len() / 2);
+                if written == 0 {
+                    break;
+                }
+                frames_left = &frames_left[(written * 2).min(frames_left.len())..];
+            }
+        }
+    })));
+    if frame.is_err() {
+        *CORE.0.borrow_mut() = None;
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    with_core(|core| nes::snapshot(&core.cpu, &core.machine).to_bytes().len()).unwrap_or(0)
+}
+
+/// # Safety
+/// `data` must point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let state = match with_core(|core| nes::snapshot(&core.cpu, &core.machine).to_bytes()) {
+        Some(state) if state.len() <= size => state,
+        _ => return false,
+    };
+    ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+    true
+}
+
+/// # Safety
+/// `data` must point to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let bytes = slice::from_raw_parts(data as *const u8, size);
+    let state = match nes::state::Snapshot::from_bytes(bytes) {
+        Ok(state) => state,
+        Err(_) => return false,
+    };
+    with_core(|core| nes::load_snapshot(&mut core.cpu, &mut core.machine, &state).is_ok())
+        .unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {
+    with_core(|core| {
+        let addresses: Vec<u16> = core.machine.cheats().iter().map(|c| c.address).collect();
+        for address in addresses {
+            core.machine.remove_cheat(address);
+        }
+    });
+}
+
+/// # Safety
+/// `code` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn retro_cheat_set(_index: u32, enabled: bool, code: *const c_char) {
+    if code.is_null() {
+        return;
+    }
+    let cheats = match parse_cheats(&CStr::from_ptr(code).to_string_lossy()) {
+        Ok(cheats) => cheats,
+        Err(e) => {
+            log_warning(&e);
+            return;
+        }
+    };
+    with_core(|core| {
+        for cheat in cheats {
+            if enabled {
+                core.machine.add_cheat(cheat);
+            }
+            else {
+                core.machine.remove_cheat(cheat.address);
+            }
+        }
+    });
+}
+
+/// # Safety
+/// `game` must point to a game info struct with the contents of a .nes file.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+    let rom = slice::from_raw_parts((*game).data as *const u8, (*game).size);
+    let cartridge = match nes::cartridge::Cartridge::from_bytes(rom) {
+        Ok(cartridge) => cartridge,
+        Err(_) => return false,
+    };
+    if let Some(environment) = callbacks().environment {
+        let mut format = PIXEL_FORMAT_XRGB8888;
+        if !environment(ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut u32 as *mut c_void) {
+            return false;
+        }
+    }
+
+    let mut machine = nes::Machine::new_headless();
+    if let Some(region) = cartridge.region() {
+        machine.set_region(region);
+    }
+    machine.load_cartridge(cartridge);
+    machine.controller.plug_in(2);
+    let mut cpu = nes::cpu::Cpu::new();
+    if panic::catch_unwind(AssertUnwindSafe(|| cpu.reset(&mut machine))).is_err() {
+        return false;
+    }
+    *CORE.0.borrow_mut() = Some(Core { cpu, machine, video: Vec::new() });
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: u32, _info: *const GameInfo,
+                                          _num_info: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *CORE.0.borrow_mut() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> u32 {
+    match with_core(|core| core.machine.region()) {
+        Some(nes::Region::Pal) => REGION_PAL,
+        _ => REGION_NTSC,
+    }
+}
+
+// The battery backed RAM, which the frontend saves and loads
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: u32) -> *mut c_void {
+    if id != MEMORY_SAVE_RAM {
+        return ptr::null_mut();
+    }
+    with_core(|core| core.machine.battery_ram_mut().map(|ram| ram.as_mut_ptr() as *mut c_void))
+        .flatten()
+        .unwrap_or(ptr::null_mut())
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: u32) -> usize {
+    if id != MEMORY_SAVE_RAM {
+        return 0;
+    }
+    with_core(|core| core.machine.battery_ram_mut().map_or(0, |ram| ram.len())).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cheat_codes_in_the_raw_format() {
+        assert_eq!(parse_cheats("0075:09").unwrap(),
+                   [nes::Cheat { address: 0x0075, value: 0x09, compare: None }]);
+        assert_eq!(parse_cheats("C123?A5:EA+0010:FF").unwrap(),
+                   [nes::Cheat { address: 0xC123, value: 0xEA, compare: Some(0xA5) },
+                    nes::Cheat { address: 0x0010, value: 0xFF, compare: None }]);
+        assert!(parse_cheats("SXIOPO").is_err());
+    }
+
+    #[test]
+    fn picture_and_sound_conversions() {
+        let mut video = Vec::new();
+        to_xrgb8888(&[0x12, 0x34, 0x56, 0xFF, 0x00, 0x80], &mut video);
+        assert_eq!(video, [0x123456, 0xFF0080]);
+        assert_eq!(to_stereo_i16(&[0.0, 1.0, -2.0]), [0, 0, 32767, 32767, -32767, -32767]);
+        assert!((frames_per_second(nes::Region::Ntsc) - 60.0988).abs() < 0.001);
+        assert!((frames_per_second(nes::Region::Pal) - 50.007).abs() < 0.001);
+    }
+}
@@ -0,0 +1,192 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+
+use nesemu::nes;
+use nesemu::nes::screen::{HEIGHT, WIDTH};
+
+use crate::played;
+
+// The list of games shown when nesemu is started without a command: the
+// games played last, then the ROMs in the ROM directory of the
+// configuration, by default the current directory. The arrow keys choose
+// one, Return plays it and Escape quits.
+
+const MAX_RECENT: usize = 10;
+
+const LINE_HEIGHT: u32 = 8;
+// The first line of the list, below the heading
+const LIST_TOP: u32 = 24;
+const VISIBLE_LINES: usize = ((HEIGHT - LIST_TOP - 24) / LINE_HEIGHT) as usize;
+// Characters of the font are 4 pixels wide
+const MAX_NAME_LENGTH: usize = ((WIDTH - 24) / 4) as usize;
+
+const BACKGROUND: [u8; 3] = [0x10, 0x10, 0x30];
+const SELECTION: [u8; 3] = [0xFF, 0xC0, 0x40];
+
+// The .nes and .zip files in the directory, by name
+pub fn list_roms(dir: &Path) -> Vec<PathBuf> {
+    let mut roms: Vec<PathBuf> = fs::read_dir(dir).into_iter().flatten().flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file() && matches!(path.extension().and_then(|e| e.to_str()), Some("nes") | Some("zip"))
+        })
+        .collect();
+    roms.sort_by_key(|path| path.file_name().map(|name| name.to_string_lossy().to_lowercase()));
+    roms
+}
+
+struct Browser {
+    // The recently played games come first
+    roms: Vec<PathBuf>,
+    // The playtime of each recently played game
+    playtimes: Vec<u64>,
+    selected: usize,
+    // The first line shown
+    top: usize,
+}
+
+impl Browser {
+    fn new(recent: Vec<played::Game>, roms: Vec<PathBuf>) -> Browser {
+        let playtimes = recent.iter().map(|game| game.playtime_seconds).collect();
+        let roms = recent.into_iter().map(|game| game.path).chain(roms).collect();
+        Browser { roms, playtimes, selected: 0, top: 0 }
+    }
+
+    fn move_selection(&mut self, lines: isize) {
+        if self.roms.is_empty() {
+            return;
+        }
+        let last = self.roms.len() as isize - 1;
+        self.selected = (self.selected as isize + lines).clamp(0, last) as usize;
+        if self.selected < self.top {
+            self.top = self.selected;
+        }
+        else if self.selected >= self.top + VISIBLE_LINES {
+            self.top = self.selected + 1 - VISIBLE_LINES;
+        }
+    }
+
+    // The lines shown, recently played games marked with a star and followed
+    // by their playtime
+    fn lines(&self) -> Vec<String> {
+        self.roms.iter().enumerate().skip(self.top).take(VISIBLE_LINES)
+            .map(|(i, rom)| {
+                let name = rom.file_stem().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+                match self.playtimes.get(i) {
+                    Some(&seconds) => {
+                        let playtime = played::format_playtime(seconds);
+                        let name: String = name.chars().take(MAX_NAME_LENGTH - 3 - playtime.len()).collect();
+                        format!("* {:width$} {}", name, playtime, width = MAX_NAME_LENGTH - 3 - playtime.len())
+                    }
+                    None => format!("  {}", name).chars().take(MAX_NAME_LENGTH).collect(),
+                }
+            })
+            .collect()
+    }
+
+    fn draw(&self, screen: &mut nes::screen::Screen, dir: &Path) {
+        screen.draw_text(8, 8, "Choose a game, Return plays it");
+        if self.roms.is_empty() {
+            screen.draw_text(8, LIST_TOP, &format!("No ROMs in {}", dir.display()));
+            screen.draw_text(8, LIST_TOP + LINE_HEIGHT, "Set rom_dir in the configuration");
+        }
+        for (i, line) in self.lines().iter().enumerate() {
+            let y = LIST_TOP + i as u32 * LINE_HEIGHT;
+            screen.draw_text(12, y, line);
+            if self.top + i == self.selected {
+                screen.draw_box(8, y - 2, WIDTH - 16, LINE_HEIGHT + 1, SELECTION);
+            }
+        }
+        if !self.playtimes.is_empty() {
+            screen.draw_text(8, HEIGHT - 16, "* Played recently, hours:minutes played");
+        }
+        let background: Vec<u8> = BACKGROUND.iter().copied().cycle().take((WIDTH * HEIGHT * 3) as usize).collect();
+        screen.present(&background, None, None);
+    }
+}
+
+// Shows the games in a window until one is chosen, or None if the window is
+// closed
+pub fn choose(dir: &Path) -> Result<Option<PathBuf>, String> {
+    let mut browser = Browser::new(played::recent(MAX_RECENT), list_roms(dir));
+    let mut sdl_context = sdl2::init()?;
+    let mut screen = nes::screen::Screen::new(&mut sdl_context, false);
+    let mut event_pump = sdl_context.event_pump()?;
+    browser.draw(&mut screen, dir);
+    loop {
+        let event = event_pump.wait_event();
+        for event in std::iter::once(event).chain(event_pump.poll_iter()) {
+            match event {
+                Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    return Ok(None);
+                }
+                Event::KeyDown { keycode: Some(Keycode::Return), .. }
+                | Event::KeyDown { keycode: Some(Keycode::KpEnter), .. } => {
+                    if let Some(rom) = browser.roms.get(browser.selected) {
+                        return Ok(Some(rom.clone()));
+                    }
+                }
+                Event::KeyDown { keycode: Some(keycode), .. } => {
+                    let page = VISIBLE_LINES as isize;
+                    match keycode {
+                        Keycode::Up => browser.move_selection(-1),
+                        Keycode::Down => browser.move_selection(1),
+                        Keycode::PageUp => browser.move_selection(-page),
+                        Keycode::PageDown => browser.move_selection(page),
+                        Keycode::Home => browser.move_selection(isize::MIN / 2),
+                        Keycode::End => browser.move_selection(isize::MAX / 2),
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        browser.draw(&mut screen, dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn roms_and_recent_games_are_listed() {
+        let dir = env::temp_dir().join(format!("nesemu-browser-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in &["b.nes", "A.zip", "c.txt", "d.nes.sav"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        assert_eq!(list_roms(&dir), [dir.join("A.zip"), dir.join("b.nes")]);
+
+        let recent = played::Game { path: dir.join("b.nes"), crc32: "00000000".to_string(),
+                                    last_played: 0, playtime_seconds: 3 * 3600 + 120 };
+        let mut browser = Browser::new(vec![recent], list_roms(&dir));
+        let lines = browser.lines();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("* b ") && lines[0].ends_with(" 3:02"));
+        assert_eq!(lines[0].len(), MAX_NAME_LENGTH);
+        assert_eq!(lines[1..], ["  A", "  b"]);
+        browser.move_selection(5);
+        assert_eq!(browser.selected, 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_selection_stays_in_view() {
+        let roms = (0..100).map(|i| PathBuf::from(format!("{}.nes", i))).collect();
+        let mut browser = Browser::new(Vec::new(), roms);
+        browser.move_selection(VISIBLE_LINES as isize);
+        assert_eq!((browser.selected, browser.top), (VISIBLE_LINES, 1));
+        assert_eq!(browser.lines()[0], "  1");
+        browser.move_selection(-3);
+        assert_eq!(browser.top, 1);
+        browser.move_selection(isize::MAX / 2);
+        assert_eq!((browser.selected, browser.top), (99, 100 - VISIBLE_LINES));
+        browser.move_selection(isize::MIN / 2);
+        assert_eq!((browser.selected, browser.top), (0, 0));
+    }
+}
@@ -0,0 +1,521 @@
+use std::path::PathBuf;
+
+use crate::nes::{FastForwardAudio, LeftClipMode, PpuVariant, RamInit, Region, SPEED_RANGE};
+use crate::nes::screen::VideoFilter;
+use crate::clock::ClockMode;
+use crate::netplay;
+
+pub const USAGE: &str = "\
+Usage:
+    nesemu
+    nesemu run <rom> [options]
+    nesemu disassemble <rom> <start> <end> [--trace] [--cdl <file>]
+    nesemu test <rom>
+    nesemu selftest
+    nesemu bench <rom> [--frames <n>]
+    nesemu diff <state> <state>
+    nesemu import-save <rom> <file> [--force]
+    nesemu watch <host:port>
+    nesemu list
+    nesemu help
+
+Without a command, nesemu lists the ROMs in rom_dir of the configuration (by default the
+current directory) and the games played recently, to choose one to play.
+
+Commands:
+    run            Play a ROM
+    disassemble    Disassemble the CPU address range <start>-<end> (hexadecimal). With
+                   a code/data log from run --cdl, bytes only read as data are shown as .db.
+                   --trace follows the code from the reset, NMI and IRQ vectors instead
+                   of reading the range in order, and labels the jump and branch targets
+    test           Run a test ROM that reports its result at $6000 and print the result,
+                   and the text it writes to the debug port
+    selftest       Check the cycle counts and flag results of the CPU instructions, without ROMs
+    bench          Run a ROM without window and sound as fast as possible for <n> frames
+                   (default 1000) and print the speed and the time taken by the CPU, PPU and APU
+    diff           Print the differences between two machine states saved with Shift+F1-F10
+    import-save    Use the save RAM from a battery save (.sav/.srm) or FCEUX state (.fc0-.fc9)
+                   as the save file of the ROM, --force overwrites an existing save file
+    watch          Watch the game another nesemu streams with --stream, without playing
+    list           Print the games played, the last played first, with the time played
+
+Options for run:
+    --scale <n>            Window size as a multiple of 256x240 (default 2)
+    --aspect-correction    Stretch the picture to the 8:7 pixel aspect ratio
+    --fullscreen           Start in fullscreen (toggle with Alt+Enter)
+    --filter <nearest|bilinear|scanlines|aperture>
+                           How to scale the picture: sharp pixels (default), smoothed, or
+                           with scan lines or an aperture grille drawn over it (cycle with F)
+    --frame-blend <percent>
+                           Mix this much of the previous frame into each frame, so that
+                           flickering sprites look transparent (default 0, off)
+    --palette <file>       Load colors from a 192 byte .pal file
+    --sample-rate <hz>     Audio sample rate (default 44100)
+    --audio-channels <1|2> Play the sound in mono (default) or in both stereo channels
+    --audio-device-buffer <samples>
+                           Samples the audio device asks for at a time, a power of two like
+                           512 (by default chosen by SDL)
+    --audio-buffer-ms <ms> Sound kept queued ahead of the audio device; less gives lower
+                           latency, but may crackle (default 35)
+    --fast-forward-audio <mute|drop|pitch>
+                           Sound while fast-forwarding with Tab: none, running as fast as
+                           possible (default), or parts of it or all of it at a higher
+                           pitch, running 4 times as fast
+    --speed <percent>      Run slower or faster than the console, from 25 to 400, with the
+                           sound pitched along (default 100, change with - and =)
+    --clock <audio|vsync|free>
+                           What keeps the game at its speed: the sound (default), the
+                           refresh of the display, or the clock of the computer
+    --show-fps             Show the frames per second and how far they drift from the
+                           frame rate of the console
+    --reduce-pops          Hold the triangle channel still when a game sets it to an
+                           ultrasonic period, instead of letting it pop like the console
+    --region <ntsc|pal>    Console region to emulate (default ntsc)
+    --ppu <ntsc|pal|dendy|rgb>
+                           PPU to emulate, for games made for the Dendy or the RGB PPU of
+                           the PlayChoice-10 (by default that of the region)
+    --config <file>        Read settings from this file instead of the default location
+    --resume               Continue from where the game was when nesemu last exited
+    --no-resume            Start from power-on even if resume is set in the configuration
+    --movie <file>         Play back the input of an FCEUX movie (.fm2), including its
+                           resets and power cycles
+    --ram-init <zero|ff|pages|random>
+                           Contents of the work RAM at power-on: zeros (default), 0xFF,
+                           pages of 0x00 and 0xFF in turn, or random
+    --seed <n>             Seed for the random RAM contents, to make runs repeatable
+                           (by default a new seed is used every time)
+    --pause-in-background  Pause the game, and its sound, while another program has the
+                           keyboard focus
+    --swap-ports           Connect the controller to port 2 (toggle with F12)
+    --four-score           Connect the Four Score adapter for players 3 and 4
+    --left-column <clip|dim|black|show>
+                           How to show the leftmost 8 pixels when the game hides them
+                           (default clip, which shows the backdrop color like the console)
+    --show-nametable       Show the name tables and the oscilloscope in separate windows
+                           (toggle with F11)
+    --ppu-warm-up          Ignore writes to $2000, $2001, $2005 and $2006 for the first
+                           frame after power-on and reset, like the console
+    --bus-conflicts <on|off>
+                           Whether writes to the bank registers of the discrete boards are
+                           ANDed with the ROM byte at the address (by default from the
+                           mapper and the NES 2.0 submapper)
+    --cycle-accurate       Step the machine on every CPU memory access, for games that
+                           depend on exact timing (slower)
+    --trace-scroll         Log the scroll registers (v, t, x, w) after each write to them,
+                           like RUST_LOG=ppu=trace
+    --mitigate-dmc-conflict
+                           Hide the controller input corruption caused by DMC DMA
+    --measure-latency      Flash the screen on button presses and report input latency
+    --debug-port           Print the text the ROM writes to $4018, a line at a time
+    --console              Read debugging commands from the terminal, like RAM search and
+                           cheats (type help for the commands)
+    --cdl <file>           Record which bytes of the PRG ROM are executed as code and which
+                           are read as data, in an FCEUX .cdl file. An existing log is
+                           continued.
+    --apu-log <file.vgm>   Record the writes to the sound registers and save them as a VGM
+                           file on exit, for playing or studying the music
+    --clip-seconds <n>     Keep the last <n> seconds of pictures, saved as an animated GIF
+                           with G (by default none are kept)
+    --history <n>          Keep the last <n> executed instructions and print them if the
+                           emulator crashes
+    --script <file.rhai>   Run a Rhai script along with the game, see the README
+    --host <port>          Wait for a second player to connect for netplay, and play as
+                           player 1
+    --connect <host:port>  Join the netplay game of another nesemu as player 2
+    --input-delay <frames> Frames between pressing a button and the game seeing it during
+                           netplay, which hides the network latency (default 2, set by the
+                           host)
+    --remote <port|path>   Take JSON requests from test scripts and other programs on a
+                           TCP port on localhost, or a Unix socket, see the README
+    --stream <port>        Let spectators connect to the port with nesemu watch and see and
+                           hear the game
+
+Settings not given on the command line are read from the configuration file,
+by default $XDG_CONFIG_HOME/nesemu/config.toml or ~/.config/nesemu/config.toml.";
+
+pub struct RunOptions {
+    pub scale: Option<u32>,
+    pub aspect_correction: bool,
+    pub fullscreen: bool,
+    pub filter: Option<VideoFilter>,
+    pub frame_blend: Option<u32>,
+    pub palette: Option<PathBuf>,
+    pub sample_rate: Option<u32>,
+    pub audio_channels: Option<u8>,
+    pub audio_device_buffer: Option<u16>,
+    pub audio_buffer_ms: Option<u32>,
+    pub fast_forward_audio: Option<FastForwardAudio>,
+    pub speed: Option<u32>,
+    pub clock: Option<ClockMode>,
+    pub show_fps: bool,
+    pub reduce_pops: bool,
+    pub region: Option<Region>,
+    pub ppu_variant: Option<PpuVariant>,
+    pub config: Option<PathBuf>,
+    pub movie: Option<PathBuf>,
+    pub resume: Option<bool>,
+    pub ram_init: RamInit,
+    pub seed: Option<u64>,
+    pub swap_ports: bool,
+    pub four_score: bool,
+    pub pause_in_background: bool,
+    pub left_clip_mode: LeftClipMode,
+    pub ppu_warm_up: bool,
+    pub bus_conflicts: Option<bool>,
+    pub show_name_table: bool,
+    pub trace_scroll: bool,
+    pub cycle_accurate: bool,
+    pub measure_latency: bool,
+    pub debug_port: bool,
+    pub mitigate_dmc_conflict: bool,
+    pub console: bool,
+    pub script: Option<PathBuf>,
+    pub cdl: Option<PathBuf>,
+    pub apu_log: Option<PathBuf>,
+    pub clip_seconds: Option<u32>,
+    pub history: usize,
+    pub netplay: Option<Netplay>,
+    pub input_delay: u32,
+    pub remote: Option<String>,
+    pub stream: Option<u16>,
+}
+
+pub enum Netplay {
+    Host(u16),
+    Connect(String),
+}
+
+const DEFAULT_BENCH_FRAMES: u32 = 1000;
+
+pub enum Command {
+    Help,
+    Run { rom: PathBuf, options: Box<RunOptions> },
+    Disassemble { rom: PathBuf, start: u16, end: u16, trace: bool, cdl: Option<PathBuf> },
+    Test { rom: PathBuf },
+    SelfTest,
+    Bench { rom: PathBuf, frames: u32 },
+    Diff { first: PathBuf, second: PathBuf },
+    ImportSave { rom: PathBuf, source: PathBuf, overwrite: bool },
+    Watch { address: String },
+    List,
+    // Choose the ROM to run from a list
+    Browse,
+}
+
+fn parse_address(value: Option<&String>, name: &str) -> Result<u16, String> {
+    let value = value.ok_or(format!("Missing {} address", name))?;
+    let value = value.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(value, 16).map_err(|_| format!("Invalid {} address: {}", name, value))
+}
+
+impl Default for RunOptions {
+    fn default() -> RunOptions {
+        RunOptions {
+            scale: None,
+            aspect_correction: false,
+            fullscreen: false,
+            filter: None,
+            frame_blend: None,
+            palette: None,
+            sample_rate: None,
+            audio_channels: None,
+            audio_device_buffer: None,
+            audio_buffer_ms: None,
+            fast_forward_audio: None,
+            speed: None,
+            clock: None,
+            show_fps: false,
+            reduce_pops: false,
+            region: None,
+            ppu_variant: None,
+            config: None,
+            movie: None,
+            resume: None,
+            ram_init: RamInit::Zero,
+            seed: None,
+            swap_ports: false,
+            four_score: false,
+            pause_in_background: false,
+            left_clip_mode: LeftClipMode::Clip,
+            ppu_warm_up: false,
+            bus_conflicts: None,
+            show_name_table: false,
+            trace_scroll: false,
+            cycle_accurate: false,
+            measure_latency: false,
+            debug_port: false,
+            mitigate_dmc_conflict: false,
+            console: false,
+            script: None,
+            cdl: None,
+            apu_log: None,
+            clip_seconds: None,
+            history: 0,
+            netplay: None,
+            input_delay: netplay::DEFAULT_INPUT_DELAY,
+            remote: None,
+            stream: None,
+        }
+    }
+}
+
+fn parse_run_options(args: &[String]) -> Result<RunOptions, String> {
+    let mut options = RunOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scale" => {
+                let scale = args.next().and_then(|s| s.parse().ok()).filter(|s| *s >= 1)
+                    .ok_or("--scale requires a positive number, e.g. --scale 3")?;
+                options.scale = Some(scale);
+            }
+            "--aspect-correction" => { options.aspect_correction = true; }
+            "--fullscreen" => { options.fullscreen = true; }
+            "--filter" => {
+                let filter = args.next().and_then(|s| VideoFilter::from_name(s))
+                    .ok_or("--filter must be nearest, bilinear, scanlines or aperture")?;
+                options.filter = Some(filter);
+            }
+            "--frame-blend" => {
+                let strength = args.next().and_then(|s| s.parse().ok()).filter(|s| *s <= 100)
+                    .ok_or("--frame-blend requires a percentage, e.g. --frame-blend 50")?;
+                options.frame_blend = Some(strength);
+            }
+            "--palette" => {
+                let path = args.next().ok_or("--palette requires a file name")?;
+                options.palette = Some(PathBuf::from(path));
+            }
+            "--sample-rate" => {
+                let rate = args.next().and_then(|s| s.parse().ok())
+                    .ok_or("--sample-rate requires a number of Hz, e.g. --sample-rate 48000")?;
+                options.sample_rate = Some(rate);
+            }
+            "--audio-channels" => {
+                let channels = args.next().and_then(|s| s.parse().ok())
+                    .ok_or("--audio-channels requires 1 or 2")?;
+                options.audio_channels = Some(channels);
+            }
+            "--audio-device-buffer" => {
+                let samples = args.next().and_then(|s| s.parse().ok())
+                    .ok_or("--audio-device-buffer requires a number of samples, e.g. --audio-device-buffer 512")?;
+                options.audio_device_buffer = Some(samples);
+            }
+            "--audio-buffer-ms" => {
+                let ms = args.next().and_then(|s| s.parse().ok())
+                    .ok_or("--audio-buffer-ms requires a number of milliseconds, e.g. --audio-buffer-ms 50")?;
+                options.audio_buffer_ms = Some(ms);
+            }
+            "--fast-forward-audio" => {
+                let audio = args.next().and_then(|s| FastForwardAudio::from_name(s))
+                    .ok_or("--fast-forward-audio must be mute, drop or pitch")?;
+                options.fast_forward_audio = Some(audio);
+            }
+            "--speed" => {
+                let speed = args.next().and_then(|s| s.parse().ok()).filter(|s| SPEED_RANGE.contains(s))
+                    .ok_or("--speed requires a percentage from 25 to 400, e.g. --speed 50")?;
+                options.speed = Some(speed);
+            }
+            "--clock" => {
+                let clock = args.next().and_then(|s| ClockMode::from_name(s))
+                    .ok_or("--clock must be audio, vsync or free")?;
+                options.clock = Some(clock);
+            }
+            "--show-fps" => { options.show_fps = true; }
+            "--region" => {
+                let region = args.next().and_then(|s| Region::from_name(s))
+                    .ok_or("--region must be ntsc or pal")?;
+                options.region = Some(region);
+            }
+            "--ppu" => {
+                let variant = args.next().and_then(|s| PpuVariant::from_name(s))
+                    .ok_or("--ppu must be ntsc, pal, dendy or rgb")?;
+                options.ppu_variant = Some(variant);
+            }
+            "--config" => {
+                let path = args.next().ok_or("--config requires a file name")?;
+                options.config = Some(PathBuf::from(path));
+            }
+            "--movie" => {
+                let path = args.next().ok_or("--movie requires a file name")?;
+                options.movie = Some(PathBuf::from(path));
+            }
+            "--ram-init" => {
+                options.ram_init = args.next().and_then(|s| RamInit::from_name(s))
+                    .ok_or("--ram-init must be zero, ff, pages or random")?;
+            }
+            "--seed" => {
+                let seed = args.next().and_then(|s| s.parse().ok())
+                    .ok_or("--seed requires a number, e.g. --seed 1234")?;
+                options.seed = Some(seed);
+            }
+            "--resume" => { options.resume = Some(true); }
+            "--no-resume" => { options.resume = Some(false); }
+            "--swap-ports" => { options.swap_ports = true; }
+            "--four-score" => { options.four_score = true; }
+            "--pause-in-background" => { options.pause_in_background = true; }
+            "--reduce-pops" => { options.reduce_pops = true; }
+            "--left-column" => {
+                options.left_clip_mode = args.next().and_then(|s| LeftClipMode::from_name(s))
+                    .ok_or("--left-column must be clip, dim, black or show")?;
+            }
+            "--ppu-warm-up" => { options.ppu_warm_up = true; }
+            "--bus-conflicts" => {
+                options.bus_conflicts = match args.next().map(|s| s.as_str()) {
+                    Some("on") => Some(true),
+                    Some("off") => Some(false),
+                    _ => { return Err("--bus-conflicts must be on or off".to_string()); }
+                };
+            }
+            "--show-nametable" => { options.show_name_table = true; }
+            "--trace-scroll" => { options.trace_scroll = true; }
+            "--cycle-accurate" => { options.cycle_accurate = true; }
+            "--measure-latency" => { options.measure_latency = true; }
+            "--debug-port" => { options.debug_port = true; }
+            "--mitigate-dmc-conflict" => { options.mitigate_dmc_conflict = true; }
+            "--console" => { options.console = true; }
+            "--script" => {
+                let path = args.next().ok_or("--script requires a file name")?;
+                options.script = Some(PathBuf::from(path));
+            }
+            "--history" => {
+                options.history = args.next().and_then(|s| s.parse().ok())
+                    .ok_or("--history requires a number of instructions, e.g. --history 100")?;
+            }
+            "--cdl" => {
+                let path = args.next().ok_or("--cdl requires a file name")?;
+                options.cdl = Some(PathBuf::from(path));
+            }
+            "--apu-log" => {
+                let path = args.next().ok_or("--apu-log requires a file name")?;
+                options.apu_log = Some(PathBuf::from(path));
+            }
+            "--clip-seconds" => {
+                let seconds = args.next().and_then(|s| s.parse().ok())
+                    .ok_or("--clip-seconds requires a number of seconds, e.g. --clip-seconds 10")?;
+                options.clip_seconds = Some(seconds);
+            }
+            "--host" => {
+                let port = args.next().and_then(|s| s.parse().ok())
+                    .ok_or("--host requires a port number, e.g. --host 4100")?;
+                options.netplay = Some(Netplay::Host(port));
+            }
+            "--connect" => {
+                let address = args.next().ok_or("--connect requires an address, e.g. --connect host:4100")?;
+                options.netplay = Some(Netplay::Connect(address.clone()));
+            }
+            "--input-delay" => {
+                options.input_delay = args.next().and_then(|s| s.parse().ok())
+                    .filter(|d| *d <= netplay::MAX_INPUT_DELAY)
+                    .ok_or(format!("--input-delay requires a number of frames up to {}",
+                                   netplay::MAX_INPUT_DELAY))?;
+            }
+            "--remote" => {
+                let address = args.next().ok_or("--remote requires a port number or a socket path")?;
+                options.remote = Some(address.clone());
+            }
+            "--stream" => {
+                let port = args.next().and_then(|s| s.parse().ok())
+                    .ok_or("--stream requires a port number, e.g. --stream 4200")?;
+                options.stream = Some(port);
+            }
+            option => { return Err(format!("Unknown option: {}", option)); }
+        }
+    }
+    Ok(options)
+}
+
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let command = match args.get(1) {
+        Some(command) => command,
+        None => { return Ok(Command::Browse); }
+    };
+    if command == "help" || command == "--help" || command == "-h" {
+        return Ok(Command::Help);
+    }
+    if command == "selftest" {
+        if args.len() > 2 {
+            return Err(format!("Unexpected argument: {}", args[2]));
+        }
+        return Ok(Command::SelfTest);
+    }
+    if command == "list" {
+        if args.len() > 2 {
+            return Err(format!("Unexpected argument: {}", args[2]));
+        }
+        return Ok(Command::List);
+    }
+    if command == "diff" {
+        let first = PathBuf::from(args.get(2).ok_or("Missing state files")?);
+        let second = PathBuf::from(args.get(3).ok_or("Missing second state file")?);
+        if args.len() > 4 {
+            return Err(format!("Unexpected argument: {}", args[4]));
+        }
+        return Ok(Command::Diff { first, second });
+    }
+    if command == "watch" {
+        let address = args.get(2).ok_or("Missing address, e.g. host:4200")?.clone();
+        if args.len() > 3 {
+            return Err(format!("Unexpected argument: {}", args[3]));
+        }
+        return Ok(Command::Watch { address });
+    }
+    if !["run", "disassemble", "test", "bench", "import-save"].contains(&command.as_str()) {
+        return Err(format!("Unknown command: {}", command));
+    }
+    let rom = PathBuf::from(args.get(2).ok_or("Missing ROM file")?);
+    match command.as_str() {
+        "run" => {
+            let options = parse_run_options(&args[3..])?;
+            Ok(Command::Run { rom, options: Box::new(options) })
+        }
+        "disassemble" => {
+            let start = parse_address(args.get(3), "start")?;
+            let end = parse_address(args.get(4), "end")?;
+            let mut trace = false;
+            let mut cdl = None;
+            let mut options = args[5.min(args.len())..].iter();
+            while let Some(arg) = options.next() {
+                match arg.as_str() {
+                    "--trace" => { trace = true; }
+                    "--cdl" => {
+                        let path = options.next().ok_or("--cdl requires a file name")?;
+                        cdl = Some(PathBuf::from(path));
+                    }
+                    arg => { return Err(format!("Unexpected argument: {}", arg)); }
+                }
+            }
+            Ok(Command::Disassemble { rom, start, end, trace, cdl })
+        }
+        "import-save" => {
+            let source = PathBuf::from(args.get(3).ok_or("Missing save file")?);
+            let overwrite = match args.get(4).map(|s| s.as_str()) {
+                None => false,
+                Some("--force") => true,
+                Some(arg) => { return Err(format!("Unexpected argument: {}", arg)); }
+            };
+            if args.len() > 5 {
+                return Err(format!("Unexpected argument: {}", args[5]));
+            }
+            Ok(Command::ImportSave { rom, source, overwrite })
+        }
+        "test" => {
+            if args.len() > 3 {
+                return Err(format!("Unexpected argument: {}", args[3]));
+            }
+            Ok(Command::Test { rom })
+        }
+        "bench" => {
+            let frames = match args.get(3).map(|s| s.as_str()) {
+                None => DEFAULT_BENCH_FRAMES,
+                Some("--frames") => args.get(4).and_then(|s| s.parse().ok()).filter(|n| *n > 0)
+                    .ok_or("--frames requires a number, e.g. --frames 1000")?,
+                Some(arg) => { return Err(format!("Unexpected argument: {}", arg)); }
+            };
+            if args.len() > 5 {
+                return Err(format!("Unexpected argument: {}", args[5]));
+            }
+            Ok(Command::Bench { rom, frames })
+        }
+        _ => unreachable!(),
+    }
+}
@@ -0,0 +1,205 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Keeps the game at its speed. By default the sound sets the pace: a frame
+// is run whenever the sound queued for the audio device runs low, so that it
+// never runs out. With vsync the display does instead, as showing a picture
+// waits for its next refresh, and the free running clock of the computer
+// runs the frames at the frame rate of the console whatever the sound and
+// the display do. The last two let more sound than asked for queue up, or
+// run out now and then, when their rate differs from that of the sound; the
+// drift shows by how much.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ClockMode {
+    Audio,
+    Vsync,
+    Free,
+}
+
+impl ClockMode {
+    pub fn from_name(name: &str) -> Option<ClockMode> {
+        match name {
+            "audio" => Some(ClockMode::Audio),
+            "vsync" => Some(ClockMode::Vsync),
+            "free" => Some(ClockMode::Free),
+            _ => None,
+        }
+    }
+}
+
+// The sound is never let further behind the picture than this, in any mode
+const MAX_QUEUED_AUDIO_MS: usize = 200;
+
+// The free running clock gives up catching up after falling this many frames
+// behind, e.g. when the computer was busy
+const MAX_FRAMES_BEHIND: u32 = 5;
+
+// While paused, the picture is updated this often
+const IDLE_INTERVAL: Duration = Duration::from_millis(1000 / 60);
+const BACKGROUND_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct FrameClock {
+    mode: ClockMode,
+    audio_buffer_ms: usize,
+    frame_time: Duration,
+    // When the next frame is due, for the free running clock
+    next_frame: Instant,
+    // The frames run since the frame rate changed or the game paused
+    measure_start: Instant,
+    measured_frames: u32,
+    // The frame rate over the last second
+    fps: f64,
+    fps_start: Instant,
+    fps_frames: u32,
+}
+
+impl FrameClock {
+    pub fn new(mode: ClockMode, frame_rate: f64, audio_buffer_ms: usize) -> FrameClock {
+        let now = Instant::now();
+        FrameClock {
+            mode,
+            audio_buffer_ms,
+            frame_time: Duration::from_secs_f64(1.0 / frame_rate),
+            next_frame: now,
+            measure_start: now,
+            measured_frames: 0,
+            fps: 0.0,
+            fps_start: now,
+            fps_frames: 0,
+        }
+    }
+
+    // E.g. when the speed of the game changes
+    pub fn set_frame_rate(&mut self, frame_rate: f64) {
+        let frame_time = Duration::from_secs_f64(1.0 / frame_rate);
+        if frame_time != self.frame_time {
+            self.frame_time = frame_time;
+            self.restart(Instant::now());
+        }
+    }
+
+    // Called after each frame that has been run and shown, with the sound
+    // queued for the audio device. Waits until the next frame is due.
+    pub fn end_frame(&mut self, queued_audio_ms: usize) {
+        match self.mode {
+            ClockMode::Audio => {
+                sleep_ms(queued_audio_ms.saturating_sub(self.audio_buffer_ms));
+            }
+            ClockMode::Vsync => {
+                sleep_ms(queued_audio_ms.saturating_sub(MAX_QUEUED_AUDIO_MS));
+            }
+            ClockMode::Free => {
+                thread::sleep(self.wait_for_next_frame(Instant::now()));
+                sleep_ms(queued_audio_ms.saturating_sub(MAX_QUEUED_AUDIO_MS));
+            }
+        }
+        self.count_frame(Instant::now());
+    }
+
+    // How long the free running clock waits until the next frame is due
+    fn wait_for_next_frame(&mut self, now: Instant) -> Duration {
+        self.next_frame += self.frame_time;
+        if self.next_frame > now {
+            return self.next_frame - now;
+        }
+        if now - self.next_frame > self.frame_time * MAX_FRAMES_BEHIND {
+            self.next_frame = now;
+        }
+        Duration::from_secs(0)
+    }
+
+    // Called instead of end_frame while the game is paused or stopped
+    pub fn idle(&mut self, in_background: bool) {
+        thread::sleep(if in_background { BACKGROUND_INTERVAL } else { IDLE_INTERVAL });
+        self.fps = 0.0;
+        self.restart(Instant::now());
+    }
+
+    fn restart(&mut self, now: Instant) {
+        self.next_frame = now;
+        self.measure_start = now;
+        self.measured_frames = 0;
+        self.fps_start = now;
+        self.fps_frames = 0;
+    }
+
+    fn count_frame(&mut self, now: Instant) {
+        self.measured_frames += 1;
+        self.fps_frames += 1;
+        let elapsed = now - self.fps_start;
+        if elapsed >= Duration::from_secs(1) {
+            self.fps = self.fps_frames as f64 / elapsed.as_secs_f64();
+            self.fps_start = now;
+            self.fps_frames = 0;
+        }
+    }
+
+    // The frames run per second, measured over a second
+    pub fn fps(&self) -> f64 {
+        self.fps
+    }
+
+    // How far the frames run lag behind the frame rate, in milliseconds,
+    // since it was last set or the game was paused. Negative when they are
+    // ahead.
+    pub fn drift_ms(&self) -> f64 {
+        self.drift_ms_at(Instant::now())
+    }
+
+    fn drift_ms_at(&self, now: Instant) -> f64 {
+        let expected = self.frame_time.as_secs_f64() * self.measured_frames as f64;
+        ((now - self.measure_start).as_secs_f64() - expected) * 1000.0
+    }
+}
+
+fn sleep_ms(ms: usize) {
+    if ms > 0 {
+        thread::sleep(Duration::from_millis(ms as u64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_free_clock_keeps_the_frame_rate() {
+        let mut clock = FrameClock::new(ClockMode::Free, 100.0, 35);
+        let start = clock.measure_start;
+        let mut now = start;
+        // Each frame takes 2ms to run, and the clock waits for the rest
+        let run_frame = |clock: &mut FrameClock, now: &mut Instant| {
+            *now += Duration::from_millis(2);
+            *now += clock.wait_for_next_frame(*now);
+            clock.count_frame(*now);
+        };
+        for _ in 0..10 {
+            run_frame(&mut clock, &mut now);
+        }
+        assert_eq!(now - start, Duration::from_millis(100));
+        assert!(clock.drift_ms_at(now).abs() < 0.001, "{}", clock.drift_ms_at(now));
+
+        // After falling far behind the clock does not try to catch up
+        now += Duration::from_secs(1);
+        assert_eq!(clock.wait_for_next_frame(now), Duration::from_secs(0));
+        let behind = now;
+        run_frame(&mut clock, &mut now);
+        assert_eq!(now - behind, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn drift_is_measured_against_the_frame_rate() {
+        let mut clock = FrameClock::new(ClockMode::Audio, 50.0, 35);
+        let start = clock.measure_start;
+        for _ in 0..100 {
+            clock.count_frame(start);
+        }
+        // 100 frames take 2 seconds
+        assert_eq!(clock.drift_ms_at(start + Duration::from_secs(3)).round(), 1000.0);
+        assert_eq!(clock.drift_ms_at(start + Duration::from_secs(1)).round(), -1000.0);
+        clock.set_frame_rate(60.0);
+        assert_eq!(clock.measured_frames, 0);
+        assert!(ClockMode::from_name("vsync") == Some(ClockMode::Vsync) && ClockMode::from_name("x").is_none());
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::clock::ClockMode;
+use crate::nes::{FastForwardAudio, Region};
+use crate::nes::screen::{Layout, VideoFilter};
+
+// Settings that can be given globally or for a single game. A game section
+// is keyed by the CRC32 of the ROM (PRG and CHR data) in hexadecimal:
+//
+//     scale = 3
+//     audio_buffer_ms = 50
+//     sample_rate = 48000
+//
+//     [keys]
+//     a = "X"
+//     b = "Z"
+//
+//     [keys3]
+//     a = "Keypad 1"
+//
+//     [game.158B0388]
+//     region = "pal"
+//     resume = true
+//     save_dir = "saves"
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub scale: Option<u32>,
+    pub filter: Option<String>,
+    pub frame_blend: Option<u32>,
+    pub audio_buffer_ms: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub audio_channels: Option<u8>,
+    pub audio_device_buffer: Option<u16>,
+    pub fast_forward_audio: Option<String>,
+    pub reduce_pops: Option<bool>,
+    pub speed: Option<u32>,
+    pub clock: Option<String>,
+    pub clip_seconds: Option<u32>,
+    pub palette: Option<PathBuf>,
+    pub region: Option<String>,
+    pub four_score: Option<bool>,
+    pub ppu_warm_up: Option<bool>,
+    pub bus_conflicts: Option<bool>,
+    pub pause_in_background: Option<bool>,
+    pub resume: Option<bool>,
+    pub save_dir: Option<PathBuf>,
+    // Where the games listed without a command are
+    pub rom_dir: Option<PathBuf>,
+    // Key bindings of players 1 to 4
+    pub keys: BTreeMap<String, String>,
+    pub keys2: BTreeMap<String, String>,
+    pub keys3: BTreeMap<String, String>,
+    pub keys4: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    #[serde(flatten)]
+    global: Settings,
+    game: BTreeMap<String, Settings>,
+}
+
+impl Settings {
+    // Returns these settings with the ones given in overrides replacing them
+    fn merge(&self, overrides: &Settings) -> Settings {
+        let merge_keys = |keys: &BTreeMap<String, String>, overrides: &BTreeMap<String, String>| {
+            let mut keys = keys.clone();
+            keys.extend(overrides.clone());
+            keys
+        };
+        Settings {
+            scale: overrides.scale.or(self.scale),
+            filter: overrides.filter.clone().or_else(|| self.filter.clone()),
+            frame_blend: overrides.frame_blend.or(self.frame_blend),
+            audio_buffer_ms: overrides.audio_buffer_ms.or(self.audio_buffer_ms),
+            sample_rate: overrides.sample_rate.or(self.sample_rate),
+            audio_channels: overrides.audio_channels.or(self.audio_channels),
+            audio_device_buffer: overrides.audio_device_buffer.or(self.audio_device_buffer),
+            fast_forward_audio: overrides.fast_forward_audio.clone()
+                .or_else(|| self.fast_forward_audio.clone()),
+            reduce_pops: overrides.reduce_pops.or(self.reduce_pops),
+            speed: overrides.speed.or(self.speed),
+            clock: overrides.clock.clone().or_else(|| self.clock.clone()),
+            clip_seconds: overrides.clip_seconds.or(self.clip_seconds),
+            palette: overrides.palette.clone().or_else(|| self.palette.clone()),
+            region: overrides.region.clone().or_else(|| self.region.clone()),
+            four_score: overrides.four_score.or(self.four_score),
+            ppu_warm_up: overrides.ppu_warm_up.or(self.ppu_warm_up),
+            bus_conflicts: overrides.bus_conflicts.or(self.bus_conflicts),
+            pause_in_background: overrides.pause_in_background.or(self.pause_in_background),
+            resume: overrides.resume.or(self.resume),
+            save_dir: overrides.save_dir.clone().or_else(|| self.save_dir.clone()),
+            rom_dir: overrides.rom_dir.clone().or_else(|| self.rom_dir.clone()),
+            keys: merge_keys(&self.keys, &overrides.keys),
+            keys2: merge_keys(&self.keys2, &overrides.keys2),
+            keys3: merge_keys(&self.keys3, &overrides.keys3),
+            keys4: merge_keys(&self.keys4, &overrides.keys4),
+        }
+    }
+
+    pub fn region(&self) -> Result<Option<Region>, String> {
+        match self.region {
+            Some(ref name) => Region::from_name(name).map(Some)
+                .ok_or(format!("Invalid region in configuration: {}", name)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn filter(&self) -> Result<Option<VideoFilter>, String> {
+        match self.filter {
+            Some(ref name) => VideoFilter::from_name(name).map(Some)
+                .ok_or(format!("Invalid filter in configuration: {}", name)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn fast_forward_audio(&self) -> Result<Option<FastForwardAudio>, String> {
+        match self.fast_forward_audio {
+            Some(ref name) => FastForwardAudio::from_name(name).map(Some)
+                .ok_or(format!("Invalid fast_forward_audio in configuration: {}", name)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn clock(&self) -> Result<Option<ClockMode>, String> {
+        match self.clock {
+            Some(ref name) => ClockMode::from_name(name).map(Some)
+                .ok_or(format!("Invalid clock in configuration: {}", name)),
+            None => Ok(None),
+        }
+    }
+
+    // The bindings as player (numbered from 1), button and key
+    pub fn key_bindings(&self) -> Result<Vec<(usize, &str, sdl2::keyboard::Keycode)>, String> {
+        let players = [&self.keys, &self.keys2, &self.keys3, &self.keys4];
+        players.iter().enumerate().flat_map(|(index, keys)| {
+            keys.iter().map(move |(button, key)| {
+                sdl2::keyboard::Keycode::from_name(key)
+                    .map(|keycode| (index + 1, button.as_str(), keycode))
+                    .ok_or(format!("Unknown key for {} in configuration: {}", button, key))
+            })
+        }).collect()
+    }
+}
+
+impl Config {
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(config_dir.join("nesemu").join("config.toml"))
+    }
+
+    // A missing file gives the default configuration
+    pub fn load(path: &Path) -> Result<Config, String> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let mut config: Config = toml::from_str(&text)
+            .map_err(|e| format!("Invalid configuration in {}: {}", path.display(), e))?;
+
+        // Relative palette, save and ROM paths are relative to the configuration
+        // file
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        let settings = Some(&mut config.global).into_iter().chain(config.game.values_mut());
+        for settings in settings {
+            if let Some(ref palette) = settings.palette {
+                settings.palette = Some(base.join(palette));
+            }
+            if let Some(ref save_dir) = settings.save_dir {
+                settings.save_dir = Some(base.join(save_dir));
+            }
+            if let Some(ref rom_dir) = settings.rom_dir {
+                settings.rom_dir = Some(base.join(rom_dir));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn settings_for_game(&self, crc32: u32) -> Settings {
+        let game = self.game.iter()
+            .find(|(key, _)| u32::from_str_radix(key, 16).ok() == Some(crc32));
+        match game {
+            Some((_, overrides)) => self.global.merge(overrides),
+            None => self.global.clone(),
+        }
+    }
+}
+
+// The window layout is kept next to the default configuration file
+pub fn layout_path() -> Option<PathBuf> {
+    Config::default_path().map(|path| path.with_file_name("layout.toml"))
+}
+
+// The user's game database is kept next to the default configuration file
+pub fn database_path() -> Option<PathBuf> {
+    Config::default_path().map(|path| path.with_file_name("database.toml"))
+}
+
+// The state saved when nesemu exits, kept next to the default configuration
+// file by the CRC32 of the game
+pub fn resume_path(crc32: u32) -> Option<PathBuf> {
+    Config::default_path().map(|path| path.with_file_name("resume").join(format!("{:08X}.state", crc32)))
+}
+
+// Battery saves are named after the ROM and its CRC32 and kept in the save
+// directory of the configuration, by default in the data directory, so that
+// ROMs with the same file name in different directories keep their own saves.
+// Without a home directory they are kept next to the ROM.
+pub fn save_path(rom: &Path, crc32: u32, save_dir: Option<&Path>) -> PathBuf {
+    let default_dir = || {
+        let data_dir = match env::var_os("XDG_DATA_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".local").join("share"),
+        };
+        Some(data_dir.join("nesemu").join("saves"))
+    };
+    let save_file = rom.with_extension("sav");
+    match save_dir.map(Path::to_path_buf).or_else(default_dir) {
+        Some(dir) => {
+            let stem = save_file.file_stem().unwrap().to_string_lossy();
+            dir.join(format!("{}-{:08X}.sav", stem, crc32))
+        }
+        None => save_file,
+    }
+}
+
+// A missing file gives the default layout
+pub fn load_layout(path: &Path) -> Result<Layout, String> {
+    if !path.exists() {
+        return Ok(Layout::default());
+    }
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    toml::from_str(&text).map_err(|e| format!("Invalid window layout in {}: {}", path.display(), e))
+}
+
+pub fn save_layout(path: &Path, layout: &Layout) -> Result<(), String> {
+    let text = toml::to_string(layout).map_err(|e| e.to_string())?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Unable to create {}: {}", dir.display(), e))?;
+    }
+    fs::write(path, text).map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nes::screen::WindowGeometry;
+
+    #[test]
+    fn layout_round_trip() {
+        let path = env::temp_dir().join(format!("nesemu-layout-{}", std::process::id()))
+            .join("layout.toml");
+        assert_eq!(load_layout(&path).unwrap(), Layout::default());
+        let layout = Layout {
+            debug_windows: true,
+            main: Some(WindowGeometry { x: 10, y: 20, width: 512, height: 480 }),
+            name_table: None,
+            scope: Some(WindowGeometry { x: 600, y: 20, width: 512, height: 480 }),
+        };
+        save_layout(&path, &layout).unwrap();
+        assert_eq!(load_layout(&path).unwrap(), layout);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn audio_settings_can_be_given_per_game() {
+        let config: Config = toml::from_str("sample_rate = 48000\naudio_channels = 2\n\
+                                             [game.158B0388]\naudio_device_buffer = 512\n").unwrap();
+        let settings = config.global.merge(&config.game["158B0388"]);
+        assert_eq!((settings.sample_rate, settings.audio_channels, settings.audio_device_buffer),
+                   (Some(48000), Some(2), Some(512)));
+    }
+
+    #[test]
+    fn fast_forward_audio_is_checked() {
+        let config: Config = toml::from_str("fast_forward_audio = \"pitch\"\n\
+                                             [game.158B0388]\nfast_forward_audio = \"loud\"\n").unwrap();
+        assert_eq!(config.global.fast_forward_audio(), Ok(Some(FastForwardAudio::Pitch)));
+        assert!(config.settings_for_game(0x158B0388).fast_forward_audio().is_err());
+    }
+
+    #[test]
+    fn save_file_is_named_after_the_rom() {
+        assert_eq!(save_path(Path::new("roms/game.nes"), 0x158B0388, Some(Path::new("saves"))),
+                   Path::new("saves/game-158B0388.sav"));
+        assert_eq!(save_path(Path::new("game.zip"), 0x0A, Some(Path::new("saves"))),
+                   Path::new("saves/game-0000000A.sav"));
+    }
+}
@@ -0,0 +1,357 @@
+use std::fs;
+use std::io::BufRead;
+use std::sync::mpsc;
+use std::thread;
+
+use nesemu::nes;
+
+use crate::ram_search::{self, Filter, RamSearch};
+
+// Commands typed in the terminal while a game runs, with --console. The
+// lines are read on a thread of their own and run between frames.
+
+const HELP: &str = "\
+Commands:
+    search                   Start a RAM search with all addresses of the work RAM and
+                             the cartridge RAM
+    search <filter>          Keep the addresses whose value matches the filter, compared to
+                             the last search: eq <n>, ne <n>, inc, dec, changed or same
+    hits                     List the addresses found, numbered #1, #2 and so on
+    cheat <address> <value>  Make the game read the value from the address
+    cheat <address> off      Remove the cheat
+    cheats                   List the cheats
+    watch <address>          Show the value at the address on the screen
+    unwatch <address>        Stop showing it
+    break <address>          Pause the game before the CPU executes the instruction at the
+                             address, and print the instructions executed before it
+    unbreak <address>        Remove the breakpoint
+    breaks                   List the breakpoints
+    dump [space] <address> [length]
+                             Print the memory from the address on, 128 bytes by default.
+                             The spaces are cpu (the default), ppu, oam and palette.
+    poke [space] <address> <value>...
+                             Write the values from the address on
+    view [space] <address>   Show 128 bytes from the address on the screen, as they change
+    view off                 Stop showing them
+    events on                Start recording the writes to the PPU, APU and mapper
+                             registers and the interrupts
+    events off               Stop recording them
+    events                   List the events of the last frame
+    events save <file>       Write all recorded events to the file
+    events show              Mark the events of each frame on the picture, where the PPU
+                             was drawing when they happened
+    events hide              Stop marking them
+    ppu                      Print the scan line and dot the PPU is at and its scroll
+                             registers: the VRAM address v, the temporary address t, the
+                             fine X scroll and the write toggle w
+Addresses are hexadecimal, or #<n> for a hit of the search. Values are decimal, or
+hexadecimal with $.";
+
+const MAX_LISTED_HITS: usize = 20;
+
+// Enough for several frames of a game that writes a lot
+const EVENT_LOG_CAPACITY: usize = 100_000;
+
+const DUMP_LENGTH: usize = 128;
+const DUMP_BYTES_PER_LINE: usize = 16;
+// Fewer fit across the picture
+const VIEW_BYTES_PER_LINE: usize = 8;
+
+// E.g. "0300: 00 01 02 03"
+fn hex_lines(machine: &mut nes::Machine, space: nes::MemorySpace, address: u16, length: usize,
+             bytes_per_line: usize) -> Vec<String> {
+    let addresses: Vec<u16> = (0..length).map(|i| address.wrapping_add(i as u16)).collect();
+    addresses.chunks(bytes_per_line).map(|line| {
+        let values: Vec<String> = line.iter()
+            .map(|a| format!("{:02X}", machine.peek(space, *a)))
+            .collect();
+        format!("{:04X}: {}", line[0], values.join(" "))
+    }).collect()
+}
+
+pub struct Console {
+    lines: mpsc::Receiver<String>,
+    search: Option<RamSearch>,
+    watches: Vec<u16>,
+    view: Option<(nes::MemorySpace, u16)>,
+    show_events: bool,
+}
+
+fn event_color(kind: nes::EventKind) -> [u8; 3] {
+    match kind {
+        nes::EventKind::PpuWrite => [0x00, 0xFF, 0x00],
+        nes::EventKind::ApuWrite => [0xFF, 0xFF, 0x00],
+        nes::EventKind::MapperWrite => [0xFF, 0x00, 0xFF],
+        nes::EventKind::Nmi => [0xFF, 0x00, 0x00],
+        nes::EventKind::Irq => [0x00, 0xFF, 0xFF],
+    }
+}
+
+impl Console {
+    pub fn start() -> Console {
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                if line.map(|line| sender.send(line)).is_err() {
+                    break;
+                }
+            }
+        });
+        println!("Type help for the console commands");
+        Console::new(lines)
+    }
+
+    fn new(lines: mpsc::Receiver<String>) -> Console {
+        Console {
+            lines,
+            search: None,
+            watches: Vec::new(),
+            view: None,
+            show_events: false,
+        }
+    }
+
+    // Runs the commands typed since the last call
+    pub fn run_commands(&mut self, machine: &mut nes::Machine) {
+        while let Ok(line) = self.lines.try_recv() {
+            if let Err(e) = self.run_command(&line, machine) {
+                println!("{}", e);
+            }
+        }
+    }
+
+    fn parse_address(&self, text: &str) -> Result<u16, String> {
+        if let Some(number) = text.strip_prefix('#') {
+            let hits = self.search.as_ref().map_or(&[][..], |s| s.candidates());
+            number.parse::<usize>().ok().filter(|n| *n >= 1).and_then(|n| hits.get(n - 1))
+                .map(|hit| hit.address)
+                .ok_or(format!("No hit {}", text))
+        }
+        else {
+            let hex = text.trim_start_matches('$').trim_start_matches("0x");
+            u16::from_str_radix(hex, 16).map_err(|_| format!("Invalid address: {}", text))
+        }
+    }
+
+    // An address, optionally after the name of its space. Returns the number
+    // of words used as well.
+    fn parse_location(&self, words: &[&str]) -> Result<(nes::MemorySpace, u16, usize), String> {
+        let first = words.first().ok_or("Missing address")?;
+        match nes::MemorySpace::from_name(first) {
+            Some(space) => {
+                let address = self.parse_address(words.get(1).ok_or("Missing address")?)?;
+                Ok((space, address, 2))
+            }
+            None => Ok((nes::MemorySpace::Cpu, self.parse_address(first)?, 1)),
+        }
+    }
+
+    fn run_command(&mut self, line: &str, machine: &mut nes::Machine) -> Result<(), String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [] => {}
+            ["help"] => { println!("{}", HELP); }
+            ["search"] => {
+                let search = RamSearch::new(machine);
+                println!("Searching {} addresses", search.candidates().len());
+                self.search = Some(search);
+            }
+            ["search", ref filter @ ..] => {
+                let filter = Filter::parse(filter)?;
+                let search = self.search.as_mut().ok_or("Start a search first with search")?;
+                search.filter(filter, machine);
+                println!("{} addresses left", search.candidates().len());
+            }
+            ["hits"] => {
+                let hits = self.search.as_ref().map_or(&[][..], |s| s.candidates());
+                for (i, hit) in hits.iter().take(MAX_LISTED_HITS).enumerate() {
+                    println!("#{} ${:04X}: {} (was {})", i + 1, hit.address, hit.current, hit.previous);
+                }
+                if hits.len() > MAX_LISTED_HITS {
+                    println!("and {} more", hits.len() - MAX_LISTED_HITS);
+                }
+            }
+            ["cheat", address, "off"] => {
+                machine.remove_cheat(self.parse_address(address)?);
+            }
+            ["cheat", address, value] => {
+                let address = self.parse_address(address)?;
+                let value = ram_search::parse_value(value).ok_or(format!("Invalid value: {}", value))?;
+                machine.add_cheat(nes::Cheat { address, value, compare: None });
+            }
+            ["cheats"] => {
+                for cheat in machine.cheats() {
+                    println!("${:04X}: {}", cheat.address, cheat.value);
+                }
+            }
+            ["watch", address] => {
+                let address = self.parse_address(address)?;
+                if !self.watches.contains(&address) {
+                    self.watches.push(address);
+                }
+            }
+            ["unwatch", address] => {
+                let address = self.parse_address(address)?;
+                self.watches.retain(|a| *a != address);
+            }
+            ["break", address] => {
+                let address = self.parse_address(address)?;
+                machine.add_breakpoint(address);
+            }
+            ["unbreak", address] => {
+                let address = self.parse_address(address)?;
+                machine.remove_breakpoint(address);
+            }
+            ["breaks"] => {
+                for address in machine.breakpoints() {
+                    println!("${:04X}", address);
+                }
+            }
+            ["dump", ref location @ ..] => {
+                let (space, address, used) = self.parse_location(location)?;
+                let length = match location.get(used) {
+                    Some(length) => {
+                        let hex = length.strip_prefix('$').or_else(|| length.strip_prefix("0x"));
+                        hex.map_or_else(|| length.parse().ok(), |hex| usize::from_str_radix(hex, 16).ok())
+                            .ok_or(format!("Invalid length: {}", length))?
+                    }
+                    None => DUMP_LENGTH,
+                };
+                for line in hex_lines(machine, space, address, length.min(space.size()), DUMP_BYTES_PER_LINE) {
+                    println!("{}", line);
+                }
+            }
+            ["poke", ref location @ ..] => {
+                let (space, address, used) = self.parse_location(location)?;
+                if location.len() == used {
+                    return Err("Missing value".to_string());
+                }
+                for (i, value) in location[used..].iter().enumerate() {
+                    let value = ram_search::parse_value(value).ok_or(format!("Invalid value: {}", value))?;
+                    machine.poke(space, address.wrapping_add(i as u16), value);
+                }
+            }
+            ["view", "off"] => { self.view = None; }
+            ["view", ref location @ ..] => {
+                let (space, address, _) = self.parse_location(location)?;
+                self.view = Some((space, address));
+            }
+            ["events", "on"] => { machine.set_event_log(Some(EVENT_LOG_CAPACITY)); }
+            ["events", "off"] => {
+                machine.set_event_log(None);
+                self.show_events = false;
+            }
+            ["events"] => {
+                let log = machine.event_log().ok_or("Start recording first with events on")?;
+                for event in log.last_frame() {
+                    println!("{}", event);
+                }
+            }
+            ["events", "save", path] => {
+                let log = machine.event_log().ok_or("Start recording first with events on")?;
+                let text: String = log.events().map(|e| format!("{}\n", e)).collect();
+                fs::write(path, text).map_err(|e| format!("Unable to write {}: {}", path, e))?;
+            }
+            ["events", "show"] => {
+                if machine.event_log().is_none() {
+                    machine.set_event_log(Some(EVENT_LOG_CAPACITY));
+                }
+                self.show_events = true;
+            }
+            ["events", "hide"] => { self.show_events = false; }
+            ["ppu"] => {
+                let state = machine.ppu_state();
+                println!("line {} dot {}: v:{:04X} t:{:04X} x:{} w:{} A12 rises:{}", state.scan_line,
+                         state.dot, state.v, state.t, state.fine_x, state.w as u8,
+                         machine.a12_rises());
+            }
+            _ => { return Err(format!("Unknown command: {} (type help for the commands)", line.trim())); }
+        }
+        Ok(())
+    }
+
+    // Shows the watched values in the top left corner of the next picture,
+    // the memory view in the top right corner and the events where they
+    // happened. Events outside the visible part of the frame are not shown.
+    pub fn draw(&self, machine: &mut nes::Machine) {
+        if self.show_events {
+            let marks: Vec<_> = machine.event_log().map_or(Vec::new(), |log| {
+                log.last_frame()
+                    .filter(|e| (0..nes::screen::HEIGHT as i16).contains(&e.scan_line) &&
+                            (e.dot as u32) < nes::screen::WIDTH)
+                    .map(|e| (e.dot as u32, e.scan_line as u32, event_color(e.kind)))
+                    .collect()
+            });
+            if let Some(screen) = machine.screen.as_mut() {
+                for (x, y, color) in marks {
+                    screen.draw_box(x.saturating_sub(1), y.saturating_sub(1), 3, 3, color);
+                }
+            }
+        }
+        for (i, address) in self.watches.iter().enumerate() {
+            let text = format!("{:04X}: {}", address, machine.peek_mem(*address));
+            if let Some(screen) = machine.screen.as_mut() {
+                screen.draw_text(8, 8 + i as u32 * 8, &text);
+            }
+        }
+        if let Some((space, address)) = self.view {
+            let lines = hex_lines(machine, space, address, DUMP_LENGTH, VIEW_BYTES_PER_LINE);
+            if let Some(screen) = machine.screen.as_mut() {
+                for (i, line) in lines.iter().enumerate() {
+                    screen.draw_text(nes::screen::WIDTH - 8 - line.len() as u32 * 4, 8 + i as u32 * 8, line);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hits_become_cheats_and_watches() {
+        let (_, mut machine) = nes::cartridge::start_controller_reading_machine();
+        let (_, lines) = mpsc::channel();
+        let mut console = Console::new(lines);
+
+        assert!(console.run_command("watch #1", &mut machine).is_err());
+        assert!(console.run_command("search inc", &mut machine).is_err());
+        machine.poke_mem(0x0042, 5);
+        console.run_command("search", &mut machine).unwrap();
+        machine.poke_mem(0x0042, 6);
+        console.run_command("search inc", &mut machine).unwrap();
+        console.run_command("watch #1", &mut machine).unwrap();
+        console.run_command("cheat #1 $63", &mut machine).unwrap();
+        assert_eq!(console.watches, [0x0042]);
+        assert_eq!(machine.peek_mem(0x0042), 99);
+
+        console.run_command("cheat 42 off", &mut machine).unwrap();
+        assert_eq!(machine.peek_mem(0x0042), 6);
+        assert!(console.run_command("frobnicate", &mut machine).is_err());
+    }
+
+    #[test]
+    fn memory_is_poked_in_any_space() {
+        let (_, mut machine) = nes::cartridge::start_controller_reading_machine();
+        let (_, lines) = mpsc::channel();
+        let mut console = Console::new(lines);
+
+        console.run_command("poke 300 1 2 $FF", &mut machine).unwrap();
+        console.run_command("poke oam 10 7", &mut machine).unwrap();
+        console.run_command("poke ppu 2400 5", &mut machine).unwrap();
+        console.run_command("poke palette 11 $30", &mut machine).unwrap();
+        assert_eq!(hex_lines(&mut machine, nes::MemorySpace::Cpu, 0x0300, 4, 16), ["0300: 01 02 FF 00"]);
+        assert_eq!(machine.peek(nes::MemorySpace::Oam, 0x10), 7);
+        // Horizontal mirroring
+        assert_eq!(machine.peek(nes::MemorySpace::Ppu, 0x2000), 5);
+        assert_eq!(machine.peek(nes::MemorySpace::Ppu, 0x3F11), 0x30);
+        assert!(console.run_command("poke oam 10", &mut machine).is_err());
+        console.run_command("view palette 0", &mut machine).unwrap();
+        assert!(console.run_command("events", &mut machine).is_err());
+        console.run_command("events on", &mut machine).unwrap();
+        console.run_command("poke 2005 7", &mut machine).unwrap();
+        assert_eq!(machine.event_log().unwrap().events().next().unwrap().kind, nes::EventKind::PpuWrite);
+        assert_eq!(console.view, Some((nes::MemorySpace::Palette, 0)));
+    }
+}
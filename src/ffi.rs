@@ -0,0 +1,265 @@
+// A C API for programs in other languages that embed the emulator, with the
+// ffi feature. The declarations are in include/nesemu.h. A library to link
+// with is built with
+//
+//     cargo rustc --release --lib --no-default-features --features ffi --crate-type cdylib
+//
+// or --crate-type staticlib. All functions take the handle from
+// nesemu_create and do nothing, or return 0, false or null, when it is null.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use crate::nes;
+
+pub struct Nesemu {
+    cpu: nes::cpu::Cpu,
+    machine: nes::Machine,
+    // The sound of the last frame
+    samples: Vec<f32>,
+    rom_loaded: bool,
+}
+
+impl Nesemu {
+    fn new() -> Nesemu {
+        Nesemu {
+            cpu: nes::cpu::Cpu::new(),
+            machine: nes::Machine::new_headless(),
+            samples: Vec::new(),
+            rom_loaded: false,
+        }
+    }
+}
+
+unsafe fn with_nesemu<T>(nesemu: *mut Nesemu, default: T, f: impl FnOnce(&mut Nesemu) -> T) -> T {
+    match nesemu.as_mut() {
+        Some(nesemu) => f(nesemu),
+        None => default,
+    }
+}
+
+// A console without a cartridge
+#[no_mangle]
+pub extern "C" fn nesemu_create() -> *mut Nesemu {
+    Box::into_raw(Box::new(Nesemu::new()))
+}
+
+/// # Safety
+/// `nesemu` must come from `nesemu_create` and is not valid afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_destroy(nesemu: *mut Nesemu) {
+    if !nesemu.is_null() {
+        drop(Box::from_raw(nesemu));
+    }
+}
+
+/// Inserts a cartridge from the contents of a .nes file into a console just
+/// switched on. Returns false if it is not a ROM that nesemu can run.
+///
+/// # Safety
+/// `nesemu` must come from `nesemu_create` and `data` must point to `size`
+/// readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_load_rom(nesemu: *mut Nesemu, data: *const u8, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    let rom = slice::from_raw_parts(data, size);
+    with_nesemu(nesemu, false, |nesemu| {
+        *nesemu = Nesemu::new();
+        let cartridge = match nes::cartridge::Cartridge::from_bytes(rom) {
+            Ok(cartridge) => cartridge,
+            Err(_) => return false,
+        };
+        if let Some(region) = cartridge.region() {
+            nesemu.machine.set_region(region);
+        }
+        nesemu.machine.load_cartridge(cartridge);
+        let Nesemu { cpu, machine, .. } = nesemu;
+        let started = panic::catch_unwind(AssertUnwindSafe(|| cpu.reset(machine))).is_ok();
+        nesemu.rom_loaded = started;
+        started
+    })
+}
+
+/// Runs the console until the next picture is complete. Returns false
+/// without a ROM or if the CPU has stopped on an opcode it can not execute,
+/// which leaves the last picture and no sound until a state is loaded. A
+/// panic in the emulator also returns false, and the ROM has to be loaded
+/// again.
+///
+/// # Safety
+/// `nesemu` must come from `nesemu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_run_frame(nesemu: *mut Nesemu) -> bool {
+    with_nesemu(nesemu, false, |nesemu| {
+        if !nesemu.rom_loaded {
+            return false;
+        }
+        let Nesemu { cpu, machine, .. } = nesemu;
+        let samples = panic::catch_unwind(AssertUnwindSafe(|| {
+            nes::try_run_frame(cpu, machine).map(|frame| frame.samples)
+        }));
+        match samples {
+            Ok(Ok(samples)) => {
+                nesemu.samples = samples;
+                true
+            }
+            Ok(Err(_)) => {
+                nesemu.samples.clear();
+                false
+            }
+            Err(_) => {
+                nesemu.samples.clear();
+                nesemu.rom_loaded = false;
+                false
+            }
+        }
+    })
+}
+
+/// The last complete picture, 256x240 pixels of 3 bytes (RGB) each, valid
+/// until the next call to `nesemu_run_frame`.
+///
+/// # Safety
+/// `nesemu` must come from `nesemu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_get_framebuffer(nesemu: *mut Nesemu) -> *const u8 {
+    with_nesemu(nesemu, ptr::null(), |nesemu| nesemu.machine.frame_buffer().as_ptr())
+}
+
+/// The sound of the last frame, mono samples from -1.0 to 1.0 at
+/// `nesemu_audio_sample_rate`, valid until the next call to
+/// `nesemu_run_frame`. The number of samples is written to `count`.
+///
+/// # Safety
+/// `nesemu` must come from `nesemu_create` and `count` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_get_audio(nesemu: *mut Nesemu, count: *mut usize) -> *const f32 {
+    with_nesemu(nesemu, ptr::null(), |nesemu| {
+        if let Some(count) = count.as_mut() {
+            *count = nesemu.samples.len();
+        }
+        nesemu.samples.as_ptr()
+    })
+}
+
+/// # Safety
+/// `nesemu` must come from `nesemu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_audio_sample_rate(nesemu: *mut Nesemu) -> u32 {
+    with_nesemu(nesemu, 0, |nesemu| nesemu.machine.audio_sample_rate())
+}
+
+/// Sets the buttons a player, numbered from 1 to 4, holds from now on. Bit 0
+/// is A, then B, select, start, up, down, left and right. Players 3 and 4
+/// need the Four Score, which is used as soon as their buttons are set.
+/// Returns false for other players.
+///
+/// # Safety
+/// `nesemu` must come from `nesemu_create`.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_set_input(nesemu: *mut Nesemu, player: u32, buttons: u8) -> bool {
+    let player = player as usize;
+    if !(1..=nes::PLAYERS).contains(&player) {
+        return false;
+    }
+    with_nesemu(nesemu, false, |nesemu| {
+        let controller = &mut nesemu.machine.controller;
+        if player > 2 {
+            controller.set_four_score(true);
+        }
+        controller.plug_in(player);
+        controller.set_player_buttons(player, [0, 1, 2, 3, 4, 5, 6, 7].map(|bit| buttons & (1 << bit) != 0));
+        true
+    })
+}
+
+/// Writes the state of the console to `buffer` if it holds `size` bytes or
+/// more, and returns the size of the state either way, so that a null
+/// buffer asks for the size. Returns 0 without a ROM or on a panic.
+///
+/// # Safety
+/// `nesemu` must come from `nesemu_create` and `buffer` must be null or
+/// point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_save_state(nesemu: *mut Nesemu, buffer: *mut u8, size: usize) -> usize {
+    with_nesemu(nesemu, 0, |nesemu| {
+        if !nesemu.rom_loaded {
+            return 0;
+        }
+        let snapshot = || nes::snapshot(&nesemu.cpu, &nesemu.machine).to_bytes();
+        let state = match panic::catch_unwind(AssertUnwindSafe(snapshot)) {
+            Ok(state) => state,
+            Err(_) => return 0,
+        };
+        if !buffer.is_null() && state.len() <= size {
+            ptr::copy_nonoverlapping(state.as_ptr(), buffer, state.len());
+        }
+        state.len()
+    })
+}
+
+/// Restores a state from `nesemu_save_state` of the same ROM. Returns false
+/// if it is not one, which leaves the console as it was, or on a panic.
+///
+/// # Safety
+/// `nesemu` must come from `nesemu_create` and `data` must point to `size`
+/// readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nesemu_load_state(nesemu: *mut Nesemu, data: *const u8, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    let bytes = slice::from_raw_parts(data, size);
+    with_nesemu(nesemu, false, |nesemu| {
+        let Nesemu { cpu, machine, .. } = nesemu;
+        panic::catch_unwind(AssertUnwindSafe(|| match nes::state::Snapshot::from_bytes(bytes) {
+            Ok(state) => nes::load_snapshot(cpu, machine, &state).is_ok(),
+            Err(_) => false,
+        })).unwrap_or(false)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_frame_and_a_state_through_the_c_api() {
+        unsafe {
+            let nesemu = nesemu_create();
+            assert!(!nesemu_run_frame(nesemu));
+            assert!(!nesemu_load_rom(nesemu, b"not a rom".as_ptr(), 9));
+            // The controller of player 2 is read into $01
+            let rom = nes::cartridge::controller_reading_image();
+            assert!(nesemu_load_rom(nesemu, rom.as_ptr(), rom.len()));
+
+            assert!(nesemu_set_input(nesemu, 2, 0x01));
+            assert!(!nesemu_set_input(nesemu, 5, 0x01));
+            assert!(nesemu_run_frame(nesemu));
+            assert!(!nesemu_get_framebuffer(nesemu).is_null());
+            let mut count = 0;
+            assert!(!nesemu_get_audio(nesemu, &mut count).is_null());
+            let expected = nesemu_audio_sample_rate(nesemu) as usize / 60;
+            assert!(count > expected * 9 / 10 && count < expected * 11 / 10, "{} samples", count);
+            // The first NMI, which reads the controllers, comes in the second frame
+            assert!(nesemu_run_frame(nesemu));
+            assert_eq!((*nesemu).machine.peek_mem(0x01) & 1, 1);
+
+            let size = nesemu_save_state(nesemu, ptr::null_mut(), 0);
+            let mut state = vec![0; size];
+            assert_eq!(nesemu_save_state(nesemu, state.as_mut_ptr(), size), size);
+            nesemu_set_input(nesemu, 2, 0x00);
+            nesemu_run_frame(nesemu);
+            assert_eq!((*nesemu).machine.peek_mem(0x01) & 1, 0);
+            assert!(nesemu_load_state(nesemu, state.as_ptr(), size));
+            assert_eq!((*nesemu).machine.peek_mem(0x01) & 1, 1);
+            assert!(!nesemu_load_state(nesemu, state.as_ptr(), size / 2));
+
+            nesemu_destroy(nesemu);
+            assert!(!nesemu_run_frame(ptr::null_mut()));
+        }
+    }
+}
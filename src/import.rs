@@ -0,0 +1,153 @@
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use flate2::read::ZlibDecoder;
+
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    let bytes = data.get(pos..pos + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// FCEUX states start with a 16 byte header ("FCSX", uncompressed size, version
+// and compressed size, or 0xFFFFFFFF if not compressed) followed by sections
+// of named chunks. Mappers with save RAM store it in a chunk named "WRAM".
+fn extract_fceux_save_ram(data: &[u8]) -> Result<Vec<u8>, String> {
+    let invalid = || "Invalid FCEUX state".to_string();
+    let compressed_size = read_u32(data, 12).ok_or_else(invalid)?;
+    let body = if compressed_size == 0xFFFF_FFFF {
+        data.get(16..).ok_or_else(invalid)?.to_vec()
+    }
+    else {
+        let compressed = data.get(16..16 + compressed_size as usize).ok_or_else(invalid)?;
+        let mut body = Vec::new();
+        ZlibDecoder::new(compressed).read_to_end(&mut body).map_err(|_| invalid())?;
+        body
+    };
+
+    let mut pos = 0;
+    while pos < body.len() {
+        let section_size = read_u32(&body, pos + 1).ok_or_else(invalid)? as usize;
+        let section_end = pos + 5 + section_size;
+        let mut chunk = pos + 5;
+        while chunk + 8 <= section_end.min(body.len()) {
+            let name = &body[chunk..chunk + 4];
+            let size = read_u32(&body, chunk + 4).ok_or_else(invalid)? as usize;
+            if name == b"WRAM" {
+                return body.get(chunk + 8..chunk + 8 + size).map(|d| d.to_vec())
+                    .ok_or_else(invalid);
+            }
+            chunk += 8 + size;
+        }
+        pos = section_end;
+    }
+    Err("The FCEUX state has no save RAM".to_string())
+}
+
+// Imports the save RAM from a battery save (.sav/.srm, as written by FCEUX,
+// Mesen and most other emulators) or an FCEUX state (.fc0-.fc9/.fcs) and
+// writes it as the save file of a ROM
+pub fn import_save_ram(save_path: &Path, source: &Path, save_ram_size: usize, overwrite: bool)
+                       -> Result<(), String> {
+    let data = fs::read(source)
+        .map_err(|e| format!("Unable to read {}: {}", source.display(), e))?;
+    let mut save_ram = if data.starts_with(b"FCSX") {
+        extract_fceux_save_ram(&data)?
+    }
+    else if data.starts_with(b"MST") {
+        return Err("Mesen states are not supported, import the battery save (.sav) \
+                    from Mesen's Saves folder instead".to_string());
+    }
+    else {
+        data
+    };
+    if save_ram.is_empty() || save_ram.len() > save_ram_size {
+        return Err(format!("{} does not look like save RAM ({} bytes)",
+                           source.display(), save_ram.len()));
+    }
+    save_ram.resize(save_ram_size, 0);
+
+    if save_path.exists() && !overwrite {
+        return Err(format!("{} already exists, use --force to overwrite it",
+                           save_path.display()));
+    }
+    if let Some(dir) = save_path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Unable to create {}: {}", dir.display(), e))?;
+    }
+    fs::write(save_path, &save_ram)
+        .map_err(|e| format!("Unable to write {}: {}", save_path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+
+    fn chunk(name: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = name.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn section(kind: u8, chunks: &[Vec<u8>]) -> Vec<u8> {
+        let contents = chunks.concat();
+        let mut section = vec![kind];
+        section.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        section.extend_from_slice(&contents);
+        section
+    }
+
+    fn fceux_state(body: &[u8], compressed_size: u32) -> Vec<u8> {
+        let mut state = b"FCSX".to_vec();
+        state.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        state.extend_from_slice(&22020u32.to_le_bytes());
+        state.extend_from_slice(&compressed_size.to_le_bytes());
+        state
+    }
+
+    #[test]
+    fn save_ram_is_found_in_fceux_states() {
+        let body = [section(1, &[chunk(b"PC\0\0", &[0, 0x80])]),
+                    section(16, &[chunk(b"REGS", &[1, 2, 3]), chunk(b"WRAM", &[7, 8, 9])])].concat();
+        let mut state = fceux_state(&body, 0xFFFF_FFFF);
+        state.extend_from_slice(&body);
+        assert_eq!(extract_fceux_save_ram(&state).unwrap(), [7, 8, 9]);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut state = fceux_state(&body, compressed.len() as u32);
+        state.extend_from_slice(&compressed);
+        assert_eq!(extract_fceux_save_ram(&state).unwrap(), [7, 8, 9]);
+
+        assert!(extract_fceux_save_ram(&state[..14]).is_err());
+        assert!(extract_fceux_save_ram(&state[..state.len() - 1]).is_err());
+        let mut state = fceux_state(&body, 0xFFFF_FFFF);
+        state.extend_from_slice(&section(1, &[chunk(b"PC\0\0", &[0, 0x80])]));
+        assert!(extract_fceux_save_ram(&state).is_err());
+    }
+
+    #[test]
+    fn save_ram_is_padded_to_the_size_of_the_games_ram() {
+        let dir = std::env::temp_dir().join(format!("nesemu-import-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.sav");
+        let save_path = dir.join("game.sav");
+
+        fs::write(&source, vec![0x55; 0x6000]).unwrap();
+        import_save_ram(&save_path, &source, 0x8000, false).unwrap();
+        let save_ram = fs::read(&save_path).unwrap();
+        assert_eq!(save_ram.len(), 0x8000);
+        assert!(save_ram[..0x6000].iter().all(|&b| b == 0x55));
+        assert!(save_ram[0x6000..].iter().all(|&b| b == 0));
+
+        assert!(import_save_ram(&save_path, &source, 0x8000, false).is_err());
+        assert!(import_save_ram(&save_path, &source, 0x2000, true).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
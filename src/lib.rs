@@ -0,0 +1,31 @@
+//! The NES emulation of nesemu, for use in other programs.
+//!
+//! A [`nes::Machine`] is the console without its CPU, and a [`nes::cpu::Cpu`]
+//! executes instructions on it. A headless machine has no window, sound or
+//! keyboard input, so the program embedding it provides the input and takes
+//! the picture and sound:
+//!
+//! ```no_run
+//! use nesemu::nes;
+//!
+//! let rom = std::fs::read("game.nes").unwrap();
+//! let mut machine = nes::Machine::new_headless();
+//! let mut cpu = nes::cpu::Cpu::new();
+//! machine.load_cartridge(nes::cartridge::Cartridge::from_bytes(&rom).unwrap());
+//! cpu.reset(&mut machine);
+//!
+//! for frame in 0..600 {
+//!     // Press start now and then to get past the title screen
+//!     let start = frame % 60 < 5;
+//!     machine.set_buttons([false, false, false, start, false, false, false, false]);
+//!     let frame = nes::run_frame(&mut cpu, &mut machine);
+//!     let _picture = frame.picture; // 256x240 RGB
+//!     let _sound = frame.samples;   // mono f32
+//! }
+//! ```
+//!
+//! See the examples directory for complete programs.
+
+pub mod nes;
+#[cfg(feature = "ffi")]
+pub mod ffi;
@@ -1,108 +1,1022 @@
 extern crate sdl2;
-extern crate time;
 
 use std::env;
-use time::Duration;
+use std::fs;
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-mod nes;
+mod cli;
+mod clock;
+mod config;
+mod browser;
+mod console;
+mod import;
+mod movie;
+mod netplay;
+mod played;
+mod ram_search;
+mod remote;
+mod rewind;
+mod script;
+mod selftest;
+mod spectate;
 
-#[cfg(test)]
-use std::fs::File;
+use nesemu::nes;
+
+use std::path::{Path, PathBuf};
 
+// Writes an NROM image with the program at $8000 and runs the given number of
+// instructions on a headless machine. Returns the value at $0000.
 #[cfg(test)]
-use std::io::{BufRead, BufReader};
+fn run_headless_program(name: &str, program: &[u8], instructions: usize) -> u8 {
+    let path = env::temp_dir().join(format!("nesemu-{}-{}.nes", name, process::id()));
+    fs::write(&path, nes::cartridge::nrom_image(program)).unwrap();
+
+    let mut machine = nes::Machine::new_headless();
+    let mut cpu = nes::cpu::Cpu::new();
+    machine.load_cartridge(nes::cartridge::Cartridge::load(&path).unwrap());
+    fs::remove_file(&path).unwrap();
+    cpu.reset(&mut machine);
+    for _ in 0..instructions {
+        cpu.execute(&mut machine);
+    }
+    machine.peek_mem(0)
+}
 
-use std::path::Path;
+#[test]
+fn headless_machines_in_parallel() {
+    // LDX #0; loop: INX; STX $00; JMP loop
+    let first = std::thread::spawn(|| {
+        run_headless_program("first", &[0xA2, 0x00, 0xE8, 0x86, 0x00, 0x4C, 0x02, 0x80], 301)
+    });
+    // LDX #0; loop: INX; INX; STX $00; JMP loop
+    let second = std::thread::spawn(|| {
+        run_headless_program("second", &[0xA2, 0x00, 0xE8, 0xE8, 0x86, 0x00, 0x4C, 0x02, 0x80], 401)
+    });
+    assert_eq!(first.join().unwrap(), 100);
+    assert_eq!(second.join().unwrap(), 200);
+}
 
-// Needs nestest.nes and nestest.log from wiki.nesdev.com in same directory
-#[cfg(test)]
-fn test_nestest_rom(verbose: bool) {
-    let mut machine = nes::Machine::new(false);
+// Runs the ROMs listed in golden_frames.txt and compares the hash of their
+// last frame with the one recorded there. ROMs that are not found are
+// skipped.
+#[test]
+fn golden_frames() {
+    let golden = fs::read_to_string("golden_frames.txt").expect("Unable to read golden_frames.txt");
+    let mut failures = Vec::new();
+    for line in golden.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let (rom, frames, hash) = match fields[..] {
+            [rom, frames, hash] => (Path::new(rom), frames.parse::<u32>().unwrap(),
+                                    u32::from_str_radix(hash, 16).unwrap()),
+            _ => panic!("Invalid line in golden_frames.txt: {}", line),
+        };
+        if !rom.is_file() {
+            println!("Skipping {}, not found", rom.display());
+            continue;
+        }
+        let (mut machine, mut cpu) = load_machine(nes::Machine::new_headless(),
+                                                  load_cartridge(rom), nes::Region::Ntsc);
+        for _ in 0..frames {
+            nes::run_frame(&mut cpu, &mut machine);
+        }
+        if machine.frame_hash() != hash {
+            failures.push(format!("{} after {} frames: {:08X}, expected {:08X}",
+                                  rom.display(), frames, machine.frame_hash(), hash));
+        }
+    }
+    assert!(failures.is_empty(), "Frames differ:\n{}", failures.join("\n"));
+}
+
+#[test]
+fn resume_state_is_not_saved_while_a_movie_plays() {
+    let rom = nes::cartridge::nrom_image(&[0x4C, 0x00, 0x80]); // loop: JMP loop
+    let (machine, cpu) = load_machine(nes::Machine::new_headless(),
+                                      nes::cartridge::Cartridge::from_bytes(&rom).unwrap(),
+                                      nes::Region::Ntsc);
+    let path = env::temp_dir().join(format!("nesemu-resume-{}.state", process::id()));
+    save_resume_state(&path, &cpu, &machine, 0, true);
+    assert!(!path.exists());
+    save_resume_state(&path, &cpu, &machine, 0, false);
+    assert!(path.is_file());
+    fs::remove_file(&path).unwrap();
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn load_cartridge(rom: &Path) -> nes::cartridge::Cartridge {
+    if !rom.is_file() {
+        exit_with_error(&format!("ROM file not found: {}", rom.display()));
+    }
+    let database = match config::database_path() {
+        Some(path) => nes::database::Database::load(&path).unwrap_or_else(|e| exit_with_error(&e)),
+        None => nes::database::Database::built_in(),
+    };
+    nes::cartridge::Cartridge::load_with_database(rom, &database)
+        .unwrap_or_else(|e| exit_with_error(&format!("Unable to load {}: {}", rom.display(), e)))
+}
+
+fn load_machine(mut machine: nes::Machine, cartridge: nes::cartridge::Cartridge,
+                region: nes::Region) -> (nes::Machine, nes::cpu::Cpu) {
     let mut cpu = nes::cpu::Cpu::new();
-    let cartridge = nes::cartridge::Cartridge::load(Path::new("nestest.nes"));
+    machine.set_region(region);
     machine.load_cartridge(cartridge);
     cpu.reset(&mut machine);
-    cpu.set_program_counter(0xc000);
-    machine.set_scan_line(241);
+    (machine, cpu)
+}
+
+// Settings for the game from the configuration file; the command line
+// options take precedence over these
+fn load_settings(config_path: Option<&PathBuf>, crc32: u32) -> config::Settings {
+    let path = match config_path {
+        Some(path) => {
+            if !path.is_file() {
+                exit_with_error(&format!("Configuration file not found: {}", path.display()));
+            }
+            Some(path.clone())
+        }
+        None => config::Config::default_path(),
+    };
+    match path {
+        Some(path) => config::Config::load(&path).unwrap_or_else(|e| exit_with_error(&e))
+            .settings_for_game(crc32),
+        None => config::Settings::default(),
+    }
+}
+
+// Applies the input of the next movie frame. Returns false when the movie has ended.
+fn play_movie_frame(movie: &mut movie::Movie, cpu: &mut nes::cpu::Cpu,
+                    machine: &mut nes::Machine) -> bool {
+    let frame = match movie.next_frame() {
+        Some(frame) => frame,
+        None => { return false; }
+    };
+    match frame.event {
+        Some(nes::SystemEvent::Reset) => { nes::reset(cpu, machine); }
+        Some(nes::SystemEvent::PowerCycle) => { nes::power_cycle(cpu, machine); }
+        _ => {}
+    }
+    machine.controller.set_buttons(frame.buttons);
+    true
+}
+
+// In the top right corner
+fn show_fps(machine: &mut nes::Machine, clock: &clock::FrameClock) {
+    if let Some(ref mut screen) = machine.screen {
+        let text = format!("{:.1} fps {:+.0} ms", clock.fps(), clock.drift_ms());
+        screen.draw_text(nes::screen::WIDTH - 8 - text.len() as u32 * 4, 8, &text);
+    }
+}
+
+fn run(rom: &Path, options: &cli::RunOptions) {
+    let mut cartridge = load_cartridge(rom);
+    let crc32 = cartridge.crc32();
+    let earlier_playtime = played::start(rom, crc32);
+    let started = Instant::now();
+    let playtime = || earlier_playtime + started.elapsed().as_secs();
+    let settings = load_settings(options.config.as_ref(), crc32);
+    cartridge.set_save_path(&config::save_path(rom, crc32, settings.save_dir.as_deref()));
+    if let Some(enabled) = options.bus_conflicts.or(settings.bus_conflicts) {
+        cartridge.set_bus_conflicts(enabled);
+    }
+    let mut movie = options.movie.as_ref()
+        .map(|path| movie::Movie::load(path).unwrap_or_else(|e| exit_with_error(&e)));
+    let region = match (options.region, movie.as_ref()) {
+        (Some(region), _) => region,
+        (None, Some(movie)) => movie.region,
+        (None, None) => settings.region().unwrap_or_else(|e| exit_with_error(&e))
+            .or_else(|| cartridge.region())
+            .unwrap_or(nes::Region::Ntsc),
+    };
+    let default_audio = nes::AudioOutput::default();
+    let audio = nes::AudioOutput {
+        sample_rate: options.sample_rate.or(settings.sample_rate).unwrap_or(default_audio.sample_rate),
+        channels: options.audio_channels.or(settings.audio_channels).unwrap_or(default_audio.channels),
+        device_buffer: options.audio_device_buffer.or(settings.audio_device_buffer),
+    };
+    audio.check().unwrap_or_else(|e| exit_with_error(&e));
+    let fast_forward_audio = match options.fast_forward_audio {
+        Some(audio) => Some(audio),
+        None => settings.fast_forward_audio().unwrap_or_else(|e| exit_with_error(&e)),
+    };
+    let clock_mode = match options.clock {
+        Some(mode) => mode,
+        None => settings.clock().unwrap_or_else(|e| exit_with_error(&e)).unwrap_or(clock::ClockMode::Audio),
+    };
+    if clock_mode == clock::ClockMode::Vsync {
+        // Showing the picture then waits for the display
+        sdl2::hint::set("SDL_RENDER_VSYNC", "1");
+    }
+    let machine = nes::Machine::new(options.show_name_table, audio);
+    let (mut machine, mut cpu) = load_machine(machine, cartridge, region);
+    if let Some(audio) = fast_forward_audio {
+        machine.apu.set_fast_forward_audio(audio);
+    }
+    machine.apu.set_reduce_pops(options.reduce_pops || settings.reduce_pops.unwrap_or(false));
+    if let Some(speed) = options.speed.or(settings.speed) {
+        if !nes::SPEED_RANGE.contains(&speed) {
+            exit_with_error(&format!("The speed must be from 25 to 400 percent, not {}", speed));
+        }
+        machine.set_speed(speed);
+    }
+    if let Some(variant) = options.ppu_variant {
+        machine.set_ppu_variant(variant);
+    }
+
+    if let Some(ref mut screen) = machine.screen {
+        if let Some(scale) = options.scale.or(settings.scale) {
+            screen.set_scale(scale);
+        }
+        screen.set_aspect_correction(options.aspect_correction);
+        screen.set_frame_blend(options.frame_blend.or(settings.frame_blend).unwrap_or(0));
+        let filter = match options.filter {
+            Some(filter) => Some(filter),
+            None => settings.filter().unwrap_or_else(|e| exit_with_error(&e)),
+        };
+        if let Some(filter) = filter {
+            screen.set_filter(filter);
+        }
+        if options.fullscreen {
+            screen.toggle_fullscreen();
+        }
+    }
+    let layout_path = config::layout_path();
+    if let (Some(screen), Some(path)) = (machine.screen.as_mut(), layout_path.as_ref()) {
+        match config::load_layout(path) {
+            Ok(layout) => screen.set_layout(&layout),
+            Err(e) => eprintln!("{}", e),
+        }
+        if options.show_name_table && !screen.shows_name_table() {
+            screen.toggle_debug_windows();
+        }
+    }
+    for (player, button, keycode) in settings.key_bindings().unwrap_or_else(|e| exit_with_error(&e)) {
+        machine.controller.set_key_binding(player, button, keycode)
+            .unwrap_or_else(|e| exit_with_error(&e));
+    }
+    if let Some(path) = options.palette.as_ref().or(settings.palette.as_ref()) {
+        let colors = fs::read(path).unwrap_or_else(
+            |e| exit_with_error(&format!("Unable to read palette {}: {}", path.display(), e)));
+        if colors.len() < 64 * 3 {
+            exit_with_error(&format!("Palette {} must contain 64 RGB colors", path.display()));
+        }
+        machine.ppu.set_palette(&colors);
+    }
+    machine.ppu.set_left_clip_mode(options.left_clip_mode);
+    machine.ppu.set_warm_up(options.ppu_warm_up || settings.ppu_warm_up.unwrap_or(false));
+    machine.controller.set_ports_swapped(options.swap_ports);
+    machine.controller.set_four_score(options.four_score || settings.four_score.unwrap_or(false));
+    machine.set_pause_on_focus_loss(options.pause_in_background
+                                    || settings.pause_in_background.unwrap_or(false));
+    machine.set_dmc_conflict_mitigation(options.mitigate_dmc_conflict);
+    cpu.set_cycle_accurate(options.cycle_accurate);
+    cpu.set_history_size(options.history);
+    let seed = options.seed.unwrap_or_else(|| {
+        // The same movie always plays the same way
+        if movie.is_some() {
+            return 0;
+        }
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        if options.ram_init == nes::RamInit::Random {
+            println!("Random seed: {}", seed);
+        }
+        seed
+    });
+    machine.set_seed(seed);
+    machine.set_ram_init(options.ram_init);
+    if options.measure_latency {
+        machine.enable_latency_probe();
+    }
+    if options.debug_port {
+        machine.enable_debug_port();
+    }
 
-    let baseline = File::open("nestest.log")
-        .expect("Unable to open nestest.log");
-    let mut baseline = BufReader::new(baseline);
+    if let Some(path) = options.cdl.as_ref() {
+        start_code_data_log(&mut machine, path);
+    }
+    if options.apu_log.is_some() {
+        machine.start_apu_log();
+    }
+    // Recording costs time on every frame, so it is only done when asked for
+    let clip_seconds = options.clip_seconds.or(settings.clip_seconds).unwrap_or(0);
+    if clip_seconds > 0 {
+        machine.start_clip_recording(clip_seconds);
+    }
 
-    let mut line_no = 1;
-    loop {
-        if verbose {
-            println!("{}", nes::get_state_string(&cpu, &mut machine));
+    let resume_path = config::resume_path(crc32);
+    if options.resume.or(settings.resume).unwrap_or(false) && movie.is_none() {
+        if let Some(path) = resume_path.as_ref().filter(|path| path.is_file()) {
+            match load_state_file(path, &mut cpu, &mut machine) {
+                Ok(()) => report(&mut machine, "Resumed"),
+                Err(e) => eprintln!("Unable to resume from {}: {}", path.display(), e),
+            }
         }
+    }
+
+    const DEFAULT_BUFFER_SIZE_MS: u32 = 35;
+    let audio_buffer_ms = options.audio_buffer_ms.or(settings.audio_buffer_ms)
+        .unwrap_or(DEFAULT_BUFFER_SIZE_MS);
+    let mut clock = clock::FrameClock::new(clock_mode, region.exact_frame_rate(), audio_buffer_ms as usize);
+
+    if options.netplay.is_some() && movie.is_some() {
+        exit_with_error("--movie can not be used with netplay");
+    }
+    // A script could make the input or the machine differ between the sides
+    if options.netplay.is_some() && options.script.is_some() {
+        exit_with_error("--script can not be used with netplay");
+    }
+    let mut netplay = options.netplay.as_ref()
+        .map(|netplay| start_netplay(netplay, options.input_delay, &mut cpu, &mut machine));
+
+    let broadcast = options.stream.map(|port| start_stream(port, &mut machine));
 
-        let mut baseline_line = String::new();
-        baseline.read_line(&mut baseline_line).unwrap();
-        baseline_line = baseline_line.trim().to_string();
+    let mut script = options.script.as_ref().map(|path| {
+        script::Script::load(path, &mut machine).unwrap_or_else(|e| exit_with_error(&e))
+    });
 
-        if baseline_line == "" {
-            break; // finished
+    let mut console = if options.console { Some(console::Console::start()) } else { None };
+
+    if options.remote.is_some() && netplay.is_some() {
+        exit_with_error("--remote can not be used with netplay");
+    }
+    let mut remote = options.remote.as_ref()
+        .map(|address| remote::Remote::start(address).unwrap_or_else(|e| exit_with_error(&e)));
+
+    if let Some(ref mut m) = movie {
+        machine.set_status("playing movie", true);
+        play_movie_frame(m, &mut cpu, &mut machine);
+    }
+
+    // The battery backed RAM is written to disk when it has changed, once a
+    // second, so that little is lost if nesemu does not exit cleanly
+    const SAVE_INTERVAL_FRAMES: u32 = 60;
+    let mut frames_since_save = 0;
+    let mut jam_reported = false;
+    let mut rewind = rewind::Rewind::new();
+    let mut paused_by_key = false;
+    let mut advance = false;
+    // The saved states, while they are shown
+    let mut states_shown: Option<Vec<(u8, Option<nes::state::StateInfo>)>> = None;
+
+    'running: loop {
+        for event in machine.handle_events() {
+            match event {
+                nes::SystemEvent::Quit => {
+                    break 'running;
+                }
+                // Both sides have to do these on the same frame
+                nes::SystemEvent::Reset | nes::SystemEvent::PowerCycle | nes::SystemEvent::LoadState(_)
+                | nes::SystemEvent::ShowStates | nes::SystemEvent::Pause | nes::SystemEvent::FrameAdvance | nes::SystemEvent::FrameBack
+                    if netplay.is_some() => {
+                    report(&mut machine, "Not available during netplay");
+                }
+                nes::SystemEvent::Reset => {
+                    if let Err(e) = machine.save_if_changed() {
+                        report(&mut machine, &e);
+                    }
+                    nes::reset(&mut cpu, &mut machine);
+                    rewind.clear();
+                }
+                nes::SystemEvent::PowerCycle => {
+                    if let Err(e) = machine.save_if_changed() {
+                        report(&mut machine, &e);
+                    }
+                    nes::power_cycle(&mut cpu, &mut machine);
+                    rewind.clear();
+                }
+                nes::SystemEvent::SaveState(slot) => {
+                    save_state_slot(rom, slot, &cpu, &mut machine, playtime());
+                    if states_shown.is_some() {
+                        states_shown = Some(state_slots(rom));
+                    }
+                }
+                nes::SystemEvent::LoadState(slot) => {
+                    load_state_slot(rom, slot, &mut cpu, &mut machine);
+                    rewind.clear();
+                    states_shown = None;
+                }
+                nes::SystemEvent::ShowStates if states_shown.is_some() => {
+                    states_shown = None;
+                }
+                nes::SystemEvent::ShowStates => {
+                    let slots = state_slots(rom);
+                    if slots.is_empty() {
+                        report(&mut machine, "No saved states");
+                    }
+                    else {
+                        print_state_slots(&slots);
+                        states_shown = Some(slots);
+                    }
+                }
+                nes::SystemEvent::SaveClip => {
+                    save_clip(rom, &mut machine);
+                }
+                nes::SystemEvent::Pause => {
+                    paused_by_key = !paused_by_key;
+                    machine.set_status("paused", paused_by_key);
+                }
+                nes::SystemEvent::FrameAdvance => {
+                    paused_by_key = true;
+                    advance = true;
+                    machine.set_status("paused", true);
+                }
+                // The frames of a movie or a script are not kept, see below
+                nes::SystemEvent::FrameBack if movie.is_some() || script.is_some() => {
+                    report(&mut machine, "Not available while a movie or a script runs");
+                }
+                nes::SystemEvent::FrameBack => {
+                    paused_by_key = true;
+                    machine.set_status("paused", true);
+                    if let Err(e) = rewind.step_back(&mut cpu, &mut machine) {
+                        report(&mut machine, &e);
+                    }
+                }
+            }
+        }
+        if let Some(ref mut session) = netplay {
+            let result = session.exchange_input(machine.controller.keyboard_buttons(1)).map(|buttons| {
+                machine.controller.set_player_buttons(1, buttons[0]);
+                machine.controller.set_player_buttons(2, buttons[1]);
+            });
+            if let Err(e) = result {
+                stop_netplay(&mut machine, &e);
+                netplay = None;
+            }
+        }
+        if let Some(ref mut r) = remote {
+            r.run_requests(&mut machine);
+        }
+        // The other player would have to wait during netplay
+        let in_background = machine.is_paused_in_background() && netplay.is_none();
+        let paused = (paused_by_key && !advance) || in_background || states_shown.is_some()
+            || remote.as_ref().is_some_and(|r| r.is_paused());
+        advance = false;
+        let running = cpu.error().is_none() && !paused;
+        if running {
+            // Running them again would not repeat what the movie, the script
+            // or the other player did
+            if movie.is_none() && script.is_none() && netplay.is_none() {
+                rewind.start_frame(&cpu, &machine);
+            }
+            else {
+                rewind.clear();
+            }
+            let frame = panic::catch_unwind(AssertUnwindSafe(|| {
+                run_game_frame(&mut script, &mut cpu, &mut machine);
+            }));
+            if let Err(panic) = frame {
+                print_history(&cpu);
+                panic::resume_unwind(panic);
+            }
+            if let Some(e) = cpu.error() {
+                report(&mut machine, &format!("{}, reset to continue", e));
+                print_history(&cpu);
+            }
+            if let Some(address) = machine.take_breakpoint_hit() {
+                paused_by_key = true;
+                machine.set_status("paused", true);
+                report(&mut machine, &format!("Breakpoint at ${:04X}, paused", address));
+                print_history(&cpu);
+            }
+            if cpu.is_jammed() != jam_reported {
+                jam_reported = cpu.is_jammed();
+                if jam_reported {
+                    let pc = cpu.state().pc;
+                    report(&mut machine, &format!("The CPU jammed at ${:04X}, reset to continue", pc));
+                    print_history(&cpu);
+                }
+            }
+            if let Some(ref mut r) = remote {
+                r.end_frame(&mut machine);
+            }
+            if let Some(ref b) = broadcast {
+                let samples = machine.take_played_samples();
+                b.send_frame(machine.frame_buffer(), samples);
+            }
+        }
+        if let Some(ref mut session) = netplay {
+            if let Err(e) = session.check_state(&cpu, &machine) {
+                stop_netplay(&mut machine, &e);
+                netplay = None;
+            }
+        }
+        if let Some(ref mut c) = console {
+            c.run_commands(&mut machine);
+            c.draw(&mut machine);
+        }
+        if options.show_fps {
+            show_fps(&mut machine, &clock);
+        }
+        if let Some(ref slots) = states_shown {
+            draw_state_slots(&mut machine, slots);
+        }
+        machine.present();
+        frames_since_save += 1;
+        if frames_since_save == SAVE_INTERVAL_FRAMES {
+            if let Err(e) = machine.save_if_changed() {
+                report(&mut machine, &e);
+            }
+            frames_since_save = 0;
+        }
+        // Movie frames start at vertical blank, like in FCEUX
+        if let Some(m) = movie.as_mut().filter(|_| !paused) {
+            if !play_movie_frame(m, &mut cpu, &mut machine) {
+                println!("Movie finished");
+                machine.controller.enable_keyboard();
+                machine.set_status("playing movie", false);
+                movie = None;
+            }
+        }
+        print_debug_output(&mut machine);
+        if running {
+            clock.set_frame_rate(machine.region().exact_frame_rate() * machine.speed() as f64 / 100.0);
+            clock.end_frame(machine.get_audio_queue_size_ms());
         }
-        if baseline_line != nes::get_state_string(&cpu, &mut machine) {
-            assert!(false, "Mismatch at line {}!\n{}\nBaseline:\n{}\n",
-                    line_no, nes::get_state_string(&cpu, &mut machine), baseline_line);
-            break;
+        else {
+            // The game has stopped or is paused; the picture stays. In the
+            // background the window is hardly seen, so it is updated less.
+            clock.idle(in_background);
         }
+    }
 
-        cpu.execute(&mut machine);
-        line_no += 1;
+    if let (Some(screen), Some(path)) = (machine.screen.as_ref(), layout_path.as_ref()) {
+        if let Err(e) = config::save_layout(path, &screen.layout()) {
+            eprintln!("{}", e);
+        }
+    }
+    if let Some(path) = resume_path.as_ref() {
+        save_resume_state(path, &cpu, &machine, playtime(), movie.is_some());
+    }
+    if let Some(path) = options.cdl.as_ref() {
+        save_code_data_log(&machine, path);
     }
+    if let Some(path) = options.apu_log.as_ref() {
+        save_apu_log(&machine, path);
+    }
+    if let Err(e) = machine.save() {
+        eprintln!("{}", e);
+    }
+    played::add_playtime(rom, started.elapsed().as_secs());
 }
 
-#[test]
-fn nestest_rom() {
-    test_nestest_rom(false);
+// The slots with a saved state, with its info unless an older version of
+// nesemu saved it
+fn state_slots(rom: &Path) -> Vec<(u8, Option<nes::state::StateInfo>)> {
+    (1..=10).filter_map(|slot| {
+        let bytes = fs::read(state_slot_path(rom, slot)).ok()?;
+        let info = nes::state::parse_state_file(&bytes).ok().and_then(|(info, _)| info);
+        Some((slot, info))
+    }).collect()
 }
 
-fn main()
-{
-    let mut machine = nes::Machine::new(false);
-    let mut cpu = nes::cpu::Cpu::new();
-    let args: Vec<_> = env::args().collect();
+fn print_state_slots(slots: &[(u8, Option<nes::state::StateInfo>)]) {
+    for (slot, info) in slots {
+        match info {
+            Some(info) => println!("State {}: saved {} UTC, played {}", slot,
+                                   played::format_time(info.saved_at),
+                                   played::format_playtime(info.playtime_seconds)),
+            None => println!("State {}: saved by an older version", slot),
+        }
+    }
+}
 
-    let cartridge = nes::cartridge::Cartridge::load(Path::new(&args[1]));
-    machine.load_cartridge(cartridge);
-    cpu.reset(&mut machine);
+// The thumbnails of the states four to a row, each with its slot number and
+// the playtime when it was saved
+fn draw_state_slots(machine: &mut nes::Machine, slots: &[(u8, Option<nes::state::StateInfo>)]) {
+    use nes::state::{THUMBNAIL_HEIGHT, THUMBNAIL_WIDTH};
+    const TOP: u32 = 16;
+    let screen = match machine.screen.as_mut() {
+        Some(screen) => screen,
+        None => { return; }
+    };
+    screen.draw_text(8, 4, "F1-F10 loads a state, L returns to the game");
+    for (slot, info) in slots {
+        let index = *slot as u32 - 1;
+        let x = index % 4 * THUMBNAIL_WIDTH;
+        let y = TOP + index / 4 * (THUMBNAIL_HEIGHT + 4);
+        match info {
+            Some(info) => {
+                screen.draw_image(x, y, THUMBNAIL_WIDTH, &info.thumbnail);
+                screen.draw_text(x + 2, y + THUMBNAIL_HEIGHT - 7,
+                                 &played::format_playtime(info.playtime_seconds));
+            }
+            None => {
+                screen.draw_box(x + 1, y + 1, THUMBNAIL_WIDTH - 2, THUMBNAIL_HEIGHT - 2, [0x80; 3]);
+            }
+        }
+        screen.draw_text(x + 2, y + 2, &slot.to_string());
+    }
+}
+
+// Runs a frame, with the script if there is one
+fn run_game_frame(script: &mut Option<script::Script>, cpu: &mut nes::cpu::Cpu,
+                  machine: &mut nes::Machine) {
+    if let Some(ref mut s) = script {
+        if let Err(e) = s.run_frame(cpu, machine) {
+            report(machine, &format!("Script stopped: {}", e));
+            machine.controller.clear_overrides();
+            *script = None;
+        }
+    }
+    else {
+        // An error stops the CPU, see cpu.error()
+        nes::try_run_frame(cpu, machine).ok();
+    }
+}
+
+fn print_history(cpu: &nes::cpu::Cpu) {
+    let history = cpu.history();
+    if !history.is_empty() {
+        eprintln!("The last instructions executed were:");
+        for line in history {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+// Continues the log in the file if there is one
+fn start_code_data_log(machine: &mut nes::Machine, path: &Path) {
+    let previous = if path.exists() {
+        Some(fs::read(path)
+             .unwrap_or_else(|e| exit_with_error(&format!("Unable to read {}: {}", path.display(), e))))
+    }
+    else {
+        None
+    };
+    machine.start_code_data_log(previous.as_deref()).unwrap_or_else(|e| exit_with_error(&e));
+}
+
+fn save_apu_log(machine: &nes::Machine, path: &Path) {
+    let writes = machine.apu_log().unwrap().write_count();
+    match fs::write(path, machine.apu_log_vgm().unwrap()) {
+        Ok(()) => println!("{} sound register writes saved to {}", writes, path.display()),
+        Err(e) => eprintln!("Unable to write {}: {}", path.display(), e),
+    }
+}
+
+fn save_code_data_log(machine: &nes::Machine, path: &Path) {
+    let log = machine.code_data_log().unwrap();
+    match fs::write(path, log.to_bytes()) {
+        Ok(()) => {
+            let (code, data) = log.coverage();
+            println!("Code/data log written to {}: {} bytes of code and {} of data",
+                     path.display(), code, data);
+        }
+        Err(e) => eprintln!("Unable to write {}: {}", path.display(), e),
+    }
+}
+
+// Waits for or connects to the other player. The local keyboard plays player
+// 1 on the host and player 2 on the other side.
+fn start_netplay(netplay: &cli::Netplay, input_delay: u32, cpu: &mut nes::cpu::Cpu,
+                 machine: &mut nes::Machine) -> netplay::Session {
+    let session = match netplay {
+        cli::Netplay::Host(port) => {
+            let listener = TcpListener::bind(("0.0.0.0", *port)).unwrap_or_else(
+                |e| exit_with_error(&format!("Unable to listen on port {}: {}", port, e)));
+            println!("Waiting for player 2 to connect to port {}", port);
+            netplay::Session::host(&listener, input_delay, cpu, machine)
+        }
+        cli::Netplay::Connect(address) => {
+            println!("Connecting to {}", address);
+            netplay::Session::connect(address, cpu, machine)
+        }
+    }.unwrap_or_else(|e| exit_with_error(&e));
+    machine.controller.plug_in(2);
+    machine.set_status("netplay", true);
+    report(machine, &format!("Netplay started as player {}", session.player()));
+    session
+}
+
+// The game goes on without the other player
+fn stop_netplay(machine: &mut nes::Machine, message: &str) {
+    report(machine, message);
+    machine.controller.enable_keyboard();
+    machine.set_status("netplay", false);
+}
+
+// Spectators see the pictures and hear the sound of the frames that have run
+fn start_stream(port: u16, machine: &mut nes::Machine) -> spectate::Broadcast {
+    let listener = TcpListener::bind(("0.0.0.0", port)).unwrap_or_else(
+        |e| exit_with_error(&format!("Unable to listen on port {}: {}", port, e)));
+    println!("Spectators can watch with nesemu watch <host>:{}", port);
+    machine.set_keep_played_samples(true);
+    machine.set_status("streaming", true);
+    spectate::Broadcast::start(listener, machine.audio_sample_rate())
+}
+
+fn disassemble(rom: &Path, start: u16, end: u16, trace: bool, cdl: Option<&Path>) {
+    let (mut machine, mut cpu) = load_machine(nes::Machine::new_headless(), load_cartridge(rom),
+                                              nes::Region::Ntsc);
+    if let Some(path) = cdl {
+        let data = fs::read(path)
+            .unwrap_or_else(|e| exit_with_error(&format!("Unable to read {}: {}", path.display(), e)));
+        machine.start_code_data_log(Some(&data)).unwrap_or_else(|e| exit_with_error(&e));
+    }
+    let lines = if trace {
+        cpu.disassemble_traced(&mut machine, start as usize, end as usize)
+    }
+    else {
+        cpu.disassemble(&mut machine, start as usize, end as usize)
+    };
+    for line in lines {
+        println!("{}", line);
+    }
+}
 
-    if args.len() >= 3 && args[2] == "disassemble" {
-        for line in cpu.disassemble(
-            &mut machine,
-            usize::from_str_radix(&args[3], 16).unwrap(),
-            usize::from_str_radix(&args[4], 16).unwrap(),
-        ) {
-            println!("{}", line);
+// The state slots are kept next to the ROM and its save file, as
+// game.state1 to game.state10
+fn state_slot_path(rom: &Path, slot: u8) -> PathBuf {
+    rom.with_extension(format!("state{}", slot))
+}
+
+// The first of game-1.gif, game-2.gif and so on that does not exist yet
+fn clip_path(rom: &Path) -> PathBuf {
+    let stem = rom.file_stem().unwrap_or_default().to_string_lossy();
+    (1..).map(|n| rom.with_file_name(format!("{}-{}.gif", stem, n)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+// The GIF is encoded on a thread of its own, which takes a moment
+fn save_clip(rom: &Path, machine: &mut nes::Machine) {
+    let clip = match machine.clip() {
+        Some(clip) if clip.frame_count() > 0 => clip.clone(),
+        _ => {
+            report(machine, "No clip recorded, see --clip-seconds");
+            return;
         }
+    };
+    let path = clip_path(rom);
+    report(machine, &format!("Saving the last {:.1} s as {}", clip.seconds(),
+                             path.file_name().unwrap().to_string_lossy()));
+    std::thread::spawn(move || {
+        if let Err(e) = fs::write(&path, clip.to_gif()) {
+            eprintln!("Unable to write {}: {}", path.display(), e);
+        }
+    });
+}
+
+// Prints the message and shows it on the screen
+fn report(machine: &mut nes::Machine, message: &str) {
+    println!("{}", message);
+    if let Some(screen) = machine.screen.as_mut() {
+        screen.show_message(message);
+    }
+}
+
+// With the info listed before loading a state: the time, the game, how long
+// it has been played and a thumbnail of the picture
+fn save_state_file(path: &Path, cpu: &nes::cpu::Cpu, machine: &nes::Machine, playtime_seconds: u64)
+                   -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    let info = nes::state::StateInfo {
+        saved_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+        crc32: machine.rom_crc32(),
+        playtime_seconds,
+        thumbnail: nes::state::thumbnail(machine.frame_buffer()),
+    };
+    let bytes = nes::state::state_file_bytes(&info, &nes::snapshot(cpu, machine));
+    fs::write(path, bytes).map_err(|e| e.to_string())
+}
+
+// Saves the state to continue from with --resume. Not while a movie plays,
+// as the movie and not the player got the game there.
+fn save_resume_state(path: &Path, cpu: &nes::cpu::Cpu, machine: &nes::Machine, playtime_seconds: u64,
+                     movie_playing: bool) {
+    if movie_playing {
         return;
     }
+    if let Err(e) = save_state_file(path, cpu, machine, playtime_seconds) {
+        eprintln!("Unable to write {}: {}", path.display(), e);
+    }
+}
 
-    'running: loop {
-        match machine.handle_events() {
-            Some(ref e) if *e == nes::SystemEvent::Quit => {
-                break 'running;
+fn load_state_file(path: &Path, cpu: &mut nes::cpu::Cpu, machine: &mut nes::Machine)
+                   -> Result<(), String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let (_, state) = nes::state::parse_state_file(&bytes)?;
+    nes::load_snapshot(cpu, machine, &state)
+}
+
+fn save_state_slot(rom: &Path, slot: u8, cpu: &nes::cpu::Cpu, machine: &mut nes::Machine,
+                   playtime_seconds: u64) {
+    let path = state_slot_path(rom, slot);
+    match save_state_file(&path, cpu, machine, playtime_seconds) {
+        Ok(()) => report(machine, &format!("Saved state {}", slot)),
+        Err(e) => {
+            eprintln!("Unable to write {}: {}", path.display(), e);
+            report(machine, &format!("State {} not saved", slot));
+        }
+    }
+}
+
+fn load_state_slot(rom: &Path, slot: u8, cpu: &mut nes::cpu::Cpu, machine: &mut nes::Machine) {
+    let path = state_slot_path(rom, slot);
+    if !path.is_file() {
+        report(machine, &format!("No state {}", slot));
+        return;
+    }
+    match load_state_file(&path, cpu, machine) {
+        Ok(()) => report(machine, &format!("Loaded state {}", slot)),
+        Err(e) => {
+            eprintln!("Unable to load {}: {}", path.display(), e);
+            report(machine, &format!("State {} not loaded", slot));
+        }
+    }
+}
+
+// Runs the ROM headless as fast as possible, without input and from zeroed
+// RAM, so that every run does the same work
+fn bench(rom: &Path, frames: u32) {
+    let (mut machine, mut cpu) = load_machine(nes::Machine::new_headless(), load_cartridge(rom),
+                                              nes::Region::Ntsc);
+    machine.enable_profiling();
+    let start = Instant::now();
+    for _ in 0..frames {
+        nes::run_frame(&mut cpu, &mut machine);
+    }
+    let total = start.elapsed().as_secs_f64();
+    let profile = machine.profile().unwrap();
+    let ppu = profile.ppu.as_secs_f64();
+    let apu = profile.apu.as_secs_f64();
+    let cpu_time = total - ppu - apu;
+
+    const NTSC_FRAME_RATE: f64 = 60.0988;
+    let frames_per_second = frames as f64 / total;
+    println!("{} frames in {:.2} s: {:.1} frames/s, {:.1} times real time",
+             frames, total, frames_per_second, frames_per_second / NTSC_FRAME_RATE);
+    println!("{} instructions, {:.2} million/s",
+             cpu.instruction_count(), cpu.instruction_count() as f64 / total / 1e6);
+    for (name, time) in &[("CPU", cpu_time), ("PPU", ppu), ("APU", apu)] {
+        println!("{}: {:6.2} s {:5.1}%", name, time, time / total * 100.0);
+    }
+    println!("Hash of the last frame: {:08X}", machine.frame_hash());
+}
+
+fn print_debug_output(machine: &mut nes::Machine) {
+    for line in machine.take_debug_output() {
+        println!("debug: {}", line);
+    }
+}
+
+// Runs a test ROM using the protocol of blargg's test ROMs: $6000 holds the
+// status (0x80 while running, 0x81 when the ROM wants to be reset, otherwise
+// the result code), $6001-$6003 the signature DE B0 61 and $6004 the message.
+fn run_test_rom(rom: &Path) -> i32 {
+    let (mut machine, mut cpu) = load_machine(nes::Machine::new_headless(), load_cartridge(rom),
+                                              nes::Region::Ntsc);
+    machine.enable_debug_port();
+
+    const MAX_FRAMES: u32 = 60 * 60;
+    const RESET_DELAY_FRAMES: u32 = 10;
+    let mut frame = 0;
+    let mut reset_frame = None;
+    while frame < MAX_FRAMES {
+        nes::run_frame(&mut cpu, &mut machine);
+        frame += 1;
+        print_debug_output(&mut machine);
+
+        let signature = [machine.peek_mem(0x6001), machine.peek_mem(0x6002),
+                         machine.peek_mem(0x6003)];
+        if signature != [0xDE, 0xB0, 0x61] {
+            continue;
+        }
+        match machine.peek_mem(0x6000) {
+            0x80 => {}
+            0x81 => {
+                match reset_frame {
+                    None => { reset_frame = Some(frame + RESET_DELAY_FRAMES); }
+                    Some(f) if f == frame => {
+                        nes::reset(&mut cpu, &mut machine);
+                        reset_frame = None;
+                    }
+                    Some(_) => {}
+                }
             }
-            Some(ref e) if *e == nes::SystemEvent::Reset => {
-                cpu.reset(&mut machine);
+            result => {
+                let mut message = String::new();
+                let mut address = 0x6004;
+                while address < 0x8000 {
+                    let c = machine.peek_mem(address);
+                    if c == 0 {
+                        break;
+                    }
+                    message.push(c as char);
+                    address += 1;
+                }
+                println!("{}", message.trim_end());
+                println!("Result: {}", if result == 0 { "passed".to_string() }
+                                       else { format!("failed ({})", result) });
+                return result as i32;
             }
-            None | Some(_) => {}
         }
-        let prev_quarter_frame_count = machine.apu.quarter_frame_count;
-        while machine.apu.quarter_frame_count == prev_quarter_frame_count {
-            let prev_vblank = machine.ppu.vblank;
-            cpu.execute(&mut machine);
-            if machine.ppu.vblank && !prev_vblank {
-                machine.present();
+    }
+    eprintln!("Test did not finish within {} frames", MAX_FRAMES);
+    -1
+}
+
+fn diff_states(first: &Path, second: &Path) {
+    let load = |path: &Path| {
+        let bytes = fs::read(path).unwrap_or_else(
+            |e| exit_with_error(&format!("Unable to read {}: {}", path.display(), e)));
+        nes::state::parse_state_file(&bytes).map(|(_, state)| state).unwrap_or_else(
+            |e| exit_with_error(&format!("{}: {}", path.display(), e)))
+    };
+    let differences = load(first).diff(&load(second));
+    if differences.is_empty() {
+        println!("The states are identical");
+    }
+    for line in differences {
+        println!("{}", line);
+    }
+}
+
+fn import_save(rom: &Path, source: &Path, overwrite: bool) {
+    let cartridge = load_cartridge(rom);
+    if !cartridge.has_battery() {
+        exit_with_error(&format!("{} has no battery backed save RAM", rom.display()));
+    }
+    let settings = load_settings(None, cartridge.crc32());
+    let save_path = config::save_path(rom, cartridge.crc32(), settings.save_dir.as_deref());
+    match import::import_save_ram(&save_path, source, cartridge.prg_ram_size(), overwrite) {
+        Ok(()) => println!("Imported save RAM to {}", save_path.display()),
+        Err(message) => exit_with_error(&message),
+    }
+}
+
+// Warnings and errors are shown. RUST_LOG shows more, by level and by the
+// targets cpu, ppu, apu, mapper and latency, e.g. RUST_LOG=cpu=trace,mapper=debug.
+fn init_logger(trace_scroll: bool, measure_latency: bool) {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"));
+    if trace_scroll {
+        builder.filter(Some("ppu"), log::LevelFilter::Trace);
+    }
+    if measure_latency {
+        builder.filter(Some("latency"), log::LevelFilter::Info);
+    }
+    builder.format_timestamp(None).format_target(true).init();
+}
+
+fn main()
+{
+    let args: Vec<_> = env::args().collect();
+    let command = match cli::parse(&args) {
+        Ok(command) => command,
+        Err(message) => {
+            exit_with_error(&format!("{}\n\n{}", message, cli::USAGE));
+        }
+    };
+    let (trace_scroll, measure_latency) = match command {
+        cli::Command::Run { ref options, .. } => (options.trace_scroll, options.measure_latency),
+        _ => (false, false),
+    };
+    init_logger(trace_scroll, measure_latency);
+
+    match command {
+        cli::Command::Help => {
+            println!("{}", cli::USAGE);
+        }
+        cli::Command::Run { rom, options } => {
+            run(&rom, &options);
+        }
+        cli::Command::Disassemble { rom, start, end, trace, cdl } => {
+            disassemble(&rom, start, end, trace, cdl.as_deref());
+        }
+        cli::Command::Test { rom } => {
+            process::exit(run_test_rom(&rom));
+        }
+        cli::Command::SelfTest => {
+            process::exit(selftest::run());
+        }
+        cli::Command::Bench { rom, frames } => {
+            bench(&rom, frames);
+        }
+        cli::Command::Diff { first, second } => {
+            diff_states(&first, &second);
+        }
+        cli::Command::ImportSave { rom, source, overwrite } => {
+            import_save(&rom, &source, overwrite);
+        }
+        cli::Command::Browse => {
+            let settings = load_settings(None, 0);
+            let dir = settings.rom_dir.unwrap_or_else(|| PathBuf::from("."));
+            if let Some(rom) = browser::choose(&dir).unwrap_or_else(|e| exit_with_error(&e)) {
+                run(&rom, &cli::RunOptions::default());
             }
         }
-        const TARGET_BUFFER_SIZE_MS: i64 = 35;
-        let sleep_time = machine.get_audio_queue_size_ms() as i64 - TARGET_BUFFER_SIZE_MS;
-        if sleep_time > 0 {
-            std::thread::sleep(Duration::milliseconds(sleep_time).to_std().unwrap());
+        cli::Command::Watch { address } => {
+            spectate::watch(&address).unwrap_or_else(|e| exit_with_error(&e));
+        }
+        cli::Command::List => {
+            played::print_list();
         }
     }
-
-    machine.save();
 }
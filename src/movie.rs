@@ -0,0 +1,124 @@
+use std::fs;
+use std::path::Path;
+
+use crate::nes::{Region, SystemEvent};
+
+// The input of one frame. The event, if any, happens at the start of the frame.
+pub struct Frame {
+    pub event: Option<SystemEvent>,
+    pub buttons: [bool; 8],
+}
+
+// Input recorded in an FCEUX movie (.fm2) that starts from power-on.
+// Only the controller in port 0 is played back.
+pub struct Movie {
+    frames: Vec<Frame>,
+    position: usize,
+    pub region: Region,
+}
+
+// Commands in the first field of an input line
+const COMMAND_RESET: u32 = 0x01;
+const COMMAND_POWER: u32 = 0x02;
+
+// Buttons of a controller field, in the order RLDUTSBA. This is the reverse
+// of the order they are shifted out in.
+fn parse_buttons(field: &str) -> Result<[bool; 8], String> {
+    let chars: Vec<_> = field.chars().collect();
+    if chars.len() != 8 {
+        return Err(format!("Invalid controller input: {}", field));
+    }
+    let mut buttons = [false; 8];
+    for (i, c) in chars.iter().enumerate() {
+        buttons[7 - i] = *c != '.' && *c != ' ';
+    }
+    Ok(buttons)
+}
+
+fn parse_frame(line: &str) -> Result<Frame, String> {
+    let fields: Vec<_> = line.split('|').collect();
+    if fields.len() < 3 {
+        return Err(format!("Invalid input line: {}", line));
+    }
+    let commands: u32 = fields[1].trim().parse()
+        .map_err(|_| format!("Invalid commands in input line: {}", line))?;
+    // A power cycle includes a reset, so it takes precedence
+    let event = if commands & COMMAND_POWER != 0 {
+        Some(SystemEvent::PowerCycle)
+    }
+    else if commands & COMMAND_RESET != 0 {
+        Some(SystemEvent::Reset)
+    }
+    else {
+        None
+    };
+    let buttons = if fields[2].is_empty() { [false; 8] } else { parse_buttons(fields[2])? };
+    Ok(Frame { event, buttons })
+}
+
+impl Movie {
+    pub fn load(path: &Path) -> Result<Movie, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        Movie::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, String> {
+        let mut frames = Vec::new();
+        let mut region = Region::Ntsc;
+        for line in text.lines() {
+            if line.starts_with('|') {
+                frames.push(parse_frame(line)?);
+                continue;
+            }
+            let mut parts = line.trim().splitn(2, ' ');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("").trim();
+            match key {
+                "binary" if value != "0" => {
+                    return Err("Binary movies are not supported".to_string());
+                }
+                "savestate" => {
+                    return Err("Movies that start from a save state are not supported".to_string());
+                }
+                "palFlag" if value == "1" => { region = Region::Pal; }
+                _ => {}
+            }
+        }
+        Ok(Movie { frames, position: 0, region })
+    }
+
+    // The input for the next frame, or None when the movie has ended
+    pub fn next_frame(&mut self) -> Option<&Frame> {
+        let frame = self.frames.get(self.position)?;
+        self.position += 1;
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fm2() {
+        let text = "version 3\nemuVersion 22020\npalFlag 0\nport0 1\n\
+                    |0|........|||\n|0|R......A|||\n|1|...U....|||\n|2|||\n";
+        let mut movie = Movie::parse(text).unwrap();
+        assert_eq!(movie.region, Region::Ntsc);
+
+        let frame = movie.next_frame().unwrap();
+        assert_eq!(frame.event, None);
+        assert_eq!(frame.buttons, [false; 8]);
+        let frame = movie.next_frame().unwrap();
+        assert_eq!(frame.buttons, [true, false, false, false, false, false, false, true]);
+        let frame = movie.next_frame().unwrap();
+        assert_eq!(frame.event, Some(SystemEvent::Reset));
+        assert_eq!(frame.buttons, [false, false, false, false, true, false, false, false]);
+        let frame = movie.next_frame().unwrap();
+        assert_eq!(frame.event, Some(SystemEvent::PowerCycle));
+        assert!(movie.next_frame().is_none());
+
+        assert!(Movie::parse("savestate base64:AAAA\n|0|........|||\n").is_err());
+    }
+}
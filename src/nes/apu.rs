@@ -1,6 +1,88 @@
+#[cfg(feature = "sdl")]
 use sdl2::audio::{AudioQueue, AudioSpecDesired};
 
-const CYCLE_FREQ: f64 = 1.789773 * 1000000.0 / 2.0;
+use std::time::Instant;
+
+use crate::nes::frontend::AudioSink;
+use crate::nes::Region;
+use crate::nes::state::{Chunk, ComponentState, Snapshot};
+
+// Sound channels on the cartridge that are mixed with the APU output
+pub trait ExpansionAudio {
+    // Clocked every CPU cycle
+    fn step_cycle(&mut self);
+    // Level on the same scale as the output of the APU mixer
+    fn output_level(&self) -> f32;
+}
+
+// The version of the sections in save_state, for ComponentState and the
+// APU chunk of machine states
+pub const STATE_VERSION: u32 = 1;
+
+// Channels in the order of the mute/solo hotkeys
+#[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+pub const CHANNEL_NAMES: [&str; 5] = ["pulse 1", "pulse 2", "triangle", "noise", "DMC"];
+
+// How the sound is played: the sample rate, the number of channels, which
+// all get the same sound, and the number of samples the audio device asks for
+// at a time, which SDL chooses if not given. Smaller buffers give less
+// latency, but may crackle.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct AudioOutput {
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub device_buffer: Option<u16>,
+}
+
+impl Default for AudioOutput {
+    fn default() -> AudioOutput {
+        AudioOutput { sample_rate: SAMPLE_RATE as u32, channels: 1, device_buffer: None }
+    }
+}
+
+impl AudioOutput {
+    pub fn check(&self) -> Result<(), String> {
+        if !(8000..=192000).contains(&self.sample_rate) {
+            return Err(format!("The sample rate must be from 8000 to 192000 Hz, not {}", self.sample_rate));
+        }
+        if self.channels != 1 && self.channels != 2 {
+            return Err(format!("The audio must have 1 or 2 channels, not {}", self.channels));
+        }
+        if let Some(samples) = self.device_buffer {
+            if !samples.is_power_of_two() {
+                return Err(format!("The audio device buffer must be a power of two, like 512 or 1024, not {}",
+                                   samples));
+            }
+        }
+        Ok(())
+    }
+}
+
+// What is played while fast-forwarding. The emulation is paced by the sound
+// that is queued, so less sound makes it run faster.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FastForwardAudio {
+    // Nothing, and the emulation runs as fast as it can
+    Mute,
+    // Only a part of the sound, which keeps the pitch
+    Drop,
+    // All the sound, sped up to a higher pitch
+    Pitch,
+}
+
+impl FastForwardAudio {
+    pub fn from_name(name: &str) -> Option<FastForwardAudio> {
+        match name {
+            "mute" => Some(FastForwardAudio::Mute),
+            "drop" => Some(FastForwardAudio::Drop),
+            "pitch" => Some(FastForwardAudio::Pitch),
+            _ => None,
+        }
+    }
+}
+
+// How many times faster than normal the dropping and pitching fast-forward is
+const FAST_FORWARD_SPEED: u32 = 4;
 
 enum FrameCounterSequence {
     FourStep,
@@ -8,77 +90,208 @@ enum FrameCounterSequence {
 }
 
 pub struct Apu {
+    region: Region,
     output_sample_generator: OutputSampleGenerator,
     frame_counter_sequence: FrameCounterSequence,
+    frame_counter_reset_delay: Option<u8>,
     interrupt_inhibit_flag: bool,
+    frame_interrupt_flag: bool,
     cycle_count: u64,
     pub quarter_frame_count: u64,
     audio_level: f32,
     pulse1: PulseChannel,
     pulse2: PulseChannel,
     triangle: TriangleChannel,
+    noise: NoiseChannel,
+    dmc: DmcChannel,
+    dmc_dma_request_cycle: Option<u16>,
+    channels_enabled: [bool; 5],
+    // The levels of the channels before the mixer, every other cycle, for
+    // the oscilloscope
+    scope_levels: Option<Vec<[u8; 5]>>,
+    pulse_table: Vec<f32>,
+    tnd_table: Vec<f32>,
+    pub mem_read_mut_enabled: bool,
+}
+
+// The APU is stepped after the CPU has executed an instruction, so register
+// writes are seen at the start of the instruction. $4017 is in practice
+// written with an absolute store, which writes on its fourth cycle.
+const WRITE_CYCLE_OFFSET: u8 = 3;
+
+// The mixer output for each sum of the pulse channel levels (0-30)
+fn create_pulse_table() -> Vec<f32> {
+    (0..31).map(|n| if n == 0 { 0.0 } else { 95.52 / (8128.0 / n as f32 + 100.0) }).collect()
+}
+
+// The mixer output for each 3 * triangle + 2 * noise + DMC level (0-202)
+fn create_tnd_table() -> Vec<f32> {
+    (0..203).map(|n| if n == 0 { 0.0 } else { 163.67 / (24329.0 / n as f32 + 100.0) }).collect()
 }
 
 impl Apu {
-    pub fn new(sdl_context: &mut sdl2::Sdl) -> Apu {
+    // Without a sink the samples are generated but not played
+    pub fn new(sink: Option<Box<dyn AudioSink>>) -> Apu {
         Apu {
-            output_sample_generator: OutputSampleGenerator::new(sdl_context),
+            region: Region::Ntsc,
+            output_sample_generator: OutputSampleGenerator::new(sink),
             frame_counter_sequence: FrameCounterSequence::FourStep,
+            frame_counter_reset_delay: None,
             interrupt_inhibit_flag: false,
+            frame_interrupt_flag: false,
             cycle_count: 0,
             quarter_frame_count: 0,
             audio_level: 0.0,
             pulse1: PulseChannel::new(true),
             pulse2: PulseChannel::new(false),
             triangle: TriangleChannel::new(),
+            noise: NoiseChannel::new(),
+            dmc: DmcChannel::new(),
+            dmc_dma_request_cycle: None,
+            channels_enabled: [true; 5],
+            scope_levels: None,
+            pulse_table: create_pulse_table(),
+            tnd_table: create_tnd_table(),
+            mem_read_mut_enabled: true,
         }
     }
 
-    pub fn step_cycle(&mut self, count: u16) -> bool {
-        let mut irq_triggered = false;
-        for _ in 0..count {
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        // Samples are generated every other CPU cycle
+        self.output_sample_generator.set_cycle_frequency(region.cpu_frequency() / 2.0);
+    }
+
+    // Silences the channels and restarts the frame counter as at power-on
+    pub fn power_cycle(&mut self) {
+        self.frame_counter_sequence = FrameCounterSequence::FourStep;
+        self.frame_counter_reset_delay = None;
+        self.interrupt_inhibit_flag = false;
+        self.frame_interrupt_flag = false;
+        self.cycle_count = 0;
+        self.audio_level = 0.0;
+        self.pulse1 = PulseChannel::new(true);
+        self.pulse2 = PulseChannel::new(false);
+        self.triangle = TriangleChannel { reduce_pops: self.triangle.reduce_pops, ..TriangleChannel::new() };
+        self.noise = NoiseChannel::new();
+        self.dmc = DmcChannel::new();
+        self.dmc_dma_request_cycle = None;
+    }
+
+    // The reset button silences the channels, as if 0 was written to $4015,
+    // and restarts the frame counter in the mode it was in
+    pub fn reset(&mut self) {
+        self.write_mem(0x4015, 0);
+        let five_step = matches!(self.frame_counter_sequence, FrameCounterSequence::FiveStep);
+        let mode = (five_step as u8) << 7 | (self.interrupt_inhibit_flag as u8) << 6;
+        self.write_mem(0x4017, mode);
+        self.frame_interrupt_flag = false;
+    }
+
+    // Frame counter steps in APU cycles, the step happens on CPU cycle 2 * step + 1.
+    // The first four are used by the four step sequence, the fifth replaces the
+    // fourth in the five step sequence.
+    fn frame_counter_steps(&self) -> [u64; 5] {
+        match self.region {
+            Region::Ntsc => [3728, 7456, 11185, 14914, 18640],
+            Region::Pal => [4156, 8313, 12469, 16626, 20782],
+        }
+    }
+
+    pub fn step_cycle(&mut self, count: u16,
+                      mut expansion_audio: Option<&mut dyn ExpansionAudio>) -> bool {
+        let steps = self.frame_counter_steps();
+        self.dmc_dma_request_cycle = None;
+        for i in 0..count {
             self.triangle.update_level();
+            self.noise.update_level();
+            let dma_requested = self.dmc.get_dma_address().is_some();
+            self.dmc.update_level();
+            if !dma_requested && self.dmc.get_dma_address().is_some() {
+                self.dmc_dma_request_cycle = Some(i);
+            }
+            if let Some(audio) = expansion_audio.as_deref_mut() {
+                audio.step_cycle();
+            }
             if self.cycle_count % 2 == 0 {
                 self.pulse1.update_level();
                 self.pulse2.update_level();
-                self.update_audio_level();
+                let expansion_level = expansion_audio.as_ref().map_or(0.0, |a| a.output_level());
+                self.update_audio_level(expansion_level);
                 self.output_sample_generator.maybe_generate(self.audio_level);
+                if let Some(ref mut levels) = self.scope_levels {
+                    levels.push([self.pulse1.output_level, self.pulse2.output_level,
+                                 self.triangle.output_level, self.noise.output_level,
+                                 self.dmc.output_level]);
+                }
             }
             self.cycle_count += 1;
             let cycle_wrap_around = match self.frame_counter_sequence {
-                FrameCounterSequence::FourStep => 14915 * 2,
-                FrameCounterSequence::FiveStep => 18641 * 2,
+                FrameCounterSequence::FourStep => (steps[3] + 1) * 2,
+                FrameCounterSequence::FiveStep => (steps[4] + 1) * 2,
             };
-            if self.cycle_count >= cycle_wrap_around {
+            let wrapped = self.cycle_count >= cycle_wrap_around;
+            if wrapped {
                 self.cycle_count = 0;
             }
+            self.step_frame_counter_reset();
             match self.frame_counter_sequence {
                 FrameCounterSequence::FourStep => {
-                    if self.cycle_count == 7456*2+1 || self.cycle_count == 14914*2+1 {
+                    if self.cycle_count == steps[1]*2+1 || self.cycle_count == steps[3]*2+1 {
                         self.step_quarter_frame_clock();
                         self.step_half_frame_clock();
                     }
-                    else if self.cycle_count == 3728*2+1 || self.cycle_count == 11185*2+1 {
+                    else if self.cycle_count == steps[0]*2+1 || self.cycle_count == steps[2]*2+1 {
                         self.step_quarter_frame_clock();
                     }
-                    if self.cycle_count == 0 || self.cycle_count >= 14914*2 {
-                        if !self.interrupt_inhibit_flag {
-                            irq_triggered = true;
-                        }
+                    if (wrapped || self.cycle_count >= steps[3]*2) && !self.interrupt_inhibit_flag {
+                        self.frame_interrupt_flag = true;
                     }
                 }
                 FrameCounterSequence::FiveStep => {
-                    if self.cycle_count == 7456*2+1 || self.cycle_count == 18640*2+1 {
+                    if self.cycle_count == steps[1]*2+1 || self.cycle_count == steps[4]*2+1 {
                         self.step_quarter_frame_clock();
                         self.step_half_frame_clock();
                     }
-                    else if self.cycle_count == 3728*2+1 || self.cycle_count == 11185*2+1 {
+                    else if self.cycle_count == steps[0]*2+1 || self.cycle_count == steps[2]*2+1 {
                         self.step_quarter_frame_clock();
                     }
                 }
             }
         }
-        irq_triggered
+        self.frame_interrupt_flag || self.dmc.interrupt_flag
+    }
+
+    // Restarts the frame counter sequence once the delay after a $4017 write
+    // has passed. Starting the five step sequence clocks the quarter and
+    // half frame units right away.
+    fn step_frame_counter_reset(&mut self) {
+        match self.frame_counter_reset_delay {
+            Some(0) => {
+                self.frame_counter_reset_delay = None;
+                self.cycle_count = 0;
+                if matches!(self.frame_counter_sequence, FrameCounterSequence::FiveStep) {
+                    self.step_quarter_frame_clock();
+                    self.step_half_frame_clock();
+                }
+            }
+            Some(delay) => { self.frame_counter_reset_delay = Some(delay - 1); }
+            None => {}
+        }
+    }
+
+    // Address of the sample byte the DMC wants to fetch with DMA, if any
+    pub fn get_dmc_dma_address(&self) -> Option<u16> {
+        self.dmc.get_dma_address()
+    }
+
+    // Cycle within the last step_cycle call on which the DMC requested DMA
+    pub fn get_dmc_dma_request_cycle(&self) -> Option<u16> {
+        self.dmc_dma_request_cycle
+    }
+
+    pub fn complete_dmc_dma(&mut self, value: u8) {
+        self.dmc.fill_sample_buffer(value);
     }
 
     fn step_quarter_frame_clock(&mut self) {
@@ -86,6 +299,7 @@ impl Apu {
         self.pulse1.step_envelope_clock();
         self.pulse2.step_envelope_clock();
         self.triangle.step_linear_counter_clock();
+        self.noise.step_envelope_clock();
     }
 
     fn step_half_frame_clock(&mut self) {
@@ -94,19 +308,187 @@ impl Apu {
         self.pulse2.step_length_counter_clock();
         self.pulse2.step_sweep_clock();
         self.triangle.step_length_counter_clock();
+        self.noise.step_length_counter_clock();
+    }
+
+    pub fn is_channel_enabled(&self, channel: usize) -> bool {
+        self.channels_enabled[channel]
+    }
+
+    pub fn toggle_channel_muted(&mut self, channel: usize) {
+        self.channels_enabled[channel] = !self.channels_enabled[channel];
     }
 
-    fn update_audio_level(&mut self) {
-        let pulse_out = 95.88 / ((8128.0 / (self.pulse1.output_level as f32 + self.pulse2.output_level as f32)) + 100.0);
-        let tnd_out = 159.79 / (1.0 / (self.triangle.output_level as f32 / 8227.0) + 100.0);
-        self.audio_level = pulse_out + tnd_out;
+    // Enables only the given channel, or all channels if it was already soloed
+    pub fn toggle_channel_solo(&mut self, channel: usize) {
+        let soloed = self.channels_enabled.iter().enumerate().all(|(i, e)| *e == (i == channel));
+        for (i, enabled) in self.channels_enabled.iter_mut().enumerate() {
+            *enabled = soloed || i == channel;
+        }
+    }
+
+    pub fn set_scope_enabled(&mut self, enabled: bool) {
+        if enabled != self.scope_levels.is_some() {
+            self.scope_levels = if enabled { Some(Vec::new()) } else { None };
+        }
+    }
+
+    // The channel levels recorded since the last call
+    pub fn take_scope_levels(&mut self) -> Vec<[u8; 5]> {
+        self.scope_levels.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn get_channel_level(&self, channel: usize, level: u8) -> usize {
+        if self.channels_enabled[channel] { level as usize } else { 0 }
+    }
+
+    // The non-linear mixer of the console, approximated with lookup tables
+    fn update_audio_level(&mut self, expansion_level: f32) {
+        let pulse1 = self.get_channel_level(0, self.pulse1.output_level);
+        let pulse2 = self.get_channel_level(1, self.pulse2.output_level);
+        let triangle = self.get_channel_level(2, self.triangle.output_level);
+        let noise = self.get_channel_level(3, self.noise.output_level);
+        let dmc = self.get_channel_level(4, self.dmc.output_level);
+        let pulse_out = self.pulse_table[pulse1 + pulse2];
+        let tnd_out = self.tnd_table[3 * triangle + 2 * noise + dmc];
+        self.audio_level = pulse_out + tnd_out + expansion_level;
     }
 
     pub fn get_queue_size_ms(&self) -> usize {
         self.output_sample_generator.get_queue_size_ms()
     }
 
+    // Plays the samples from now on, at the rate of the sink
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.output_sample_generator.set_sink(Some(sink));
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        (1.0 / self.output_sample_generator.output_sample_period).round() as u32
+    }
+
+    // The address and length of the sample the DMC plays
+    pub fn dmc_sample(&self) -> (u16, u16) {
+        (self.dmc.sample_address, self.dmc.sample_length)
+    }
+
+    pub fn set_fast_forward(&mut self, fast_forward: bool) {
+        self.output_sample_generator.fast_forward = fast_forward;
+    }
+
+    pub fn set_fast_forward_audio(&mut self, audio: FastForwardAudio) {
+        self.output_sample_generator.fast_forward_audio = audio;
+    }
+
+    pub fn set_speed(&mut self, speed: f64) {
+        self.output_sample_generator.speed = speed;
+    }
+
+    pub fn set_silent(&mut self, silent: bool) {
+        self.output_sample_generator.silent = silent;
+    }
+
+    pub fn set_reduce_pops(&mut self, reduce_pops: bool) {
+        self.triangle.reduce_pops = reduce_pops;
+    }
+
+    pub fn set_keep_played_samples(&mut self, keep: bool) {
+        self.output_sample_generator.played_samples = if keep { Some(Vec::new()) } else { None };
+    }
+
+    pub fn take_played_samples(&mut self) -> Vec<f32> {
+        self.output_sample_generator.played_samples.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.output_sample_generator.headless_samples)
+    }
+
+    pub fn save_state(&self, state: &mut Snapshot) {
+        state.set_version(Chunk::Apu, STATE_VERSION);
+        state.add_bool("apu.five_step_sequence",
+                       matches!(self.frame_counter_sequence, FrameCounterSequence::FiveStep));
+        state.add_u8("apu.frame_counter_reset_delay",
+                     self.frame_counter_reset_delay.unwrap_or(0xFF));
+        state.add_bool("apu.interrupt_inhibit_flag", self.interrupt_inhibit_flag);
+        state.add_bool("apu.frame_interrupt_flag", self.frame_interrupt_flag);
+        state.add_u64("apu.cycle_count", self.cycle_count);
+        state.add_u64("apu.quarter_frame_count", self.quarter_frame_count);
+        self.pulse1.save_state(state, "apu.pulse1");
+        self.pulse2.save_state(state, "apu.pulse2");
+        self.triangle.save_state(state, "apu.triangle");
+        self.noise.save_state(state, "apu.noise");
+        self.dmc.save_state(state, "apu.dmc");
+    }
+
+    pub fn load_state(&mut self, state: &Snapshot) {
+        self.frame_counter_sequence = if state.get_bool("apu.five_step_sequence") {
+            FrameCounterSequence::FiveStep
+        }
+        else {
+            FrameCounterSequence::FourStep
+        };
+        self.frame_counter_reset_delay = match state.get_u8("apu.frame_counter_reset_delay") {
+            0xFF => None,
+            delay => Some(delay),
+        };
+        self.interrupt_inhibit_flag = state.get_bool("apu.interrupt_inhibit_flag");
+        self.frame_interrupt_flag = state.get_bool("apu.frame_interrupt_flag");
+        self.cycle_count = state.get_u64("apu.cycle_count");
+        self.quarter_frame_count = state.get_u64("apu.quarter_frame_count");
+        self.pulse1.load_state(state, "apu.pulse1");
+        self.pulse2.load_state(state, "apu.pulse2");
+        self.triangle.load_state(state, "apu.triangle");
+        self.noise.load_state(state, "apu.noise");
+        self.dmc.load_state(state, "apu.dmc");
+        self.dmc_dma_request_cycle = None;
+    }
+
+    pub fn component_state(&self) -> ComponentState {
+        let mut state = Snapshot::new();
+        self.save_state(&mut state);
+        ComponentState::new(STATE_VERSION, &state)
+    }
+
+    pub fn restore_component_state(&mut self, state: &ComponentState) -> Result<(), String> {
+        let mut layout = Snapshot::new();
+        self.save_state(&mut layout);
+        self.load_state(&state.to_snapshot(STATE_VERSION, &layout)?);
+        Ok(())
+    }
+
+    // Reading the status acknowledges the frame interrupt
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        if !self.pulse1.length_counter.is_zero() {
+            status |= 0x01;
+        }
+        if !self.pulse2.length_counter.is_zero() {
+            status |= 0x02;
+        }
+        if !self.triangle.length_counter.is_zero() {
+            status |= 0x04;
+        }
+        if !self.noise.length_counter.is_zero() {
+            status |= 0x08;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0x10;
+        }
+        if self.frame_interrupt_flag {
+            status |= 0x40;
+        }
+        if self.dmc.interrupt_flag {
+            status |= 0x80;
+        }
+        if self.mem_read_mut_enabled {
+            self.frame_interrupt_flag = false;
+        }
+        status
+    }
+
     pub fn write_mem(&mut self, address: u16, value: u8) {
+        log::trace!(target: "apu", "${:04X}={:02X}", address, value);
         match address {
             0x4000 => {
                 self.pulse1.set_control1(value);
@@ -141,10 +523,33 @@ impl Apu {
             0x400B => {
                 self.triangle.set_length_counter_load_and_timer_max_high(value);
             }
+            0x400C => {
+                self.noise.set_control(value);
+            }
+            0x400E => {
+                self.noise.set_mode_and_period(value, self.region);
+            }
+            0x400F => {
+                self.noise.set_length_counter_load(value);
+            }
+            0x4010 => {
+                self.dmc.set_control(value, self.region);
+            }
+            0x4011 => {
+                self.dmc.set_output_level(value);
+            }
+            0x4012 => {
+                self.dmc.set_sample_address(value);
+            }
+            0x4013 => {
+                self.dmc.set_sample_length(value);
+            }
             0x4015 => {
                 self.pulse1.set_enabled(value & 0x01 != 0);
                 self.pulse2.set_enabled(value & 0x02 != 0);
                 self.triangle.set_enabled(value & 0x04 != 0);
+                self.noise.set_enabled(value & 0x08 != 0);
+                self.dmc.set_enabled(value & 0x10 != 0);
             }
             0x4017 => {
                 self.frame_counter_sequence = if value & 0x80 == 0 {
@@ -153,6 +558,14 @@ impl Apu {
                     FrameCounterSequence::FiveStep
                 };
                 self.interrupt_inhibit_flag = value & 0b0100_0000 != 0;
+                if self.interrupt_inhibit_flag {
+                    self.frame_interrupt_flag = false;
+                }
+                // The sequence restarts 3 or 4 CPU cycles after the write,
+                // always at the start of an APU cycle
+                let write_cycle = self.cycle_count + WRITE_CYCLE_OFFSET as u64;
+                let delay = if write_cycle & 0x1 == 0 { 4 } else { 3 };
+                self.frame_counter_reset_delay = Some(WRITE_CYCLE_OFFSET + delay - 1);
             }
             _ => { }
         }
@@ -220,6 +633,24 @@ impl Envelope {
         }
     }
 
+    fn save_state(&self, state: &mut Snapshot, prefix: &str) {
+        state.add_u8(&format!("{}.volume", prefix), self.volume);
+        state.add_bool(&format!("{}.loop_flag", prefix), self.loop_flag);
+        state.add_bool(&format!("{}.constant_volume_flag", prefix), self.constant_volume_flag);
+        state.add_bool(&format!("{}.start_flag", prefix), self.start_flag);
+        state.add_u8(&format!("{}.decay_level", prefix), self.decay_level);
+        state.add_u8(&format!("{}.divider", prefix), self.divider);
+    }
+
+    fn load_state(&mut self, state: &Snapshot, prefix: &str) {
+        self.volume = state.get_u8(&format!("{}.volume", prefix));
+        self.loop_flag = state.get_bool(&format!("{}.loop_flag", prefix));
+        self.constant_volume_flag = state.get_bool(&format!("{}.constant_volume_flag", prefix));
+        self.start_flag = state.get_bool(&format!("{}.start_flag", prefix));
+        self.decay_level = state.get_u8(&format!("{}.decay_level", prefix));
+        self.divider = state.get_u8(&format!("{}.divider", prefix));
+    }
+
     fn get_output_level(&self) -> u8 {
         if self.constant_volume_flag {
             self.volume
@@ -250,6 +681,18 @@ impl LengthCounter {
         }
     }
 
+    fn save_state(&self, state: &mut Snapshot, prefix: &str) {
+        state.add_u8(&format!("{}.counter", prefix), self.counter);
+        state.add_bool(&format!("{}.enabled", prefix), self.enabled);
+        state.add_bool(&format!("{}.halt", prefix), self.halt);
+    }
+
+    fn load_state(&mut self, state: &Snapshot, prefix: &str) {
+        self.counter = state.get_u8(&format!("{}.counter", prefix));
+        self.enabled = state.get_bool(&format!("{}.enabled", prefix));
+        self.halt = state.get_bool(&format!("{}.halt", prefix));
+    }
+
     fn step_clock(&mut self) {
         if self.counter > 0 && !self.halt {
             self.counter -= 1;
@@ -303,6 +746,26 @@ impl Sweep {
         }
     }
 
+    fn save_state(&self, state: &mut Snapshot, prefix: &str) {
+        state.add_bool(&format!("{}.enabled", prefix), self.enabled);
+        state.add_u8(&format!("{}.timer_max", prefix), self.timer_max);
+        state.add_u8(&format!("{}.timer", prefix), self.timer);
+        state.add_bool(&format!("{}.negate", prefix), self.negate);
+        state.add_u8(&format!("{}.shift_count", prefix), self.shift_count);
+        state.add_bool(&format!("{}.reload_flag", prefix), self.reload_flag);
+        state.add_bool(&format!("{}.muted", prefix), self.muted);
+    }
+
+    fn load_state(&mut self, state: &Snapshot, prefix: &str) {
+        self.enabled = state.get_bool(&format!("{}.enabled", prefix));
+        self.timer_max = state.get_u8(&format!("{}.timer_max", prefix));
+        self.timer = state.get_u8(&format!("{}.timer", prefix));
+        self.negate = state.get_bool(&format!("{}.negate", prefix));
+        self.shift_count = state.get_u8(&format!("{}.shift_count", prefix));
+        self.reload_flag = state.get_bool(&format!("{}.reload_flag", prefix));
+        self.muted = state.get_bool(&format!("{}.muted", prefix));
+    }
+
     fn step_clock(&mut self, period: &mut u16) {
         let target_period = if self.shift_count == 0 {
             *period
@@ -377,6 +840,28 @@ impl PulseChannel {
         }
     }
 
+    fn save_state(&self, state: &mut Snapshot, prefix: &str) {
+        state.add_u8(&format!("{}.duty_cycle", prefix), self.duty_cycle as u8);
+        state.add_u16(&format!("{}.timer_max", prefix), self.timer_max);
+        state.add_u16(&format!("{}.timer", prefix), self.timer);
+        state.add_u8(&format!("{}.sequence_index", prefix), self.sequence_index as u8);
+        state.add_u8(&format!("{}.output_level", prefix), self.output_level);
+        self.envelope.save_state(state, &format!("{}.envelope", prefix));
+        self.length_counter.save_state(state, &format!("{}.length_counter", prefix));
+        self.sweep.save_state(state, &format!("{}.sweep", prefix));
+    }
+
+    fn load_state(&mut self, state: &Snapshot, prefix: &str) {
+        self.duty_cycle = state.get_u8(&format!("{}.duty_cycle", prefix)) as usize;
+        self.timer_max = state.get_u16(&format!("{}.timer_max", prefix));
+        self.timer = state.get_u16(&format!("{}.timer", prefix));
+        self.sequence_index = state.get_u8(&format!("{}.sequence_index", prefix)) as usize;
+        self.output_level = state.get_u8(&format!("{}.output_level", prefix));
+        self.envelope.load_state(state, &format!("{}.envelope", prefix));
+        self.length_counter.load_state(state, &format!("{}.length_counter", prefix));
+        self.sweep.load_state(state, &format!("{}.sweep", prefix));
+    }
+
     fn update_level(&mut self) {
         if self.timer == 0 {
             self.timer = self.timer_max;
@@ -465,6 +950,20 @@ impl LinearCounter {
         }
     }
 
+    fn save_state(&self, state: &mut Snapshot, prefix: &str) {
+        state.add_u8(&format!("{}.counter", prefix), self.counter);
+        state.add_u8(&format!("{}.reload_value", prefix), self.reload_value);
+        state.add_bool(&format!("{}.reload_flag", prefix), self.reload_flag);
+        state.add_bool(&format!("{}.control_flag", prefix), self.control_flag);
+    }
+
+    fn load_state(&mut self, state: &Snapshot, prefix: &str) {
+        self.counter = state.get_u8(&format!("{}.counter", prefix));
+        self.reload_value = state.get_u8(&format!("{}.reload_value", prefix));
+        self.reload_flag = state.get_bool(&format!("{}.reload_flag", prefix));
+        self.control_flag = state.get_bool(&format!("{}.control_flag", prefix));
+    }
+
     fn setup(&mut self, value: u8) {
         self.control_flag = value & 0x80 != 0;
         self.reload_value = value & 0x7F;
@@ -486,6 +985,9 @@ struct TriangleChannel {
     length_counter: LengthCounter,
     linear_counter: LinearCounter,
     pub output_level: u8,
+    // Holds the level instead of stepping at periods of 0 and 1, which the
+    // console plays as an ultrasonic tone that mostly comes out as pops
+    reduce_pops: bool,
 }
 
 impl TriangleChannel {
@@ -502,23 +1004,42 @@ impl TriangleChannel {
             length_counter: LengthCounter::new(),
             linear_counter: LinearCounter::new(),
             output_level: 0,
+            reduce_pops: false,
         }
     }
 
+    fn save_state(&self, state: &mut Snapshot, prefix: &str) {
+        state.add_u16(&format!("{}.timer_max", prefix), self.timer_max);
+        state.add_u16(&format!("{}.timer", prefix), self.timer);
+        state.add_u8(&format!("{}.sequence_index", prefix), self.sequence_index as u8);
+        state.add_u8(&format!("{}.output_level", prefix), self.output_level);
+        self.length_counter.save_state(state, &format!("{}.length_counter", prefix));
+        self.linear_counter.save_state(state, &format!("{}.linear_counter", prefix));
+    }
+
+    fn load_state(&mut self, state: &Snapshot, prefix: &str) {
+        self.timer_max = state.get_u16(&format!("{}.timer_max", prefix));
+        self.timer = state.get_u16(&format!("{}.timer", prefix));
+        self.sequence_index = state.get_u8(&format!("{}.sequence_index", prefix)) as usize;
+        self.output_level = state.get_u8(&format!("{}.output_level", prefix));
+        self.length_counter.load_state(state, &format!("{}.length_counter", prefix));
+        self.linear_counter.load_state(state, &format!("{}.linear_counter", prefix));
+    }
+
+    // The sequencer stops while either counter is zero, and the output stays
+    // at the step it stopped on rather than going to zero
     fn update_level(&mut self) {
         if self.timer == 0 {
             self.timer = self.timer_max;
-            self.sequence_index += 1;
-            if self.sequence_index > 31 {
-                self.sequence_index = 0;
+            let silenced = self.length_counter.is_zero() || self.linear_counter.is_zero();
+            let ultrasonic = self.reduce_pops && self.timer_max < 2;
+            if !silenced && !ultrasonic {
+                self.sequence_index = (self.sequence_index + 1) % 32;
+                self.output_level = TriangleChannel::WAVEFORM[self.sequence_index];
             }
-            self.output_level = TriangleChannel::WAVEFORM[self.sequence_index];
         } else {
             self.timer -= 1
         }
-        if self.length_counter.is_zero() || self.linear_counter.is_zero() {
-            self.output_level = 0;
-        }
     }
 
     fn set_halt_and_linear_counter_load(&mut self, value: u8) {
@@ -550,58 +1071,711 @@ impl TriangleChannel {
     }
 }
 
+struct NoiseChannel {
+    mode_flag: bool,
+    timer_max: u16,
+    timer: u16,
+    shift_register: u16,
+    envelope: Envelope,
+    length_counter: LengthCounter,
+    pub output_level: u8,
+}
+
+impl NoiseChannel {
+    // Timer periods in CPU cycles
+    const NTSC_PERIODS: [u16; 16] = [
+        4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+    ];
+    const PAL_PERIODS: [u16; 16] = [
+        4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+    ];
+
+    fn new() -> NoiseChannel {
+        NoiseChannel {
+            mode_flag: false,
+            timer_max: NoiseChannel::NTSC_PERIODS[0] - 1,
+            timer: 0,
+            shift_register: 1,
+            envelope: Envelope::new(),
+            length_counter: LengthCounter::new(),
+            output_level: 0,
+        }
+    }
+
+    fn save_state(&self, state: &mut Snapshot, prefix: &str) {
+        state.add_bool(&format!("{}.mode_flag", prefix), self.mode_flag);
+        state.add_u16(&format!("{}.timer_max", prefix), self.timer_max);
+        state.add_u16(&format!("{}.timer", prefix), self.timer);
+        state.add_u16(&format!("{}.shift_register", prefix), self.shift_register);
+        state.add_u8(&format!("{}.output_level", prefix), self.output_level);
+        self.envelope.save_state(state, &format!("{}.envelope", prefix));
+        self.length_counter.save_state(state, &format!("{}.length_counter", prefix));
+    }
+
+    fn load_state(&mut self, state: &Snapshot, prefix: &str) {
+        self.mode_flag = state.get_bool(&format!("{}.mode_flag", prefix));
+        self.timer_max = state.get_u16(&format!("{}.timer_max", prefix));
+        self.timer = state.get_u16(&format!("{}.timer", prefix));
+        self.shift_register = state.get_u16(&format!("{}.shift_register", prefix));
+        self.output_level = state.get_u8(&format!("{}.output_level", prefix));
+        self.envelope.load_state(state, &format!("{}.envelope", prefix));
+        self.length_counter.load_state(state, &format!("{}.length_counter", prefix));
+    }
+
+    // Clocked every CPU cycle
+    fn update_level(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_max;
+            let tap = if self.mode_flag { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 0x01;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        }
+        else {
+            self.timer -= 1;
+        }
+        self.output_level = if self.shift_register & 0x01 != 0 || self.length_counter.is_zero() {
+            0
+        }
+        else {
+            self.envelope.get_output_level()
+        };
+    }
+
+    fn set_control(&mut self, value: u8) {
+        let loop_and_halt_flag = value & 0x20 != 0;
+        self.envelope.set_loop_flag(loop_and_halt_flag);
+        self.length_counter.set_halt(loop_and_halt_flag);
+        self.envelope.set_constant_volume_flag(value & 0x10 != 0);
+        self.envelope.set_volume(value & 0x0F);
+    }
+
+    fn set_mode_and_period(&mut self, value: u8, region: Region) {
+        self.mode_flag = value & 0x80 != 0;
+        let periods = match region {
+            Region::Ntsc => &NoiseChannel::NTSC_PERIODS,
+            Region::Pal => &NoiseChannel::PAL_PERIODS,
+        };
+        self.timer_max = periods[(value & 0x0F) as usize] - 1;
+    }
+
+    fn set_length_counter_load(&mut self, value: u8) {
+        self.length_counter.load(value >> 3);
+        self.envelope.set_start_flag();
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.length_counter.set_enabled(enabled);
+    }
+
+    fn step_envelope_clock(&mut self) {
+        self.envelope.step_clock();
+    }
+
+    fn step_length_counter_clock(&mut self) {
+        self.length_counter.step_clock();
+    }
+}
+
+struct DmcChannel {
+    irq_enabled: bool,
+    loop_flag: bool,
+    timer_max: u16,
+    timer: u16,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    interrupt_flag: bool,
+    pub output_level: u8,
+}
+
+impl DmcChannel {
+    const NTSC_RATES: [u16; 16] = [
+        428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+    ];
+    const PAL_RATES: [u16; 16] = [
+        398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+    ];
+
+    fn new() -> DmcChannel {
+        DmcChannel {
+            irq_enabled: false,
+            loop_flag: false,
+            timer_max: DmcChannel::NTSC_RATES[0] - 1,
+            timer: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            interrupt_flag: false,
+            output_level: 0,
+        }
+    }
+
+    fn save_state(&self, state: &mut Snapshot, prefix: &str) {
+        state.add_bool(&format!("{}.irq_enabled", prefix), self.irq_enabled);
+        state.add_bool(&format!("{}.loop_flag", prefix), self.loop_flag);
+        state.add_u16(&format!("{}.timer_max", prefix), self.timer_max);
+        state.add_u16(&format!("{}.timer", prefix), self.timer);
+        state.add_u16(&format!("{}.sample_address", prefix), self.sample_address);
+        state.add_u16(&format!("{}.sample_length", prefix), self.sample_length);
+        state.add_u16(&format!("{}.current_address", prefix), self.current_address);
+        state.add_u16(&format!("{}.bytes_remaining", prefix), self.bytes_remaining);
+        // The sample buffer is stored as value and a flag telling if it is full
+        state.add_bytes(&format!("{}.sample_buffer", prefix),
+                        &[self.sample_buffer.unwrap_or(0), self.sample_buffer.is_some() as u8]);
+        state.add_u8(&format!("{}.shift_register", prefix), self.shift_register);
+        state.add_u8(&format!("{}.bits_remaining", prefix), self.bits_remaining);
+        state.add_bool(&format!("{}.silence", prefix), self.silence);
+        state.add_bool(&format!("{}.interrupt_flag", prefix), self.interrupt_flag);
+        state.add_u8(&format!("{}.output_level", prefix), self.output_level);
+    }
+
+    fn load_state(&mut self, state: &Snapshot, prefix: &str) {
+        self.irq_enabled = state.get_bool(&format!("{}.irq_enabled", prefix));
+        self.loop_flag = state.get_bool(&format!("{}.loop_flag", prefix));
+        self.timer_max = state.get_u16(&format!("{}.timer_max", prefix));
+        self.timer = state.get_u16(&format!("{}.timer", prefix));
+        self.sample_address = state.get_u16(&format!("{}.sample_address", prefix));
+        self.sample_length = state.get_u16(&format!("{}.sample_length", prefix));
+        self.current_address = state.get_u16(&format!("{}.current_address", prefix));
+        self.bytes_remaining = state.get_u16(&format!("{}.bytes_remaining", prefix));
+        let sample_buffer = state.get_bytes(&format!("{}.sample_buffer", prefix));
+        self.sample_buffer = if sample_buffer[1] != 0 { Some(sample_buffer[0]) } else { None };
+        self.shift_register = state.get_u8(&format!("{}.shift_register", prefix));
+        self.bits_remaining = state.get_u8(&format!("{}.bits_remaining", prefix));
+        self.silence = state.get_bool(&format!("{}.silence", prefix));
+        self.interrupt_flag = state.get_bool(&format!("{}.interrupt_flag", prefix));
+        self.output_level = state.get_u8(&format!("{}.output_level", prefix));
+    }
+
+    // Clocked every CPU cycle
+    fn update_level(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_max;
+        if !self.silence {
+            if self.shift_register & 0x01 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            }
+            else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.shift_register = sample;
+                    self.silence = false;
+                }
+                None => {
+                    self.silence = true;
+                }
+            }
+        }
+    }
+
+    // Address the memory reader wants to fetch with DMA, if any
+    fn get_dma_address(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        }
+        else {
+            None
+        }
+    }
+
+    fn fill_sample_buffer(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+        self.current_address = if self.current_address == 0xFFFF {
+            0x8000
+        }
+        else {
+            self.current_address + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            }
+            else if self.irq_enabled {
+                self.interrupt_flag = true;
+            }
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    fn set_control(&mut self, value: u8, region: Region) {
+        self.irq_enabled = value & 0x80 != 0;
+        if !self.irq_enabled {
+            self.interrupt_flag = false;
+        }
+        self.loop_flag = value & 0x40 != 0;
+        let rates = match region {
+            Region::Ntsc => &DmcChannel::NTSC_RATES,
+            Region::Pal => &DmcChannel::PAL_RATES,
+        };
+        self.timer_max = rates[(value & 0x0F) as usize] - 1;
+    }
+
+    fn set_output_level(&mut self, value: u8) {
+        self.output_level = value & 0x7F;
+    }
+
+    fn set_sample_address(&mut self, value: u8) {
+        self.sample_address = 0xC000 + value as u16 * 64;
+    }
+
+    fn set_sample_length(&mut self, value: u8) {
+        self.sample_length = value as u16 * 16 + 1;
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.interrupt_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        }
+        else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+}
+
+// First-order filter, like the ones in the audio output path of the console
+struct Filter {
+    high_pass: bool,
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl Filter {
+    fn new(high_pass: bool, cutoff_frequency: f64, sample_rate: f64) -> Filter {
+        let rc = 1.0 / (2.0 * std::f64::consts::PI * cutoff_frequency);
+        let dt = 1.0 / sample_rate;
+        let alpha = if high_pass { rc / (rc + dt) } else { dt / (rc + dt) };
+        Filter {
+            high_pass,
+            alpha: alpha as f32,
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    fn apply(&mut self, input: f32) -> f32 {
+        let output = if self.high_pass {
+            self.alpha * (self.prev_output + input - self.prev_input)
+        }
+        else {
+            self.prev_output + self.alpha * (input - self.prev_output)
+        };
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
+
+const SAMPLE_RATE: i32 = 44100;
+// Without an audio device each sample is made available at once, so that a
+// frame comes with exactly the samples generated during it
+const HEADLESS_BUFFER_SIZE: usize = 1;
+// Samples generated without an audio device are kept until they are taken,
+// but not more than one second of them
+const HEADLESS_SAMPLE_LIMIT: usize = SAMPLE_RATE as usize;
+
+// The SDL audio device, opened with what is asked for in the output. The
+// device may not give exactly that. Without a device the game runs silently,
+// paced by a NullAudio.
+#[cfg(feature = "sdl")]
+pub fn open_sdl_audio(sdl_context: &mut sdl2::Sdl, output: AudioOutput) -> Box<dyn AudioSink> {
+    let desired_spec = AudioSpecDesired {
+        freq: Some(output.sample_rate as i32),
+        channels: Some(output.channels),
+        samples: output.device_buffer,
+    };
+    let device = sdl_context.audio()
+        .and_then(|audio_subsystem| audio_subsystem.open_queue(None, &desired_spec));
+    match device {
+        Ok(device) => {
+            device.resume();
+            Box::new(device)
+        }
+        Err(e) => {
+            log::warn!("Unable to open the audio device, continuing without sound: {}", e);
+            Box::new(NullAudio::new(output))
+        }
+    }
+}
+
+// Plays nothing, but takes the samples as fast as a device would play them,
+// so that a frontend that keeps a certain amount of sound queued still runs
+// the game at its speed
+pub struct NullAudio {
+    output: AudioOutput,
+    // The samples per channel queued since the start, when the queue was
+    // last empty
+    samples: u64,
+    start: Instant,
+}
+
+impl NullAudio {
+    pub fn new(output: AudioOutput) -> NullAudio {
+        NullAudio { output, samples: 0, start: Instant::now() }
+    }
+
+    fn queue_at(&mut self, samples: &[f32], now: Instant) {
+        if self.queued_at(now) == 0 {
+            self.samples = 0;
+            self.start = now;
+        }
+        self.samples += (samples.len() / self.channels()) as u64;
+    }
+
+    fn queued_at(&self, now: Instant) -> usize {
+        let played = ((now - self.start).as_secs_f64() * self.output.sample_rate as f64) as u64;
+        self.samples.saturating_sub(played) as usize
+    }
+}
+
+impl AudioSink for NullAudio {
+    fn sample_rate(&self) -> u32 {
+        self.output.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.output.channels as usize
+    }
+
+    fn buffer_size(&self) -> usize {
+        self.output.device_buffer.map_or(512, |samples| samples as usize)
+    }
+
+    fn queue(&mut self, samples: &[f32]) {
+        self.queue_at(samples, Instant::now());
+    }
+
+    fn queued(&self) -> usize {
+        self.queued_at(Instant::now())
+    }
+}
+
+#[cfg(feature = "sdl")]
+impl AudioSink for AudioQueue<f32> {
+    fn sample_rate(&self) -> u32 {
+        self.spec().freq as u32
+    }
+
+    fn channels(&self) -> usize {
+        self.spec().channels as usize
+    }
+
+    fn buffer_size(&self) -> usize {
+        self.spec().samples as usize
+    }
+
+    fn queue(&mut self, samples: &[f32]) {
+        AudioQueue::queue(self, samples);
+    }
+
+    fn queued(&self) -> usize {
+        let bytes_per_sample = 4;  // f32
+        self.size() as usize / bytes_per_sample / self.channels()
+    }
+}
+
 struct OutputSampleGenerator {
-    device: AudioQueue<f32>,
+    device: Option<Box<dyn AudioSink>>,
+    channels: usize,
+    headless_samples: Vec<f32>,
+    // The samples given to the device, in mono, if they are kept
+    played_samples: Option<Vec<f32>>,
     cycle_time: f64,
     time_to_next_output_sample: f64,
     output_sample_period: f64,
     output_sample_buffer: Vec<f32>,
     output_sample_index: usize,
+    level_sum: f32,
+    level_count: u32,
+    filters: Vec<Filter>,
+    fast_forward: bool,
+    fast_forward_audio: FastForwardAudio,
+    // Nothing is played or kept, e.g. while frames are run again
+    silent: bool,
+    // Times normal speed. The sound is resampled to it, and as the emulation
+    // is paced by the sound, it runs at that speed.
+    speed: f64,
+    // Buffers generated during fast-forward, of which only every
+    // FAST_FORWARD_SPEED:th is played when dropping
+    fast_forward_buffers: u32,
 }
 
 impl OutputSampleGenerator {
-    pub fn new(sdl_context: &mut sdl2::Sdl) -> OutputSampleGenerator {
-        let audio_subsystem = sdl_context.audio().unwrap();
-        let desired_spec = AudioSpecDesired {
-            freq: Some(44100),
-            channels: Some(1),  // mono
-            samples: None       // default sample size
+    pub fn new(sink: Option<Box<dyn AudioSink>>) -> OutputSampleGenerator {
+        let mut generator = OutputSampleGenerator {
+            device: None,
+            channels: 1,
+            headless_samples: Vec::new(),
+            played_samples: None,
+            cycle_time: 2.0 / Region::Ntsc.cpu_frequency(),
+            time_to_next_output_sample: 0.0,
+            output_sample_period: 0.0,
+            output_sample_buffer: Vec::new(),
+            output_sample_index: 0,
+            level_sum: 0.0,
+            level_count: 0,
+            filters: Vec::new(),
+            fast_forward: false,
+            fast_forward_audio: FastForwardAudio::Mute,
+            silent: false,
+            speed: 1.0,
+            fast_forward_buffers: 0,
         };
+        generator.set_sink(sink);
+        generator
+    }
 
-        let device = audio_subsystem.open_queue(None, &desired_spec).unwrap();
-        
-        device.resume();
-
-        let spec = device.spec().clone();
+    // The sample rate, buffer and filters follow the sink
+    fn set_sink(&mut self, sink: Option<Box<dyn AudioSink>>) {
+        let (sample_rate, buffer_size, channels) = match sink {
+            Some(ref sink) => (sink.sample_rate() as f64, sink.buffer_size().max(1), sink.channels()),
+            None => (SAMPLE_RATE as f64, HEADLESS_BUFFER_SIZE, 1),
+        };
+        self.device = sink;
+        self.channels = channels;
+        self.output_sample_period = 1.0 / sample_rate;
+        self.output_sample_buffer = vec![0.0; buffer_size];
+        self.output_sample_index = 0;
+        self.filters = vec![
+            Filter::new(true, 90.0, sample_rate),
+            Filter::new(true, 440.0, sample_rate),
+            Filter::new(false, 14000.0, sample_rate),
+        ];
+    }
 
-        OutputSampleGenerator {
-            device: device,
-            cycle_time: 1.0 / CYCLE_FREQ as f64,
-            time_to_next_output_sample: 0.0,
-            output_sample_period: 1.0 / spec.freq as f64,
-            output_sample_buffer: vec![0.0; spec.samples as usize],
-            output_sample_index: 0,
-        }
+    fn set_cycle_frequency(&mut self, frequency: f64) {
+        self.cycle_time = 1.0 / frequency;
     }
 
+    // The level is averaged over each output sample period, which removes
+    // most of the aliasing that sampling the level directly would cause
     fn maybe_generate(&mut self, audio_level: f32) {
-        self.time_to_next_output_sample -= self.cycle_time;
+        self.level_sum += audio_level;
+        self.level_count += 1;
+        // Faster emulation gives fewer samples for the same cycles, and
+        // pitching plays the cycles of FAST_FORWARD_SPEED samples in one
+        if self.fast_forward && self.fast_forward_audio == FastForwardAudio::Pitch {
+            self.time_to_next_output_sample -= self.cycle_time / (self.speed * FAST_FORWARD_SPEED as f64);
+        }
+        else {
+            self.time_to_next_output_sample -= self.cycle_time / self.speed;
+        }
         if self.time_to_next_output_sample <= 0.0 {
             self.time_to_next_output_sample += self.output_sample_period;
-            self.output_sample_buffer[self.output_sample_index] = audio_level;
+            let mut sample = self.level_sum / self.level_count as f32;
+            self.level_sum = 0.0;
+            self.level_count = 0;
+            for filter in self.filters.iter_mut() {
+                sample = filter.apply(sample);
+            }
+            self.output_sample_buffer[self.output_sample_index] = sample;
             self.output_sample_index += 1;
             if self.output_sample_index >= self.output_sample_buffer.len() {
-                self.device.queue(&self.output_sample_buffer);
                 self.output_sample_index = 0;
+                if self.fast_forward {
+                    self.fast_forward_buffers = self.fast_forward_buffers.wrapping_add(1);
+                    let played = match self.fast_forward_audio {
+                        FastForwardAudio::Mute => false,
+                        FastForwardAudio::Drop => self.fast_forward_buffers.is_multiple_of(FAST_FORWARD_SPEED),
+                        FastForwardAudio::Pitch => true,
+                    };
+                    if !played {
+                        return;
+                    }
+                }
+                if self.silent {
+                    return;
+                }
+                if let Some(ref mut device) = self.device {
+                    let channels = self.channels;
+                    if channels == 1 {
+                        device.queue(&self.output_sample_buffer);
+                    }
+                    else {
+                        let frames: Vec<f32> = self.output_sample_buffer.iter()
+                            .flat_map(|sample| std::iter::repeat_n(*sample, channels))
+                            .collect();
+                        device.queue(&frames);
+                    }
+                    if let Some(ref mut played) = self.played_samples {
+                        played.extend_from_slice(&self.output_sample_buffer);
+                        let excess = played.len().saturating_sub(HEADLESS_SAMPLE_LIMIT);
+                        played.drain(..excess);
+                    }
+                }
+                else {
+                    self.headless_samples.extend_from_slice(&self.output_sample_buffer);
+                    let excess = self.headless_samples.len().saturating_sub(HEADLESS_SAMPLE_LIMIT);
+                    self.headless_samples.drain(..excess);
+                }
             }
         }
     }
 
     pub fn get_queue_size_ms(&self) -> usize {
-        let queue_size_bytes = self.device.size();
-        let bytes_per_sample = 4;  // f32
-        let queue_size_samples = queue_size_bytes / bytes_per_sample;
+        let queue_size_samples = self.device.as_ref().map_or(0, |d| d.queued());
         let queue_size_ms = ((queue_size_samples as f64 * self.output_sample_period) * 1000.0) as usize;
         queue_size_ms
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing_triangle(reduce_pops: bool) -> TriangleChannel {
+        let mut triangle = TriangleChannel { reduce_pops, ..TriangleChannel::new() };
+        triangle.set_enabled(true);
+        triangle.set_halt_and_linear_counter_load(0xFF);
+        triangle.set_timer_max_low(0);
+        triangle.set_length_counter_load_and_timer_max_high(0x08);
+        triangle.step_linear_counter_clock();
+        triangle
+    }
+
+    // The samples generated during a tenth of a second of emulation
+    fn samples_in_a_tenth_of_a_second(generator: &mut OutputSampleGenerator) -> usize {
+        for _ in 0..(0.1 / generator.cycle_time) as usize {
+            generator.maybe_generate(0.0);
+        }
+        std::mem::take(&mut generator.headless_samples).len()
+    }
+
+    #[test]
+    fn pitched_fast_forward_generates_a_quarter_of_the_samples() {
+        let mut generator = OutputSampleGenerator::new(None);
+        let normal = samples_in_a_tenth_of_a_second(&mut generator);
+        assert!((4409..=4411).contains(&normal), "{}", normal);
+
+        generator.fast_forward = true;
+        generator.fast_forward_audio = FastForwardAudio::Pitch;
+        let pitched = samples_in_a_tenth_of_a_second(&mut generator);
+        assert!((1102..=1103).contains(&pitched), "{}", pitched);
+    }
+
+    #[test]
+    fn null_audio_plays_at_the_sample_rate() {
+        let mut audio = NullAudio::new(AudioOutput { channels: 2, ..AudioOutput::default() });
+        let start = Instant::now();
+        let after_ms = |ms| start + std::time::Duration::from_millis(ms);
+        audio.queue_at(&vec![0.0; 2 * 4410], start);
+        assert_eq!(audio.queued_at(start), 4410);
+        assert_eq!(audio.queued_at(after_ms(50)), 2205);
+        assert_eq!(audio.queued_at(after_ms(100)), 0);
+        assert_eq!(audio.queued_at(after_ms(150)), 0);
+        // Playing starts over when more is queued
+        audio.queue_at(&[0.0; 2 * 100], after_ms(150));
+        assert_eq!(audio.queued_at(after_ms(150)), 100);
+        assert_eq!(audio.queued_at(after_ms(151)), 56);
+    }
+
+    #[test]
+    fn component_state_round_trips_through_toml() {
+        let mut apu = Apu::new(None);
+        apu.write_mem(0x4015, 0x0F);
+        apu.write_mem(0x4000, 0xBF);
+        apu.write_mem(0x4003, 0x08);
+        apu.write_mem(0x4017, 0x80);
+        apu.step_cycle(100, None);
+        let text = toml::to_string(&apu.component_state()).unwrap();
+        let state: ComponentState = toml::from_str(&text).unwrap();
+
+        let mut restored = Apu::new(None);
+        restored.restore_component_state(&state).unwrap();
+        assert_eq!(restored.component_state(), apu.component_state());
+        assert_eq!(restored.read_status() & 0x01, 0x01);
+    }
+
+    // The CPU cycles until a five step sequence started by a $4017 write
+    // clocks the frame units, counted from the start of the store
+    fn cycles_until_frame_counter_reset(apu: &mut Apu) -> u64 {
+        let quarter_frame_count = apu.quarter_frame_count;
+        apu.write_mem(0x4017, 0x80);
+        (1..).find(|_| {
+            apu.step_cycle(1, None);
+            apu.quarter_frame_count != quarter_frame_count
+        }).unwrap()
+    }
+
+    #[test]
+    fn frame_counter_resets_3_or_4_cycles_after_a_4017_write() {
+        // The write on an odd cycle restarts the sequence 3 cycles later
+        let mut apu = Apu::new(None);
+        assert_eq!(cycles_until_frame_counter_reset(&mut apu), 6);
+        assert_eq!(apu.cycle_count, 0);
+
+        // and on an even cycle 4 cycles later
+        let mut apu = Apu::new(None);
+        apu.step_cycle(1, None);
+        assert_eq!(cycles_until_frame_counter_reset(&mut apu), 7);
+        assert_eq!(apu.cycle_count, 0);
+    }
+
+    #[test]
+    fn frame_interrupt_stays_set_until_the_status_is_read() {
+        let mut apu = Apu::new(None);
+        let cycles = (1..).find(|_| apu.step_cycle(1, None)).unwrap();
+        assert_eq!(cycles, 14914 * 2);
+        // The flag is kept after the sequence has started over
+        assert!(apu.step_cycle(10, None));
+        assert!(apu.cycle_count < 10);
+
+        apu.mem_read_mut_enabled = false;
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        apu.mem_read_mut_enabled = true;
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        assert_eq!(apu.read_status() & 0x40, 0);
+        assert!(!apu.step_cycle(1, None));
+
+        // Inhibiting the interrupt clears the flag too
+        (1..).find(|_| apu.step_cycle(1, None)).unwrap();
+        apu.write_mem(0x4017, 0x40);
+        assert!(!apu.step_cycle(1, None));
+        assert_eq!(apu.read_status() & 0x40, 0);
+    }
+
+    #[test]
+    fn triangle_holds_its_level_when_stopped_or_ultrasonic() {
+        let mut triangle = playing_triangle(false);
+        let levels: Vec<u8> = (0..3).map(|_| { triangle.update_level(); triangle.output_level }).collect();
+        assert_eq!(levels, [14, 13, 12]);
+        triangle.set_enabled(false);
+        triangle.update_level();
+        assert_eq!(triangle.output_level, 12);
+
+        let mut triangle = playing_triangle(true);
+        triangle.update_level();
+        assert_eq!(triangle.output_level, 0);
+        triangle.set_timer_max_low(2);
+        triangle.update_level();
+        assert_eq!(triangle.output_level, 14);
+    }
+}
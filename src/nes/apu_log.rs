@@ -0,0 +1,136 @@
+// Records the writes to the sound registers with the CPU cycle they happened
+// on, and saves them as a VGM file, which players and trackers can read to
+// play back or study the music of a game. VGM waits are counted in samples
+// at 44100 Hz, so the timing is rounded to that.
+
+use std::collections::HashSet;
+
+const VGM_SAMPLE_RATE: u64 = 44100;
+const VGM_VERSION: u32 = 0x161;
+const VGM_HEADER_SIZE: usize = 0xC0;
+
+enum Entry {
+    Write(u16, u8),
+    // The DMC sample bytes at an address, which the player needs in its
+    // memory to play them
+    Samples(u16, Vec<u8>),
+}
+
+pub struct ApuLog {
+    entries: Vec<(u64, Entry)>,
+    // The address and bytes of the samples stored in the entries
+    stored_samples: HashSet<(u16, Vec<u8>)>,
+    start_cycle: u64,
+    cpu_frequency: u32,
+}
+
+impl ApuLog {
+    pub fn new(start_cycle: u64, cpu_frequency: u32) -> ApuLog {
+        ApuLog { entries: Vec::new(), stored_samples: HashSet::new(), start_cycle, cpu_frequency }
+    }
+
+    // $4000-$4013, $4015 and $4017
+    pub fn is_logged(address: u16) -> bool {
+        matches!(address, 0x4000..=0x4013 | 0x4015 | 0x4017)
+    }
+
+    pub fn record_write(&mut self, cycle: u64, address: u16, value: u8) {
+        self.entries.push((cycle, Entry::Write(address, value)));
+    }
+
+    // Samples that are played again are only stored the first time
+    pub fn record_samples(&mut self, cycle: u64, address: u16, data: &[u8]) {
+        if self.stored_samples.insert((address, data.to_vec())) {
+            self.entries.push((cycle, Entry::Samples(address, data.to_vec())));
+        }
+    }
+
+    pub fn write_count(&self) -> usize {
+        self.entries.iter().filter(|(_, entry)| matches!(entry, Entry::Write(..))).count()
+    }
+
+    // The log up to end_cycle, in the VGM 1.61 format
+    pub fn to_vgm(&self, end_cycle: u64) -> Vec<u8> {
+        let sample = |cycle: u64| {
+            cycle.saturating_sub(self.start_cycle) * VGM_SAMPLE_RATE / self.cpu_frequency as u64
+        };
+        let mut data = vec![0; VGM_HEADER_SIZE];
+        let mut position = 0;
+        for (cycle, entry) in &self.entries {
+            // The cycle count starts over on a reset
+            let next = sample(*cycle).max(position);
+            write_wait(&mut data, next - position);
+            position = next;
+            match entry {
+                Entry::Write(address, value) => {
+                    data.extend_from_slice(&[0xB4, (address - 0x4000) as u8, *value]);
+                }
+                Entry::Samples(address, bytes) => {
+                    // A data block of NES APU RAM writes
+                    data.extend_from_slice(&[0x67, 0x66, 0xC2]);
+                    data.extend_from_slice(&(bytes.len() as u32 + 2).to_le_bytes());
+                    data.extend_from_slice(&address.to_le_bytes());
+                    data.extend_from_slice(bytes);
+                }
+            }
+        }
+        let end = sample(end_cycle).max(position);
+        write_wait(&mut data, end - position);
+        data.push(0x66);
+
+        let length = data.len();
+        let mut set_u32 = |offset: usize, value: u32| {
+            data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        set_u32(0x04, (length - 4) as u32);
+        set_u32(0x08, VGM_VERSION);
+        set_u32(0x18, end as u32);
+        // Relative to the field itself
+        set_u32(0x34, (VGM_HEADER_SIZE - 0x34) as u32);
+        set_u32(0x84, self.cpu_frequency);
+        data[..4].copy_from_slice(b"Vgm ");
+        data
+    }
+}
+
+fn write_wait(data: &mut Vec<u8>, samples: u64) {
+    let mut left = samples;
+    while left > 0 {
+        match left {
+            735 => { data.push(0x62); left = 0; }
+            882 => { data.push(0x63); left = 0; }
+            1..=16 => { data.push(0x70 + left as u8 - 1); left = 0; }
+            _ => {
+                let wait = left.min(0xFFFF);
+                data.push(0x61);
+                data.extend_from_slice(&(wait as u16).to_le_bytes());
+                left -= wait;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_are_saved_as_vgm_commands() {
+        let mut log = ApuLog::new(1000, 1789773);
+        log.record_write(1000, 0x4015, 0x0F);
+        // 1/60 s later
+        log.record_write(1000 + 29830, 0x4000, 0x3F);
+        log.record_samples(1000 + 29830, 0xC000, &[0xAA, 0x55]);
+        log.record_samples(1000 + 29830, 0xC000, &[0xAA, 0x55]);
+        let vgm = log.to_vgm(1000 + 29830 + 1000);
+        assert_eq!(&vgm[..4], b"Vgm ");
+        assert_eq!(&vgm[0x04..0x08], &(vgm.len() as u32 - 4).to_le_bytes());
+        assert_eq!(&vgm[0x18..0x1C], &(735u32 + 24).to_le_bytes());
+        assert_eq!(&vgm[0x84..0x88], &1789773u32.to_le_bytes());
+        assert_eq!(&vgm[VGM_HEADER_SIZE..],
+                   &[0xB4, 0x15, 0x0F, 0x62, 0xB4, 0x00, 0x3F,
+                     0x67, 0x66, 0xC2, 4, 0, 0, 0, 0x00, 0xC0, 0xAA, 0x55,
+                     0x61, 24, 0, 0x66][..]);
+        assert_eq!(log.write_count(), 2);
+    }
+}
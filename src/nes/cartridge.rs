@@ -1,17 +1,48 @@
-use std::fs::File;
-use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::io::Read;
+use std::mem;
 use std::path::{Path, PathBuf};
 
+use crate::nes::apu::ExpansionAudio;
+use crate::nes::database::{self, Database, GameInfo};
+use crate::nes::state::{Chunk, Snapshot};
+use crate::nes::{PpuVariant, Region};
+use crate::nes::vrc6;
+use crate::nes::zip;
+
+// The version of the sections in save_state, raised when they change
+pub const STATE_VERSION: u32 = 1;
 
 #[derive(Debug,PartialEq,Clone,Copy)]
 enum MirroringType {
     Horizontal,
     Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    // Each nametable has its own 1KB, the upper two in 2KB of RAM on the
+    // cartridge
+    FourScreen,
+}
+
+impl MirroringType {
+    // From the number saved in a machine state
+    fn from_state(value: u8) -> MirroringType {
+        match value {
+            0 => MirroringType::Horizontal,
+            1 => MirroringType::Vertical,
+            2 => MirroringType::SingleScreenLower,
+            3 => MirroringType::SingleScreenUpper,
+            _ => MirroringType::FourScreen,
+        }
+    }
 }
 
 #[derive(Debug,Clone)]
 enum Mapper {
-    NROM,
+    NROM {
+        prg_ram: Vec<u8>,
+        chr_ram: Option<Vec<u8>>,
+    },
     MMC1 {
         shift: u8,
         shift_count: u8,
@@ -24,12 +55,141 @@ enum Mapper {
         prg_bank: u8,
         prg_ram: Vec<u8>,
         chr_ram: Option<Vec<u8>>,
+        // The serial port ignores a write on the cycle after another, e.g.
+        // the second write of a read-modify-write instruction
+        last_write_cycle: Option<u64>,
     },
     CNROM {
-        bank: u8
+        bank: u8,
+        chr_ram: Option<Vec<u8>>,
+    },
+    VRC6 {
+        address_lines_swapped: bool,
+        prg_bank_16k: u8,
+        prg_bank_8k: u8,
+        chr_banks: [u8; 8],
+        mirroring: MirroringType,
+        prg_ram_enabled: bool,
+        prg_ram: Vec<u8>,
+        chr_ram: Option<Vec<u8>>,
+        irq: vrc6::IrqCounter,
+        audio: vrc6::Vrc6Audio,
+    },
+    VRC4 {
+        variant: Vrc4Variant,
+        prg_banks: [u8; 2],
+        prg_swap_mode: bool,
+        chr_banks: [u16; 8],
+        mirroring: MirroringType,
+        prg_ram: Vec<u8>,
+        chr_ram: Option<Vec<u8>>,
+        irq: vrc6::IrqCounter,
+    },
+    // The simple boards with only bank registers: UxROM (2), AxROM (7),
+    // GxROM (66), Color Dreams (11), BNROM and NINA-001 (34) and Camerica
+    // (71). UxROM and Camerica switch 16KB of PRG ROM, the others 32KB. The
+    // CHR banks are 4KB.
+    // AxROM and the Fire Hawk board of Camerica select a single screen.
+    Discrete {
+        prg_bank: u8,
+        chr_banks: [u8; 2],
+        mirroring: MirroringType,
+        prg_ram: Vec<u8>,
+        chr_ram: Option<Vec<u8>>,
+    },
+    MMC2 {
+        prg_bank: u8,
+        // The 4KB banks at $0000 for latch 0 set to $FD and $FE, then the
+        // same for $1000 and latch 1
+        chr_banks: [u8; 4],
+        // True for $FE, false for $FD
+        latches: [bool; 2],
+        chr_ram: Option<Vec<u8>>,
+        mirroring: MirroringType,
     },
 }
 
+// The VRC2 and VRC4 boards differ in which CPU address lines select the
+// registers within each $1000 block. An iNES mapper number covers boards
+// using different lines, so the lines of all of them are combined.
+#[derive(Debug,PartialEq,Clone,Copy)]
+enum Vrc4Variant {
+    // Mapper 21: VRC4a and VRC4c, A1 | A6 and A2 | A7
+    Mapper21,
+    // Mapper 22: VRC2a, A1 and A0, with the CHR banks in 2KB units. VRC2
+    // has no IRQ and only two mirroring modes.
+    Mapper22,
+    // Mapper 23: VRC2b, VRC4e and VRC4f, A0 | A2 and A1 | A3
+    Mapper23,
+    // Mapper 25: VRC2c, VRC4b and VRC4d, A1 | A3 and A0 | A2
+    Mapper25,
+}
+
+impl Vrc4Variant {
+    // The register address within the block, $x000-$x003
+    fn register_address(self, address: u16) -> u16 {
+        let line = |n: u16| (address >> n) & 0x1;
+        let (bit0, bit1) = match self {
+            Vrc4Variant::Mapper21 => (line(1) | line(6), line(2) | line(7)),
+            Vrc4Variant::Mapper22 => (line(1), line(0)),
+            Vrc4Variant::Mapper23 => (line(0) | line(2), line(1) | line(3)),
+            Vrc4Variant::Mapper25 => (line(1) | line(3), line(0) | line(2)),
+        };
+        (address & 0xF000) | (bit1 << 1) | bit0
+    }
+}
+
+// The MMC3 clocks its scan line counter when PPU address line A12 rises,
+// which happens once a line when the background and the sprites use
+// different pattern tables. A rise only counts after the line has been low
+// for a while (about three CPU cycles), so the rises between the fetches of
+// the eight sprites are filtered out.
+const A12_LOW_DOTS: u64 = 10;
+
+#[derive(Debug,Clone,Default)]
+pub struct A12Watcher {
+    high: bool,
+    low_since: u64,
+    rises: u64,
+}
+
+impl A12Watcher {
+    pub fn new() -> A12Watcher {
+        A12Watcher { high: false, low_since: 0, rises: 0 }
+    }
+
+    // A PPU fetch at the given dot, counted from power on. Returns true for
+    // a rise that passes the filter.
+    pub fn observe(&mut self, address: u16, dot: u64) -> bool {
+        let high = address & 0x1000 != 0;
+        let rise = high && !self.high && dot.saturating_sub(self.low_since) >= A12_LOW_DOTS;
+        if !high && self.high {
+            self.low_since = dot;
+        }
+        self.high = high;
+        if rise {
+            self.rises += 1;
+        }
+        rise
+    }
+
+    pub fn rises(&self) -> u64 {
+        self.rises
+    }
+
+    fn save_state(&self, state: &mut Snapshot, prefix: &str) {
+        state.add_bool(&format!("{}.high", prefix), self.high);
+        state.add_u64(&format!("{}.low_since", prefix), self.low_since);
+        state.add_u64(&format!("{}.rises", prefix), self.rises);
+    }
+
+    fn load_state(&mut self, state: &Snapshot, prefix: &str) {
+        self.high = state.get_bool(&format!("{}.high", prefix));
+        self.low_since = state.get_u64(&format!("{}.low_since", prefix));
+        self.rises = state.get_u64(&format!("{}.rises", prefix));
+    }
+}
+
 #[derive(Debug)]
 struct NesRomFile {
     header: [u8; 16],
@@ -39,196 +199,708 @@ struct NesRomFile {
     has_persistent_ram: bool,
     has_chr_ram: bool,
     mapper_id: u8,
+    trainer: Option<Vec<u8>>,
+    title: Option<String>,
 }
 
 pub struct Cartridge {
-    nes_path: PathBuf,
+    save_path: Option<PathBuf>,
+    // Set when the game writes to the PRG RAM, cleared when it is saved
+    save_ram_changed: bool,
     rom: NesRomFile,
     mapper: Mapper,
+    title: String,
+    crc32: u32,
+    // What the database knows about the game
+    game: Option<GameInfo>,
+    // The nametables at $2800-$2FFF with four-screen mirroring
+    four_screen_vram: Option<Vec<u8>>,
+    a12: A12Watcher,
+    // Set with --bus-conflicts, or else they come from the header
+    bus_conflicts: Option<bool>,
+    // The CPU cycles the cartridge has been stepped
+    cycle_count: u64,
 }
 
 impl NesRomFile {
-    fn load(path: &Path) -> Self {
-        let mut data = Vec::new();
-        let mut f = File::open(path).expect("Unable to open file");
-        f.read_to_end(&mut data).expect("Unable to read data");
+    // From a .nes file or the first .nes file in a .zip file
+    fn load(path: &Path) -> Result<Self, String> {
+        let mut data = fs::read(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        if has_extension(path, "zip") {
+            data = zip::extract_nes_file(&data)?;
+        }
+        NesRomFile::parse(&data)
+    }
 
+    fn parse(data: &[u8]) -> Result<Self, String> {
         let mut header = [0; 16];
-        header.clone_from_slice(&data[0..16]);
+        header.copy_from_slice(data.get(0..16).ok_or("Not a NES file, too short")?);
         let magic = "NES\x1a".as_bytes();
-        if &data[0..4] != magic {
-            panic!("Not a NES file");
+        if &header[0..4] != magic {
+            return Err("Not a NES file".to_string());
+        }
+        let prg_rom_size_16kb_units = header[4];
+        let chr_rom_size_8kb_units = header[5];
+        let _flags6 = header[6];
+        let mirroring = if header[6] & 0x08 != 0 {
+            MirroringType::FourScreen
         }
-        let prg_rom_size_16kb_units = data[4];
-        let chr_rom_size_8kb_units = data[5];
-        let _flags6 = data[6];
-        let mirroring = if data[6] & 0x01 != 0 {
+        else if header[6] & 0x01 != 0 {
             MirroringType::Vertical
         }
         else {
             MirroringType::Horizontal
         };
-        let has_persistent_ram = data[6] & 0x2 != 0;
-        let _has_play_choice_rom = data[7] & (1 << 2) == (1 << 2);
-        let _prg_ram_size_8kb_units = data[8];
-        let mapper_id = data[7] & 0xF0 | ((_flags6 & 0xF0) >> 4);
+        let has_persistent_ram = header[6] & 0x2 != 0;
+        let has_trainer = header[6] & 0x4 != 0;
+        let _has_play_choice_rom = header[7] & (1 << 2) == (1 << 2);
+        let _prg_ram_size_8kb_units = header[8];
+        let mapper_id = header[7] & 0xF0 | ((_flags6 & 0xF0) >> 4);
+        if prg_rom_size_16kb_units == 0 {
+            return Err("The ROM has no PRG ROM".to_string());
+        }
 
+        // The 512 byte trainer, if any, comes between the header and the
+        // PRG ROM
+        let (trainer, prg_start) = if has_trainer {
+            (Some(data.get(16..16 + 512).ok_or("The trainer is truncated")?.to_vec()), 16 + 512)
+        }
+        else {
+            (None, 16)
+        };
         let prg_size = prg_rom_size_16kb_units as usize * 16384;
         let chr_size = chr_rom_size_8kb_units as usize * 8192;
-        let mut prg_rom = vec![0; prg_size];
-        prg_rom.clone_from_slice(&data[16 .. 16 + prg_size]);
-        let mut chr_rom = vec![0; chr_size];
-        chr_rom.clone_from_slice(&data[16 + prg_size .. 16 + prg_size + chr_size]);
+        let chr_start = prg_start + prg_size;
+        let prg_rom = data.get(prg_start .. chr_start)
+            .ok_or("The PRG ROM is truncated")?.to_vec();
+        let chr_rom = data.get(chr_start .. chr_start + chr_size)
+            .ok_or("The CHR ROM is truncated")?.to_vec();
+        let title = NesRomFile::parse_title(&data[chr_start + chr_size ..]);
 
-        NesRomFile { header: header,
+        Ok(NesRomFile { header: header,
                      prg_rom: prg_rom,
                      chr_rom: chr_rom,
                      mirroring: mirroring,
                      has_persistent_ram: has_persistent_ram,
                      has_chr_ram: chr_size == 0,
-                     mapper_id: mapper_id}
+                     mapper_id: mapper_id,
+                     trainer,
+                     title})
+    }
+
+    // The NES 2.0 submapper, in the upper bits of byte 8
+    fn submapper(&self) -> Option<u8> {
+        if self.header[7] & 0x0C == 0x08 {
+            Some(self.header[8] >> 4)
+        }
+        else {
+            None
+        }
+    }
+
+    // NES 2.0 gives the sizes of the volatile and the battery backed PRG RAM
+    // as shift counts in byte 10, 64 << n bytes each, where iNES headers
+    // often have junk in byte 8. At least the 8KB all boards here take.
+    fn prg_ram_size(&self) -> usize {
+        if self.header[7] & 0x0C != 0x08 {
+            return 8192;
+        }
+        let size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+        (size(self.header[10] & 0xF) + size(self.header[10] >> 4)).max(8192)
+    }
+
+    // The discrete boards have nothing that keeps the ROM off the data bus
+    // when the CPU writes to a bank register, so the register gets the
+    // written value ANDed with the ROM byte at the address. NES 2.0 tells for
+    // UxROM and CNROM whether the board has them, submapper 1 for none and 2
+    // for AND.
+    fn has_bus_conflicts(&self) -> bool {
+        match (self.mapper_id, self.submapper()) {
+            (2, Some(1)) | (3, Some(1)) => false,
+            (2, _) | (3, _) | (11, _) | (66, _) => true,
+            // AMROM, unlike ANROM and AOROM
+            (7, Some(2)) => true,
+            // BNROM, while NINA-001 has its registers below the ROM
+            (34, _) => self.has_chr_ram,
+            _ => false,
+        }
+    }
+
+    // Some dumps carry a title block (usually 128 bytes) after the CHR data
+    fn parse_title(trailer: &[u8]) -> Option<String> {
+        let end = trailer.iter()
+            .rposition(|b| *b != 0x00 && *b != 0xFF && *b != b' ')
+            .map_or(0, |i| i + 1);
+        let text = &trailer[..end];
+        if text.is_empty() || !text.iter().all(|b| (0x20..0x7F).contains(b)) {
+            return None;
+        }
+        Some(String::from_utf8_lossy(text).trim().to_string())
+    }
+
+    // The CRC32 and the SHA-1 of the PRG and CHR ROM data
+    fn checksums(&self) -> (u32, String) {
+        let mut data = self.prg_rom.clone();
+        data.extend_from_slice(&self.chr_rom);
+        (database::crc32(&data), database::sha1(&data))
+    }
+}
+
+// Strips GoodNES/No-Intro style tags such as "(U)", "(Europe)" and "[!]" from
+// the file name and moves a trailing article to the front, so that
+// "Legend of Zelda, The (U) [!].nes" becomes "The Legend of Zelda".
+fn title_from_file_name(path: &Path) -> String {
+    let stem = path.file_stem().map_or(String::new(), |s| s.to_string_lossy().to_string());
+    let mut title = String::new();
+    let mut depth = 0;
+    for c in stem.chars() {
+        match c {
+            '(' | '[' => { depth += 1; }
+            ')' | ']' if depth > 0 => { depth -= 1; }
+            '_' if depth == 0 => { title.push(' '); }
+            _ if depth == 0 => { title.push(c); }
+            _ => {}
+        }
+    }
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    for article in &["The", "A", "An"] {
+        let suffix = format!(", {}", article);
+        if title.ends_with(&suffix) {
+            return format!("{} {}", article, &title[..title.len() - suffix.len()]);
+        }
     }
+    if title.is_empty() { stem } else { title }
+}
+
+// An NROM image with 16KB PRG ROM, CHR RAM and the program at $8000, where all
+// vectors point
+pub fn nrom_image(program: &[u8]) -> Vec<u8> {
+    let mut image = vec![0; 16 + 0x4000];
+    image[..6].copy_from_slice(b"NES\x1a\x01\x00");
+    image[16..16 + program.len()].copy_from_slice(program);
+    for vector in &[0x3FFA, 0x3FFC, 0x3FFE] {
+        image[16 + vector + 1] = 0x80;
+    }
+    image
+}
+
+// An NROM image for tests that reads the controllers on each NMI. $00 and $01
+// get the last reads of $4016 and $4017, $02 counts the frames A of player 1
+// was held and $03 counts the frames.
+pub fn controller_reading_image() -> Vec<u8> {
+    let program = [
+        0xA9, 0x80, 0x8D, 0x00, 0x20,             // LDA #$80; STA $2000 (NMI on)
+        0x4C, 0x05, 0x80,                         // loop: JMP loop
+        // NMI
+        0xA9, 0x01, 0x8D, 0x16, 0x40,             // LDA #1; STA $4016
+        0xA9, 0x00, 0x8D, 0x16, 0x40,             // LDA #0; STA $4016
+        0xAD, 0x16, 0x40, 0x85, 0x00,             // LDA $4016; STA $00
+        0x29, 0x01, 0x18, 0x65, 0x02, 0x85, 0x02, // AND #1; CLC; ADC $02; STA $02
+        0xAD, 0x17, 0x40, 0x85, 0x01,             // LDA $4017; STA $01
+        0xE6, 0x03,                               // INC $03
+        0x40,                                     // RTI
+    ];
+    let mut image = nrom_image(&program);
+    image[16 + 0x3FFA] = 0x08; // NMI vector $8008
+    image
+}
+
+// A headless machine reset into the program of controller_reading_image
+pub fn start_controller_reading_machine() -> (super::cpu::Cpu, super::Machine) {
+    let mut machine = super::Machine::new_headless();
+    let mut cpu = super::cpu::Cpu::new();
+    machine.load_cartridge(Cartridge::from_bytes(&controller_reading_image()).unwrap());
+    cpu.reset(&mut machine);
+    (cpu, machine)
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().and_then(|e| e.to_str()).is_some_and(|e| e.eq_ignore_ascii_case(extension))
+}
+
+// 8KB of CHR RAM for boards without CHR ROM
+fn create_chr_ram(rom: &NesRomFile) -> Option<Vec<u8>> {
+    if rom.has_chr_ram { Some(vec![0; 8192]) } else { None }
+}
+
+// The mapper in its power-on state
+fn create_mapper(rom: &NesRomFile, prg_ram: Vec<u8>) -> Result<Mapper, String> {
+    Ok(match rom.mapper_id {
+        0 => Mapper::NROM {
+            prg_ram,
+            chr_ram: create_chr_ram(rom),
+        },
+        1 => Mapper::MMC1 {
+            shift: 0,
+            shift_count: 0,
+            mirroring: rom.mirroring,
+            prg_swap_range_bit: true,
+            prg_size_bit: true,
+            chr_size_bit: false,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            prg_ram,
+            chr_ram: create_chr_ram(rom),
+            last_write_cycle: None,
+        },
+        3 => Mapper::CNROM {
+            bank: 0,
+            chr_ram: create_chr_ram(rom),
+        },
+        21 | 22 | 23 | 25 => Mapper::VRC4 {
+            variant: match rom.mapper_id {
+                21 => Vrc4Variant::Mapper21,
+                22 => Vrc4Variant::Mapper22,
+                23 => Vrc4Variant::Mapper23,
+                _ => Vrc4Variant::Mapper25,
+            },
+            prg_banks: [0; 2],
+            prg_swap_mode: false,
+            chr_banks: [0; 8],
+            mirroring: MirroringType::Vertical,
+            prg_ram,
+            chr_ram: create_chr_ram(rom),
+            irq: vrc6::IrqCounter::new(),
+        },
+        2 | 7 | 11 | 34 | 66 | 71 => Mapper::Discrete {
+            prg_bank: 0,
+            chr_banks: [0, 1],
+            mirroring: if rom.mapper_id == 7 { MirroringType::SingleScreenLower } else { rom.mirroring },
+            prg_ram,
+            chr_ram: create_chr_ram(rom),
+        },
+        9 => Mapper::MMC2 {
+            prg_bank: 0,
+            chr_banks: [0; 4],
+            latches: [true; 2],
+            mirroring: MirroringType::Vertical,
+            chr_ram: create_chr_ram(rom),
+        },
+        24 | 26 => Mapper::VRC6 {
+            address_lines_swapped: rom.mapper_id == 26,
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_banks: [0; 8],
+            mirroring: MirroringType::Vertical,
+            prg_ram_enabled: false,
+            prg_ram,
+            chr_ram: create_chr_ram(rom),
+            irq: vrc6::IrqCounter::new(),
+            audio: vrc6::Vrc6Audio::new(),
+        },
+        _ => { return Err(format!("Unsupported mapper {}", rom.mapper_id)); }
+    })
 }
 
 impl Cartridge {
-    pub fn load(path: &Path) -> Self {
-        if path.extension().unwrap().to_str().unwrap() == "nes" {
-            let rom = NesRomFile::load(path);
-            let save_path = path.with_extension("sav");
-            let mut save_data = vec![0; 8192];
-            if rom.has_persistent_ram {
-                match File::open(&save_path) {
-                    Ok(mut f) => {
-                        save_data.clear();
-                        f.read_to_end(&mut save_data).expect("Unable to read save data");
-                    }
-                    Err(_) => {
-                    }
+    pub fn load(path: &Path) -> Result<Self, String> {
+        Cartridge::load_with_database(path, &Database::built_in())
+    }
+
+    // Loads the ROM and identifies it in the database
+    pub fn load_with_database(path: &Path, database: &Database) -> Result<Self, String> {
+        if !has_extension(path, "nes") && !has_extension(path, "zip") {
+            return Err(format!("{} is not a .nes or .zip file", path.display()));
+        }
+        let rom = NesRomFile::load(path)?;
+        let save_path = path.with_extension("sav");
+        let mut save_data = vec![0; 8192];
+        if rom.has_persistent_ram {
+            match File::open(&save_path) {
+                Ok(mut f) => {
+                    save_data.clear();
+                    f.read_to_end(&mut save_data)
+                        .map_err(|e| format!("Unable to read {}: {}", save_path.display(), e))?;
+                }
+                Err(_) => {
                 }
             }
+        }
 
-            let mapper = match rom.mapper_id {
-                0 => Mapper::NROM,
-                1 => Mapper::MMC1 {
-                    shift: 0,
-                    shift_count: 0,
-                    mirroring: MirroringType::Vertical,
-                    prg_swap_range_bit: true,
-                    prg_size_bit: true,
-                    chr_size_bit: false,
-                    chr_bank_0: 0,
-                    chr_bank_1: 0,
-                    prg_bank: 0,
-                    prg_ram: save_data,
-                    chr_ram: if rom.has_chr_ram { Some(vec![0; 8192]) } else { None },
-                },
-                3 => Mapper::CNROM {
-                    bank: 0
-                },
-                _ => { unimplemented!(); },
-            };
+        Cartridge::create(rom, save_data, Some(path.to_path_buf()), database)
+    }
 
-            Cartridge {
-                nes_path: path.to_path_buf(),
-                rom: rom,
-                mapper: mapper,
-            }
+    // A cartridge from the contents of a .nes file, e.g. one embedded in a
+    // program. The save RAM starts out empty and is never written to disk.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        Cartridge::create(NesRomFile::parse(data)?, vec![0; 8192], None, &Database::built_in())
+    }
+
+    fn create(mut rom: NesRomFile, mut save_data: Vec<u8>, nes_path: Option<PathBuf>,
+              database: &Database) -> Result<Self, String> {
+        let (crc32, sha1) = rom.checksums();
+        let game = database.find(crc32, &sha1).cloned();
+        if let Some(mapper_id) = game.as_ref().and_then(|g| g.mapper) {
+            rom.mapper_id = mapper_id;
+        }
+        if let Some(vertical) = game.as_ref().and_then(|g| g.vertical_mirroring) {
+            rom.mirroring = if vertical { MirroringType::Vertical } else { MirroringType::Horizontal };
+        }
+        let four_screen_vram = if rom.mirroring == MirroringType::FourScreen {
+            Some(vec![0; 0x800])
         }
         else {
-            unimplemented!();
+            None
+        };
+
+        let prg_ram_size = game.as_ref().and_then(|g| g.prg_ram_size);
+        save_data.resize(prg_ram_size.unwrap_or_else(|| rom.prg_ram_size()), 0);
+        // The trainer is loaded at $7000 in the PRG RAM
+        if let Some(ref trainer) = rom.trainer {
+            save_data[0x1000..0x1200].copy_from_slice(trainer);
         }
+        let mapper = create_mapper(&rom, save_data)?;
+
+        let title = match game.as_ref().and_then(|g| g.title.as_ref()) {
+            Some(title) => title.clone(),
+            None => match (rom.title.as_ref(), nes_path.as_ref()) {
+                (Some(title), _) => title.clone(),
+                (None, Some(path)) => title_from_file_name(path),
+                (None, None) => String::new(),
+            },
+        };
+
+        Ok(Cartridge {
+            save_path: nes_path.as_ref().map(|path| path.with_extension("sav")),
+            save_ram_changed: false,
+            rom,
+            mapper,
+            title,
+            crc32,
+            game,
+            four_screen_vram,
+            a12: A12Watcher::new(),
+            bus_conflicts: None,
+            cycle_count: 0,
+        })
+    }
+
+    // An NROM cartridge without any ROM contents, for unit tests
+    #[cfg(test)]
+    pub fn new_blank() -> Self {
+        let rom = NesRomFile { header: [0; 16],
+                               prg_rom: vec![0; 0x8000],
+                               chr_rom: Vec::new(),
+                               mirroring: MirroringType::Horizontal,
+                               has_persistent_ram: false,
+                               has_chr_ram: true,
+                               mapper_id: 0,
+                               trainer: None,
+                               title: None };
+        Cartridge {
+            save_path: None,
+            save_ram_changed: false,
+            rom,
+            mapper: Mapper::NROM { prg_ram: vec![0; 0x2000], chr_ram: Some(vec![0; 0x2000]) },
+            title: String::new(),
+            crc32: 0,
+            game: None,
+            four_screen_vram: None,
+            a12: A12Watcher::new(),
+            bus_conflicts: None,
+            cycle_count: 0,
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
     }
 
-    pub fn save(&self) {
+    // The rises of PPU A12 that an MMC3 would have counted since power on
+    pub fn a12_rises(&self) -> u64 {
+        self.a12.rises()
+    }
+
+    pub fn mapper_id(&self) -> u8 {
+        self.rom.mapper_id
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.rom.has_persistent_ram
+    }
+
+    // Size of the PRG RAM, and of the save files of battery backed games
+    pub fn prg_ram_size(&self) -> usize {
+        self.game.as_ref().and_then(|g| g.prg_ram_size).unwrap_or_else(|| self.rom.prg_ram_size())
+    }
+
+    // CRC32 of the PRG and CHR ROM data, used to identify the game
+    pub fn crc32(&self) -> u32 {
+        self.crc32
+    }
+
+    // The PPU the game was made for, if the database knows it needs a
+    // particular one
+    pub fn ppu_variant(&self) -> Option<PpuVariant> {
+        self.game.as_ref().and_then(|g| g.ppu_variant)
+    }
+
+    // The region of the game, if the database knows it
+    pub fn region(&self) -> Option<Region> {
+        self.game.as_ref().and_then(|g| g.region)
+    }
+
+    // Returns the mapper to its power-on state. The PRG RAM keeps its
+    // contents, like battery backed RAM does.
+    pub fn power_cycle(&mut self) {
+        // The PRG RAM buffer moves to the new mapper as it is; boards without
+        // PRG RAM get an empty one that create_mapper does not use
+        let prg_ram = self.prg_ram_mut().map_or_else(Vec::new, mem::take);
+        self.mapper = create_mapper(&self.rom, prg_ram)
+            .expect("The mapper was created when the ROM was loaded");
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self.mapper {
+            Mapper::NROM { ref mut prg_ram, .. } | Mapper::MMC1 { ref mut prg_ram, .. } |
+            Mapper::VRC6 { ref mut prg_ram, .. } | Mapper::VRC4 { ref mut prg_ram, .. } |
+            Mapper::Discrete { ref mut prg_ram, .. } => Some(prg_ram),
+            Mapper::CNROM { .. } | Mapper::MMC2 { .. } => None,
+        }
+    }
+
+    // Overrides whether writes to the bank registers have bus conflicts
+    pub fn set_bus_conflicts(&mut self, enabled: bool) {
+        self.bus_conflicts = Some(enabled);
+    }
+
+    // The mappers that prg_ram_mut gives RAM for
+    pub fn has_prg_ram(&self) -> bool {
+        !matches!(self.mapper, Mapper::CNROM { .. } | Mapper::MMC2 { .. })
+    }
+
+    // The PRG RAM if it is battery backed, for frontends that keep it
+    // themselves. It stays at the same place in memory until the cartridge
+    // is dropped.
+    pub fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if !self.rom.has_persistent_ram {
+            return None;
+        }
+        self.prg_ram_mut().map(|ram| ram.as_mut_slice())
+    }
+
+    // Keeps the battery backed RAM in another file than the one next to the
+    // ROM. The RAM is loaded from the file if it exists, otherwise the RAM
+    // loaded so far goes there on the next save.
+    pub fn set_save_path(&mut self, path: &Path) {
         if self.rom.has_persistent_ram {
-            let save_path = self.nes_path.with_extension("sav");
-            match self.mapper {
-                Mapper::MMC1 { ref prg_ram, .. } => {
-                    let mut f = File::create(&save_path).unwrap();
-                    f.write_all(prg_ram).expect("Unable to write save data");
-                }
-                _ => { panic!("persistent ram not supported"); }
+            if let (Ok(data), Some(prg_ram)) = (fs::read(path), self.prg_ram_mut()) {
+                let size = prg_ram.len();
+                *prg_ram = data;
+                prg_ram.resize(size, 0);
             }
         }
+        self.save_path = Some(path.to_path_buf());
+    }
+
+    pub fn save(&mut self) -> Result<(), String> {
+        self.save_ram_changed = false;
+        let save_path = match self.save_path.clone() {
+            Some(path) => path,
+            None => { return Ok(()); }
+        };
+        if !self.rom.has_persistent_ram {
+            return Ok(());
+        }
+        // A battery on a board without PRG RAM has nothing to keep
+        let prg_ram = match self.prg_ram_mut() {
+            Some(prg_ram) => prg_ram,
+            None => { return Ok(()); }
+        };
+        if let Some(dir) = save_path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Unable to create {}: {}", dir.display(), e))?;
+        }
+        fs::write(&save_path, prg_ram)
+            .map_err(|e| format!("Unable to write {}: {}", save_path.display(), e))
+    }
+
+    // Saves if the game has written to the PRG RAM since the last save
+    pub fn save_if_changed(&mut self) -> Result<(), String> {
+        if self.save_ram_changed {
+            self.save()
+        }
+        else {
+            Ok(())
+        }
     }
 
     pub fn read_mem_cpu(&self, address: u16) -> u8 {
+        if let Some(offset) = self.prg_rom_offset(address) {
+            return self.rom.prg_rom[offset];
+        }
+        if address < 0x6000 {
+            return 0xFF;
+        }
         match self.mapper {
-            Mapper::NROM | Mapper::CNROM {bank: _} => {
-                if address < 0x8000 {
-                    0xFF
+            Mapper::NROM { ref prg_ram, .. } |
+            Mapper::VRC4 { ref prg_ram, .. } |
+            Mapper::Discrete { ref prg_ram, .. } => prg_ram[address as usize - 0x6000],
+            Mapper::MMC1 { prg_bank, chr_bank_0, ref prg_ram, .. } if prg_bank & 0x10 == 0 => {
+                prg_ram[Cartridge::mmc1_prg_ram_index(address, chr_bank_0, prg_ram.len())]
+            }
+            Mapper::VRC6 { prg_ram_enabled: true, ref prg_ram, .. } => {
+                prg_ram[address as usize - 0x6000]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn prg_rom_size(&self) -> usize {
+        self.rom.prg_rom.len()
+    }
+
+    pub fn chr_rom_size(&self) -> usize {
+        self.rom.chr_rom.len()
+    }
+
+    // Where in the PRG ROM the byte the CPU reads at the address comes from,
+    // with the banks currently mapped. None below $8000.
+    pub fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        if address < 0x8000 {
+            return None;
+        }
+        let prg_rom = &self.rom.prg_rom;
+        let offset = match self.mapper {
+            Mapper::NROM { .. } | Mapper::CNROM { .. } => {
+                if prg_rom.len() == 16384 {
+                    ((address - 0x8000) & 0x3FFF) as usize
                 }
                 else {
-                    let mem_address = if self.rom.prg_rom.len() == 16384 {
-                        (address - 0x8000) & 0x3FFF
-                    }
-                    else {
-                        address - 0x8000
-                    };
-                    self.rom.prg_rom[mem_address as usize]
+                    (address - 0x8000) as usize
                 }
             }
-            Mapper::MMC1 {prg_bank, prg_size_bit, prg_swap_range_bit,
-                          ref prg_ram, ..} => {
-                if address < 0x6000 {
-                    0xFF
+            Mapper::MMC1 {prg_bank, prg_size_bit, prg_swap_range_bit, chr_bank_0, ..} => {
+                // SUROM and SXROM have 512KB, the 256KB half chosen by bit 4
+                // of the CHR bank, and the fixed banks are in that half
+                let (outer_offset, outer_size) = if prg_rom.len() > 0x40000 {
+                    ((chr_bank_0 & 0x10) as usize * 0x4000, 0x40000)
                 }
-                else if address < 0x8000 {
-                    if prg_bank & 0x10 == 0 {
-                        prg_ram[address as usize - 0x6000]
+                else {
+                    (0, prg_rom.len())
+                };
+                outer_offset + if prg_size_bit { // 16KB switching
+                    let bank = (prg_bank & 0xF) as u16;
+                    let num_banks = (outer_size / 16384) as u16;
+                    let (on_lower_bank, bank_offset) = if address >= 0xC000 {
+                        (false, address - 0xC000)
                     }
                     else {
-                        0xFF
+                        (true, address - 0x8000)
+                    };
+                    let effective_bank = if on_lower_bank == prg_swap_range_bit {
+                        bank
                     }
-                }
-                else {
-                    let mem_address = if prg_size_bit { // 16KB switching
-                        let bank = (prg_bank & 0xF) as u16;
-                        let num_banks = (self.rom.prg_rom.len() / 16384) as u16;
-                        let (on_lower_bank, bank_offset) = if address >= 0xC000 {
-                            (false, address - 0xC000)
-                        }
-                        else {
-                            (true, address - 0x8000)
-                        };
-                        let effective_bank = if on_lower_bank == prg_swap_range_bit {
-                            bank
-                        }
-                        else if on_lower_bank {
-                            0
-                        }
-                        else {
-                            num_banks - 1
-                        };
-                        effective_bank as usize * 16384 + bank_offset as usize
+                    else if on_lower_bank {
+                        0
                     }
-                    else { // 32KB switching
-                        let bank = ((prg_bank & 0xF) >> 1) as u16;
-                        (bank * 32768 + address - 0x8000) as usize
+                    else {
+                        num_banks - 1
                     };
-                    self.rom.prg_rom[mem_address]
+                    effective_bank as usize * 16384 + bank_offset as usize
+                }
+                else { // 32KB switching
+                    let bank = ((prg_bank & 0xF) >> 1) as usize;
+                    bank * 32768 + (address - 0x8000) as usize
                 }
             }
-        }
+            Mapper::VRC6 {prg_bank_16k, prg_bank_8k, ..} => {
+                let mem_address = if address < 0xC000 {
+                    prg_bank_16k as usize * 0x4000 + (address - 0x8000) as usize
+                }
+                else if address < 0xE000 {
+                    prg_bank_8k as usize * 0x2000 + (address - 0xC000) as usize
+                }
+                else {
+                    prg_rom.len() - 0x2000 + (address - 0xE000) as usize
+                };
+                mem_address % prg_rom.len()
+            }
+            Mapper::VRC4 {prg_banks, prg_swap_mode, ..} => {
+                // Two switchable 8KB banks and the last two banks. The swap
+                // mode exchanges the first switchable bank with the second
+                // to last bank.
+                let second_to_last = prg_rom.len() / 0x2000 - 2;
+                let bank = match (address - 0x8000) / 0x2000 {
+                    0 if prg_swap_mode => second_to_last,
+                    0 => prg_banks[0] as usize,
+                    1 => prg_banks[1] as usize,
+                    2 if prg_swap_mode => prg_banks[0] as usize,
+                    2 => second_to_last,
+                    _ => second_to_last + 1,
+                };
+                let mem_address = bank * 0x2000 + (address & 0x1FFF) as usize;
+                mem_address % prg_rom.len()
+            }
+            Mapper::Discrete {prg_bank, ..} => {
+                let mem_address = if self.rom.mapper_id != 2 && self.rom.mapper_id != 71 {
+                    prg_bank as usize * 0x8000 + (address - 0x8000) as usize
+                }
+                else if address < 0xC000 {
+                    prg_bank as usize * 0x4000 + (address - 0x8000) as usize
+                }
+                else {
+                    prg_rom.len() - 0x4000 + (address - 0xC000) as usize
+                };
+                mem_address % prg_rom.len()
+            }
+            Mapper::MMC2 {prg_bank, ..} => {
+                // One switchable 8KB bank, then the last three banks
+                let mem_address = if address < 0xA000 {
+                    prg_bank as usize * 0x2000 + (address - 0x8000) as usize
+                }
+                else {
+                    prg_rom.len() - 0x6000 + (address - 0xA000) as usize
+                };
+                mem_address % prg_rom.len()
+            }
+        };
+        Some(offset)
     }
 
     pub fn write_mem_cpu(&mut self, address: u16, value: u8) {
+        let bus_conflicts = self.bus_conflicts.unwrap_or_else(|| self.rom.has_bus_conflicts());
+        let value = if address >= 0x8000 && bus_conflicts {
+            value & self.read_mem_cpu(address)
+        }
+        else {
+            value
+        };
+        if (0x6000..0x8000).contains(&address) {
+            self.save_ram_changed = true;
+        }
+        else {
+            log::trace!(target: "mapper", "${:04X}={:02X}", address, value);
+        }
         match self.mapper {
-            Mapper::NROM => {
+            Mapper::NROM { ref mut prg_ram, .. } => {
+                if (0x6000..0x8000).contains(&address) {
+                    prg_ram[address as usize - 0x6000] = value;
+                }
             }
             Mapper::MMC1 {ref mut prg_ram, ref mut shift,
                           ref mut shift_count, ref mut mirroring, ref mut prg_swap_range_bit,
                           ref mut prg_size_bit, ref mut chr_size_bit, ref mut chr_bank_0,
-                          ref mut chr_bank_1, ref mut prg_bank, ..} => {
+                          ref mut chr_bank_1, ref mut prg_bank, ref mut last_write_cycle, ..} => {
                 if address < 0x6000 {
                 }
                 else if address < 0x8000 {
                     if *prg_bank & 0x10 == 0 {
-                        prg_ram[address as usize - 0x6000] = value;
+                        let len = prg_ram.len();
+                        prg_ram[Cartridge::mmc1_prg_ram_index(address, *chr_bank_0, len)] = value;
                     }
                 }
                 else {
-                    if value & 0x80 != 0 {
+                    // The fast CPU mode steps the cartridge after the
+                    // instruction, so both writes come on the same cycle
+                    let cycle = self.cycle_count;
+                    let consecutive = last_write_cycle.is_some_and(|last| cycle - last <= 1);
+                    *last_write_cycle = Some(cycle);
+                    if consecutive {
+                    }
+                    else if value & 0x80 != 0 {
                         *shift = 0;
                         *shift_count = 0;
                     }
@@ -242,9 +914,10 @@ impl Cartridge {
                             *shift_count = 0;
                             if effective_address < 0xA000 {
                                 *mirroring = match effective_value & 0x3 {
+                                    0 => MirroringType::SingleScreenLower,
+                                    1 => MirroringType::SingleScreenUpper,
                                     2 => MirroringType::Vertical,
-                                    3 => MirroringType::Horizontal,
-                                    _ => unimplemented!(),
+                                    _ => MirroringType::Horizontal,
                                 };
                                 *prg_swap_range_bit = effective_value & 0x4 != 0;
                                 *prg_size_bit = effective_value & 0x8 != 0;
@@ -263,14 +936,416 @@ impl Cartridge {
                     }
                 }
             }
-            Mapper::CNROM {bank:_} => {
+            Mapper::CNROM { ref mut bank, .. } => {
                 if address >= 0x8000 {
-                    self.mapper = Mapper::CNROM {bank: value};
+                    *bank = value;
+                }
+            }
+            Mapper::VRC6 {address_lines_swapped, ref mut prg_bank_16k, ref mut prg_bank_8k,
+                          ref mut chr_banks, ref mut mirroring, ref mut prg_ram_enabled,
+                          ref mut prg_ram, ref mut irq, ref mut audio, ..} => {
+                if address < 0x6000 {
+                }
+                else if address < 0x8000 {
+                    if *prg_ram_enabled {
+                        prg_ram[address as usize - 0x6000] = value;
+                    }
+                }
+                else {
+                    // VRC6b (mapper 26) has address lines A0 and A1 swapped
+                    let address = if address_lines_swapped {
+                        (address & 0xF000) | ((address & 0x1) << 1) | ((address & 0x2) >> 1)
+                    }
+                    else {
+                        address & 0xF003
+                    };
+                    match address {
+                        0x8000..=0x8003 => { *prg_bank_16k = value & 0x0F; }
+                        0x9000..=0xB002 => { audio.write_mem(address, value); }
+                        0xB003 => {
+                            *mirroring = match (value >> 2) & 0x3 {
+                                0 => MirroringType::Vertical,
+                                1 => MirroringType::Horizontal,
+                                2 => MirroringType::SingleScreenLower,
+                                _ => MirroringType::SingleScreenUpper,
+                            };
+                            *prg_ram_enabled = value & 0x80 != 0;
+                        }
+                        0xC000..=0xC003 => { *prg_bank_8k = value & 0x1F; }
+                        0xD000..=0xD003 => { chr_banks[(address & 0x3) as usize] = value; }
+                        0xE000..=0xE003 => { chr_banks[4 + (address & 0x3) as usize] = value; }
+                        0xF000 => { irq.set_latch(value); }
+                        0xF001 => { irq.set_control(value); }
+                        0xF002 => { irq.acknowledge(); }
+                        _ => {}
+                    }
+                }
+            }
+            Mapper::VRC4 {variant, ref mut prg_banks, ref mut prg_swap_mode, ref mut chr_banks,
+                          ref mut mirroring, ref mut prg_ram, ref mut irq, ..} => {
+                if address < 0x6000 {
+                }
+                else if address < 0x8000 {
+                    prg_ram[address as usize - 0x6000] = value;
+                }
+                else {
+                    let address = variant.register_address(address);
+                    let is_vrc2 = variant == Vrc4Variant::Mapper22;
+                    match address {
+                        0x8000..=0x8003 => { prg_banks[0] = value & 0x1F; }
+                        0x9000 | 0x9001 if is_vrc2 => {
+                            *mirroring = if value & 0x01 != 0 {
+                                MirroringType::Horizontal
+                            }
+                            else {
+                                MirroringType::Vertical
+                            };
+                        }
+                        0x9000 | 0x9001 => {
+                            *mirroring = match value & 0x3 {
+                                0 => MirroringType::Vertical,
+                                1 => MirroringType::Horizontal,
+                                2 => MirroringType::SingleScreenLower,
+                                _ => MirroringType::SingleScreenUpper,
+                            };
+                        }
+                        0x9002 if !is_vrc2 => { *prg_swap_mode = value & 0x02 != 0; }
+                        0xA000..=0xA003 => { prg_banks[1] = value & 0x1F; }
+                        0xB000..=0xE003 => {
+                            // Each 1KB bank number is written four bits at a
+                            // time, the low bits first
+                            let bank = (((address - 0xB000) >> 11) | ((address >> 1) & 0x1)) as usize;
+                            let bank_number = &mut chr_banks[bank];
+                            *bank_number = if address & 0x1 != 0 {
+                                (*bank_number & 0x0F) | ((value as u16 & 0x1F) << 4)
+                            }
+                            else {
+                                (*bank_number & 0x1F0) | (value as u16 & 0x0F)
+                            };
+                        }
+                        0xF000 if !is_vrc2 => { irq.set_latch_nibble(false, value); }
+                        0xF001 if !is_vrc2 => { irq.set_latch_nibble(true, value); }
+                        0xF002 if !is_vrc2 => { irq.set_control(value); }
+                        0xF003 if !is_vrc2 => { irq.acknowledge(); }
+                        _ => {}
+                    }
+                }
+            }
+            Mapper::Discrete {ref mut prg_bank, ref mut chr_banks, ref mut mirroring,
+                              ref mut prg_ram, ref chr_ram} => {
+                if address < 0x6000 {
+                    return;
+                }
+                if address < 0x8000 {
+                    prg_ram[address as usize - 0x6000] = value;
+                }
+                match (self.rom.mapper_id, address) {
+                    // NINA-001 has its registers in the PRG RAM range
+                    (34, 0x7FFD) => { *prg_bank = value & 0x01; }
+                    (34, 0x7FFE) => { chr_banks[0] = value & 0x0F; }
+                    (34, 0x7FFF) => { chr_banks[1] = value & 0x0F; }
+                    (_, 0x6000..=0x7FFF) => {}
+                    (11, _) => {
+                        *prg_bank = value & 0x03;
+                        *chr_banks = [(value >> 4) * 2, (value >> 4) * 2 + 1];
+                    }
+                    (2, _) => { *prg_bank = value; }
+                    (66, _) => {
+                        *prg_bank = (value >> 4) & 0x03;
+                        *chr_banks = [(value & 0x03) * 2, (value & 0x03) * 2 + 1];
+                    }
+                    // BNROM, which unlike NINA-001 has CHR RAM
+                    (34, _) if chr_ram.is_some() => { *prg_bank = value; }
+                    (7, _) => {
+                        *prg_bank = value & 0x07;
+                        *mirroring = if value & 0x10 != 0 {
+                            MirroringType::SingleScreenUpper
+                        }
+                        else {
+                            MirroringType::SingleScreenLower
+                        };
+                    }
+                    // Only the Fire Hawk board has the mirroring register
+                    (71, 0x9000..=0x9FFF) => {
+                        *mirroring = if value & 0x10 != 0 {
+                            MirroringType::SingleScreenUpper
+                        }
+                        else {
+                            MirroringType::SingleScreenLower
+                        };
+                    }
+                    (71, 0xC000..=0xFFFF) => { *prg_bank = value & 0x0F; }
+                    _ => {}
+                }
+            }
+            Mapper::MMC2 {ref mut prg_bank, ref mut chr_banks, ref mut mirroring, ..} => {
+                match address & 0xF000 {
+                    0xA000 => { *prg_bank = value & 0x0F; }
+                    0xB000..=0xE000 => {
+                        chr_banks[((address - 0xB000) >> 12) as usize] = value & 0x1F;
+                    }
+                    0xF000 => {
+                        *mirroring = if value & 0x01 != 0 {
+                            MirroringType::Horizontal
+                        }
+                        else {
+                            MirroringType::Vertical
+                        };
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    pub fn save_state(&self, state: &mut Snapshot) {
+        state.set_version(Chunk::Mapper, STATE_VERSION);
+        state.add_bytes("rom.crc32", &self.crc32().to_le_bytes());
+        state.add_u8("mapper.id", self.rom.mapper_id);
+        self.a12.save_state(state, "cartridge.a12");
+        if let Some(ref extra) = self.four_screen_vram {
+            state.add_bytes("cartridge.vram", extra);
+        }
+        match self.mapper {
+            Mapper::NROM { ref prg_ram, ref chr_ram } => {
+                state.add_bytes("mapper.prg_ram", prg_ram);
+                if let Some(ref chr_ram) = *chr_ram {
+                    state.add_bytes("mapper.chr_ram", chr_ram);
+                }
+            }
+            Mapper::MMC1 { shift, shift_count, mirroring, prg_swap_range_bit, prg_size_bit,
+                           chr_size_bit, chr_bank_0, chr_bank_1, prg_bank, ref prg_ram,
+                           ref chr_ram, .. } => {
+                state.add_u8("mapper.shift", shift);
+                state.add_u8("mapper.shift_count", shift_count);
+                state.add_u8("mapper.mirroring", mirroring as u8);
+                state.add_bool("mapper.prg_swap_range_bit", prg_swap_range_bit);
+                state.add_bool("mapper.prg_size_bit", prg_size_bit);
+                state.add_bool("mapper.chr_size_bit", chr_size_bit);
+                state.add_u8("mapper.chr_bank_0", chr_bank_0);
+                state.add_u8("mapper.chr_bank_1", chr_bank_1);
+                state.add_u8("mapper.prg_bank", prg_bank);
+                state.add_bytes("mapper.prg_ram", prg_ram);
+                if let Some(ref chr_ram) = *chr_ram {
+                    state.add_bytes("mapper.chr_ram", chr_ram);
+                }
+            }
+            Mapper::CNROM { bank, ref chr_ram } => {
+                state.add_u8("mapper.bank", bank);
+                if let Some(ref chr_ram) = *chr_ram {
+                    state.add_bytes("mapper.chr_ram", chr_ram);
+                }
+            }
+            Mapper::VRC6 { prg_bank_16k, prg_bank_8k, chr_banks, mirroring, prg_ram_enabled,
+                           ref prg_ram, ref chr_ram, ref irq, ref audio, .. } => {
+                state.add_u8("mapper.prg_bank_16k", prg_bank_16k);
+                state.add_u8("mapper.prg_bank_8k", prg_bank_8k);
+                state.add_bytes("mapper.chr_banks", &chr_banks);
+                state.add_u8("mapper.mirroring", mirroring as u8);
+                state.add_bool("mapper.prg_ram_enabled", prg_ram_enabled);
+                state.add_bytes("mapper.prg_ram", prg_ram);
+                if let Some(ref chr_ram) = *chr_ram {
+                    state.add_bytes("mapper.chr_ram", chr_ram);
+                }
+                irq.save_state(state, "mapper.irq");
+                audio.save_state(state, "mapper.audio");
+            }
+            Mapper::VRC4 { prg_banks, prg_swap_mode, chr_banks, mirroring, ref prg_ram,
+                           ref chr_ram, ref irq, .. } => {
+                state.add_bytes("mapper.prg_banks", &prg_banks);
+                state.add_bool("mapper.prg_swap_mode", prg_swap_mode);
+                for (i, bank) in chr_banks.iter().enumerate() {
+                    state.add_u16(&format!("mapper.chr_bank_{}", i), *bank);
+                }
+                state.add_u8("mapper.mirroring", mirroring as u8);
+                state.add_bytes("mapper.prg_ram", prg_ram);
+                if let Some(ref chr_ram) = *chr_ram {
+                    state.add_bytes("mapper.chr_ram", chr_ram);
+                }
+                irq.save_state(state, "mapper.irq");
+            }
+            Mapper::Discrete { prg_bank, chr_banks, mirroring, ref prg_ram, ref chr_ram } => {
+                state.add_u8("mapper.prg_bank", prg_bank);
+                state.add_bytes("mapper.chr_banks", &chr_banks);
+                state.add_u8("mapper.mirroring", mirroring as u8);
+                state.add_bytes("mapper.prg_ram", prg_ram);
+                if let Some(ref chr_ram) = *chr_ram {
+                    state.add_bytes("mapper.chr_ram", chr_ram);
+                }
+            }
+            Mapper::MMC2 { prg_bank, chr_banks, latches, mirroring, ref chr_ram } => {
+                state.add_u8("mapper.prg_bank", prg_bank);
+                state.add_bytes("mapper.chr_banks", &chr_banks);
+                state.add_bool("mapper.latch_0", latches[0]);
+                state.add_bool("mapper.latch_1", latches[1]);
+                state.add_u8("mapper.mirroring", mirroring as u8);
+                if let Some(ref chr_ram) = *chr_ram {
+                    state.add_bytes("mapper.chr_ram", chr_ram);
+                }
+            }
+        }
+    }
+
+    // Adds what a machine state of the version lacks to make it one of the
+    // next version, like Ppu::upgrade_state
+    pub fn upgrade_state(state: &mut Snapshot, version: u32) {
+        if version == 0 {
+            state.add_missing("cartridge.a12.high", &[0]);
+            state.add_missing("cartridge.a12.low_since", &0u64.to_le_bytes());
+            state.add_missing("cartridge.a12.rises", &0u64.to_le_bytes());
+        }
+    }
+
+    pub fn load_state(&mut self, state: &Snapshot) {
+        self.a12.load_state(state, "cartridge.a12");
+        if let Some(ref mut extra) = self.four_screen_vram {
+            extra.copy_from_slice(state.get_bytes("cartridge.vram"));
+        }
+        match self.mapper {
+            Mapper::NROM { ref mut prg_ram, ref mut chr_ram } => {
+                prg_ram.copy_from_slice(state.get_bytes("mapper.prg_ram"));
+                if let Some(ref mut chr_ram) = *chr_ram {
+                    chr_ram.copy_from_slice(state.get_bytes("mapper.chr_ram"));
+                }
+            }
+            Mapper::MMC1 { ref mut shift, ref mut shift_count, ref mut mirroring,
+                           ref mut prg_swap_range_bit, ref mut prg_size_bit, ref mut chr_size_bit,
+                           ref mut chr_bank_0, ref mut chr_bank_1, ref mut prg_bank,
+                           ref mut prg_ram, ref mut chr_ram, .. } => {
+                *shift = state.get_u8("mapper.shift");
+                *shift_count = state.get_u8("mapper.shift_count");
+                *mirroring = MirroringType::from_state(state.get_u8("mapper.mirroring"));
+                *prg_swap_range_bit = state.get_bool("mapper.prg_swap_range_bit");
+                *prg_size_bit = state.get_bool("mapper.prg_size_bit");
+                *chr_size_bit = state.get_bool("mapper.chr_size_bit");
+                *chr_bank_0 = state.get_u8("mapper.chr_bank_0");
+                *chr_bank_1 = state.get_u8("mapper.chr_bank_1");
+                *prg_bank = state.get_u8("mapper.prg_bank");
+                prg_ram.copy_from_slice(state.get_bytes("mapper.prg_ram"));
+                if let Some(ref mut chr_ram) = *chr_ram {
+                    chr_ram.copy_from_slice(state.get_bytes("mapper.chr_ram"));
+                }
+            }
+            Mapper::CNROM { ref mut bank, ref mut chr_ram } => {
+                *bank = state.get_u8("mapper.bank");
+                if let Some(ref mut chr_ram) = *chr_ram {
+                    chr_ram.copy_from_slice(state.get_bytes("mapper.chr_ram"));
+                }
+            }
+            Mapper::VRC6 { ref mut prg_bank_16k, ref mut prg_bank_8k, ref mut chr_banks,
+                           ref mut mirroring, ref mut prg_ram_enabled, ref mut prg_ram,
+                           ref mut chr_ram, ref mut irq, ref mut audio, .. } => {
+                *prg_bank_16k = state.get_u8("mapper.prg_bank_16k");
+                *prg_bank_8k = state.get_u8("mapper.prg_bank_8k");
+                chr_banks.copy_from_slice(state.get_bytes("mapper.chr_banks"));
+                *mirroring = MirroringType::from_state(state.get_u8("mapper.mirroring"));
+                *prg_ram_enabled = state.get_bool("mapper.prg_ram_enabled");
+                prg_ram.copy_from_slice(state.get_bytes("mapper.prg_ram"));
+                if let Some(ref mut chr_ram) = *chr_ram {
+                    chr_ram.copy_from_slice(state.get_bytes("mapper.chr_ram"));
+                }
+                irq.load_state(state, "mapper.irq");
+                audio.load_state(state, "mapper.audio");
+            }
+            Mapper::VRC4 { ref mut prg_banks, ref mut prg_swap_mode, ref mut chr_banks,
+                           ref mut mirroring, ref mut prg_ram, ref mut chr_ram, ref mut irq,
+                           .. } => {
+                prg_banks.copy_from_slice(state.get_bytes("mapper.prg_banks"));
+                *prg_swap_mode = state.get_bool("mapper.prg_swap_mode");
+                for (i, bank) in chr_banks.iter_mut().enumerate() {
+                    *bank = state.get_u16(&format!("mapper.chr_bank_{}", i));
+                }
+                *mirroring = MirroringType::from_state(state.get_u8("mapper.mirroring"));
+                prg_ram.copy_from_slice(state.get_bytes("mapper.prg_ram"));
+                if let Some(ref mut chr_ram) = *chr_ram {
+                    chr_ram.copy_from_slice(state.get_bytes("mapper.chr_ram"));
+                }
+                irq.load_state(state, "mapper.irq");
+            }
+            Mapper::Discrete { ref mut prg_bank, ref mut chr_banks, ref mut mirroring,
+                               ref mut prg_ram, ref mut chr_ram } => {
+                *prg_bank = state.get_u8("mapper.prg_bank");
+                chr_banks.copy_from_slice(state.get_bytes("mapper.chr_banks"));
+                *mirroring = MirroringType::from_state(state.get_u8("mapper.mirroring"));
+                prg_ram.copy_from_slice(state.get_bytes("mapper.prg_ram"));
+                if let Some(ref mut chr_ram) = *chr_ram {
+                    chr_ram.copy_from_slice(state.get_bytes("mapper.chr_ram"));
+                }
+            }
+            Mapper::MMC2 { ref mut prg_bank, ref mut chr_banks, ref mut latches,
+                           ref mut mirroring, ref mut chr_ram } => {
+                *prg_bank = state.get_u8("mapper.prg_bank");
+                chr_banks.copy_from_slice(state.get_bytes("mapper.chr_banks"));
+                latches[0] = state.get_bool("mapper.latch_0");
+                latches[1] = state.get_bool("mapper.latch_1");
+                *mirroring = MirroringType::from_state(state.get_u8("mapper.mirroring"));
+                if let Some(ref mut chr_ram) = *chr_ram {
+                    chr_ram.copy_from_slice(state.get_bytes("mapper.chr_ram"));
+                }
+            }
+        }
+    }
+
+    // Clocks the mapper hardware, returns true if the mapper is asserting IRQ
+    pub fn step_cycle(&mut self, count: u16) -> bool {
+        self.cycle_count += count as u64;
+        match self.mapper {
+            Mapper::VRC6 { ref mut irq, .. } | Mapper::VRC4 { ref mut irq, .. } => {
+                for _ in 0..count {
+                    irq.step_cycle();
                 }
+                irq.irq_pending
             }
+            _ => false,
+        }
+    }
+
+    pub fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        match self.mapper {
+            Mapper::VRC6 { ref mut audio, .. } => Some(audio),
+            _ => None,
+        }
+    }
+
+    fn get_mirroring(&self) -> MirroringType {
+        // The four-screen boards wire the nametables past any mirroring
+        // register of the mapper
+        if self.four_screen_vram.is_some() {
+            return MirroringType::FourScreen;
+        }
+        match self.mapper {
+            Mapper::MMC1 { mirroring, .. } | Mapper::VRC6 { mirroring, .. } |
+            Mapper::VRC4 { mirroring, .. } | Mapper::Discrete { mirroring, .. } |
+            Mapper::MMC2 { mirroring, .. } => mirroring,
+            _ => self.rom.mirroring,
         }
     }
 
+    // An address in the console VRAM, or from $0800 up in the cartridge
+    // VRAM with four-screen mirroring
+    fn get_vram_address(&self, address: u16) -> usize {
+        let vram_address = match self.get_mirroring() {
+            MirroringType::Vertical => (address & 0xF7FF) - 0x2000,
+            MirroringType::Horizontal => ((address & 0xF3FF) | ((address >> 1) & 0x0400)) - 0x2000,
+            MirroringType::SingleScreenLower => address & 0x03FF,
+            MirroringType::SingleScreenUpper => 0x0400 | (address & 0x03FF),
+            MirroringType::FourScreen => address & 0x0FFF,
+        };
+        vram_address as usize
+    }
+
+    // SOROM has 16KB of PRG RAM, the 8KB bank chosen by bit 3 of the CHR
+    // bank, and SXROM 32KB chosen by bits 2 and 3. The bits are taken from
+    // the first CHR bank, as the games write the same ones to both.
+    fn mmc1_prg_ram_index(address: u16, chr_bank_0: u8, prg_ram_size: usize) -> usize {
+        let bank = match prg_ram_size / 0x2000 {
+            1 => 0,
+            2 => (chr_bank_0 >> 3) & 1,
+            _ => (chr_bank_0 >> 2) & 3,
+        };
+        (bank as usize * 0x2000 + (address - 0x6000) as usize) % prg_ram_size
+    }
+
     fn get_chr_mem_index(address: u16, chr_size_bit: bool,
                          chr_bank_0: u8, chr_bank_1: u8) -> usize {
         if chr_size_bit {
@@ -289,11 +1364,10 @@ impl Cartridge {
     pub fn read_mem_ppu(&self, address: u16, vram: &[u8]) -> u8 {
         if address < 0x2000 {
             match self.mapper {
-                Mapper::NROM => {
-                    if self.rom.chr_rom.len() > 0 {
-                        self.rom.chr_rom[address as usize]
-                    } else {
-                        0
+                Mapper::NROM { ref chr_ram, .. } => {
+                    match *chr_ram {
+                        Some(ref ram) => ram[address as usize],
+                        None => self.rom.chr_rom[address as usize],
                     }
                 }
                 Mapper::MMC1 {chr_size_bit, chr_bank_0, chr_bank_1, ref chr_ram, ..} => {
@@ -301,23 +1375,45 @@ impl Cartridge {
                         Some(ref ram) => ram,
                         None => &self.rom.chr_rom,
                     };
+                    // The boards with 8KB CHR RAM use the upper bank bits
+                    // for the PRG instead
                     let index = Cartridge::get_chr_mem_index(address, chr_size_bit,
                                                              chr_bank_0, chr_bank_1);
-                    chr_mem[index]
+                    chr_mem[index % chr_mem.len()]
+                }
+                Mapper::CNROM {bank, ref chr_ram} => {
+                    match *chr_ram {
+                        Some(ref ram) => ram[address as usize],
+                        None => self.rom.chr_rom[bank as usize * 0x2000 + address as usize],
+                    }
                 }
-                Mapper::CNROM {bank} => {
-                    self.rom.chr_rom[bank as usize * 0x2000 + address as usize]
+                Mapper::VRC6 { .. } | Mapper::VRC4 { .. } | Mapper::MMC2 { .. } => {
+                    let chr_mem = match self.mapper {
+                        Mapper::VRC6 { chr_ram: Some(ref ram), .. } |
+                        Mapper::VRC4 { chr_ram: Some(ref ram), .. } |
+                        Mapper::MMC2 { chr_ram: Some(ref ram), .. } => ram,
+                        _ => &self.rom.chr_rom,
+                    };
+                    chr_mem[self.banked_chr_index(address) % chr_mem.len()]
+                }
+                Mapper::Discrete {ref chr_banks, ref chr_ram, ..} => {
+                    match *chr_ram {
+                        Some(ref ram) => ram[address as usize],
+                        None => {
+                            let bank = chr_banks[(address >> 12) as usize] as usize;
+                            let index = bank * 0x1000 + (address & 0x0FFF) as usize;
+                            self.rom.chr_rom[index % self.rom.chr_rom.len()]
+                        }
+                    }
                 }
             }
         }
         else if address < 0x3000 {
-            let vram_address = if self.rom.mirroring == MirroringType::Vertical {
-                (address & 0xF7FF) - 0x2000
+            let vram_address = self.get_vram_address(address);
+            match self.four_screen_vram {
+                Some(ref extra) if vram_address >= 0x800 => extra[vram_address - 0x800],
+                _ => vram[vram_address],
             }
-            else {
-                ((address & 0xF3FF) | ((address >> 1) & 0x0400)) - 0x2000
-            };
-            vram[vram_address as usize]
         }
         else if address < 0x3F00 {
             self.read_mem_ppu(address - 0x1000, vram)
@@ -327,32 +1423,87 @@ impl Cartridge {
         }
     }
 
+    // The offset in the CHR memory of a pattern table address for the
+    // mappers with 1KB or 4KB CHR banks
+    fn banked_chr_index(&self, address: u16) -> usize {
+        match self.mapper {
+            Mapper::VRC6 { ref chr_banks, .. } => {
+                let bank = chr_banks[(address >> 10) as usize] as usize;
+                bank * 0x400 + (address & 0x3FF) as usize
+            }
+            Mapper::VRC4 { variant, ref chr_banks, .. } => {
+                let bank = chr_banks[(address >> 10) as usize] as usize;
+                // VRC2a leaves out the lowest bit of the bank number
+                let bank = if variant == Vrc4Variant::Mapper22 { bank >> 1 } else { bank };
+                bank * 0x400 + (address & 0x3FF) as usize
+            }
+            Mapper::MMC2 { ref chr_banks, ref latches, .. } => {
+                let half = (address >> 12) as usize;
+                let bank = chr_banks[half * 2 + latches[half] as usize] as usize;
+                bank * 0x1000 + (address & 0x0FFF) as usize
+            }
+            _ => address as usize,
+        }
+    }
+
+    // Called by the PPU after each fetch, of patterns as well as nametables,
+    // and $2007 access, with the dot it happened on, for mappers that watch
+    // the PPU address bus. MMC2 switches CHR banks when the tiles $FD and $FE
+    // are drawn, after their last byte has been fetched. The A12 rises are
+    // counted for a mapper to clock an IRQ counter on.
+    pub fn notify_ppu_fetch(&mut self, address: u16, dot: u64) {
+        self.a12.observe(address, dot);
+        if let Mapper::MMC2 { ref mut latches, .. } = self.mapper {
+            match address {
+                0x0FD8 => { latches[0] = false; }
+                0x0FE8 => { latches[0] = true; }
+                0x1FD8..=0x1FDF => { latches[1] = false; }
+                0x1FE8..=0x1FEF => { latches[1] = true; }
+                _ => {}
+            }
+        }
+    }
+
     pub fn write_mem_ppu(&mut self, address: u16, value: u8, vram: &mut [u8]) {
         if address < 0x2000 {
             match self.mapper {
-                Mapper::NROM | Mapper::CNROM { .. } => {
-                    //panic!("unexpected address: {:04X}", address);
+                Mapper::VRC6 { .. } | Mapper::VRC4 { .. } | Mapper::MMC2 { .. } => {
+                    let index = self.banked_chr_index(address);
+                    match self.mapper {
+                        Mapper::VRC6 { chr_ram: Some(ref mut ram), .. } |
+                        Mapper::VRC4 { chr_ram: Some(ref mut ram), .. } |
+                        Mapper::MMC2 { chr_ram: Some(ref mut ram), .. } => {
+                            let len = ram.len();
+                            ram[index % len] = value;
+                        }
+                        _ => {}
+                    }
                 },
                 Mapper::MMC1 {ref mut chr_ram, chr_size_bit, chr_bank_0, chr_bank_1, ..} => {
                     match chr_ram.as_mut() {
                         Some(ref mut ram) => {
                             let index = Cartridge::get_chr_mem_index(address, chr_size_bit,
                                                                      chr_bank_0, chr_bank_1);
-                            ram[index] = value;
+                            let len = ram.len();
+                            ram[index % len] = value;
                         }
                         None => {}
                     }
                 }
+                Mapper::NROM { ref mut chr_ram, .. } | Mapper::CNROM { ref mut chr_ram, .. } |
+                Mapper::Discrete { ref mut chr_ram, .. } => {
+                    if let Some(ref mut ram) = *chr_ram {
+                        ram[address as usize] = value;
+                    }
+                }
             }
         }
         else if address < 0x3000 {
-            let vram_address = if self.rom.mirroring == MirroringType::Vertical {
-                (address & 0xF7FF) - 0x2000
+            let vram_address = self.get_vram_address(address);
+            match self.four_screen_vram {
+                Some(ref mut extra) if vram_address >= 0x800 => { extra[vram_address - 0x800] = value; }
+                _ => { vram[vram_address] = value; }
             }
-            else {
-                ((address & 0xF3FF) | ((address >> 1) & 0x0400)) - 0x2000
-            };
-            vram[vram_address as usize] = value;
         }
         else if address < 0x3F00 {
             self.write_mem_ppu(address - 0x1000, value, vram)
@@ -362,3 +1513,326 @@ impl Cartridge {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An MMC2 ROM with 128KB PRG and CHR, each CHR byte the number of its
+    // 4KB bank
+    fn mmc2_cartridge() -> Cartridge {
+        let mut data = b"NES\x1a\x08\x10\x90\x00".to_vec();
+        data.resize(16 + 0x20000, 0);
+        data.extend((0..0x20000).map(|i| (i / 0x1000) as u8));
+        Cartridge::from_bytes(&data).unwrap()
+    }
+
+    // A ROM with 128KB PRG and CHR, each byte the number of its 8KB PRG or
+    // 1KB CHR bank
+    fn cartridge_with_numbered_banks(mapper_id: u8) -> Cartridge {
+        let mut data = b"NES\x1a\x08\x10".to_vec();
+        data.extend_from_slice(&[mapper_id << 4, mapper_id & 0xF0]);
+        data.resize(16, 0);
+        data.extend((0..0x20000).map(|i| (i / 0x2000) as u8));
+        data.extend((0..0x20000).map(|i| (i / 0x400) as u8));
+        Cartridge::from_bytes(&data).unwrap()
+    }
+
+    #[test]
+    fn vrc4_registers_on_either_address_lines() {
+        // VRC4b uses A1 and A0, VRC4d A3 and A2
+        let mut cartridge = cartridge_with_numbered_banks(25);
+        let vram = [0; 2048];
+        cartridge.write_mem_cpu(0x8000, 5);
+        cartridge.write_mem_cpu(0xA000, 6);
+        assert_eq!(cartridge.read_mem_cpu(0x8000), 5);
+        assert_eq!(cartridge.read_mem_cpu(0xA000), 6);
+        assert_eq!(cartridge.read_mem_cpu(0xC000), 14);
+        assert_eq!(cartridge.read_mem_cpu(0xE000), 15);
+
+        cartridge.write_mem_cpu(0x9001, 0x02);
+        assert_eq!(cartridge.read_mem_cpu(0x8000), 14);
+        assert_eq!(cartridge.read_mem_cpu(0xC000), 5);
+
+        // Bank 3 is the second bank of $C000, low bits then high bits. Bank
+        // 2 is written through the VRC4d lines.
+        cartridge.write_mem_cpu(0xC001, 0x0A);
+        cartridge.write_mem_cpu(0xC003, 0x04);
+        assert_eq!(cartridge.read_mem_ppu(0x0C00, &vram), 0x4A);
+        cartridge.write_mem_cpu(0xC000, 0x0B);
+        cartridge.write_mem_cpu(0xC008, 0x01);
+        assert_eq!(cartridge.read_mem_ppu(0x0800, &vram), 0x1B);
+    }
+
+    #[test]
+    fn vrc6_registers_irq_and_sawtooth() {
+        // VRC6b (mapper 26) swaps A0 and A1, so $B003 stays in place while
+        // $B001 and $B002 trade places
+        let mut cartridge = cartridge_with_numbered_banks(26);
+        let vram = [0; 2048];
+        cartridge.write_mem_cpu(0x8000, 3);
+        cartridge.write_mem_cpu(0xC000, 9);
+        assert_eq!(cartridge.read_mem_cpu(0x8000), 6);
+        assert_eq!(cartridge.read_mem_cpu(0xA000), 7);
+        assert_eq!(cartridge.read_mem_cpu(0xC000), 9);
+        assert_eq!(cartridge.read_mem_cpu(0xE000), 15);
+        cartridge.write_mem_cpu(0xD001, 0x21);
+        assert_eq!(cartridge.read_mem_ppu(0x0800, &vram), 0x21);
+
+        cartridge.write_mem_cpu(0x6000, 0x55);
+        assert_eq!(cartridge.read_mem_cpu(0x6000), 0xFF);
+        cartridge.write_mem_cpu(0xB003, 0x84);
+        assert_eq!(cartridge.get_mirroring(), MirroringType::Horizontal);
+        cartridge.write_mem_cpu(0x6000, 0x55);
+        assert_eq!(cartridge.read_mem_cpu(0x6000), 0x55);
+
+        // The sawtooth at rate 10, clocked every cycle: the accumulator
+        // grows on every other step and is cleared on the 14th
+        cartridge.write_mem_cpu(0xB000, 10);
+        cartridge.write_mem_cpu(0xB002, 0x00);
+        cartridge.write_mem_cpu(0xB001, 0x80);
+        let mut levels = Vec::new();
+        for _ in 0..14 {
+            let audio = cartridge.expansion_audio().unwrap();
+            audio.step_cycle();
+            levels.push((audio.output_level() / 0.00752).round() as u8);
+        }
+        assert_eq!(levels, [0, 1, 1, 2, 2, 3, 3, 5, 5, 6, 6, 7, 7, 0]);
+
+        // The IRQ counter in cycle mode is reloaded from the latch when the
+        // control register is written
+        let mut cartridge = cartridge_with_numbered_banks(24);
+        cartridge.write_mem_cpu(0xF000, 0xFD);
+        cartridge.write_mem_cpu(0xF001, 0x06);
+        assert!(!cartridge.step_cycle(2));
+        cartridge.write_mem_cpu(0xF001, 0x06);
+        assert!(!cartridge.step_cycle(2));
+        assert!(cartridge.step_cycle(1));
+        cartridge.write_mem_cpu(0xF002, 0);
+        assert!(!cartridge.step_cycle(300));
+    }
+
+    #[test]
+    fn discrete_mappers_switch_banks() {
+        let vram = [0; 2048];
+        let mut gxrom = cartridge_with_numbered_banks(66);
+        // Written where the ROM byte is 1, because of the bus conflicts
+        gxrom.write_mem_cpu(0xA000, 0x01);
+        assert_eq!(gxrom.read_mem_cpu(0x8000), 0);
+        assert_eq!(gxrom.read_mem_ppu(0x0000, &vram), 8);
+        assert_eq!(gxrom.read_mem_ppu(0x1000, &vram), 12);
+
+        let mut camerica = cartridge_with_numbered_banks(71);
+        camerica.write_mem_cpu(0xC000, 3);
+        assert_eq!(camerica.read_mem_cpu(0x8000), 6);
+        assert_eq!(camerica.read_mem_cpu(0xC000), 14);
+    }
+
+    #[test]
+    fn axrom_selects_a_single_screen() {
+        let mut vram = [0; 2048];
+        let mut axrom = cartridge_with_numbered_banks(7);
+        axrom.write_mem_cpu(0x8000, 0x13);
+        assert_eq!(axrom.read_mem_cpu(0x8000), 12);
+        axrom.write_mem_ppu(0x2C05, 0x42, &mut vram);
+        assert_eq!(vram[0x405], 0x42);
+        assert_eq!(axrom.read_mem_ppu(0x2005, &vram), 0x42);
+        axrom.write_mem_cpu(0x8000, 0x03);
+        assert_eq!(axrom.read_mem_ppu(0x2405, &vram), 0);
+    }
+
+    #[test]
+    fn four_screen_nametables_are_all_separate() {
+        let mut data = b"NES\x1a\x02\x01\x08".to_vec();
+        data.resize(16 + 0x8000 + 0x2000, 0);
+        let mut cartridge = Cartridge::from_bytes(&data).unwrap();
+        let mut vram = [0; 2048];
+        for (i, address) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+            cartridge.write_mem_ppu(address + 1, i as u8 + 1, &mut vram);
+        }
+        for (i, address) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+            assert_eq!(cartridge.read_mem_ppu(address + 1, &vram), i as u8 + 1);
+        }
+        // Only the first two are in the console VRAM
+        assert_eq!((vram[0x001], vram[0x401]), (1, 2));
+        assert_eq!(vram.iter().filter(|&&b| b != 0).count(), 2);
+
+        let mut state = Snapshot::new();
+        cartridge.save_state(&mut state);
+        cartridge.write_mem_ppu(0x2C01, 0, &mut vram);
+        cartridge.load_state(&state);
+        assert_eq!(cartridge.read_mem_ppu(0x3C01, &vram), 4);
+    }
+
+    #[test]
+    fn a12_rises_are_filtered() {
+        let mut watcher = A12Watcher::new();
+        // The background at $0000, then the sprites at $1000 two dots apart
+        assert!(!watcher.observe(0x0FF0, 250));
+        assert!(watcher.observe(0x1000, 257));
+        assert!(!watcher.observe(0x2000, 259));
+        assert!(!watcher.observe(0x1010, 261));
+        // Low for long enough again
+        assert!(!watcher.observe(0x0000, 328));
+        assert!(!watcher.observe(0x1000, 337));
+        assert!(!watcher.observe(0x0000, 340));
+        assert!(watcher.observe(0x1000, 350));
+        assert_eq!(watcher.rises(), 2);
+    }
+
+    #[test]
+    fn cnrom_has_bus_conflicts_unless_the_header_says_otherwise() {
+        let vram = [0; 2048];
+        let mut cartridge = cartridge_with_numbered_banks(3);
+        // The ROM byte at $A000 is 1
+        cartridge.write_mem_cpu(0xA000, 3);
+        assert_eq!(cartridge.read_mem_ppu(0x0000, &vram), 8);
+
+        cartridge.rom.header[7] |= 0x08;
+        cartridge.rom.header[8] = 0x10;
+        cartridge.write_mem_cpu(0xA000, 3);
+        assert_eq!(cartridge.read_mem_ppu(0x0000, &vram), 24);
+    }
+
+    #[test]
+    fn uxrom_switches_the_bank_at_8000_and_fixes_the_last() {
+        let mut cartridge = cartridge_with_numbered_banks(2);
+        cartridge.rom.header[7] |= 0x08;
+        cartridge.rom.header[8] = 0x10;
+        cartridge.write_mem_cpu(0x8000, 3);
+        assert_eq!(cartridge.read_mem_cpu(0x8000), 6);
+        assert_eq!(cartridge.read_mem_cpu(0xA000), 7);
+        assert_eq!(cartridge.read_mem_cpu(0xC000), 14);
+        assert_eq!(cartridge.read_mem_cpu(0xE000), 15);
+
+        // The ROM byte at $8000 is now 6, so 3 AND 6 selects bank 2
+        cartridge.set_bus_conflicts(true);
+        cartridge.write_mem_cpu(0x8000, 3);
+        assert_eq!(cartridge.read_mem_cpu(0x8000), 4);
+    }
+
+    #[test]
+    fn vrc4_without_chr_rom_has_chr_ram() {
+        let mut data = b"NES\x1a\x08\x00\x90\x10".to_vec();
+        data.resize(16 + 0x20000, 0);
+        let mut cartridge = Cartridge::from_bytes(&data).unwrap();
+        let mut vram = [0; 2048];
+        cartridge.write_mem_ppu(0x1234, 0x56, &mut vram);
+        assert_eq!(cartridge.read_mem_ppu(0x1234, &vram), 0x56);
+    }
+
+    #[test]
+    fn trainer_is_loaded_at_7000() {
+        let mut data = b"NES\x1a\x01\x00\x04".to_vec();
+        data.resize(16, 0);
+        data.extend((0..512).map(|i| i as u8));
+        data.extend((0..0x4000).map(|_| 0xEA));
+        let cartridge = Cartridge::from_bytes(&data).unwrap();
+        assert_eq!(cartridge.read_mem_cpu(0x7001), 1);
+        assert_eq!(cartridge.read_mem_cpu(0x71FF), 0xFF);
+        assert_eq!(cartridge.read_mem_cpu(0x8000), 0xEA);
+        assert_eq!(Cartridge::from_bytes(&data[..100]).err().as_deref(),
+                   Some("The trainer is truncated"));
+    }
+
+    #[test]
+    fn nrom_without_chr_rom_has_chr_ram() {
+        let mut cartridge = Cartridge::from_bytes(&nrom_image(&[])).unwrap();
+        let mut vram = [0; 2048];
+        cartridge.write_mem_ppu(0x1234, 0x56, &mut vram);
+        assert_eq!(cartridge.read_mem_ppu(0x1234, &vram), 0x56);
+    }
+
+    #[test]
+    fn roms_that_can_not_be_run_are_errors() {
+        let rom = nrom_image(&[]);
+        assert!(Cartridge::from_bytes(&rom[..10]).is_err());
+        assert!(Cartridge::from_bytes(&rom[..0x3000]).is_err());
+        assert!(Cartridge::from_bytes(&[&b"NES\x1b"[..], &rom[4..]].concat()).is_err());
+        let mut unknown_mapper = rom.clone();
+        unknown_mapper[6] = 0xF0;
+        assert_eq!(Cartridge::from_bytes(&unknown_mapper).err().as_deref(),
+                   Some("Unsupported mapper 15"));
+
+        let path = std::env::temp_dir().join(format!("nesemu-{}.NES", std::process::id()));
+        fs::write(&path, &rom).unwrap();
+        assert!(Cartridge::load(&path).is_ok());
+        let renamed = path.with_extension("txt");
+        fs::rename(&path, &renamed).unwrap();
+        assert!(Cartridge::load(&renamed).is_err());
+        fs::remove_file(&renamed).unwrap();
+    }
+
+    #[test]
+    fn database_overrides_a_bad_header() {
+        // nestest is NROM with horizontal mirroring
+        let mut data = match fs::read("nestest.nes") {
+            Ok(data) => data,
+            Err(_) => { return; }
+        };
+        data[6] = 0x31;
+        let cartridge = Cartridge::from_bytes(&data).unwrap();
+        assert_eq!(cartridge.rom.mapper_id, 0);
+        assert_eq!(cartridge.get_mirroring(), MirroringType::Horizontal);
+        assert_eq!(cartridge.region(), Some(Region::Ntsc));
+    }
+
+    #[test]
+    fn mmc2_switches_chr_banks_on_tiles_fd_and_fe() {
+        let mut cartridge = mmc2_cartridge();
+        let vram = [0; 2048];
+        for (register, bank) in [(0xB000, 1), (0xC000, 2), (0xD000, 3), (0xE000, 4)] {
+            cartridge.write_mem_cpu(register, bank);
+        }
+        assert_eq!(cartridge.read_mem_ppu(0x0000, &vram), 2);
+        assert_eq!(cartridge.read_mem_ppu(0x1000, &vram), 4);
+
+        cartridge.notify_ppu_fetch(0x0FD8, 0);
+        cartridge.notify_ppu_fetch(0x1FDB, 0);
+        assert_eq!(cartridge.read_mem_ppu(0x0000, &vram), 1);
+        assert_eq!(cartridge.read_mem_ppu(0x1000, &vram), 3);
+
+        // Only the first row of tile $FE sets latch 0
+        cartridge.notify_ppu_fetch(0x0FE9, 0);
+        assert_eq!(cartridge.read_mem_ppu(0x0000, &vram), 1);
+        cartridge.notify_ppu_fetch(0x0FE8, 0);
+        assert_eq!(cartridge.read_mem_ppu(0x0000, &vram), 2);
+        assert_eq!(cartridge.read_mem_ppu(0x1000, &vram), 3);
+    }
+
+    // Through the serial port, a bit at a time
+    fn write_mmc1_register(cartridge: &mut Cartridge, address: u16, value: u8) {
+        for bit in 0..5 {
+            cartridge.write_mem_cpu(address, value >> bit & 1);
+            cartridge.step_cycle(4);
+        }
+    }
+
+    #[test]
+    fn sxrom_banks_prg_rom_and_ram_with_the_chr_bank() {
+        // NES 2.0, 512KB PRG, CHR RAM and 32KB battery backed PRG RAM. Each
+        // PRG byte is the number of its 16KB bank.
+        let mut data = b"NES\x1a\x20\x00\x12\x08\x00\x00\x90".to_vec();
+        data.resize(16, 0);
+        data.extend((0..0x80000).map(|i| (i / 0x4000) as u8));
+        let mut cartridge = Cartridge::from_bytes(&data).unwrap();
+        assert_eq!(cartridge.battery_ram_mut().unwrap().len(), 0x8000);
+        assert_eq!(cartridge.read_mem_cpu(0x8000), 0);
+        assert_eq!(cartridge.read_mem_cpu(0xC000), 15);
+
+        write_mmc1_register(&mut cartridge, 0xA000, 0x10);
+        write_mmc1_register(&mut cartridge, 0xE000, 3);
+        assert_eq!(cartridge.read_mem_cpu(0x8000), 19);
+        assert_eq!(cartridge.read_mem_cpu(0xC000), 31);
+
+        // Bits 2 and 3 choose the 8KB of PRG RAM
+        cartridge.write_mem_cpu(0x6000, 0xAA);
+        write_mmc1_register(&mut cartridge, 0xA000, 0x0C);
+        assert_eq!(cartridge.read_mem_cpu(0x6000), 0);
+        cartridge.write_mem_cpu(0x6000, 0xBB);
+        assert_eq!(cartridge.read_mem_cpu(0x8000), 3);
+        write_mmc1_register(&mut cartridge, 0xA000, 0x00);
+        assert_eq!(cartridge.read_mem_cpu(0x6000), 0xAA);
+        assert_eq!(cartridge.battery_ram_mut().unwrap()[0x6000], 0xBB);
+    }
+}
@@ -0,0 +1,35 @@
+// Cheats replace the value the CPU reads from an address, like the Game
+// Genie does between the console and the cartridge. A cheat on work RAM
+// freezes the value: the game may write something else, but always reads
+// the cheat's value back.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Cheat {
+    pub address: u16,
+    pub value: u8,
+    // Only replace this value, so that a cheat on banked ROM affects one
+    // bank only
+    pub compare: Option<u8>,
+}
+
+// The value the CPU sees when it reads the value from the address
+pub fn apply(cheats: &[Cheat], address: u16, value: u8) -> u8 {
+    cheats.iter()
+        .find(|c| c.address == address && c.compare.is_none_or(|compare| compare == value))
+        .map_or(value, |c| c.value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cheats_replace_matching_reads() {
+        let cheats = [Cheat { address: 0x0075, value: 9, compare: None },
+                      Cheat { address: 0x8000, value: 0xEA, compare: Some(0x20) }];
+        assert_eq!(apply(&cheats, 0x0075, 3), 9);
+        assert_eq!(apply(&cheats, 0x0076, 3), 3);
+        assert_eq!(apply(&cheats, 0x8000, 0x20), 0xEA);
+        assert_eq!(apply(&cheats, 0x8000, 0x4C), 0x4C);
+    }
+}
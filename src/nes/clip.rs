@@ -0,0 +1,250 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::nes::screen::{HEIGHT, WIDTH};
+
+// Keeps the last seconds of pictures, to save them as an animated GIF. Every
+// other frame is kept, as GIF delays are in hundredths of a second and most
+// viewers slow down faster animations. The pixels are kept as indexes into a
+// palette of the colors seen so far; once it has 256 colors, new colors get
+// the closest one in it.
+
+const FRAME_STEP: u32 = 2;
+const MAX_COLORS: usize = 256;
+const MAX_CODE_SIZE: u8 = 12;
+
+#[derive(Clone)]
+pub struct ClipRecorder {
+    frames: VecDeque<Vec<u8>>,
+    capacity: usize,
+    frames_per_second: u32,
+    frame_count: u32,
+    palette: Vec<[u8; 3]>,
+    color_indexes: HashMap<[u8; 3], u8>,
+}
+
+impl ClipRecorder {
+    pub fn new(seconds: u32, frames_per_second: u32) -> ClipRecorder {
+        ClipRecorder {
+            frames: VecDeque::new(),
+            capacity: (seconds * frames_per_second / FRAME_STEP).max(1) as usize,
+            frames_per_second,
+            frame_count: 0,
+            palette: Vec::new(),
+            color_indexes: HashMap::new(),
+        }
+    }
+
+    // A picture of 256x240 pixels with 3 bytes (RGB) each
+    pub fn record(&mut self, picture: &[u8]) {
+        self.frame_count += 1;
+        if !self.frame_count.is_multiple_of(FRAME_STEP) {
+            return;
+        }
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        let frame = picture.chunks(3).map(|p| self.color_index([p[0], p[1], p[2]])).collect();
+        self.frames.push_back(frame);
+    }
+
+    fn color_index(&mut self, color: [u8; 3]) -> u8 {
+        if let Some(&index) = self.color_indexes.get(&color) {
+            return index;
+        }
+        let index = if self.palette.len() < MAX_COLORS {
+            self.palette.push(color);
+            self.palette.len() - 1
+        }
+        else {
+            let distance = |c: &[u8; 3]| {
+                (0..3).map(|i| (c[i] as i32 - color[i] as i32).pow(2)).sum::<i32>()
+            };
+            (0..self.palette.len()).min_by_key(|&i| distance(&self.palette[i])).unwrap()
+        };
+        self.color_indexes.insert(color, index as u8);
+        index as u8
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn seconds(&self) -> f64 {
+        (self.frames.len() as u32 * FRAME_STEP) as f64 / self.frames_per_second as f64
+    }
+
+    // The kept frames as a GIF that loops forever
+    pub fn to_gif(&self) -> Vec<u8> {
+        let mut data = b"GIF89a".to_vec();
+        data.extend_from_slice(&(WIDTH as u16).to_le_bytes());
+        data.extend_from_slice(&(HEIGHT as u16).to_le_bytes());
+        // A global color table of 256 colors, with 8 bits per component
+        data.extend_from_slice(&[0xF7, 0, 0]);
+        for i in 0..MAX_COLORS {
+            data.extend_from_slice(self.palette.get(i).unwrap_or(&[0; 3]));
+        }
+        data.extend_from_slice(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00");
+        for (i, frame) in self.frames.iter().enumerate() {
+            // In hundredths of a second, rounded so that they add up
+            let time = |frame: usize| frame as u32 * FRAME_STEP * 100 / self.frames_per_second;
+            let delay = (time(i + 1) - time(i)) as u16;
+            data.extend_from_slice(&[0x21, 0xF9, 0x04, 0x00]);
+            data.extend_from_slice(&delay.to_le_bytes());
+            data.extend_from_slice(&[0x00, 0x00]);
+
+            data.extend_from_slice(&[0x2C, 0, 0, 0, 0]);
+            data.extend_from_slice(&(WIDTH as u16).to_le_bytes());
+            data.extend_from_slice(&(HEIGHT as u16).to_le_bytes());
+            data.push(0x00);
+            data.push(8);
+            for block in lzw_encode(frame).chunks(255) {
+                data.push(block.len() as u8);
+                data.extend_from_slice(block);
+            }
+            data.push(0x00);
+        }
+        data.push(0x3B);
+        data
+    }
+}
+
+// The variable length LZW of GIF, for 8 bit pixels
+fn lzw_encode(pixels: &[u8]) -> Vec<u8> {
+    const CLEAR: u16 = 256;
+    const END: u16 = 257;
+    let mut writer = BitWriter { data: Vec::new(), bits: 0, bit_count: 0 };
+    let mut codes: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next_code = END + 1;
+    let mut code_size = 9;
+    writer.write(CLEAR, code_size);
+    let mut prefix = match pixels.first() {
+        Some(&pixel) => pixel as u16,
+        None => {
+            writer.write(END, code_size);
+            return writer.finish();
+        }
+    };
+    for &pixel in &pixels[1..] {
+        if let Some(&code) = codes.get(&(prefix, pixel)) {
+            prefix = code;
+            continue;
+        }
+        writer.write(prefix, code_size);
+        if next_code == 1 << MAX_CODE_SIZE {
+            writer.write(CLEAR, code_size);
+            codes.clear();
+            next_code = END + 1;
+            code_size = 9;
+        }
+        else {
+            codes.insert((prefix, pixel), next_code);
+            if next_code == 1 << code_size {
+                code_size += 1;
+            }
+            next_code += 1;
+        }
+        prefix = pixel as u16;
+    }
+    writer.write(prefix, code_size);
+    writer.write(END, code_size);
+    writer.finish()
+}
+
+// Packs codes from the least significant bit up
+struct BitWriter {
+    data: Vec<u8>,
+    bits: u32,
+    bit_count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u8) {
+        self.bits |= (code as u32) << self.bit_count;
+        self.bit_count += size;
+        while self.bit_count >= 8 {
+            self.data.push(self.bits as u8);
+            self.bits >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            self.data.push(self.bits as u8);
+        }
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A plain LZW decoder, to check that the encoder gives the pixels back
+    fn lzw_decode(data: &[u8]) -> Vec<u8> {
+        let mut pixels = Vec::new();
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut code_size = 9;
+        let mut previous: Option<Vec<u8>> = None;
+        let mut position = 0;
+        loop {
+            let mut code = 0;
+            for bit in 0..code_size {
+                let byte = data[(position + bit) / 8];
+                code |= ((byte >> ((position + bit) % 8)) as usize & 1) << bit;
+            }
+            position += code_size;
+            if code == 256 {
+                table = (0..=257).map(|i| vec![i as u8]).collect();
+                code_size = 9;
+                previous = None;
+                continue;
+            }
+            if code == 257 {
+                return pixels;
+            }
+            let entry = match (table.get(code), previous.as_ref()) {
+                (Some(entry), _) if !(256..=257).contains(&code) => entry.clone(),
+                (_, Some(previous)) => [&previous[..], &previous[..1]].concat(),
+                _ => panic!("Invalid code {}", code),
+            };
+            if let Some(previous) = previous {
+                table.push([&previous[..], &entry[..1]].concat());
+                if table.len() == 1 << code_size && code_size < MAX_CODE_SIZE as usize {
+                    code_size += 1;
+                }
+            }
+            pixels.extend_from_slice(&entry);
+            previous = Some(entry);
+        }
+    }
+
+    #[test]
+    fn encoded_pixels_decode_to_the_same() {
+        // Long enough for the table to fill up and be cleared
+        let pixels: Vec<u8> = (0..WIDTH * HEIGHT).map(|i| ((i * 7) ^ (i / 300)) as u8).collect();
+        assert_eq!(lzw_decode(&lzw_encode(&pixels)), pixels);
+        assert_eq!(lzw_decode(&lzw_encode(&[3, 3, 3, 3])), [3, 3, 3, 3]);
+    }
+
+    #[test]
+    fn the_last_seconds_are_kept_with_their_colors() {
+        let mut recorder = ClipRecorder::new(1, 60);
+        for i in 0..100 {
+            let color = [i as u8, 0, 0];
+            recorder.record(&color.repeat((WIDTH * HEIGHT) as usize));
+        }
+        assert_eq!(recorder.frame_count(), 30);
+        assert_eq!(recorder.seconds(), 1.0);
+        // The kept frames are 41, 43 and so on, counting from 0
+        assert_eq!(recorder.palette[recorder.frames[0][0] as usize], [41, 0, 0]);
+
+        let gif = recorder.to_gif();
+        assert_eq!(&gif[..6], b"GIF89a");
+        assert_eq!(&gif[6..10], &[0, 1, 240, 0]);
+        assert_eq!(gif.last(), Some(&0x3B));
+        // The first graphic control extension, after the color table and
+        // the looping extension, with a delay of 3/100 s
+        assert_eq!(&gif[13 + 768 + 19..13 + 768 + 19 + 6], &[0x21, 0xF9, 0x04, 0x00, 3, 0]);
+    }
+}
@@ -0,0 +1,73 @@
+// Records which bytes of the PRG ROM the CPU has executed as code and which
+// it has read as data, in the .cdl format of FCEUX: a byte of flags for each
+// byte of PRG ROM, followed by one for each byte of CHR ROM. Only the PRG ROM
+// is tracked; the CHR ROM flags are left cleared. The indirect access flags
+// are not set either.
+
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+// Read by the DMC as a sample
+pub const PCM: u8 = 0x40;
+
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr_size: usize,
+}
+
+impl CodeDataLog {
+    pub fn new(prg_size: usize, chr_size: usize) -> CodeDataLog {
+        CodeDataLog { prg: vec![0; prg_size], chr_size }
+    }
+
+    // Continues a log saved earlier for the same ROM
+    pub fn from_bytes(data: &[u8], prg_size: usize, chr_size: usize)
+                      -> Result<CodeDataLog, String> {
+        if data.len() != prg_size + chr_size {
+            return Err(format!("The code/data log is {} bytes, but should be {} for this ROM",
+                               data.len(), prg_size + chr_size));
+        }
+        Ok(CodeDataLog { prg: data[..prg_size].to_vec(), chr_size })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.prg.clone();
+        data.resize(self.prg.len() + self.chr_size, 0);
+        data
+    }
+
+    // Bits 2 and 3 tell which 8KB of the CPU address space the byte was
+    // mapped to when it was accessed
+    pub fn record(&mut self, offset: usize, address: u16, flags: u8) {
+        let bank = ((address >> 13) & 0x3) as u8;
+        self.prg[offset] |= flags | bank << 2;
+    }
+
+    pub fn flags(&self, offset: usize) -> u8 {
+        self.prg[offset]
+    }
+
+    // The number of PRG ROM bytes seen as code and as data
+    pub fn coverage(&self) -> (usize, usize) {
+        let count = |flag| self.prg.iter().filter(|f| *f & flag != 0).count();
+        (count(CODE), count(DATA))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_are_saved_in_the_fceux_layout() {
+        let mut log = CodeDataLog::new(4, 2);
+        log.record(0, 0xC000, CODE);
+        log.record(0, 0xC000, DATA);
+        log.record(3, 0xFFFF, DATA | PCM);
+        assert_eq!(log.to_bytes(), [0x0B, 0, 0, 0x4E, 0, 0]);
+        assert_eq!(log.coverage(), (1, 2));
+
+        let log = CodeDataLog::from_bytes(&[1, 2, 0, 0, 0, 0], 4, 2).unwrap();
+        assert_eq!(log.flags(1), DATA);
+        assert!(CodeDataLog::from_bytes(&[1, 2, 0], 4, 2).is_err());
+    }
+}
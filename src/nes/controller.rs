@@ -1,94 +1,336 @@
+#[cfg(feature = "sdl")]
 use sdl2::keyboard::Keycode;
 
-enum Key {
-    A,
-    B,
-    Select,
-    Start,
-    Up,
-    Down,
-    Left,
-    Right
+use crate::nes::state::Snapshot;
+
+// Buttons in the order they are shifted out
+pub const BUTTON_NAMES: [&str; 8] = ["a", "b", "select", "start", "up", "down", "left", "right"];
+
+pub const PLAYERS: usize = 4;
+
+// The Four Score sends its signature after the two controllers of each port,
+// bit 0 first
+const FOUR_SCORE_SIGNATURES: [u8; 2] = [0x10, 0x20];
+
+// Player 1 plays with the keyboard
+#[cfg(feature = "sdl")]
+fn default_key_bindings() -> [[Option<Keycode>; 8]; PLAYERS] {
+    let mut key_bindings = [[None; 8]; PLAYERS];
+    key_bindings[0] = [Some(Keycode::F), Some(Keycode::D), Some(Keycode::S), Some(Keycode::Return),
+                       Some(Keycode::Up), Some(Keycode::Down), Some(Keycode::Left), Some(Keycode::Right)];
+    key_bindings
 }
 
+// The controllers of players 1 to 4. Player 1 is connected to port 1 and
+// player 2, when it has keys bound, to port 2. Players 3 and 4 need the Four
+// Score adapter, which adds them after players 1 and 2 on the same ports.
 pub struct Controller {
-    key_state: [bool; 8],
+    key_state: [[bool; 8]; PLAYERS],
+    // The keys held, also while the buttons are set some other way
+    keyboard_state: [[bool; 8]; PLAYERS],
+    #[cfg(feature = "sdl")]
+    key_bindings: [[Option<Keycode>; 8]; PLAYERS],
     strobe: bool,
-    key_index: u8,
+    shift_registers: [u32; 2],
+    ports_swapped: bool,
+    four_score: bool,
+    keyboard_enabled: bool,
+    // Buttons forced down or up whatever the other input is, e.g. by a script
+    overrides: [[Option<bool>; 8]; PLAYERS],
+    // Players whose controller is connected even without keys bound
+    plugged_in: [bool; PLAYERS],
     pub mem_read_mut_enabled: bool,
 }
 
 impl Controller {
     pub fn new() -> Controller {
         Controller {
-            key_state: [false; 8],
+            key_state: [[false; 8]; PLAYERS],
+            keyboard_state: [[false; 8]; PLAYERS],
+            #[cfg(feature = "sdl")]
+            key_bindings: default_key_bindings(),
             strobe: false,
-            key_index: 0,
+            shift_registers: [0; 2],
+            ports_swapped: false,
+            four_score: false,
+            keyboard_enabled: true,
+            overrides: [[None; 8]; PLAYERS],
+            plugged_in: [false; PLAYERS],
             mem_read_mut_enabled: true,
         }
     }
 
-    fn get_key_from_keycode(keycode: Keycode) -> Option<Key> {
-        match keycode {
-            Keycode::F => Some(Key::A),
-            Keycode::D => Some(Key::B),
-            Keycode::S => Some(Key::Select),
-            Keycode::Return => Some(Key::Start),
-            Keycode::Up => Some(Key::Up),
-            Keycode::Down => Some(Key::Down),
-            Keycode::Left => Some(Key::Left),
-            Keycode::Right => Some(Key::Right),
-            _ => None,
+    #[cfg(feature = "sdl")]
+    fn get_key_from_keycode(&self, keycode: Keycode) -> Option<(usize, usize)> {
+        self.key_bindings.iter().enumerate().find_map(|(player, bindings)| {
+            bindings.iter().position(|k| *k == Some(keycode)).map(|key| (player, key))
+        })
+    }
+
+    // Players are numbered from 1
+    #[cfg(feature = "sdl")]
+    pub fn set_key_binding(&mut self, player: usize, button: &str, keycode: Keycode) -> Result<(), String> {
+        if !(1..=PLAYERS).contains(&player) {
+            return Err(format!("Invalid player: {}", player));
         }
+        let key = BUTTON_NAMES.iter().position(|b| *b == button)
+            .ok_or(format!("Unknown controller button: {}", button))?;
+        self.key_bindings[player - 1][key] = Some(keycode);
+        Ok(())
+    }
+
+    #[cfg(feature = "sdl")]
+    pub fn has_key(&self, keycode: Keycode) -> bool {
+        self.get_key_from_keycode(keycode).is_some()
     }
 
+    #[cfg(feature = "sdl")]
     pub fn handle_key_change(&mut self, keycode: Keycode, is_pressed: bool) {
-        match Controller::get_key_from_keycode(keycode) {
-            Some(key) => { self.key_state[key as usize] = is_pressed; },
-            None => {},
+        if let Some((player, key)) = self.get_key_from_keycode(keycode) {
+            self.keyboard_state[player][key] = is_pressed;
+            if self.keyboard_enabled {
+                self.key_state[player][key] = is_pressed;
+            }
         }
     }
 
+    // The buttons held on the keyboard, whether or not they control the game.
+    // Players are numbered from 1.
+    pub fn keyboard_buttons(&self, player: usize) -> [bool; 8] {
+        self.keyboard_state[player - 1]
+    }
+
+    #[cfg(feature = "sdl")]
     pub fn handle_key_down(&mut self, keycode: Keycode) {
         self.handle_key_change(keycode, true);
     }
 
+    #[cfg(feature = "sdl")]
     pub fn handle_key_up(&mut self, keycode: Keycode) {
         self.handle_key_change(keycode, false);
     }
 
+    // The buttons the game sees, with the overrides. Players are numbered
+    // from 1.
+    pub fn buttons(&self, player: usize) -> [bool; 8] {
+        let mut buttons = self.key_state[player - 1];
+        for (pressed, forced) in buttons.iter_mut().zip(&self.overrides[player - 1]) {
+            *pressed = forced.unwrap_or(*pressed);
+        }
+        buttons
+    }
+
+    // Sets all buttons of player 1 at once, e.g. from a movie. The keyboard
+    // is ignored while the buttons are set this way.
+    pub fn set_buttons(&mut self, buttons: [bool; 8]) {
+        self.set_player_buttons(1, buttons);
+    }
+
+    // Players are numbered from 1
+    pub fn set_player_buttons(&mut self, player: usize, buttons: [bool; 8]) {
+        self.key_state[player - 1] = buttons;
+        self.keyboard_enabled = false;
+    }
+
+    // Holds a button down or up until clear_overrides, whether it is pressed
+    // on the keyboard, in a movie or by the other player. Players are
+    // numbered from 1.
+    pub fn override_button(&mut self, player: usize, button: usize, pressed: bool) {
+        self.overrides[player - 1][button] = Some(pressed);
+    }
+
+    pub fn clear_overrides(&mut self) {
+        self.overrides = [[None; 8]; PLAYERS];
+    }
+
+    pub fn enable_keyboard(&mut self) {
+        self.key_state = self.keyboard_state;
+        self.keyboard_enabled = true;
+    }
+
+    pub fn power_cycle(&mut self) {
+        self.strobe = false;
+        self.shift_registers = [0; 2];
+    }
+
+    pub fn save_state(&self, state: &mut Snapshot) {
+        state.add_bool("controller.strobe", self.strobe);
+        let mut shift_registers = Vec::new();
+        for register in &self.shift_registers {
+            shift_registers.extend_from_slice(&register.to_le_bytes());
+        }
+        state.add_bytes("controller.shift_registers", &shift_registers);
+    }
+
+    pub fn load_state(&mut self, state: &Snapshot) {
+        self.strobe = state.get_bool("controller.strobe");
+        let shift_registers = state.get_bytes("controller.shift_registers");
+        for (register, bytes) in self.shift_registers.iter_mut().zip(shift_registers.chunks(4)) {
+            *register = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+    }
+
+    // Connects the controller to port 2 instead of port 1
+    pub fn set_ports_swapped(&mut self, swapped: bool) {
+        self.ports_swapped = swapped;
+    }
+
+    pub fn ports_swapped(&self) -> bool {
+        self.ports_swapped
+    }
+
+    // Connects the controller of a player whose buttons are set by
+    // set_player_buttons rather than the keyboard. Players are numbered from 1.
+    pub fn plug_in(&mut self, player: usize) {
+        self.plugged_in[player - 1] = true;
+    }
+
+    pub fn set_four_score(&mut self, enabled: bool) {
+        self.four_score = enabled;
+    }
+
+    // The player whose controller is plugged directly into the port, 0 for
+    // port 1
+    fn player_in_port(&self, port: usize) -> usize {
+        if self.ports_swapped { port ^ 1 } else { port }
+    }
+
+    fn is_connected(&self, port: usize) -> bool {
+        let player = self.player_in_port(port);
+        self.four_score || player == 0 || self.plugged_in[player] || self.has_keys_bound(player)
+    }
+
+    #[cfg(feature = "sdl")]
+    fn has_keys_bound(&self, player: usize) -> bool {
+        self.key_bindings[player].iter().any(|k| k.is_some())
+    }
+
+    #[cfg(not(feature = "sdl"))]
+    fn has_keys_bound(&self, _player: usize) -> bool {
+        false
+    }
+
+    // The buttons of a player as loaded into a shift register, A in bit 0
+    fn latched_buttons(&self, player: usize) -> u8 {
+        self.key_state[player].iter().zip(&self.overrides[player]).rev()
+            .fold(0, |bits, (pressed, forced)| (bits << 1) | forced.unwrap_or(*pressed) as u8)
+    }
+
+    // All that is shifted out of a port: a controller, then ones. With the
+    // Four Score the second controller and the signature come before the
+    // ones.
+    fn latched_port(&self, port: usize) -> u32 {
+        let player = self.player_in_port(port);
+        let first = self.latched_buttons(player) as u32;
+        if self.four_score {
+            let second = self.latched_buttons(player + 2) as u32;
+            let signature = FOUR_SCORE_SIGNATURES[port] as u32;
+            first | (second << 8) | (signature << 16) | 0xFF00_0000
+        }
+        else {
+            first | 0xFFFF_FF00
+        }
+    }
+
+    fn latch(&mut self) {
+        self.shift_registers = [self.latched_port(0), self.latched_port(1)];
+    }
+
+    // The buttons are shifted out one at a time, starting with A. While the
+    // strobe is high the shift register is reloaded continuously, so every
+    // read returns A. After the last bit the register is filled with ones.
+    // A port without a controller always reads 0.
     pub fn read_mem(&mut self, cpu_address: u16) -> u8 {
         if !self.mem_read_mut_enabled {
             return 0;
         }
-        match cpu_address {
-            0x4016 => {
-                if self.strobe {
-                    if self.key_state[self.key_index as usize] { 1 } else { 0 }
-                }
-                else {
-                    let result = self.key_state[self.key_index as usize];
-                    self.key_index += 1;
-                    if result { 1 } else { 0 }
-                }
-            },
-            0x4017 => { 0 },
+        let port = match cpu_address {
+            0x4016 => 0,
+            0x4017 => 1,
             _ => panic!("Unimplemented read address: {:04X}", cpu_address)
+        };
+        if !self.is_connected(port) {
+            return 0;
+        }
+        if self.strobe {
+            self.latch();
         }
+        let bit = (self.shift_registers[port] & 0x01) as u8;
+        self.shift_registers[port] = (self.shift_registers[port] >> 1) | 0x8000_0000;
+        bit
     }
 
     pub fn write_mem(&mut self, cpu_address: u16, value: u8) {
         match cpu_address {
             0x4016 => {
-                if value & 0x01 != 0 {
-                    self.strobe = true;
-                    self.key_index = 0;
-                }
-                else {
-                    self.strobe = false;
+                // The buttons are latched while the strobe is high and when
+                // it goes low
+                if self.strobe || value & 0x01 != 0 {
+                    self.latch();
                 }
+                self.strobe = value & 0x01 != 0;
             }
             _ => panic!("Unimplemented write address: {:04X}", cpu_address)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_buttons(controller: &mut Controller, count: usize) -> Vec<u8> {
+        (0..count).map(|_| controller.read_mem(0x4016)).collect()
+    }
+
+    #[test]
+    fn buttons_are_shifted_out_then_ones() {
+        let mut controller = Controller::new();
+        controller.set_buttons([true, false, false, true, false, false, false, true]);
+        controller.write_mem(0x4016, 1);
+        controller.write_mem(0x4016, 0);
+        assert_eq!(read_buttons(&mut controller, 10), [1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+        assert_eq!(controller.read_mem(0x4017), 0);
+    }
+
+    #[test]
+    fn strobe_high_returns_a_and_latches_on_falling_edge() {
+        let mut controller = Controller::new();
+        controller.set_buttons([false, true, false, false, false, false, false, false]);
+        controller.write_mem(0x4016, 1);
+        assert_eq!(read_buttons(&mut controller, 3), [0, 0, 0]);
+
+        // Buttons changing while the strobe is high are picked up
+        controller.set_buttons([true, false, true, false, false, false, false, false]);
+        assert_eq!(read_buttons(&mut controller, 2), [1, 1]);
+        controller.write_mem(0x4016, 0);
+
+        // But not after it has gone low
+        controller.set_buttons([true; 8]);
+        assert_eq!(read_buttons(&mut controller, 4), [1, 0, 1, 0]);
+        controller.write_mem(0x4016, 0);
+        assert_eq!(read_buttons(&mut controller, 1), [0]);
+    }
+
+    #[test]
+    fn four_score_sends_two_controllers_and_the_signature() {
+        let mut controller = Controller::new();
+        controller.set_four_score(true);
+        controller.set_player_buttons(1, [true, false, false, false, false, false, false, false]);
+        controller.set_player_buttons(2, [false, true, false, false, false, false, false, false]);
+        controller.set_player_buttons(3, [false, false, true, false, false, false, false, false]);
+        controller.set_player_buttons(4, [false, false, false, true, false, false, false, false]);
+        controller.write_mem(0x4016, 1);
+        controller.write_mem(0x4016, 0);
+        let port1: Vec<u8> = (0..26).map(|_| controller.read_mem(0x4016)).collect();
+        let port2: Vec<u8> = (0..26).map(|_| controller.read_mem(0x4017)).collect();
+        assert_eq!(port1, [1, 0, 0, 0, 0, 0, 0, 0,
+                           0, 0, 1, 0, 0, 0, 0, 0,
+                           0, 0, 0, 0, 1, 0, 0, 0,
+                           1, 1]);
+        assert_eq!(port2, [0, 1, 0, 0, 0, 0, 0, 0,
+                           0, 0, 0, 1, 0, 0, 0, 0,
+                           0, 0, 0, 0, 0, 1, 0, 0,
+                           1, 1]);
+    }
+}
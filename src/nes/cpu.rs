@@ -1,5 +1,12 @@
-use crate::nes::Machine;
-use std::collections::HashMap;
+use crate::nes::{EventKind, Machine};
+use crate::nes::state::{Chunk, Snapshot};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+
+// The version of the sections in save_state, raised when they change
+pub const STATE_VERSION: u32 = 1;
 
 #[derive(Debug)]
 struct Registers {
@@ -38,18 +45,129 @@ enum AddressingMode {
     IndirectY,
 }
 
+// The registers, with the status flags also decoded, for debuggers and tests
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct CpuState {
+    pub pc: u16,
+    pub sp: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub status: u8,
+    pub instruction_count: u64,
+    pub jammed: bool,
+    pub flags: StatusFlags,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct StatusFlags {
+    pub carry: bool,
+    pub zero: bool,
+    pub interrupt_disable: bool,
+    pub decimal: bool,
+    pub overflow: bool,
+    pub negative: bool,
+}
+
 pub struct Cpu {
     reg: Registers,
     instructions: HashMap<u8, Instruction>,
     nmi_triggered: bool,
     irq_triggered: bool,
+    cycles: Option<CycleState>,
+    access: AccessState,
+    instruction_count: u64,
+    history: VecDeque<HistoryEntry>,
+    history_size: usize,
+    // Set when the CPU stops on an error, until it is reset
+    error: Option<EmulationError>,
+    // Set by the KIL opcodes, which stop the CPU until it is reset while the
+    // rest of the machine runs on
+    jammed: bool,
+}
+
+// Why the CPU stopped. The addressing mode panics in the instructions can
+// only happen if the instruction table is wrong, which the selftest checks.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EmulationError {
+    UnknownOpcode { pc: u16, op_code: u8 },
+    // A bug in the instruction table rather than in the game
+    UnexpectedAddressingMode { pc: u16, op_code: u8 },
+}
+
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EmulationError::UnknownOpcode { pc, op_code } => {
+                write!(f, "Unknown opcode ${:02X} at ${:04X}", op_code, pc)
+            }
+            EmulationError::UnexpectedAddressingMode { pc, op_code } => {
+                write!(f, "Unexpected addressing mode of opcode ${:02X} at ${:04X}", op_code, pc)
+            }
+        }
+    }
+}
+
+impl Error for EmulationError {}
+
+// An executed instruction, with the registers before it
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct HistoryEntry {
+    pc: u16,
+    bytes: [u8; 3],
+    a: u8,
+    x: u8,
+    y: u8,
+    status: u8,
+    sp: u8,
 }
 
+// Bookkeeping for the cycle accurate mode, where the machine is stepped on
+// every bus access instead of after each instruction
+#[derive(Default)]
+struct CycleState {
+    requested: u16,
+    done: u16,
+    operands: [Option<u8>; 2],
+    nmi_seen: bool,
+    nmi_seen_before_last: bool,
+    irq_line: bool,
+    irq_line_before_last: bool,
+    // The interrupt lines as polled by a taken branch that stays on the page,
+    // which polls before its extra cycle instead of on the second-to-last
+    early_poll: Option<(bool, bool)>,
+    // The interrupt disable flag as it was when the interrupts were polled
+    interrupt_disable: bool,
+}
+
+// The memory operand of the executing instruction. Like the 6502, the
+// instructions do the dummy reads of the indexed addressing modes, and the
+// read-modify-write instructions write the unmodified value before the
+// result, which registers with side effects notice: reading $2007 or $4015,
+// or writing to a mapper. In the cycle accurate mode, the accesses are also
+// done on the right cycles.
+#[derive(Default)]
+struct AccessState {
+    // The address and whether the indexing crossed a page
+    address: Option<(u16, u16)>,
+    indexed_write: bool,
+    rmw_value: u8,
+    op_code: u8,
+}
+
+// Instructions that write to memory, which always do the dummy read of the
+// indexed addressing modes
+const WRITE_MNEMONICS: [&str; 16] = [
+    "STA", "STX", "STY", "*SAX", "ASL", "LSR", "ROL", "ROR", "INC", "DEC",
+    "*SLO", "*SRE", "*RLA", "*RRA", "*DCP", "*ISB",
+];
+
 #[derive(Debug)]
 struct Instruction {
     op_code: u8,
     mnemonic: String,
     addressing_mode: AddressingMode,
+    writes: bool,
 }
 
 impl Instruction {
@@ -57,10 +175,66 @@ impl Instruction {
            addressing_mode: AddressingMode) -> Instruction {
         Instruction { op_code: op_code,
                       mnemonic: mnemonic.to_string(),
-                      addressing_mode: addressing_mode }
+                      addressing_mode: addressing_mode,
+                      writes: WRITE_MNEMONICS.contains(&mnemonic) }
+    }
+}
+
+// The value shown for a memory operand in the disassembly. The APU registers
+// are shown as FF, like in the Nintendulator logs that nestest.log is from.
+fn operand_value(m: &mut Machine, address: u16) -> u8 {
+    if (0x4000..=0x4015).contains(&address) {
+        0xFF
+    }
+    else {
+        m.read_mem(address)
     }
 }
 
+// The length in bytes of an instruction using the addressing mode
+fn instruction_size(addressing_mode: AddressingMode) -> usize {
+    match addressing_mode {
+        AddressingMode::Implied | AddressingMode::Accumulator => 1,
+        AddressingMode::Absolute | AddressingMode::AbsoluteX | AddressingMode::AbsoluteY |
+        AddressingMode::Indirect => 3,
+        _ => 2,
+    }
+}
+
+// The operand in assembler syntax, with the addresses of absolute operands
+// and branch targets named by label
+fn format_operand(addressing_mode: AddressingMode, operands: &[u8], next_address: u16,
+                  label: impl Fn(u16) -> String) -> String {
+    let byte = || operands[0];
+    let word = || ((operands[1] as u16) << 8) + operands[0] as u16;
+    match addressing_mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => " A".to_string(),
+        AddressingMode::Immediate => format!(" #${:02X}", byte()),
+        AddressingMode::Relative => format!(" {}", label(next_address.wrapping_add(byte() as i8 as u16))),
+        AddressingMode::Absolute => format!(" {}", label(word())),
+        AddressingMode::AbsoluteX => format!(" {},X", label(word())),
+        AddressingMode::AbsoluteY => format!(" {},Y", label(word())),
+        AddressingMode::ZeroPage => format!(" ${:02X}", byte()),
+        AddressingMode::ZeroPageX => format!(" ${:02X},X", byte()),
+        AddressingMode::ZeroPageY => format!(" ${:02X},Y", byte()),
+        AddressingMode::Indirect => format!(" ({})", label(word())),
+        AddressingMode::IndirectX => format!(" (${:02X},X)", byte()),
+        AddressingMode::IndirectY => format!(" (${:02X}),Y", byte()),
+    }
+}
+
+// The unofficial instructions, which start with *, are one character to the
+// left of the others, like in nestest.log
+fn format_instruction(instr: &Instruction, operand: &str) -> String {
+    let space = if instr.mnemonic.starts_with('*') { "" } else { " " };
+    format!("{}{}{}", space, instr.mnemonic, operand)
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
+}
+
 fn set_flag(status: &mut u8, flag: StatusFlag, enabled: bool) {
     if enabled {
         *status |= 1 << flag as u8;
@@ -70,6 +244,12 @@ fn set_flag(status: &mut u8, flag: StatusFlag, enabled: bool) {
     }
 }
 
+impl Default for Cpu {
+    fn default() -> Self {
+        Cpu::new()
+    }
+}
+
 impl Cpu {
     pub fn new() -> Self {
         Cpu {
@@ -77,10 +257,115 @@ impl Cpu {
             instructions: Cpu::add_instructions(),
             nmi_triggered: false,
             irq_triggered: false,
+            cycles: None,
+            access: AccessState::default(),
+            instruction_count: 0,
+            history: VecDeque::new(),
+            history_size: 0,
+            error: None,
+            jammed: false,
+        }
+    }
+
+    // Keeps the last size executed instructions, for finding out how the
+    // program got where it is when something goes wrong. 0 turns it off.
+    pub fn set_history_size(&mut self, size: usize) {
+        self.history_size = size;
+        self.history = VecDeque::with_capacity(size);
+    }
+
+    // The kept instructions, oldest first, disassembled with the registers
+    // as they were before each
+    pub fn history(&self) -> Vec<String> {
+        self.history.iter().map(|entry| {
+            let registers = format!("A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+                                    entry.a, entry.x, entry.y, entry.status, entry.sp);
+            let (code_str, instr_str) = match self.instructions.get(&entry.bytes[0]) {
+                Some(instr) => {
+                    let size = instruction_size(instr.addressing_mode);
+                    let operand = format_operand(instr.addressing_mode, &entry.bytes[1..size],
+                                                 entry.pc.wrapping_add(size as u16),
+                                                 |address| format!("${:04X}", address));
+                    (hex_bytes(&entry.bytes[..size]), format_instruction(instr, &operand))
+                }
+                None => (hex_bytes(&entry.bytes[..1]), " <unknown>".to_string()),
+            };
+            format!("{:04X}  {:8} {:12} {}", entry.pc, code_str, instr_str, registers)
+        }).collect()
+    }
+
+    fn record_history(&mut self, sys: &mut Machine) {
+        if self.history.len() == self.history_size {
+            self.history.pop_front();
         }
+        let pc = self.reg.pc;
+        let bytes = [sys.peek_mem(pc), sys.peek_mem(pc.wrapping_add(1)), sys.peek_mem(pc.wrapping_add(2))];
+        self.history.push_back(HistoryEntry { pc, bytes, a: self.reg.a, x: self.reg.x, y: self.reg.y,
+                                              status: self.reg.status, sp: self.reg.sp });
+    }
+
+    // In the cycle accurate mode, the machine is stepped one cycle on every
+    // bus access, including the dummy reads and writes of the 6502, and the
+    // interrupts are polled on the second-to-last cycle of each instruction.
+    // It is slower, but places register accesses on the right cycle.
+    pub fn set_cycle_accurate(&mut self, enabled: bool) {
+        self.cycles = if enabled { Some(CycleState::default()) } else { None };
+    }
+
+    // The number of calls to execute, each of which runs one instruction or
+    // interrupt sequence
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    pub fn state(&self) -> CpuState {
+        let flag = |flag: StatusFlag| self.reg.status & (1 << flag as u8) != 0;
+        CpuState {
+            pc: self.reg.pc,
+            sp: self.reg.sp,
+            a: self.reg.a,
+            x: self.reg.x,
+            y: self.reg.y,
+            status: self.reg.status,
+            instruction_count: self.instruction_count,
+            jammed: self.jammed,
+            flags: StatusFlags {
+                carry: flag(StatusFlag::Carry),
+                zero: flag(StatusFlag::Zero),
+                interrupt_disable: flag(StatusFlag::InterruptDisable),
+                decimal: flag(StatusFlag::DecimalMode),
+                overflow: flag(StatusFlag::Overflow),
+                negative: flag(StatusFlag::Negative),
+            },
+        }
+    }
+
+    pub fn is_implemented(&self, op_code: u8) -> bool {
+        self.instructions.contains_key(&op_code)
+    }
+
+    // The power-on register values followed by the reset sequence
+    pub fn power_cycle(&mut self, m: &mut Machine) {
+        self.reg = Registers { pc:0, sp:0xfd, a:0, x:0, y:0, status:0x24 };
+        self.nmi_triggered = false;
+        self.irq_triggered = false;
+        self.reset(m);
+    }
+
+    // Pressing the reset button. The CPU goes through the interrupt sequence
+    // with the stack writes suppressed, so S still goes down by three, and
+    // interrupts get disabled.
+    pub fn soft_reset(&mut self, m: &mut Machine) {
+        self.reg.sp = self.reg.sp.wrapping_sub(3);
+        set_flag(&mut self.reg.status, StatusFlag::InterruptDisable, true);
+        self.nmi_triggered = false;
+        self.irq_triggered = false;
+        self.reset(m);
     }
 
     pub fn reset(&mut self, m: &mut Machine) {
+        self.error = None;
+        self.jammed = false;
         self.perform_interrupt(m, 0xffc, 0xffd, false);
         self.reg.pc = ((m.read_mem(0xfffd) as u16) << 8) +
             m.read_mem(0xfffc) as u16;
@@ -93,17 +378,39 @@ impl Cpu {
 
     fn perform_interrupt(&mut self, m: &mut Machine,
                          pcl_addr: u16, pch_addr: u16, write_to_stack: bool) {
+        let (mut pcl_addr, mut pch_addr) = (pcl_addr, pch_addr);
         if write_to_stack {
+            // The next opcode is read twice and thrown away
+            let pc = self.reg.pc;
+            self.read_code(m, pc);
+            self.read_code(m, pc);
             let pch = (self.reg.pc >> 8) as u8;
             let pcl = (self.reg.pc & 0xff) as u8;
             self.push(m, pch);
             self.push(m, pcl);
             let status = self.reg.status;
             self.push(m, status);
+            // An NMI that comes while an IRQ is pushing the registers hijacks
+            // it, so the NMI vector is used and the NMI is not taken again
+            if pcl_addr == 0xfffe {
+                if let Some(ref mut cycles) = self.cycles {
+                    if cycles.nmi_seen {
+                        cycles.nmi_seen = false;
+                        pcl_addr = 0xfffa;
+                        pch_addr = 0xfffb;
+                    }
+                }
+            }
+            set_flag(&mut self.reg.status, StatusFlag::InterruptDisable, true);
+            m.log_event(if pcl_addr == 0xfffa { EventKind::Nmi } else { EventKind::Irq }, 0, 0);
         }
-        let pch = m.read_mem(pch_addr) as u16;
-        let pcl = m.read_mem(pcl_addr) as u16;
+        let pch = self.read(m, pch_addr) as u16;
+        let pcl = self.read(m, pcl_addr) as u16;
         let new_pc = (pch << 8) + pcl;
+        if write_to_stack {
+            log::trace!(target: "cpu", "{} from ${:04X} to ${:04X}",
+                        if pcl_addr == 0xfffa { "NMI" } else { "IRQ" }, self.reg.pc, new_pc);
+        }
         self.reg.pc = new_pc;
     }
 
@@ -119,6 +426,55 @@ impl Cpu {
         ((self.get_op(m, 2) as u16) << 8) + self.get_op(m, 1) as u16
     }
 
+    // Memory access by the executing instruction
+    fn read(&mut self, m: &mut Machine, address: u16) -> u8 {
+        let value = m.read_mem(address);
+        self.tick(m);
+        value
+    }
+
+    fn read_code(&mut self, m: &mut Machine, address: u16) -> u8 {
+        let value = m.read_code(address);
+        self.tick(m);
+        value
+    }
+
+    fn write(&mut self, m: &mut Machine, address: u16, value: u8) {
+        m.write_mem(address, value);
+        self.tick(m);
+    }
+
+    // Operand of the executing instruction. The cycle accurate mode reads
+    // each operand only once.
+    fn fetch_op(&mut self, m: &mut Machine, op_index: u8) -> u8 {
+        let index = op_index as usize - 1;
+        if let Some(value) = self.cycles.as_ref().and_then(|c| c.operands[index]) {
+            return value;
+        }
+        let value = self.read_code(m, self.reg.pc + op_index as u16);
+        if let Some(ref mut cycles) = self.cycles {
+            cycles.operands[index] = Some(value);
+        }
+        value
+    }
+
+    fn fetch_op_u16(&mut self, m: &mut Machine) -> u16 {
+        ((self.fetch_op(m, 2) as u16) << 8) + self.fetch_op(m, 1) as u16
+    }
+
+    // Steps the machine one cycle in the cycle accurate mode, remembering the
+    // interrupt lines as they were before the cycle
+    fn tick(&mut self, m: &mut Machine) {
+        if let Some(ref mut cycles) = self.cycles {
+            let (nmi_triggered, irq_triggered) = m.step_cycle(1);
+            cycles.done += 1;
+            cycles.nmi_seen_before_last = cycles.nmi_seen;
+            cycles.nmi_seen |= nmi_triggered;
+            cycles.irq_line_before_last = cycles.irq_line;
+            cycles.irq_line = irq_triggered;
+        }
+    }
+
     fn decode_instruction(&self, m: &mut Machine) -> (String, usize) {
         m.ppu.mem_read_mut_enabled = false;
         m.controller.mem_read_mut_enabled = false;
@@ -156,14 +512,14 @@ impl Cpu {
             },
             AddressingMode::Relative => {
                 disass_str += &format!(" ${:04X}",
-                                       (self.reg.pc as i16 + 2 +
-                                        (self.get_op(m, 1) as i8) as i16) as u16);
+                                       self.reg.pc.wrapping_add(2)
+                                       .wrapping_add((self.get_op(m, 1) as i8) as u16));
             }
             AddressingMode::Absolute => {
                 let address = self.get_op_u16(m);
                 disass_str += &format!(" ${:04X}", address);
                 if instr.mnemonic != "JMP" && instr.mnemonic != "JSR" {
-                    disass_str += &format!(" = {:02X}", m.read_mem(address));
+                    disass_str += &format!(" = {:02X}", operand_value(m, address));
                 }
             },
             AddressingMode::ZeroPage => {
@@ -189,14 +545,14 @@ impl Cpu {
             AddressingMode::AbsoluteX => {
                 let address = self.get_op_u16(m);
                 let indirect_address = address.wrapping_add(self.reg.x as u16);
-                let value = m.read_mem(indirect_address);
+                let value = operand_value(m, indirect_address);
                 disass_str += &format!(" ${:04X},X @ {:04X} = {:02X}",
                                        address, indirect_address, value);
             }
             AddressingMode::AbsoluteY => {
                 let address = self.get_op_u16(m);
                 let indirect_address = address.wrapping_add(self.reg.y as u16);
-                let value = m.read_mem(indirect_address);
+                let value = operand_value(m, indirect_address);
                 disass_str += &format!(" ${:04X},Y @ {:04X} = {:02X}",
                                        address, indirect_address, value);
             }
@@ -213,7 +569,7 @@ impl Cpu {
                 let indirect_address_low = m.read_mem((address + x) & 0xff) as u16;
                 let indirect_address_high = m.read_mem((address + x + 1) & 0xff) as u16;
                 let indirect_address = (indirect_address_high << 8) + indirect_address_low;
-                let value = m.read_mem(indirect_address);
+                let value = operand_value(m, indirect_address);
                 disass_str += &format!(" (${:02X},X) @ {:02X} = {:04X} = {:02X}",
                                        address, (address + x) & 0xff, indirect_address, value);
             }
@@ -223,7 +579,7 @@ impl Cpu {
                 let indirect_address_high = m.read_mem((address + 1) & 0xff) as u16;
                 let indirect_address = (indirect_address_high << 8) + indirect_address_low;
                 let final_address = indirect_address.wrapping_add(self.reg.y as u16);
-                let value = m.read_mem(final_address);
+                let value = operand_value(m, final_address);
                 disass_str += &format!(" (${:02X}),Y = {:04X} @ {:04X} = {:02X}",
                                        address, indirect_address, final_address, value);
             }
@@ -236,24 +592,30 @@ impl Cpu {
 
     fn push(&mut self, m: &mut Machine, value: u8) {
         let address = 0x100 + self.reg.sp as u16;
-        m.write_mem(address, value);
-        self.reg.sp -= 1;
+        self.write(m, address, value);
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
     }
 
     fn pop(&mut self, m: &mut Machine) -> u8 {
-        self.reg.sp += 1;
-        m.read_mem(0x100 + self.reg.sp as u16)
+        self.reg.sp = self.reg.sp.wrapping_add(1);
+        self.read(m, 0x100 + self.reg.sp as u16)
     }
 
     fn branch_immediate(&mut self, m: &mut Machine) {
-        let offset = self.get_op(m, 1) as i8;
+        let offset = self.fetch_op(m, 1) as i8;
         self.reg.pc += 2;
         let old_pc = self.reg.pc;
-        self.reg.pc = (self.reg.pc as i16 + offset as i16) as u16;
+        self.reg.pc = self.reg.pc.wrapping_add(offset as u16);
         self.step_cycle(m, 1);
         if (old_pc & 0xFF00) != (self.reg.pc & 0xFF00) {
             self.step_cycle(m, 1);
         }
+        else if let Some(ref mut cycles) = self.cycles {
+            // The interrupts were polled after the first cycle, so one that
+            // comes during the rest of the branch waits for one more
+            // instruction
+            cycles.early_poll = Some((cycles.nmi_seen_before_last, cycles.irq_line_before_last));
+        }
     }
 
     fn update_zero_negative(status: &mut u8, value: u8) {
@@ -272,6 +634,7 @@ impl Cpu {
             };
 
             add(0x01, "ORA", AddressingMode::IndirectX);
+            add(0x02, "*KIL", AddressingMode::Implied);
             add(0x03, "*SLO", AddressingMode::IndirectX);
             add(0x04, "*NOP", AddressingMode::ZeroPage);
             add(0x05, "ORA", AddressingMode::ZeroPage);
@@ -286,6 +649,7 @@ impl Cpu {
             add(0x0F, "*SLO", AddressingMode::Absolute);
             add(0x10, "BPL", AddressingMode::Relative);
             add(0x11, "ORA", AddressingMode::IndirectY);
+            add(0x12, "*KIL", AddressingMode::Implied);
             add(0x13, "*SLO", AddressingMode::IndirectY);
             add(0x14, "*NOP", AddressingMode::ZeroPageX);
             add(0x15, "ORA", AddressingMode::ZeroPageX);
@@ -301,6 +665,7 @@ impl Cpu {
             add(0x1F, "*SLO", AddressingMode::AbsoluteX);
             add(0x20, "JSR", AddressingMode::Absolute);
             add(0x21, "AND", AddressingMode::IndirectX);
+            add(0x22, "*KIL", AddressingMode::Implied);
             add(0x23, "*RLA", AddressingMode::IndirectX);
             add(0x25, "AND", AddressingMode::ZeroPage);
             add(0x27, "*RLA", AddressingMode::ZeroPage);
@@ -315,6 +680,7 @@ impl Cpu {
             add(0x2F, "*RLA", AddressingMode::Absolute);
             add(0x30, "BMI", AddressingMode::Relative);
             add(0x31, "AND", AddressingMode::IndirectY);
+            add(0x32, "*KIL", AddressingMode::Implied);
             add(0x33, "*RLA", AddressingMode::IndirectY);
             add(0x34, "*NOP", AddressingMode::ZeroPageX);
             add(0x35, "AND", AddressingMode::ZeroPageX);
@@ -330,6 +696,7 @@ impl Cpu {
             add(0x3F, "*RLA", AddressingMode::AbsoluteX);
             add(0x40, "RTI", AddressingMode::Implied);
             add(0x41, "EOR", AddressingMode::IndirectX);
+            add(0x42, "*KIL", AddressingMode::Implied);
             add(0x43, "*SRE", AddressingMode::IndirectX);
             add(0x44, "*NOP", AddressingMode::ZeroPage);
             add(0x45, "EOR", AddressingMode::ZeroPage);
@@ -344,11 +711,13 @@ impl Cpu {
             add(0x4F, "*SRE", AddressingMode::Absolute);
             add(0x50, "BVC", AddressingMode::Relative);
             add(0x51, "EOR", AddressingMode::IndirectY);
+            add(0x52, "*KIL", AddressingMode::Implied);
             add(0x53, "*SRE", AddressingMode::IndirectY);
             add(0x54, "*NOP", AddressingMode::ZeroPageX);
             add(0x55, "EOR", AddressingMode::ZeroPageX);
             add(0x56, "LSR", AddressingMode::ZeroPageX);
             add(0x57, "*SRE", AddressingMode::ZeroPageX);
+            add(0x58, "CLI", AddressingMode::Implied);
             add(0x59, "EOR", AddressingMode::AbsoluteY);
             add(0x5A, "*NOP", AddressingMode::Implied);
             add(0x5B, "*SRE", AddressingMode::AbsoluteY);
@@ -358,6 +727,7 @@ impl Cpu {
             add(0x5F, "*SRE", AddressingMode::AbsoluteX);
             add(0x60, "RTS", AddressingMode::Implied);
             add(0x61, "ADC", AddressingMode::IndirectX);
+            add(0x62, "*KIL", AddressingMode::Implied);
             add(0x63, "*RRA", AddressingMode::IndirectX);
             add(0x64, "*NOP", AddressingMode::ZeroPage);
             add(0x65, "ADC", AddressingMode::ZeroPage);
@@ -372,6 +742,7 @@ impl Cpu {
             add(0x6F, "*RRA", AddressingMode::Absolute);
             add(0x70, "BVS", AddressingMode::Relative);
             add(0x71, "ADC", AddressingMode::IndirectY);
+            add(0x72, "*KIL", AddressingMode::Implied);
             add(0x73, "*RRA", AddressingMode::IndirectY);
             add(0x74, "*NOP", AddressingMode::ZeroPageX);
             add(0x75, "ADC", AddressingMode::ZeroPageX);
@@ -400,6 +771,7 @@ impl Cpu {
             add(0x8F, "*SAX", AddressingMode::Absolute);
             add(0x90, "BCC", AddressingMode::Relative);
             add(0x91, "STA", AddressingMode::IndirectY);
+            add(0x92, "*KIL", AddressingMode::Implied);
             add(0x94, "STY", AddressingMode::ZeroPageX);
             add(0x95, "STA", AddressingMode::ZeroPageX);
             add(0x96, "STX", AddressingMode::ZeroPageY);
@@ -425,6 +797,7 @@ impl Cpu {
             add(0xAF, "*LAX", AddressingMode::Absolute);
             add(0xB0, "BCS", AddressingMode::Relative);
             add(0xB1, "LDA", AddressingMode::IndirectY);
+            add(0xB2, "*KIL", AddressingMode::Implied);
             add(0xB3, "*LAX", AddressingMode::IndirectY);
             add(0xB4, "LDY", AddressingMode::ZeroPageX);
             add(0xB5, "LDA", AddressingMode::ZeroPageX);
@@ -453,6 +826,7 @@ impl Cpu {
             add(0xCF, "*DCP", AddressingMode::Absolute);
             add(0xD0, "BNE", AddressingMode::Relative);
             add(0xD1, "CMP", AddressingMode::IndirectY);
+            add(0xD2, "*KIL", AddressingMode::Implied);
             add(0xD3, "*DCP", AddressingMode::IndirectY);
             add(0xD4, "*NOP", AddressingMode::ZeroPageX);
             add(0xD5, "CMP", AddressingMode::ZeroPageX);
@@ -483,6 +857,7 @@ impl Cpu {
             add(0xEF, "*ISB", AddressingMode::Absolute);
             add(0xF0, "BEQ", AddressingMode::Relative);
             add(0xF1, "SBC", AddressingMode::IndirectY);
+            add(0xF2, "*KIL", AddressingMode::Implied);
             add(0xF3, "*ISB", AddressingMode::IndirectY);
             add(0xF4, "*NOP", AddressingMode::ZeroPageX);
             add(0xF5, "SBC", AddressingMode::ZeroPageX);
@@ -500,64 +875,96 @@ impl Cpu {
         instructions
     }
 
-    fn get_address(&self, m: &mut Machine, addr_mode: AddressingMode) -> (u16, u16) {
+    fn get_address(&mut self, m: &mut Machine, addr_mode: AddressingMode)
+                   -> Result<(u16, u16), EmulationError> {
+        if let Some(address) = self.access.address {
+            return Ok(address);
+        }
+        let address = self.compute_address(m, addr_mode)?;
+        self.perform_dummy_read(m, addr_mode, address);
+        self.access.address = Some(address);
+        Ok(address)
+    }
+
+    // The indexed addressing modes read the base address, or the address
+    // before the page crossing has been fixed, while adding the index
+    fn perform_dummy_read(&mut self, m: &mut Machine, addr_mode: AddressingMode,
+                          (address, oops): (u16, u16)) {
+        let indexed_write = self.access.indexed_write;
         match addr_mode {
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY | AddressingMode::IndirectX => {
+                let base = self.fetch_op(m, 1) as u16;
+                self.read(m, base);
+            }
+            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY |
+            AddressingMode::IndirectY if oops != 0 || indexed_write => {
+                let unfixed_address = if oops != 0 { address.wrapping_sub(0x100) } else { address };
+                self.read(m, unfixed_address);
+            }
+            _ => {}
+        }
+    }
+
+    fn compute_address(&mut self, m: &mut Machine, addr_mode: AddressingMode)
+                       -> Result<(u16, u16), EmulationError> {
+        Ok(match addr_mode {
             AddressingMode::ZeroPage => {
-                (self.get_op(m, 1) as u16, 0)
+                (self.fetch_op(m, 1) as u16, 0)
             }
             AddressingMode::ZeroPageX => {
-                (self.get_op(m, 1).wrapping_add(self.reg.x) as u16, 0)
+                (self.fetch_op(m, 1).wrapping_add(self.reg.x) as u16, 0)
             }
             AddressingMode::ZeroPageY => {
-                (self.get_op(m, 1).wrapping_add(self.reg.y) as u16, 0)
+                (self.fetch_op(m, 1).wrapping_add(self.reg.y) as u16, 0)
             }
             AddressingMode::Absolute => {
-                (self.get_op_u16(m), 0)
+                (self.fetch_op_u16(m), 0)
             }
             AddressingMode::AbsoluteX => {
-                let address = self.get_op_u16(m);
+                let address = self.fetch_op_u16(m);
                 let oops = (address & 0xFF) + self.reg.x as u16 > 255;
                 (address.wrapping_add(self.reg.x as u16), if oops {1} else {0})
             }
             AddressingMode::AbsoluteY => {
-                let address = self.get_op_u16(m);
+                let address = self.fetch_op_u16(m);
                 let oops = (address & 0xFF) + self.reg.y as u16 > 255;
                 (address.wrapping_add(self.reg.y as u16), if oops {1} else {0})
             }
             AddressingMode::Indirect => {
-                let address = self.get_op_u16(m);
-                let indirect_address_low = m.read_mem(address) as u16;
+                let address = self.fetch_op_u16(m);
+                let indirect_address_low = self.read(m, address) as u16;
                 let indirect_address_high = if (address & 0xFF) == 0xFF {
-                    m.read_mem(address + 1 - 0x100) as u16
+                    self.read(m, address + 1 - 0x100) as u16
                 }
                 else {
-                    m.read_mem(address + 1) as u16
+                    self.read(m, address + 1) as u16
                 };
                 let indirect_address = (indirect_address_high << 8) + indirect_address_low;
                 (indirect_address, 0)
             }
             AddressingMode::IndirectX => {
-                let address = self.get_op(m, 1) as u16 + self.reg.x as u16;
-                let indirect_address_low = m.read_mem(address & 0xff) as u16;
-                let indirect_address_high = m.read_mem((address + 1) & 0xff) as u16;
+                let address = self.fetch_op(m, 1) as u16 + self.reg.x as u16;
+                let indirect_address_low = self.read(m, address & 0xff) as u16;
+                let indirect_address_high = self.read(m, (address + 1) & 0xff) as u16;
                 let indirect_address = (indirect_address_high << 8) + indirect_address_low;
                 (indirect_address, 0)
             }
             AddressingMode::IndirectY => {
-                let address = self.get_op(m, 1) as u16;
-                let indirect_address_low = m.read_mem(address) as u16;
-                let indirect_address_high = m.read_mem((address + 1) & 0xff) as u16;
+                let address = self.fetch_op(m, 1) as u16;
+                let indirect_address_low = self.read(m, address) as u16;
+                let indirect_address_high = self.read(m, (address + 1) & 0xff) as u16;
                 let indirect_address = (indirect_address_high << 8) + indirect_address_low;
                 let final_address = indirect_address.wrapping_add(self.reg.y as u16);
                 let oops = (self.reg.y as u16).wrapping_add(indirect_address & 0xFF) > 255;
                 (final_address, if oops {1} else {0})
             }
-            _ => { panic!("Unsupported addressing mode"); }
-        }
+            _ => { return Err(self.addressing_mode_error()); }
+        })
     }
 
-    fn get_byte(&self, m: &mut Machine, addr_mode: AddressingMode) -> (u8, u16) {
-        match addr_mode {
+    fn get_byte(&mut self, m: &mut Machine, addr_mode: AddressingMode)
+                -> Result<(u8, u16), EmulationError> {
+        Ok(match addr_mode {
             AddressingMode::Implied => {
                 (0, 0)
             }
@@ -565,7 +972,7 @@ impl Cpu {
                 (self.reg.a, 0)
             }
             AddressingMode::Immediate => {
-                (self.get_op(m, 1), 0)
+                (self.fetch_op(m, 1), 0)
             }
             AddressingMode::Absolute |
             AddressingMode::ZeroPage |
@@ -575,14 +982,17 @@ impl Cpu {
             AddressingMode::AbsoluteY |
             AddressingMode::IndirectX |
             AddressingMode::IndirectY => {
-                let (address, oops) = self.get_address(m, addr_mode);
-                (m.read_mem(address), oops)
+                let (address, oops) = self.get_address(m, addr_mode)?;
+                let value = self.read(m, address);
+                self.access.rmw_value = value;
+                (value, oops)
             }
-            _ => { panic!("Unsupported addressing mode"); }
-        }
+            _ => { return Err(self.addressing_mode_error()); }
+        })
     }
 
-    fn set_byte(&mut self, m: &mut Machine, addr_mode: AddressingMode, value: u8) {
+    fn set_byte(&mut self, m: &mut Machine, addr_mode: AddressingMode, value: u8)
+                -> Result<(), EmulationError> {
         match addr_mode {
             AddressingMode::Accumulator => {
                 self.reg.a = value;
@@ -595,11 +1005,15 @@ impl Cpu {
             AddressingMode::ZeroPageY |
             AddressingMode::IndirectX |
             AddressingMode::IndirectY => {
-                let (address, _) = self.get_address(m, addr_mode);
-                m.write_mem(address, value);
+                let (address, _) = self.get_address(m, addr_mode)?;
+                // Read-modify-write instructions write the unmodified value first
+                let old_value = self.access.rmw_value;
+                self.write(m, address, old_value);
+                self.write(m, address, value);
             }
-            _ => { panic!("Unsupported addressing mode"); }
+            _ => { return Err(self.addressing_mode_error()); }
         }
+        Ok(())
     }
 
     fn step_pc_and_cycle(&mut self, m: &mut Machine, counts: (u16, u16)) {
@@ -609,6 +1023,10 @@ impl Cpu {
     }
 
     fn step_cycle(&mut self, m: &mut Machine, count: u16) {
+        if let Some(ref mut cycles) = self.cycles {
+            cycles.requested += count;
+            return;
+        }
         let (nmi_triggered, irq_triggered) = m.step_cycle(count);
         self.nmi_triggered = nmi_triggered;
         self.irq_triggered = irq_triggered;
@@ -640,28 +1058,122 @@ impl Cpu {
         Cpu::update_zero_negative(&mut self.reg.status, self.reg.a);
     }
 
+    // Panics on the errors that try_execute returns
     pub fn execute(&mut self, m: &mut Machine) {
+        if let Err(e) = self.try_execute(m) {
+            panic!("{}", e);
+        }
+    }
+
+    // Executes an instruction, or an interrupt. On an error, the CPU stops
+    // before the instruction and keeps returning the error until it is reset
+    // or a state is loaded.
+    pub fn try_execute(&mut self, m: &mut Machine) -> Result<(), EmulationError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        if self.jammed {
+            // Not even interrupts are taken
+            m.step_cycle(1);
+            return Ok(());
+        }
+        self.instruction_count += 1;
+        if self.cycles.is_some() {
+            return self.execute_cycle_accurate(m);
+        }
         if self.nmi_triggered {
             self.nmi_triggered = false;
             self.perform_interrupt(m, 0xfffa, 0xfffb, true);
         }
         else if self.irq_triggered {
             self.irq_triggered = false;
-            if !self.get_status_flag(StatusFlag::InterruptDisable) {
-                self.perform_interrupt(m, 0xfffe, 0xffff, true);
-            }
+            self.perform_interrupt(m, 0xfffe, 0xffff, true);
+        }
+        else {
+            return self.execute_instruction(m);
+        }
+        Ok(())
+    }
+
+    pub fn error(&self) -> Option<EmulationError> {
+        self.error
+    }
+
+    pub fn is_jammed(&self) -> bool {
+        self.jammed
+    }
+
+    // Stops the CPU on the instruction at PC
+    fn stop(&mut self, op_code: u8) -> EmulationError {
+        let error = EmulationError::UnknownOpcode { pc: self.reg.pc, op_code };
+        self.error = Some(error);
+        error
+    }
+
+    // For an instruction whose addressing mode its code does not handle
+    fn addressing_mode_error(&self) -> EmulationError {
+        EmulationError::UnexpectedAddressingMode { pc: self.reg.pc, op_code: self.access.op_code }
+    }
+
+    fn execute_cycle_accurate(&mut self, m: &mut Machine) -> Result<(), EmulationError> {
+        if let Some(ref mut cycles) = self.cycles {
+            // An NMI seen on the last cycle of the previous instruction is kept
+            let nmi_seen = cycles.nmi_seen;
+            *cycles = CycleState { nmi_seen, ..CycleState::default() };
+        }
+        let mut result = Ok(());
+        if self.nmi_triggered {
+            self.perform_interrupt(m, 0xfffa, 0xfffb, true);
+            self.step_cycle(m, 7);
+            self.poll_interrupt_disable(true);
+        }
+        else if self.irq_triggered {
+            self.perform_interrupt(m, 0xfffe, 0xffff, true);
+            self.step_cycle(m, 7);
+            self.poll_interrupt_disable(true);
         }
         else {
-            self.execute_instruction(m);
+            result = self.execute_instruction(m);
+        }
+        while self.cycles.as_ref().is_some_and(|c| c.done < c.requested) {
+            self.tick(m);
         }
+        if let Some(ref mut cycles) = self.cycles {
+            let (nmi, irq) = cycles.early_poll
+                .unwrap_or((cycles.nmi_seen_before_last, cycles.irq_line_before_last));
+            self.nmi_triggered = nmi;
+            self.irq_triggered = irq && !cycles.interrupt_disable;
+            if self.nmi_triggered {
+                cycles.nmi_seen = false;
+            }
+        }
+        result
     }
 
-    fn execute_instruction(&mut self, sys: &mut Machine) {
-        let op_code = sys.read_mem(self.reg.pc);
-        let addr_mode = self.instructions.get(&op_code).unwrap().addressing_mode.clone();
+    // The CPU stops on an error, see try_execute
+    fn execute_instruction(&mut self, sys: &mut Machine) -> Result<(), EmulationError> {
+        let result = self.execute_op_code(sys);
+        if let Err(error) = result {
+            self.error = Some(error);
+        }
+        result
+    }
+
+    fn execute_op_code(&mut self, sys: &mut Machine) -> Result<(), EmulationError> {
+        if self.history_size > 0 {
+            self.record_history(sys);
+        }
+        let op_code = self.read_code(sys, self.reg.pc);
+        let instruction = match self.instructions.get(&op_code) {
+            Some(instruction) => instruction,
+            None => { return Err(self.stop(op_code)); }
+        };
+        let addr_mode = instruction.addressing_mode;
+        self.access = AccessState { indexed_write: instruction.writes, op_code, ..AccessState::default() };
+        let mut interrupt_disable = self.get_status_flag(StatusFlag::InterruptDisable);
         match op_code {
             0x01 | 0x05 | 0x09 | 0x0D | 0x11 | 0x15 | 0x19 | 0x1D => { // ORA
-                let (value, oops) = self.get_byte(sys, addr_mode);
+                let (value, oops) = self.get_byte(sys, addr_mode)?;
                 self.reg.a = self.reg.a | value;
                 Cpu::update_zero_negative(&mut self.reg.status, self.reg.a);
                 self.step_pc_and_cycle(sys, match addr_mode {
@@ -673,42 +1185,42 @@ impl Cpu {
                     AddressingMode::AbsoluteY => (3, 4 + oops),
                     AddressingMode::IndirectX => (2, 6),
                     AddressingMode::IndirectY => (2, 5 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0x03 | 0x07 | 0x0F | 0x13 | 0x17 | 0x1B | 0x1F => { // *SLO
-                let (mut value, oops) = self.get_byte(sys, addr_mode);
+                let mut value = self.get_byte(sys, addr_mode)?.0;
                 let carry = value & 0x80 != 0;
                 value <<= 1;
                 set_flag(&mut self.reg.status, StatusFlag::Carry, carry);
                 self.reg.a = self.reg.a | value;
                 Cpu::update_zero_negative(&mut self.reg.status, self.reg.a);
-                self.set_byte(sys, addr_mode, value);
+                self.set_byte(sys, addr_mode, value)?;
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::ZeroPage => (2, 5),
                     AddressingMode::ZeroPageX => (2, 6),
                     AddressingMode::Absolute => (3, 6),
                     AddressingMode::AbsoluteX |
-                    AddressingMode::AbsoluteY => (3, 6 + oops),
+                    AddressingMode::AbsoluteY => (3, 7),
                     AddressingMode::IndirectX => (2, 8),
-                    AddressingMode::IndirectY => (2, 7 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    AddressingMode::IndirectY => (2, 8),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0x06 | 0x0A | 0x0E | 0x16 | 0x1E => { // ASL
-                let mut value = self.get_byte(sys, addr_mode).0;
+                let mut value = self.get_byte(sys, addr_mode)?.0;
                 let carry = value & 0x80 != 0;
                 value <<= 1;
                 set_flag(&mut self.reg.status, StatusFlag::Carry, carry);
                 Cpu::update_zero_negative(&mut self.reg.status, value);
-                self.set_byte(sys, addr_mode, value);
+                self.set_byte(sys, addr_mode, value)?;
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::Accumulator => (1, 2),
                     AddressingMode::ZeroPage => (2, 5),
                     AddressingMode::ZeroPageX => (2, 6),
                     AddressingMode::Absolute => (3, 6),
                     AddressingMode::AbsoluteX => (3, 7),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0x08 => { // PHP
@@ -736,12 +1248,12 @@ impl Cpu {
                 self.push(sys, (return_addr >> 8) as u8);
                 self.push(sys, (return_addr & 0xFF) as u8);
                 let new_pc =
-                    self.get_op(sys, 2) as u16 * 256 + self.get_op(sys, 1) as u16;
+                    self.fetch_op(sys, 2) as u16 * 256 + self.fetch_op(sys, 1) as u16;
                 self.reg.pc = new_pc;
                 self.step_cycle(sys, 6);
             }
             0x24 | 0x2C => { // BIT
-                let value = self.get_byte(sys, addr_mode).0;
+                let value = self.get_byte(sys, addr_mode)?.0;
                 let mask = self.reg.a & value;
                 set_flag(&mut self.reg.status, StatusFlag::Zero, mask == 0);
                 set_flag(&mut self.reg.status, StatusFlag::Overflow, value & 0x40 != 0);
@@ -749,7 +1261,7 @@ impl Cpu {
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::ZeroPage => (2, 3),
                     AddressingMode::Absolute => (3, 4),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0x28 => { // PLP
@@ -761,7 +1273,7 @@ impl Cpu {
                 self.step_cycle(sys, 4);
             }
             0x21 | 0x25 | 0x29 | 0x2D | 0x31 | 0x35 | 0x39 | 0x3D => { // AND
-                let (value, oops) = self.get_byte(sys, addr_mode);
+                let (value, oops) = self.get_byte(sys, addr_mode)?;
                 self.reg.a = self.reg.a & value;
                 Cpu::update_zero_negative(&mut self.reg.status, self.reg.a);
                 self.step_pc_and_cycle(sys, match addr_mode {
@@ -773,18 +1285,18 @@ impl Cpu {
                     AddressingMode::AbsoluteY => (3, 4 + oops),
                     AddressingMode::IndirectX => (2, 6),
                     AddressingMode::IndirectY => (2, 5 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0x23 | 0x27 | 0x2F | 0x33 | 0x37 | 0x3B | 0x3F => { // *RLA
-                let (mut value, oops) = self.get_byte(sys, addr_mode);
+                let mut value = self.get_byte(sys, addr_mode)?.0;
                 let new_carry = value & 0x80 != 0;
                 value <<= 1;
                 if self.get_status_flag(StatusFlag::Carry) {
                     value |= 0x01;
                 }
                 set_flag(&mut self.reg.status, StatusFlag::Carry, new_carry);
-                self.set_byte(sys, addr_mode, value);
+                self.set_byte(sys, addr_mode, value)?;
                 self.reg.a = self.reg.a & value;
                 Cpu::update_zero_negative(&mut self.reg.status, self.reg.a);
                 self.step_pc_and_cycle(sys, match addr_mode {
@@ -792,14 +1304,14 @@ impl Cpu {
                     AddressingMode::ZeroPageX => (2, 6),
                     AddressingMode::Absolute => (3, 6),
                     AddressingMode::AbsoluteX |
-                    AddressingMode::AbsoluteY => (3, 6 + oops),
+                    AddressingMode::AbsoluteY => (3, 7),
                     AddressingMode::IndirectX => (2, 8),
-                    AddressingMode::IndirectY => (2, 7 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    AddressingMode::IndirectY => (2, 8),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0x26 | 0x2A | 0x2E | 0x36 | 0x3E => { // ROL
-                let mut value = self.get_byte(sys, addr_mode).0;
+                let mut value = self.get_byte(sys, addr_mode)?.0;
                 let new_carry = value & 0x80 != 0;
                 value <<= 1;
                 if self.get_status_flag(StatusFlag::Carry) {
@@ -807,14 +1319,14 @@ impl Cpu {
                 }
                 set_flag(&mut self.reg.status, StatusFlag::Carry, new_carry);
                 Cpu::update_zero_negative(&mut self.reg.status, value);
-                self.set_byte(sys, addr_mode, value);
+                self.set_byte(sys, addr_mode, value)?;
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::Accumulator => (1, 2),
                     AddressingMode::ZeroPage => (2, 5),
                     AddressingMode::ZeroPageX => (2, 6),
                     AddressingMode::Absolute => (3, 6),
                     AddressingMode::AbsoluteX => (3, 7),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0x30 => { // BMI
@@ -848,16 +1360,16 @@ impl Cpu {
                 self.step_cycle(sys, 3);
             }
             0x4C | 0x6C => { // JMP
-                let new_pc = self.get_address(sys, addr_mode).0;
+                let new_pc = self.get_address(sys, addr_mode)?.0;
                 self.reg.pc = new_pc;
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::Absolute => (0, 3),
                     AddressingMode::Indirect => (0, 5),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     })
             }
             0x41 | 0x45 | 0x49 | 0x4D | 0x51 | 0x55 | 0x59 | 0x5D => { // EOR
-                let (value, oops) = self.get_byte(sys, addr_mode);
+                let (value, oops) = self.get_byte(sys, addr_mode)?;
                 self.reg.a = self.reg.a ^ value;
                 Cpu::update_zero_negative(&mut self.reg.status, self.reg.a);
                 self.step_pc_and_cycle(sys, match addr_mode {
@@ -869,15 +1381,15 @@ impl Cpu {
                     AddressingMode::AbsoluteY => (3, 4 + oops),
                     AddressingMode::IndirectX => (2, 6),
                     AddressingMode::IndirectY => (2, 5 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0x43 | 0x47 | 0x4F | 0x53 | 0x57 | 0x5B | 0x5F => { // *SRE
-                let (mut value, oops) = self.get_byte(sys, addr_mode);
+                let mut value = self.get_byte(sys, addr_mode)?.0;
                 let carry = value & 0x01 != 0;
                 value >>= 1;
                 set_flag(&mut self.reg.status, StatusFlag::Carry, carry);
-                self.set_byte(sys, addr_mode, value);
+                self.set_byte(sys, addr_mode, value)?;
                 self.reg.a = self.reg.a ^ value;
                 Cpu::update_zero_negative(&mut self.reg.status, self.reg.a);
                 self.step_pc_and_cycle(sys, match addr_mode {
@@ -885,26 +1397,26 @@ impl Cpu {
                     AddressingMode::ZeroPageX => (2, 6),
                     AddressingMode::Absolute => (3, 6),
                     AddressingMode::AbsoluteX |
-                    AddressingMode::AbsoluteY => (3, 6 + oops),
+                    AddressingMode::AbsoluteY => (3, 7),
                     AddressingMode::IndirectX => (2, 8),
-                    AddressingMode::IndirectY => (2, 7 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    AddressingMode::IndirectY => (2, 8),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0x46 | 0x4A | 0x4E | 0x56 | 0x5E => { // LSR
-                let mut value = self.get_byte(sys, addr_mode).0;
+                let mut value = self.get_byte(sys, addr_mode)?.0;
                 let carry = value & 0x01 != 0;
                 value >>= 1;
                 set_flag(&mut self.reg.status, StatusFlag::Carry, carry);
                 Cpu::update_zero_negative(&mut self.reg.status, value);
-                self.set_byte(sys, addr_mode, value);
+                self.set_byte(sys, addr_mode, value)?;
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::Accumulator => (1, 2),
                     AddressingMode::ZeroPage => (2, 5),
                     AddressingMode::ZeroPageX => (2, 6),
                     AddressingMode::Absolute => (3, 6),
                     AddressingMode::AbsoluteX => (3, 7),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     })
             }
             0x50 => { // BVC
@@ -916,6 +1428,11 @@ impl Cpu {
                 }
                 self.step_cycle(sys, 2);
             }
+            0x58 => { // CLI
+                set_flag(&mut self.reg.status, StatusFlag::InterruptDisable, false);
+                self.reg.pc += 1;
+                self.step_cycle(sys, 2);
+            }
             0x60 => { // RTS
                 let low = self.pop(sys) as u16;
                 let high = self.pop(sys) as u16;
@@ -931,7 +1448,7 @@ impl Cpu {
             }
             0x61 | 0x65 | 0x69 | 0x6D | 0x71 | 0x75 | 0x79 | 0x7D => { // ADC
                 let a = self.reg.a;
-                let (m, oops) = self.get_byte(sys, addr_mode);
+                let (m, oops) = self.get_byte(sys, addr_mode)?;
                 self.compute_adc(a, m);
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::Immediate => (2, 2),
@@ -942,18 +1459,18 @@ impl Cpu {
                     AddressingMode::AbsoluteY => (3, 4 + oops),
                     AddressingMode::IndirectX => (2, 6),
                     AddressingMode::IndirectY => (2, 5 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0x63 | 0x67 | 0x6F | 0x73 | 0x77 | 0x7B | 0x7F => { // *RRA
-                let (mut value, oops) = self.get_byte(sys, addr_mode);
+                let mut value = self.get_byte(sys, addr_mode)?.0;
                 let new_carry = value & 0x01 != 0;
                 value >>= 1;
                 if self.get_status_flag(StatusFlag::Carry) {
                     value |= 0x80;
                 }
                 set_flag(&mut self.reg.status, StatusFlag::Carry, new_carry);
-                self.set_byte(sys, addr_mode, value);
+                self.set_byte(sys, addr_mode, value)?;
                 let a = self.reg.a;
                 self.compute_adc(a, value);
                 self.step_pc_and_cycle(sys, match addr_mode {
@@ -961,14 +1478,14 @@ impl Cpu {
                     AddressingMode::ZeroPageX => (2, 6),
                     AddressingMode::Absolute => (3, 6),
                     AddressingMode::AbsoluteX |
-                    AddressingMode::AbsoluteY => (3, 6 + oops),
+                    AddressingMode::AbsoluteY => (3, 7),
                     AddressingMode::IndirectX => (2, 8),
-                    AddressingMode::IndirectY => (2, 7 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    AddressingMode::IndirectY => (2, 8),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0x66 | 0x6A | 0x6E | 0x76 | 0x7E => { // ROR
-                let mut value = self.get_byte(sys, addr_mode).0;
+                let mut value = self.get_byte(sys, addr_mode)?.0;
                 let new_carry = value & 0x01 != 0;
                 value >>= 1;
                 if self.get_status_flag(StatusFlag::Carry) {
@@ -976,14 +1493,14 @@ impl Cpu {
                 }
                 set_flag(&mut self.reg.status, StatusFlag::Carry, new_carry);
                 Cpu::update_zero_negative(&mut self.reg.status, value);
-                self.set_byte(sys, addr_mode, value);
+                self.set_byte(sys, addr_mode, value)?;
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::Accumulator => (1, 2),
                     AddressingMode::ZeroPage => (2, 5),
                     AddressingMode::ZeroPageX => (2, 6),
                     AddressingMode::Absolute => (3, 6),
                     AddressingMode::AbsoluteX => (3, 7),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0x70 => { // BVS
@@ -1001,9 +1518,9 @@ impl Cpu {
                 self.step_cycle(sys, 2);
             }
             0x81 | 0x85 | 0x8D | 0x91 | 0x95 | 0x99 | 0x9D => { // STA
-                let (addr, _) = self.get_address(sys, addr_mode);
+                let (addr, _) = self.get_address(sys, addr_mode)?;
                 let value = self.reg.a;
-                sys.write_mem(addr, value);
+                self.write(sys, addr, value);
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::ZeroPage => (2, 3),
                     AddressingMode::ZeroPageX => (2, 4),
@@ -1012,41 +1529,41 @@ impl Cpu {
                     AddressingMode::AbsoluteY => (3, 5),
                     AddressingMode::IndirectX => (2, 6),
                     AddressingMode::IndirectY => (2, 6),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     })
             }
             0x83 | 0x87 | 0x8F | 0x97 => { // *SAX
-                let (addr, _) = self.get_address(sys, addr_mode);
+                let (addr, _) = self.get_address(sys, addr_mode)?;
                 let  value = self.reg.a & self.reg.x;
-                sys.write_mem(addr, value); 
+                self.write(sys, addr, value); 
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::ZeroPage => (2, 3),
                     AddressingMode::ZeroPageY => (2, 4),
                     AddressingMode::Absolute => (3, 4),
                     AddressingMode::IndirectX => (2, 6),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     })
             }
             0x84 | 0x8C | 0x94 => { // STY
-                let (addr, _) = self.get_address(sys, addr_mode);
+                let (addr, _) = self.get_address(sys, addr_mode)?;
                 let value = self.reg.y;
-                sys.write_mem(addr, value);
+                self.write(sys, addr, value);
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::ZeroPage => (2, 3),
                     AddressingMode::ZeroPageX => (2, 4),
                     AddressingMode::Absolute => (3, 4),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     })
             }
             0x86 | 0x8E | 0x96 => { // STX
-                let (addr, _) = self.get_address(sys, addr_mode);
+                let (addr, _) = self.get_address(sys, addr_mode)?;
                 let value = self.reg.x;
-                sys.write_mem(addr, value);
+                self.write(sys, addr, value);
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::ZeroPage => (2, 3),
                     AddressingMode::ZeroPageY => (2, 4),
                     AddressingMode::Absolute => (3, 4),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     })
             }
             0x88 => { // DEY
@@ -1082,7 +1599,7 @@ impl Cpu {
                 self.step_cycle(sys, 2);
             }
             0xA0 | 0xA4 | 0xAC | 0xB4 | 0xBC => { // LDY
-                let (value, oops) = self.get_byte(sys, addr_mode);
+                let (value, oops) = self.get_byte(sys, addr_mode)?;
                 self.reg.y = value;
                 Cpu::update_zero_negative(&mut self.reg.status, self.reg.y);
                 self.step_pc_and_cycle(sys, match addr_mode {
@@ -1091,11 +1608,11 @@ impl Cpu {
                     AddressingMode::ZeroPageX => (2, 4),
                     AddressingMode::Absolute => (3, 4),
                     AddressingMode::AbsoluteX => (3, 4 + oops),
-                    _ => unreachable!(),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0xA2 | 0xA6 | 0xAE | 0xB6 | 0xBE => { // LDX
-                let (value, oops) = self.get_byte(sys, addr_mode);
+                let (value, oops) = self.get_byte(sys, addr_mode)?;
                 self.reg.x = value;
                 Cpu::update_zero_negative(&mut self.reg.status, self.reg.x);
                 self.step_pc_and_cycle(sys, match addr_mode {
@@ -1104,11 +1621,11 @@ impl Cpu {
                     AddressingMode::ZeroPageY => (2, 4),
                     AddressingMode::Absolute => (3, 4),
                     AddressingMode::AbsoluteY => (3, 4 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0xA3 | 0xA7 | 0xAF | 0xB3 | 0xB7 | 0xBF => { // *LAX
-                let (value, oops) = self.get_byte(sys, addr_mode);
+                let (value, oops) = self.get_byte(sys, addr_mode)?;
                 self.reg.a = value;
                 self.reg.x = value;
                 Cpu::update_zero_negative(&mut self.reg.status, self.reg.x);
@@ -1119,7 +1636,7 @@ impl Cpu {
                     AddressingMode::IndirectY => (2, 5 + oops),
                     AddressingMode::Absolute => (3, 4),
                     AddressingMode::AbsoluteY => (3, 4 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0xA8 => { // TAY
@@ -1129,7 +1646,7 @@ impl Cpu {
                 self.step_cycle(sys, 2);
             }
             0xA1 | 0xA5 | 0xA9 | 0xAD | 0xB1 | 0xB5 | 0xB9 | 0xBD => { // LDA
-                let (value, oops) = self.get_byte(sys, addr_mode);
+                let (value, oops) = self.get_byte(sys, addr_mode)?;
                 self.reg.a = value;
                 Cpu::update_zero_negative(&mut self.reg.status, self.reg.a);
                 self.step_pc_and_cycle(sys, match addr_mode {
@@ -1141,7 +1658,7 @@ impl Cpu {
                     AddressingMode::AbsoluteY => (3, 4 + oops),
                     AddressingMode::IndirectX => (2, 6),
                     AddressingMode::IndirectY => (2, 5 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0xAA => { // TAX
@@ -1171,7 +1688,7 @@ impl Cpu {
                 self.step_cycle(sys, 2);
             }
             0xC0 | 0xC4 | 0xCC => { // CPY
-                let m = self.get_byte(sys, addr_mode).0;
+                let m = self.get_byte(sys, addr_mode)?.0;
                 let result = self.reg.y.wrapping_sub(m);
                 set_flag(&mut self.reg.status, StatusFlag::Carry, self.reg.y >= m);
                 Cpu::update_zero_negative(&mut self.reg.status, result);
@@ -1179,7 +1696,7 @@ impl Cpu {
                     AddressingMode::Immediate => (2, 2),
                     AddressingMode::ZeroPage => (2, 3),
                     AddressingMode::Absolute => (3, 4),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0xC8 => { // INY
@@ -1189,7 +1706,7 @@ impl Cpu {
                 self.step_cycle(sys, 2);
             }
             0xC1 | 0xC5 | 0xC9 | 0xCD | 0xD1 | 0xD5 | 0xD9 | 0xDD => { // CMP
-                let (m, oops) = self.get_byte(sys, addr_mode);
+                let (m, oops) = self.get_byte(sys, addr_mode)?;
                 let result = self.reg.a.wrapping_sub(m);
                 set_flag(&mut self.reg.status, StatusFlag::Carry, self.reg.a >= m);
                 Cpu::update_zero_negative(&mut self.reg.status, result);
@@ -1202,13 +1719,13 @@ impl Cpu {
                     AddressingMode::AbsoluteY => (3, 4 + oops),
                     AddressingMode::IndirectX => (2, 6),
                     AddressingMode::IndirectY => (2, 5 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0xC3 | 0xC7 | 0xCF | 0xD3 | 0xD7 | 0xDB | 0xDF => { // *DCP
-                let (mut m, oops) = self.get_byte(sys, addr_mode);
+                let mut m = self.get_byte(sys, addr_mode)?.0;
                 m = m.wrapping_sub(1);
-                self.set_byte(sys, addr_mode, m);
+                self.set_byte(sys, addr_mode, m)?;
                 let result = self.reg.a.wrapping_sub(m);
                 set_flag(&mut self.reg.status, StatusFlag::Carry, self.reg.a >= m);
                 Cpu::update_zero_negative(&mut self.reg.status, result);
@@ -1218,23 +1735,23 @@ impl Cpu {
                     AddressingMode::ZeroPageX => (2, 6),
                     AddressingMode::Absolute => (3, 6),
                     AddressingMode::AbsoluteX |
-                    AddressingMode::AbsoluteY => (3, 6 + oops),
+                    AddressingMode::AbsoluteY => (3, 7),
                     AddressingMode::IndirectX => (2, 8),
-                    AddressingMode::IndirectY => (2, 7 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    AddressingMode::IndirectY => (2, 8),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0xC6 | 0xCE | 0xD6 | 0xDE => { // DEC
-                let mut m = self.get_byte(sys, addr_mode).0;
+                let mut m = self.get_byte(sys, addr_mode)?.0;
                 m = m.wrapping_sub(1);
-                self.set_byte(sys, addr_mode, m);
+                self.set_byte(sys, addr_mode, m)?;
                 Cpu::update_zero_negative(&mut self.reg.status, m);
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::ZeroPage => (2, 5),
                     AddressingMode::ZeroPageX => (2, 6),
                     AddressingMode::Absolute => (3, 6),
                     AddressingMode::AbsoluteX => (3, 7),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0xCA => { // DEX
@@ -1258,7 +1775,7 @@ impl Cpu {
                 self.step_cycle(sys, 2);
             }
             0xE0 | 0xE4 | 0xEC => { // CPX
-                let m = self.get_byte(sys, addr_mode).0;
+                let m = self.get_byte(sys, addr_mode)?.0;
                 let result = self.reg.x.wrapping_sub(m);
                 set_flag(&mut self.reg.status, StatusFlag::Carry, self.reg.x >= m);
                 Cpu::update_zero_negative(&mut self.reg.status, result);
@@ -1266,37 +1783,37 @@ impl Cpu {
                     AddressingMode::Immediate => (2, 2),
                     AddressingMode::ZeroPage => (2, 3),
                     AddressingMode::Absolute => (3, 4),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0xE3 | 0xE7 | 0xEF | 0xF3 | 0xF7 | 0xFB | 0xFF => { // *ISB
                 let a = self.reg.a;
-                let (mut m, oops) = self.get_byte(sys, addr_mode);
+                let mut m = self.get_byte(sys, addr_mode)?.0;
                 m = m.wrapping_add(1);
-                self.set_byte(sys, addr_mode, m);
+                self.set_byte(sys, addr_mode, m)?;
                 self.compute_sbc(a, m);
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::ZeroPage => (2, 5),
                     AddressingMode::ZeroPageX => (2, 6),
                     AddressingMode::IndirectX => (2, 8),
-                    AddressingMode::IndirectY => (2, 7 + oops),
+                    AddressingMode::IndirectY => (2, 8),
                     AddressingMode::Absolute => (3, 6),
                     AddressingMode::AbsoluteX |
-                    AddressingMode::AbsoluteY => (3, 6 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    AddressingMode::AbsoluteY => (3, 7),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0xE6 | 0xEE | 0xF6 | 0xFE => { // INC
-                let mut m = self.get_byte(sys, addr_mode).0;
+                let mut m = self.get_byte(sys, addr_mode)?.0;
                 m = m.wrapping_add(1);
-                self.set_byte(sys, addr_mode, m);
+                self.set_byte(sys, addr_mode, m)?;
                 Cpu::update_zero_negative(&mut self.reg.status, m);
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::ZeroPage => (2, 5),
                     AddressingMode::ZeroPageX => (2, 6),
                     AddressingMode::Absolute => (3, 6),
                     AddressingMode::AbsoluteX => (3, 7),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0xE8 => { // INX
@@ -1308,7 +1825,7 @@ impl Cpu {
             
             0xE1 | 0xE5 | 0xE9 | 0xED | 0xF1 | 0xF5 | 0xF9 | 0xFD | 0xEB => { // SBC
                 let a = self.reg.a;
-                let (m, oops) = self.get_byte(sys, addr_mode);
+                let (m, oops) = self.get_byte(sys, addr_mode)?;
                 self.compute_sbc(a, m);
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::Immediate => (2, 2),
@@ -1319,13 +1836,13 @@ impl Cpu {
                     AddressingMode::AbsoluteY => (3, 4 + oops),
                     AddressingMode::IndirectX => (2, 6),
                     AddressingMode::IndirectY => (2, 5 + oops),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0x04 | 0x0C | 0x14 | 0x1A | 0x1C | 0x34 | 0x3A | 0x3C | 0x44 |
             0x54 | 0x5A | 0x5C | 0x64 | 0x74 | 0x7A | 0x7C | 0x80 | 0xD4 | 0xDA |
             0xDC | 0xEA | 0xF4 | 0xFA | 0xFC => { // NOP
-                let (_, oops) = self.get_byte(sys, addr_mode);
+                let (_, oops) = self.get_byte(sys, addr_mode)?;
                 self.step_pc_and_cycle(sys, match addr_mode {
                     AddressingMode::Implied => (1, 2),
                     AddressingMode::Immediate => (2, 2),
@@ -1334,7 +1851,7 @@ impl Cpu {
                     AddressingMode::ZeroPage => (2, 3),
                     AddressingMode::ZeroPageX => (2, 4),
                     AddressingMode::Absolute => (3, 4),
-                    _ => panic!("Unexpected addressing mode"),
+                    _ => return Err(self.addressing_mode_error()),
                     });
             }
             0xF0 => { // BEQ
@@ -1351,26 +1868,175 @@ impl Cpu {
                 self.reg.pc += 1;
                 self.step_cycle(sys, 2);
             }
-            _ => { panic!("unexpected opcode {:02X}", op_code); }
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => { // *KIL
+                // PC stays on the opcode, to show where the CPU jammed
+                self.jammed = true;
+                log::warn!(target: "cpu", "Jammed by ${:02X} at ${:04X}", op_code, self.reg.pc);
+                self.step_cycle(sys, 2);
+            }
+            _ => { return Err(self.stop(op_code)); }
+        }
+        // CLI, SEI and PLP change the flag after the interrupts have been
+        // polled, so it takes effect one instruction late
+        if !matches!(op_code, 0x28 | 0x58 | 0x78) {
+            interrupt_disable = self.get_status_flag(StatusFlag::InterruptDisable);
+        }
+        self.poll_interrupt_disable(interrupt_disable);
+        Ok(())
+    }
+
+    // A pending IRQ is only taken if interrupts were enabled when it was
+    // polled
+    fn poll_interrupt_disable(&mut self, interrupt_disable: bool) {
+        match self.cycles {
+            Some(ref mut cycles) => cycles.interrupt_disable = interrupt_disable,
+            None => self.irq_triggered &= !interrupt_disable,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut Snapshot) {
+        state.set_version(Chunk::Cpu, STATE_VERSION);
+        state.add_u16("cpu.pc", self.reg.pc);
+        state.add_u8("cpu.sp", self.reg.sp);
+        state.add_u8("cpu.a", self.reg.a);
+        state.add_u8("cpu.x", self.reg.x);
+        state.add_u8("cpu.y", self.reg.y);
+        state.add_u8("cpu.status", self.reg.status);
+        state.add_bool("cpu.nmi_triggered", self.nmi_triggered);
+        state.add_bool("cpu.irq_triggered", self.irq_triggered);
+        state.add_bool("cpu.jammed", self.jammed);
+    }
+
+    pub fn load_state(&mut self, state: &Snapshot) {
+        self.reg.pc = state.get_u16("cpu.pc");
+        self.reg.sp = state.get_u8("cpu.sp");
+        self.reg.a = state.get_u8("cpu.a");
+        self.reg.x = state.get_u8("cpu.x");
+        self.reg.y = state.get_u8("cpu.y");
+        self.reg.status = state.get_u8("cpu.status");
+        self.nmi_triggered = state.get_bool("cpu.nmi_triggered");
+        self.irq_triggered = state.get_bool("cpu.irq_triggered");
+        self.error = None;
+        self.jammed = state.get_bool("cpu.jammed");
+        if let Some(ref mut cycles) = self.cycles {
+            *cycles = CycleState::default();
         }
     }
 
     #[allow(dead_code)]
     pub fn get_state_string(&self, sys: &mut Machine) -> String {
+        let state = self.state();
         let reg_str = format!("A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
-                              self.reg.a, self.reg.x, self.reg.y,
-                              self.reg.status, self.reg.sp);
+                              state.a, state.x, state.y, state.status, state.sp);
         let (instr_str, _size) = self.decode_instruction(sys);
         
         format!("{:04X}  {}{}", self.reg.pc, instr_str, reg_str)
     }
 
+    // Disassembles the range, telling code from data by following the jumps,
+    // calls and branches from the reset, NMI and IRQ vectors. Their targets
+    // get labels. Only the banks mapped at the moment are followed, so code
+    // reached through jump tables, JMP (indirect) or other banks is shown as
+    // data.
+    pub fn disassemble_traced(&self, sys: &mut Machine, start: usize, end: usize) -> Vec<String> {
+        let mut labels = HashMap::new();
+        let mut pending = vec![];
+        for &(vector, name) in &[(0xfffc, "reset"), (0xfffa, "nmi"), (0xfffe, "irq")] {
+            let address = ((sys.peek_mem(vector + 1) as u16) << 8) + sys.peek_mem(vector) as u16;
+            labels.entry(address).or_insert_with(|| name.to_string());
+            pending.push(address);
+        }
+        let mut code = HashMap::new();
+        while let Some(address) = pending.pop() {
+            if address < 0x8000 || code.contains_key(&address) {
+                continue;
+            }
+            let instr = match self.instructions.get(&sys.peek_mem(address)) {
+                Some(instr) => instr,
+                None => { continue; }
+            };
+            let size = instruction_size(instr.addressing_mode);
+            if address as usize + size > 0x10000 {
+                continue;
+            }
+            let operands: Vec<u8> = (1..size as u16).map(|i| sys.peek_mem(address + i)).collect();
+            let next = address + size as u16;
+            let target = match instr.addressing_mode {
+                AddressingMode::Relative => Some(next.wrapping_add(operands[0] as i8 as u16)),
+                AddressingMode::Absolute if instr.mnemonic == "JMP" || instr.mnemonic == "JSR" => {
+                    Some(((operands[1] as u16) << 8) + operands[0] as u16)
+                }
+                _ => None,
+            };
+            if let Some(target) = target {
+                labels.entry(target).or_insert_with(|| format!("L{:04X}", target));
+                pending.push(target);
+            }
+            if !["JMP", "RTS", "RTI", "BRK", "*KIL"].contains(&instr.mnemonic.as_str()) {
+                pending.push(next);
+            }
+            code.insert(address, (instr, operands));
+        }
+
+        let label = |address: u16| labels.get(&address).cloned()
+            .unwrap_or_else(|| format!("${:04X}", address));
+        let mut result = vec![];
+        let mut data: Vec<u8> = vec![];
+        let mut data_start = start;
+        let flush_data = |data: &mut Vec<u8>, data_start: usize, result: &mut Vec<String>| {
+            if !data.is_empty() {
+                let bytes: Vec<_> = data.iter().map(|b| format!("${:02X}", b)).collect();
+                result.push(format!("{:04X}  .db {}", data_start, bytes.join(", ")));
+                data.clear();
+            }
+        };
+        let mut address = start;
+        while address <= end && address <= 0xffff {
+            if let Some(name) = labels.get(&(address as u16)) {
+                flush_data(&mut data, data_start, &mut result);
+                result.push(format!("{}:", name));
+            }
+            match code.get(&(address as u16)) {
+                Some((instr, operands)) => {
+                    flush_data(&mut data, data_start, &mut result);
+                    let mut bytes = vec![instr.op_code];
+                    bytes.extend(operands);
+                    let next = (address + bytes.len()) as u16;
+                    let operand = format_operand(instr.addressing_mode, operands, next, label);
+                    result.push(format!("{:04X}  {:8} {}", address, hex_bytes(&bytes),
+                                        format_instruction(instr, &operand)));
+                    address += bytes.len();
+                }
+                None => {
+                    if data.is_empty() {
+                        data_start = address;
+                    }
+                    data.push(sys.peek_mem(address as u16));
+                    if data.len() == 8 {
+                        flush_data(&mut data, data_start, &mut result);
+                    }
+                    address += 1;
+                }
+            }
+        }
+        flush_data(&mut data, data_start, &mut result);
+        result
+    }
+
     pub fn disassemble(&mut self, sys: &mut Machine, start: usize, end: usize) -> Vec<String> {
         let orig_pc = self.reg.pc;
         self.reg.pc = start as u16;
         let mut result = vec![];
         loop {
-            let (instr_str, size) = self.decode_instruction(sys);
+            // Bytes the code/data log has seen read but never executed are
+            // shown as data
+            let (instr_str, size) = if sys.is_logged_as_data(self.reg.pc) {
+                let value = sys.peek_mem(self.reg.pc);
+                (format!("{:02X}        {:32}", value, format!(" .db ${:02X}", value)), 1)
+            }
+            else {
+                self.decode_instruction(sys)
+            };
             result.push(format!("{:04X}  {}", self.reg.pc, instr_str));
             if self.reg.pc as usize + size > end {
                 break;
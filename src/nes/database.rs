@@ -0,0 +1,242 @@
+// Database of known games, identified by the CRC32 or the SHA-1 of their PRG
+// and CHR ROM data (i.e. without the iNES header, so that re-headered dumps
+// still match). The mapper, mirroring, region and PRG RAM size, when given,
+// replace what the header says, as many dumps have bad headers and iNES
+// headers cannot tell more than 8KB of PRG RAM.
+
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::nes::{PpuVariant, Region};
+
+#[derive(Clone)]
+pub struct GameInfo {
+    crc32: Option<u32>,
+    // In lowercase hex, like sha1sum prints it
+    sha1: Option<String>,
+    pub title: Option<String>,
+    // For games made for a clone or arcade PPU, e.g. Dendy games that
+    // depend on its vblank timing
+    pub ppu_variant: Option<PpuVariant>,
+    pub mapper: Option<u8>,
+    pub vertical_mirroring: Option<bool>,
+    pub region: Option<Region>,
+    // In bytes
+    pub prg_ram_size: Option<usize>,
+}
+
+pub struct Database {
+    games: Vec<GameInfo>,
+}
+
+// A game in a database file, e.g.
+//
+//     [[game]]
+//     crc32 = "158B0388"
+//     title = "nestest"
+//     mapper = 0
+//     mirroring = "horizontal"
+//     region = "ntsc"
+//
+//     [[game]]
+//     sha1 = "4131307f0f69f2a5c54b7d438328c5b2a5ed0820"
+//     prg_ram = 16
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GameEntry {
+    crc32: Option<String>,
+    sha1: Option<String>,
+    title: Option<String>,
+    mapper: Option<u8>,
+    mirroring: Option<String>,
+    region: Option<String>,
+    ppu: Option<String>,
+    // In KB
+    prg_ram: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct DatabaseFile {
+    game: Vec<GameEntry>,
+}
+
+impl GameEntry {
+    fn to_game_info(&self) -> Result<GameInfo, String> {
+        if self.crc32.is_none() && self.sha1.is_none() {
+            return Err("A game needs its crc32 or sha1".to_string());
+        }
+        let crc32 = match self.crc32 {
+            Some(ref crc32) => Some(u32::from_str_radix(crc32, 16)
+                .map_err(|_| format!("Invalid crc32: {}", crc32))?),
+            None => None,
+        };
+        let sha1 = match self.sha1 {
+            Some(ref sha1) if sha1.len() == 40 && sha1.chars().all(|c| c.is_ascii_hexdigit()) => {
+                Some(sha1.to_ascii_lowercase())
+            }
+            Some(ref sha1) => { return Err(format!("Invalid sha1: {}", sha1)); }
+            None => None,
+        };
+        let vertical_mirroring = match self.mirroring.as_deref() {
+            Some("horizontal") => Some(false),
+            Some("vertical") => Some(true),
+            Some(mirroring) => { return Err(format!("Invalid mirroring: {}", mirroring)); }
+            None => None,
+        };
+        let region = match self.region {
+            Some(ref name) => Some(Region::from_name(name)
+                .ok_or(format!("Invalid region: {}", name))?),
+            None => None,
+        };
+        let ppu_variant = match self.ppu {
+            Some(ref name) => Some(PpuVariant::from_name(name)
+                .ok_or(format!("Invalid ppu: {}", name))?),
+            None => None,
+        };
+        // The mappers take at least the 8KB at $6000-$7FFF
+        let prg_ram_size = match self.prg_ram {
+            Some(size) if size < 8 => {
+                return Err(format!("prg_ram must be at least 8 (KB), not {}", size));
+            }
+            Some(size) => Some(size * 1024),
+            None => None,
+        };
+        Ok(GameInfo {
+            crc32,
+            sha1,
+            title: self.title.clone(),
+            ppu_variant,
+            mapper: self.mapper,
+            vertical_mirroring,
+            region,
+            prg_ram_size,
+        })
+    }
+}
+
+impl Database {
+    pub fn built_in() -> Database {
+        Database {
+            games: vec![
+                GameInfo { crc32: Some(0x158B0388),
+                           sha1: Some("4131307f0f69f2a5c54b7d438328c5b2a5ed0820".to_string()),
+                           title: Some("nestest".to_string()), ppu_variant: None, mapper: Some(0),
+                           vertical_mirroring: Some(false), region: Some(Region::Ntsc),
+                           prg_ram_size: None },
+            ],
+        }
+    }
+
+    // The built-in games and those in a database file, which are searched
+    // first so that they can correct the built-in ones. A missing file gives
+    // the built-in database.
+    pub fn load(path: &Path) -> Result<Database, String> {
+        let mut database = Database::built_in();
+        if !path.exists() {
+            return Ok(database);
+        }
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let mut games = Database::parse(&text)
+            .map_err(|e| format!("Invalid game database {}: {}", path.display(), e))?;
+        games.append(&mut database.games);
+        database.games = games;
+        Ok(database)
+    }
+
+    fn parse(text: &str) -> Result<Vec<GameInfo>, String> {
+        let file: DatabaseFile = toml::from_str(text).map_err(|e| e.to_string())?;
+        file.game.iter().map(GameEntry::to_game_info).collect()
+    }
+
+    // A game with a SHA-1 has to match it; the CRC32 alone is enough for the
+    // others
+    pub fn find(&self, crc32: u32, sha1: &str) -> Option<&GameInfo> {
+        self.games.iter().find(|g| match g.sha1 {
+            Some(ref game_sha1) => game_sha1 == sha1,
+            None => g.crc32 == Some(crc32),
+        })
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// In lowercase hex
+pub fn sha1(data: &[u8]) -> String {
+    let mut h: [u32; 5] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476, 0xC3D2_E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e)
+                .wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, added) in h.iter_mut().zip([a, b, c, d, e].iter()) {
+            *value = value.wrapping_add(*added);
+        }
+    }
+    h.iter().map(|value| format!("{:08x}", value)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha1_of_the_test_vectors() {
+        assert_eq!(sha1(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(sha1(&[b'a'; 1000]), "291e9a6c66994949b57ba5e650361e98fc36b1ba");
+    }
+
+    #[test]
+    fn games_in_a_file_come_before_the_built_in_ones() {
+        let mut games = Database::parse("[[game]]\ncrc32 = \"158B0388\"\ntitle = \"Test\"\n\
+                                         [[game]]\nsha1 = \"0123456789abcdef0123456789ABCDEF01234567\"\n\
+                                         prg_ram = 32\n").unwrap();
+        games.append(&mut Database::built_in().games);
+        let database = Database { games };
+        assert_eq!(database.find(0x158B0388, "").unwrap().title.as_deref(), Some("Test"));
+        let game = database.find(0, "0123456789abcdef0123456789abcdef01234567").unwrap();
+        assert_eq!(game.prg_ram_size, Some(32 * 1024));
+        assert!(database.find(0x12345678, "").is_none());
+        assert!(Database::parse("[[game]]\ntitle = \"No checksum\"\n").is_err());
+        assert!(Database::parse("[[game]]\ncrc32 = \"1\"\nprg_ram = 2\n").is_err());
+    }
+}
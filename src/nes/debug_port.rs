@@ -0,0 +1,52 @@
+// Log output for homebrew and test ROMs. Bytes written to the debug port, an
+// address that is unused on the console, are collected as text and a line
+// feed ends each line. Writing to the address does nothing on real hardware,
+// so ROMs can log unconditionally.
+pub const ADDRESS: u16 = 0x4018;
+
+pub struct DebugPort {
+    line: Vec<u8>,
+    lines: Vec<String>,
+}
+
+impl DebugPort {
+    pub fn new() -> DebugPort {
+        DebugPort {
+            line: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn write(&mut self, value: u8) {
+        match value {
+            b'\n' => {
+                self.lines.push(String::from_utf8_lossy(&self.line).to_string());
+                self.line.clear();
+            }
+            b'\r' => {}
+            _ => { self.line.push(value); }
+        }
+    }
+
+    // The lines completed since the last call
+    pub fn take_lines(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_lines() {
+        let mut port = DebugPort::new();
+        for b in b"Hello\r\nworld\npartial" {
+            port.write(*b);
+        }
+        assert_eq!(port.take_lines(), vec!["Hello", "world"]);
+        assert!(port.take_lines().is_empty());
+        port.write(b'\n');
+        assert_eq!(port.take_lines(), vec!["partial"]);
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+// A timeline of what happened when during the frames, for finding out why a
+// raster effect is off: the writes to the PPU, APU and mapper registers and
+// the interrupts, with the scan line and dot the PPU was at. The oldest
+// events are dropped when the log is full.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EventKind {
+    PpuWrite,
+    ApuWrite,
+    MapperWrite,
+    Nmi,
+    Irq,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LoggedEvent {
+    pub frame: u64,
+    pub scan_line: i16,
+    pub dot: u16,
+    pub kind: EventKind,
+    // The register and value written
+    pub address: u16,
+    pub value: u8,
+}
+
+impl fmt::Display for LoggedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "frame {} line {:3} dot {:3}: ", self.frame, self.scan_line, self.dot)?;
+        match self.kind {
+            EventKind::PpuWrite => write!(f, "PPU ${:04X} = ${:02X}", self.address, self.value),
+            EventKind::ApuWrite => write!(f, "APU ${:04X} = ${:02X}", self.address, self.value),
+            EventKind::MapperWrite => write!(f, "mapper ${:04X} = ${:02X}", self.address, self.value),
+            EventKind::Nmi => write!(f, "NMI"),
+            EventKind::Irq => write!(f, "IRQ"),
+        }
+    }
+}
+
+pub struct EventLog {
+    events: VecDeque<LoggedEvent>,
+    capacity: usize,
+    frame: u64,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> EventLog {
+        EventLog {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+            frame: 0,
+        }
+    }
+
+    pub fn record(&mut self, event: LoggedEvent) {
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(LoggedEvent { frame: self.frame, ..event });
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    // The events of the frames so far, oldest first
+    pub fn events(&self) -> impl Iterator<Item = &LoggedEvent> {
+        self.events.iter()
+    }
+
+    // The events of the last frame that has ended
+    pub fn last_frame(&self) -> impl Iterator<Item = &LoggedEvent> {
+        let frame = self.frame.wrapping_sub(1);
+        self.events.iter().filter(move |e| e.frame == frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ppu_write(value: u8) -> LoggedEvent {
+        LoggedEvent { frame: 0, scan_line: 30, dot: 256, kind: EventKind::PpuWrite, address: 0x2005, value }
+    }
+
+    #[test]
+    fn old_events_are_dropped() {
+        let mut log = EventLog::new(3);
+        log.record(ppu_write(1));
+        log.end_frame();
+        for value in 2..5 {
+            log.record(ppu_write(value));
+        }
+        log.end_frame();
+        assert_eq!(log.events().map(|e| e.value).collect::<Vec<_>>(), [2, 3, 4]);
+        assert_eq!(log.last_frame().count(), 3);
+        assert_eq!(log.events().next().unwrap().to_string(), "frame 1 line  30 dot 256: PPU $2005 = $02");
+    }
+}
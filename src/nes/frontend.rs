@@ -0,0 +1,42 @@
+// What a machine needs from the program around it: somewhere to show the
+// picture, somewhere to play the sound and the buttons that are held. The
+// SDL window, audio queue and keyboard of Machine::new are one such frontend;
+// a headless machine can be given others with set_video_sink,
+// set_audio_sink and set_input_source, e.g. a canvas and WebAudio in a
+// browser. The input is read by handle_events and the picture is passed on by
+// present, which the frontend calls before and after each run_frame.
+
+pub trait VideoSink {
+    // A finished picture of 256x240 pixels with 3 bytes (RGB) each
+    fn present_frame(&mut self, picture: &[u8]);
+}
+
+pub trait AudioSink {
+    fn sample_rate(&self) -> u32;
+
+    // Channels all get the same sound
+    fn channels(&self) -> usize {
+        1
+    }
+
+    // The number of samples per channel passed to queue at a time. Smaller
+    // buffers give less latency, but are passed more often.
+    fn buffer_size(&self) -> usize {
+        512
+    }
+
+    // Samples from -1.0 to 1.0, interleaved if there are several channels,
+    // to play after those queued before
+    fn queue(&mut self, samples: &[f32]);
+
+    // The samples per channel queued but not yet played, if known
+    fn queued(&self) -> usize {
+        0
+    }
+}
+
+pub trait InputSource {
+    // The buttons of a player, numbered from 1, in the order of BUTTON_NAMES.
+    // Asked at the start of every frame; None leaves them as they were.
+    fn buttons(&mut self, player: usize) -> Option<[bool; 8]>;
+}
@@ -0,0 +1,67 @@
+use std::time::{Duration, Instant};
+
+// Diagnostic for measuring host-side input latency. A button press is
+// followed through the pipeline: the host event, the end of the first
+// emulated frame after it and the presentation of that frame, which is
+// flashed white so that the result can be verified with a camera.
+pub struct LatencyProbe {
+    input_time: Option<Instant>,
+    frame_time: Option<Instant>,
+    total: Duration,
+    min: Duration,
+    max: Duration,
+    count: u32,
+}
+
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl LatencyProbe {
+    pub fn new() -> LatencyProbe {
+        LatencyProbe {
+            input_time: None,
+            frame_time: None,
+            total: Duration::from_secs(0),
+            min: Duration::from_secs(u64::MAX),
+            max: Duration::from_secs(0),
+            count: 0,
+        }
+    }
+
+    pub fn record_input(&mut self, time: Instant) {
+        if self.input_time.is_none() {
+            self.input_time = Some(time);
+        }
+    }
+
+    // Returns true if the frame just completed should be flashed
+    pub fn record_frame(&mut self) -> bool {
+        if self.input_time.is_some() && self.frame_time.is_none() {
+            self.frame_time = Some(Instant::now());
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    pub fn record_present(&mut self) {
+        if let (Some(input_time), Some(frame_time)) = (self.input_time, self.frame_time) {
+            let present_time = Instant::now();
+            let latency = present_time - input_time;
+            self.total += latency;
+            self.min = self.min.min(latency);
+            self.max = self.max.max(latency);
+            self.count += 1;
+            log::info!(target: "latency",
+                       "Input latency: {:.1} ms (event to frame {:.1} ms, frame to present {:.1} ms), \
+                        average {:.1} ms, min {:.1} ms, max {:.1} ms over {} presses",
+                       as_ms(latency), as_ms(frame_time - input_time),
+                       as_ms(present_time - frame_time), as_ms(self.total / self.count),
+                       as_ms(self.min), as_ms(self.max), self.count);
+            self.input_time = None;
+            self.frame_time = None;
+        }
+    }
+}
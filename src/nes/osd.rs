@@ -0,0 +1,271 @@
+// On-screen messages and overlays, drawn into the picture with a 3x5 pixel
+// font of capital letters, digits and some punctuation. Other characters are
+// left as spaces.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::nes::screen::{HEIGHT, WIDTH};
+
+const MESSAGE_DURATION: Duration = Duration::from_secs(2);
+
+// Messages shown at the same time, the newest at the bottom
+const MAX_MESSAGES: usize = 3;
+
+// Rows from top to bottom, the leftmost pixel in bit 2
+const LETTERS: [[u8; 5]; 26] = [
+    [0b010, 0b101, 0b111, 0b101, 0b101], // A
+    [0b110, 0b101, 0b110, 0b101, 0b110], // B
+    [0b011, 0b100, 0b100, 0b100, 0b011], // C
+    [0b110, 0b101, 0b101, 0b101, 0b110], // D
+    [0b111, 0b100, 0b110, 0b100, 0b111], // E
+    [0b111, 0b100, 0b110, 0b100, 0b100], // F
+    [0b011, 0b100, 0b101, 0b101, 0b011], // G
+    [0b101, 0b101, 0b111, 0b101, 0b101], // H
+    [0b111, 0b010, 0b010, 0b010, 0b111], // I
+    [0b001, 0b001, 0b001, 0b101, 0b010], // J
+    [0b101, 0b101, 0b110, 0b101, 0b101], // K
+    [0b100, 0b100, 0b100, 0b100, 0b111], // L
+    [0b101, 0b111, 0b111, 0b101, 0b101], // M
+    [0b110, 0b101, 0b101, 0b101, 0b101], // N
+    [0b010, 0b101, 0b101, 0b101, 0b010], // O
+    [0b110, 0b101, 0b110, 0b100, 0b100], // P
+    [0b010, 0b101, 0b101, 0b110, 0b011], // Q
+    [0b110, 0b101, 0b110, 0b101, 0b101], // R
+    [0b011, 0b100, 0b010, 0b001, 0b110], // S
+    [0b111, 0b010, 0b010, 0b010, 0b010], // T
+    [0b101, 0b101, 0b101, 0b101, 0b111], // U
+    [0b101, 0b101, 0b101, 0b101, 0b010], // V
+    [0b101, 0b101, 0b111, 0b111, 0b101], // W
+    [0b101, 0b101, 0b010, 0b101, 0b101], // X
+    [0b101, 0b101, 0b010, 0b010, 0b010], // Y
+    [0b111, 0b001, 0b010, 0b100, 0b111], // Z
+];
+
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111], // 0
+    [0b010, 0b110, 0b010, 0b010, 0b111], // 1
+    [0b110, 0b001, 0b010, 0b100, 0b111], // 2
+    [0b110, 0b001, 0b010, 0b001, 0b110], // 3
+    [0b101, 0b101, 0b111, 0b001, 0b001], // 4
+    [0b111, 0b100, 0b110, 0b001, 0b110], // 5
+    [0b011, 0b100, 0b111, 0b101, 0b111], // 6
+    [0b111, 0b001, 0b010, 0b010, 0b010], // 7
+    [0b111, 0b101, 0b111, 0b101, 0b111], // 8
+    [0b111, 0b101, 0b111, 0b001, 0b110], // 9
+];
+
+const PUNCTUATION: [(char, [u8; 5]); 12] = [
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    (',', [0b000, 0b000, 0b000, 0b010, 0b100]),
+    (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+    ('!', [0b010, 0b010, 0b010, 0b000, 0b010]),
+    ('?', [0b110, 0b001, 0b010, 0b000, 0b010]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('+', [0b000, 0b010, 0b111, 0b010, 0b000]),
+    ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    ('%', [0b101, 0b001, 0b010, 0b100, 0b101]),
+    ('(', [0b001, 0b010, 0b010, 0b010, 0b001]),
+    (')', [0b100, 0b010, 0b010, 0b010, 0b100]),
+    ('\'', [0b010, 0b010, 0b000, 0b000, 0b000]),
+];
+
+fn glyph(c: char) -> Option<&'static [u8; 5]> {
+    match c.to_ascii_uppercase() {
+        c @ 'A'..='Z' => Some(&LETTERS[c as usize - 'A' as usize]),
+        c @ '0'..='9' => Some(&DIGITS[c as usize - '0' as usize]),
+        c => PUNCTUATION.iter().find(|(p, _)| *p == c).map(|(_, rows)| rows),
+    }
+}
+
+fn set_pixel(frame: &mut [u8], x: u32, y: u32, color: [u8; 3]) {
+    if x < WIDTH && y < HEIGHT {
+        let offset = ((y * WIDTH + x) * 3) as usize;
+        frame[offset..offset + 3].copy_from_slice(&color);
+    }
+}
+
+// Draws the text in white with a black shadow, each character 4 pixels wide
+pub fn draw_text(frame: &mut [u8], x: u32, y: u32, text: &str) {
+    for (shadow, color) in &[(1, 0x00), (0, 0xFF)] {
+        for (i, c) in text.chars().enumerate() {
+            let rows = match glyph(c) {
+                Some(rows) => rows,
+                None => { continue; }
+            };
+            for (row, bits) in rows.iter().enumerate() {
+                for column in 0..3 {
+                    if bits & (0b100 >> column) != 0 {
+                        set_pixel(frame, x + i as u32 * 4 + column + shadow,
+                                  y + row as u32 + shadow, [*color; 3]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The messages shown for a while at the bottom of the picture
+pub struct Messages {
+    shown: VecDeque<(String, Instant)>,
+}
+
+impl Messages {
+    pub fn new() -> Messages {
+        Messages { shown: VecDeque::new() }
+    }
+
+    pub fn show(&mut self, text: &str, now: Instant) {
+        if self.shown.len() == MAX_MESSAGES {
+            self.shown.pop_front();
+        }
+        self.shown.push_back((text.to_string(), now + MESSAGE_DURATION));
+    }
+
+    // Forgets the messages whose time is up. Returns true if any are left.
+    pub fn update(&mut self, now: Instant) -> bool {
+        self.shown.retain(|(_, until)| now < *until);
+        !self.shown.is_empty()
+    }
+
+    pub fn draw(&self, frame: &mut [u8]) {
+        let bottom = HEIGHT - 16;
+        for (i, (text, _)) in self.shown.iter().rev().enumerate() {
+            draw_text(frame, 8, bottom - i as u32 * 8, text);
+        }
+    }
+}
+
+// The outline of a rectangle, clipped to the picture
+pub fn draw_box(frame: &mut [u8], x: u32, y: u32, width: u32, height: u32, color: [u8; 3]) {
+    if width == 0 || height == 0 {
+        return;
+    }
+    for i in x..x.saturating_add(width) {
+        set_pixel(frame, i, y, color);
+        set_pixel(frame, i, y.saturating_add(height - 1), color);
+    }
+    for i in y..y.saturating_add(height) {
+        set_pixel(frame, x, i, color);
+        set_pixel(frame, x.saturating_add(width - 1), i, color);
+    }
+}
+
+// An RGB picture of the width, clipped to the picture
+pub fn draw_image(frame: &mut [u8], x: u32, y: u32, width: u32, pixels: &[u8]) {
+    for (i, color) in pixels.chunks(3).enumerate() {
+        let i = i as u32;
+        set_pixel(frame, x + i % width, y + i / width, [color[0], color[1], color[2]]);
+    }
+}
+
+enum Shape {
+    Text { x: u32, y: u32, text: String },
+    Box { x: u32, y: u32, width: u32, height: u32, color: [u8; 3] },
+    Image { x: u32, y: u32, width: u32, pixels: Vec<u8> },
+}
+
+// Text and boxes drawn over the next picture only, e.g. by a script that
+// draws them again every frame
+pub struct Overlay {
+    shapes: Vec<Shape>,
+}
+
+impl Overlay {
+    pub fn new() -> Overlay {
+        Overlay { shapes: Vec::new() }
+    }
+
+    pub fn add_text(&mut self, x: u32, y: u32, text: &str) {
+        self.shapes.push(Shape::Text { x, y, text: text.to_string() });
+    }
+
+    pub fn add_box(&mut self, x: u32, y: u32, width: u32, height: u32, color: [u8; 3]) {
+        self.shapes.push(Shape::Box { x, y, width, height, color });
+    }
+
+    pub fn add_image(&mut self, x: u32, y: u32, width: u32, pixels: &[u8]) {
+        self.shapes.push(Shape::Image { x, y, width, pixels: pixels.to_vec() });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shapes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.shapes.clear();
+    }
+
+    pub fn draw(&self, frame: &mut [u8]) {
+        for shape in &self.shapes {
+            match shape {
+                Shape::Text { x, y, text } => draw_text(frame, *x, *y, text),
+                Shape::Box { x, y, width, height, color } => draw_box(frame, *x, *y, *width, *height, *color),
+                Shape::Image { x, y, width, pixels } => draw_image(frame, *x, *y, *width, pixels),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(frame: &[u8], x: u32, y: u32) -> u8 {
+        frame[((y * WIDTH + x) * 3) as usize]
+    }
+
+    #[test]
+    fn draws_characters_with_shadow() {
+        let mut frame = vec![0x80; (WIDTH * HEIGHT * 3) as usize];
+        draw_text(&mut frame, 10, 20, "l1");
+        // The top row of L and 1
+        assert_eq!([10, 11, 12, 14, 15, 16].iter().map(|x| pixel(&frame, *x, 20)).collect::<Vec<_>>(),
+                   [0xFF, 0x80, 0x80, 0x80, 0xFF, 0x80]);
+        // The shadow of the bottom row of L
+        assert_eq!(pixel(&frame, 13, 25), 0x00);
+        assert_eq!(pixel(&frame, 12, 24), 0xFF);
+        // Drawing near the edge is clipped
+        draw_text(&mut frame, WIDTH - 2, HEIGHT - 2, "8");
+    }
+
+    #[test]
+    fn messages_expire_and_stack() {
+        let start = Instant::now();
+        let mut messages = Messages::new();
+        for text in &["1", "2", "3", "4"] {
+            messages.show(text, start);
+        }
+        messages.show("-", start + Duration::from_secs(1));
+        assert!(messages.update(start + Duration::from_secs(1)));
+        assert_eq!(messages.shown.iter().map(|(t, _)| t.as_str()).collect::<Vec<_>>(),
+                   ["3", "4", "-"]);
+
+        // The newest is at the bottom
+        let mut frame = vec![0x80; (WIDTH * HEIGHT * 3) as usize];
+        messages.draw(&mut frame);
+        assert_eq!(pixel(&frame, 8, HEIGHT - 14), 0xFF);
+        assert_eq!(pixel(&frame, 8, HEIGHT - 16 - 8), 0xFF);
+
+        assert!(messages.update(start + Duration::from_millis(2500)));
+        assert!(!messages.update(start + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn overlay_draws_box_outlines() {
+        let mut frame = vec![0x80; (WIDTH * HEIGHT * 3) as usize];
+        let mut overlay = Overlay::new();
+        overlay.add_box(10, 10, 4, 3, [0xFF, 0, 0]);
+        overlay.add_box(WIDTH - 2, HEIGHT - 2, 10, 10, [0, 0, 0xFF]);
+        overlay.draw(&mut frame);
+        assert_eq!(frame[((10 * WIDTH + 13) * 3) as usize..][..3], [0xFF, 0, 0]);
+        assert_eq!(frame[((12 * WIDTH + 10) * 3) as usize..][..3], [0xFF, 0, 0]);
+        assert_eq!(pixel(&frame, 11, 11), 0x80);
+        // Only the top and left edges are inside the picture
+        assert_eq!(pixel(&frame, WIDTH - 1, HEIGHT - 2), 0);
+        assert_eq!(pixel(&frame, WIDTH - 1, HEIGHT - 1), 0x80);
+
+        overlay.clear();
+        assert!(overlay.is_empty());
+    }
+}
@@ -1,10 +1,11 @@
-extern crate sdl2;
+use std::mem;
 
-use crate::nes::cartridge;
+use serde::{Deserialize, Serialize};
 
-use sdl2::render::WindowCanvas;
-use sdl2::pixels::Color;
-use sdl2::rect::Point;
+use crate::nes::cartridge;
+use crate::nes::screen;
+use crate::nes::state::{Chunk, ComponentState, Snapshot};
+use crate::nes::Region;
 
 struct Registers {
     v: u16,
@@ -19,10 +20,71 @@ struct Registers {
     bg_attribute_lower: u8,
 }
 
+// The version of the sections in save_state, for ComponentState and the
+// PPU chunk of machine states. Version 2 added the pending sprite 0 hit and
+// the warm-up.
+pub const STATE_VERSION: u32 = 2;
+
+// Where the PPU is in the frame and its registers, for debuggers and tests
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct PpuState {
+    pub scan_line: i16,
+    pub dot: u16,
+    pub odd_frame: bool,
+    pub vblank: bool,
+    // The internal scroll registers: the VRAM address, the temporary address,
+    // the fine X scroll and the write toggle
+    pub v: u16,
+    pub t: u16,
+    pub fine_x: u8,
+    pub w: bool,
+    pub nmi_enabled: bool,
+    pub background_enabled: bool,
+    pub sprites_enabled: bool,
+    pub sprite0_hit: bool,
+    pub sprite_overflow: bool,
+    pub oam_address: u8,
+}
+
+// A sprite fetched for the current scan line
+#[derive(Clone,Copy,Default)]
+struct Sprite {
+    x: u8,
+    attributes: u8,
+    pattern_lower: u8,
+    pattern_upper: u8,
+}
+
+// Progress of the sprite evaluation for the next scan line
+#[derive(Default)]
+struct SpriteEvaluation {
+    n: u8, // sprite in OAM
+    m: u8, // byte of the sprite
+    found: u8,
+    done: bool,
+    sprite0_in_range: bool,
+}
+
+impl SpriteEvaluation {
+    fn next_sprite(&mut self) {
+        self.n += 1;
+        if self.n == 64 {
+            self.n = 0;
+            self.done = true;
+        }
+    }
+}
+
 pub struct Ppu {
+    variant: PpuVariant,
+    dot_remainder: u16,
+    // The dots since power on, for the mappers timing the fetches
+    dot_count: u64,
     pub scan_line: i16,
     pub cycle_count: u16,
     pub vblank: bool,
+    vblank_suppressed: bool,
+    odd_frame: bool,
     vram_addr_increment: u16,
     gen_nmi_at_vblank: bool,
     pub mem_read_mut_enabled: bool,
@@ -30,23 +92,115 @@ pub struct Ppu {
     sprites_leftmost_enabled: bool,
     background_enabled: bool,
     sprites_enabled: bool,
+    greyscale: bool,
+    emphasis: u8,
+    left_clip_mode: LeftClipMode,
+    // Whether writes to $2000, $2001, $2005 and $2006 are ignored after
+    // power-on and reset, until the PPU reaches the pre-render line at the
+    // end of the first vblank, about 29658 CPU cycles later on NTSC
+    warm_up: bool,
+    warming_up: bool,
+    // Layers left out of the picture for debugging, while the game sees
+    // them drawn as usual
+    background_layer_shown: bool,
+    sprite_layer_shown: bool,
     vram: [u8; 2048],
     palette_ram: [u8; 32],
     oam: [u8; 256],
     secondary_oam: [u8; 32],
     oam_addr: u8,
+    oam_latch: u8,
+    evaluation: SpriteEvaluation,
+    sprites: [Sprite; 8],
     reg: Registers,
-    last_written_value: u8,
+    // The address of the second $2006 write and the dots until it reaches v
+    v_update: Option<(u16, u8)>,
+    io_latch: u8,
+    io_latch_age: [u8; 8], // frames since each bit was refreshed
     bg_pattern_table_addr: u16,
     sprite_pattern_table_addr: u16,
     sprite_height: u8,
     sprite0_enabled: bool,
     sprite0_hit: bool,
-    renderer: WindowCanvas,
-    renderer_nametable: Option<WindowCanvas>,
+    // A hit found while drawing a pixel reaches the status register on the
+    // next dot, as the console outputs pixel x on dot x + 1
+    sprite0_hit_pending: bool,
+    sprite_overflow: bool,
+    // The frame being drawn, and the last one completed, which is copied
+    // from it when vblank starts
+    picture: Vec<u8>,
+    frame_buffer: Vec<u8>,
     colors: Vec<u8>,
 }
 
+// How to show the leftmost 8 pixels when $2001 clips them
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub enum LeftClipMode {
+    Clip, // Backdrop color, as on the console
+    Dim,
+    Black,
+    Show,
+}
+
+impl LeftClipMode {
+    pub fn from_name(name: &str) -> Option<LeftClipMode> {
+        match name {
+            "clip" => Some(LeftClipMode::Clip),
+            "dim" => Some(LeftClipMode::Dim),
+            "black" => Some(LeftClipMode::Black),
+            "show" => Some(LeftClipMode::Show),
+            _ => None,
+        }
+    }
+}
+
+// The PPU chips of the consoles and their clones, which differ in frame
+// timing and colors
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub enum PpuVariant {
+    Ntsc,  // RP2C02
+    Pal,   // RP2C07
+    Dendy, // UA6538 of the Dendy and other PAL famiclones
+    Rgb,   // RP2C03 of the PlayChoice-10 and Vs. System, with RGB output
+}
+
+impl PpuVariant {
+    pub fn from_name(name: &str) -> Option<PpuVariant> {
+        match name {
+            "ntsc" => Some(PpuVariant::Ntsc),
+            "pal" => Some(PpuVariant::Pal),
+            "dendy" => Some(PpuVariant::Dendy),
+            "rgb" => Some(PpuVariant::Rgb),
+            _ => None,
+        }
+    }
+
+    pub fn for_region(region: Region) -> PpuVariant {
+        match region {
+            Region::Ntsc => PpuVariant::Ntsc,
+            Region::Pal => PpuVariant::Pal,
+        }
+    }
+}
+
+// About 600 ms
+const IO_LATCH_DECAY_FRAMES: u8 = 36;
+
+// Dots from the second $2006 write until v is updated
+const V_UPDATE_DELAY: u8 = 3;
+
+// The colors of the RGB PPU, with three bits per component
+const RGB_PALETTE: [u16; 64] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420,
+    0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630,
+    0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750,
+    0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772,
+    0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000,
+];
+
 #[derive(PartialEq)]
 enum SpritePriority {
     Back,
@@ -59,34 +213,16 @@ fn copy_bits(dest: u16, src: u16, mask: u16) -> u16 {
 }
 
 impl Ppu {
-    pub fn new(sdl_context: &mut sdl2::Sdl, show_name_table: bool) -> Ppu {
-        let video_subsystem = sdl_context.video().unwrap();
-
-        const SCALE_FACTOR: u32 = 2;
-
-        let window = video_subsystem.window("nesemu", 256 * SCALE_FACTOR, 240 * SCALE_FACTOR)
-            .position_centered()
-            .build()
-            .unwrap();
-
-        let mut renderer = window.into_canvas().build().unwrap();
-        renderer.set_scale(SCALE_FACTOR as f32, SCALE_FACTOR as f32).unwrap();
-
-        let renderer_nametable = if show_name_table {
-            let window = video_subsystem.window("nametable", 512, 480)
-                .position_centered()
-                .build()
-                .unwrap();
-            Some(window.into_canvas().build().unwrap())
-        }
-        else {
-            None
-        };
-
+    pub fn new() -> Ppu {
         Ppu {
+            variant: PpuVariant::Ntsc,
+            dot_remainder: 0,
+            dot_count: 0,
             scan_line: 0,
             cycle_count: 0,
             vblank: false,
+            vblank_suppressed: false,
+            odd_frame: false,
             vram_addr_increment: 1,
             gen_nmi_at_vblank: false,
             mem_read_mut_enabled: true,
@@ -94,24 +230,38 @@ impl Ppu {
             sprites_leftmost_enabled: true,
             background_enabled: true,
             sprites_enabled: true,
+            greyscale: false,
+            emphasis: 0,
+            left_clip_mode: LeftClipMode::Clip,
+            warm_up: false,
+            warming_up: false,
+            background_layer_shown: true,
+            sprite_layer_shown: true,
             vram: [0; 0x800],
             palette_ram: [0; 32],
             oam: [0; 256],
             secondary_oam: [0xFF; 32],
             oam_addr: 0,
+            oam_latch: 0,
+            evaluation: SpriteEvaluation::default(),
+            sprites: [Sprite::default(); 8],
             reg: Registers { t: 0, v: 0, x: 0, w: false,
                              vram_read_buffer: 0,
                              bg_pattern_upper: 0, bg_pattern_lower: 0,
                              bg_attribute_latch: 0,
                              bg_attribute_upper: 0, bg_attribute_lower: 0 },
-            last_written_value: 0,
+            v_update: None,
+            io_latch: 0,
+            io_latch_age: [0; 8],
             bg_pattern_table_addr: 0x0000,
             sprite_pattern_table_addr: 0x0000,
             sprite_height: 8,
             sprite0_enabled: false,
             sprite0_hit: false,
-            renderer: renderer,
-            renderer_nametable: renderer_nametable,
+            sprite0_hit_pending: false,
+            sprite_overflow: false,
+            picture: vec![0; (screen::WIDTH * screen::HEIGHT * 3) as usize],
+            frame_buffer: vec![0; (screen::WIDTH * screen::HEIGHT * 3) as usize],
             colors: vec![
                 84, 84, 84,     0, 30, 116,     8, 16, 144,     48, 0, 136,
                 68, 0, 100,     92, 0, 48,      84, 4, 0,       60, 24, 0,
@@ -133,7 +283,9 @@ impl Ppu {
         }
     }
 
-    fn render_name_table(&mut self, cartridge: &cartridge::Cartridge) {
+    // Renders all four name tables into a 512x480 RGB image
+    pub fn render_name_table(&self, cartridge: &cartridge::Cartridge) -> Vec<u8> {
+        let mut image = vec![0; (4 * screen::WIDTH * screen::HEIGHT * 3) as usize];
         for nt_y in 0..2 {
             for nt_x in 0..2 {
                 let base_address = 0x2000 + 0x400 * (nt_y * 2 + nt_x);
@@ -189,30 +341,82 @@ impl Ppu {
                                 let palette_address = 0x3F00 + (index as u16);
                                 let color_index =
                                     self.read_mem_ppu(palette_address, cartridge) as usize;
-                                let red = self.colors[color_index * 3 + 0];
-                                let green = self.colors[color_index * 3 + 1];
-                                let blue = self.colors[color_index * 3 + 2];
-                                let renderer = self.renderer_nametable.as_mut().unwrap();
-                                renderer.set_draw_color(Color::RGB(red, green, blue));
-                                renderer.draw_point(
-                                    Point::new(screen_x as i32, screen_y as i32)).unwrap();
+                                let offset = (screen_y as usize * 2 * screen::WIDTH as usize +
+                                              screen_x as usize) * 3;
+                                image[offset..offset + 3].clone_from_slice(
+                                    &self.colors[color_index * 3..color_index * 3 + 3]);
                             }
                         }
                     }
                 }
             }
         }
+        image
     }
 
-    pub fn present(&mut self, cartridge: &cartridge::Cartridge) {
-        self.renderer.present();
-        match self.renderer_nametable {
-            Some(_) => {
-                self.render_name_table(cartridge);
-                self.renderer_nametable.as_mut().unwrap().present();
-            }
-            None => {
-            }
+    pub fn set_region(&mut self, region: Region) {
+        self.set_variant(PpuVariant::for_region(region));
+    }
+
+    // Also replaces the colors with those of the variant
+    pub fn set_variant(&mut self, variant: PpuVariant) {
+        self.variant = variant;
+        self.colors = match variant {
+            PpuVariant::Rgb => RGB_PALETTE.iter()
+                .flat_map(|c| vec![(c >> 6) & 0x7, (c >> 3) & 0x7, c & 0x7])
+                .map(|component| (component * 255 / 7) as u8)
+                .collect(),
+            _ => Ppu::new().colors,
+        };
+    }
+
+    // Returns to the power-on state, keeping the emulator settings
+    pub fn power_cycle(&mut self) {
+        let mut ppu = Ppu::new();
+        ppu.variant = self.variant;
+        ppu.left_clip_mode = self.left_clip_mode;
+        ppu.warm_up = self.warm_up;
+        ppu.warming_up = self.warm_up;
+        ppu.colors = mem::take(&mut self.colors);
+        *self = ppu;
+    }
+
+    // The reset button clears PPUCTRL, PPUMASK, the scroll, the write toggle
+    // and the read buffer. OAM, the palette, VRAM and the status flags keep
+    // their values.
+    pub fn reset(&mut self) {
+        self.vram_addr_increment = 1;
+        self.gen_nmi_at_vblank = false;
+        self.bg_pattern_table_addr = 0;
+        self.sprite_pattern_table_addr = 0;
+        self.sprite_height = 8;
+        self.greyscale = false;
+        self.emphasis = 0;
+        self.background_leftmost_enabled = false;
+        self.sprites_leftmost_enabled = false;
+        self.background_enabled = false;
+        self.sprites_enabled = false;
+        self.reg.t = 0;
+        self.reg.x = 0;
+        self.reg.w = false;
+        self.reg.vram_read_buffer = 0;
+        self.v_update = None;
+        self.warming_up = self.warm_up;
+    }
+
+    fn pre_render_scan_line(&self) -> i16 {
+        match self.variant {
+            PpuVariant::Ntsc | PpuVariant::Rgb => 261,
+            PpuVariant::Pal | PpuVariant::Dendy => 311,
+        }
+    }
+
+    // The Dendy PPU has its extra scan lines before vblank instead of after,
+    // which delays vblank and the NMI by 50 lines
+    fn vblank_scan_line(&self) -> i16 {
+        match self.variant {
+            PpuVariant::Dendy => 291,
+            _ => 241,
         }
     }
 
@@ -221,9 +425,202 @@ impl Ppu {
         self.scan_line = scan_line;
     }
 
-    fn get_background_pixel(&self) -> u8 {
+    // The last completed frame, 256x240 pixels with 3 bytes (RGB) each. It
+    // stays the same while the next one is drawn.
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.frame_buffer
+    }
+
+    pub fn state(&self) -> PpuState {
+        PpuState {
+            scan_line: self.scan_line,
+            dot: self.cycle_count,
+            odd_frame: self.odd_frame,
+            vblank: self.vblank,
+            v: self.reg.v,
+            t: self.reg.t,
+            fine_x: self.reg.x,
+            w: self.reg.w,
+            nmi_enabled: self.gen_nmi_at_vblank,
+            background_enabled: self.background_enabled,
+            sprites_enabled: self.sprites_enabled,
+            sprite0_hit: self.sprite0_hit,
+            sprite_overflow: self.sprite_overflow,
+            oam_address: self.oam_addr,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut Snapshot) {
+        state.set_version(Chunk::Ppu, STATE_VERSION);
+        state.add_u16("ppu.scan_line", self.scan_line as u16);
+        state.add_u16("ppu.cycle_count", self.cycle_count);
+        state.add_u16("ppu.dot_remainder", self.dot_remainder);
+        state.add_u64("ppu.dot_count", self.dot_count);
+        state.add_bool("ppu.vblank", self.vblank);
+        state.add_bool("ppu.vblank_suppressed", self.vblank_suppressed);
+        state.add_bool("ppu.odd_frame", self.odd_frame);
+        state.add_u16("ppu.vram_addr_increment", self.vram_addr_increment);
+        state.add_bool("ppu.gen_nmi_at_vblank", self.gen_nmi_at_vblank);
+        state.add_bool("ppu.background_leftmost_enabled", self.background_leftmost_enabled);
+        state.add_bool("ppu.sprites_leftmost_enabled", self.sprites_leftmost_enabled);
+        state.add_bool("ppu.background_enabled", self.background_enabled);
+        state.add_bool("ppu.sprites_enabled", self.sprites_enabled);
+        state.add_bool("ppu.greyscale", self.greyscale);
+        state.add_u8("ppu.emphasis", self.emphasis);
+        state.add_u16("ppu.bg_pattern_table_addr", self.bg_pattern_table_addr);
+        state.add_u16("ppu.sprite_pattern_table_addr", self.sprite_pattern_table_addr);
+        state.add_u8("ppu.sprite_height", self.sprite_height);
+        state.add_bool("ppu.sprite0_enabled", self.sprite0_enabled);
+        state.add_bool("ppu.sprite0_hit", self.sprite0_hit);
+        state.add_bool("ppu.sprite0_hit_pending", self.sprite0_hit_pending);
+        state.add_bool("ppu.warming_up", self.warming_up);
+        state.add_bool("ppu.sprite_overflow", self.sprite_overflow);
+        state.add_u8("ppu.oam_addr", self.oam_addr);
+        state.add_u8("ppu.io_latch", self.io_latch);
+        state.add_bytes("ppu.io_latch_age", &self.io_latch_age);
+        state.add_u16("ppu.v", self.reg.v);
+        state.add_u16("ppu.t", self.reg.t);
+        state.add_u8("ppu.x", self.reg.x);
+        state.add_bool("ppu.w", self.reg.w);
+        let (v_update, v_update_delay) = self.v_update.unwrap_or((0, 0));
+        state.add_u16("ppu.v_update", v_update);
+        state.add_u8("ppu.v_update_delay", v_update_delay);
+        state.add_u8("ppu.vram_read_buffer", self.reg.vram_read_buffer);
+        state.add_u16("ppu.bg_pattern_upper", self.reg.bg_pattern_upper);
+        state.add_u16("ppu.bg_pattern_lower", self.reg.bg_pattern_lower);
+        state.add_u8("ppu.bg_attribute_latch", self.reg.bg_attribute_latch);
+        state.add_u8("ppu.bg_attribute_upper", self.reg.bg_attribute_upper);
+        state.add_u8("ppu.bg_attribute_lower", self.reg.bg_attribute_lower);
+        state.add_bytes("ppu.vram", &self.vram);
+        state.add_bytes("ppu.palette_ram", &self.palette_ram);
+        state.add_bytes("ppu.oam", &self.oam);
+        state.add_bytes("ppu.secondary_oam", &self.secondary_oam);
+        state.add_u8("ppu.oam_latch", self.oam_latch);
+        state.add_u8("ppu.evaluation.n", self.evaluation.n);
+        state.add_u8("ppu.evaluation.m", self.evaluation.m);
+        state.add_u8("ppu.evaluation.found", self.evaluation.found);
+        state.add_bool("ppu.evaluation.done", self.evaluation.done);
+        state.add_bool("ppu.evaluation.sprite0_in_range", self.evaluation.sprite0_in_range);
+        let sprites: Vec<u8> = self.sprites.iter()
+            .flat_map(|s| vec![s.x, s.attributes, s.pattern_lower, s.pattern_upper])
+            .collect();
+        state.add_bytes("ppu.sprites", &sprites);
+    }
+
+    pub fn load_state(&mut self, state: &Snapshot) {
+        self.scan_line = state.get_u16("ppu.scan_line") as i16;
+        self.cycle_count = state.get_u16("ppu.cycle_count");
+        self.dot_remainder = state.get_u16("ppu.dot_remainder");
+        self.dot_count = state.get_u64("ppu.dot_count");
+        self.vblank = state.get_bool("ppu.vblank");
+        self.vblank_suppressed = state.get_bool("ppu.vblank_suppressed");
+        self.odd_frame = state.get_bool("ppu.odd_frame");
+        self.vram_addr_increment = state.get_u16("ppu.vram_addr_increment");
+        self.gen_nmi_at_vblank = state.get_bool("ppu.gen_nmi_at_vblank");
+        self.background_leftmost_enabled = state.get_bool("ppu.background_leftmost_enabled");
+        self.sprites_leftmost_enabled = state.get_bool("ppu.sprites_leftmost_enabled");
+        self.background_enabled = state.get_bool("ppu.background_enabled");
+        self.sprites_enabled = state.get_bool("ppu.sprites_enabled");
+        self.greyscale = state.get_bool("ppu.greyscale");
+        self.emphasis = state.get_u8("ppu.emphasis");
+        self.bg_pattern_table_addr = state.get_u16("ppu.bg_pattern_table_addr");
+        self.sprite_pattern_table_addr = state.get_u16("ppu.sprite_pattern_table_addr");
+        self.sprite_height = state.get_u8("ppu.sprite_height");
+        self.sprite0_enabled = state.get_bool("ppu.sprite0_enabled");
+        self.sprite0_hit = state.get_bool("ppu.sprite0_hit");
+        self.sprite0_hit_pending = state.get_bool("ppu.sprite0_hit_pending");
+        self.warming_up = state.get_bool("ppu.warming_up");
+        self.sprite_overflow = state.get_bool("ppu.sprite_overflow");
+        self.oam_addr = state.get_u8("ppu.oam_addr");
+        self.io_latch = state.get_u8("ppu.io_latch");
+        self.io_latch_age.copy_from_slice(state.get_bytes("ppu.io_latch_age"));
+        self.reg.v = state.get_u16("ppu.v");
+        self.reg.t = state.get_u16("ppu.t");
+        self.reg.x = state.get_u8("ppu.x");
+        self.reg.w = state.get_bool("ppu.w");
+        self.v_update = match state.get_u8("ppu.v_update_delay") {
+            0 => None,
+            delay => Some((state.get_u16("ppu.v_update"), delay)),
+        };
+        self.reg.vram_read_buffer = state.get_u8("ppu.vram_read_buffer");
+        self.reg.bg_pattern_upper = state.get_u16("ppu.bg_pattern_upper");
+        self.reg.bg_pattern_lower = state.get_u16("ppu.bg_pattern_lower");
+        self.reg.bg_attribute_latch = state.get_u8("ppu.bg_attribute_latch");
+        self.reg.bg_attribute_upper = state.get_u8("ppu.bg_attribute_upper");
+        self.reg.bg_attribute_lower = state.get_u8("ppu.bg_attribute_lower");
+        self.vram.copy_from_slice(state.get_bytes("ppu.vram"));
+        self.palette_ram.copy_from_slice(state.get_bytes("ppu.palette_ram"));
+        self.oam.copy_from_slice(state.get_bytes("ppu.oam"));
+        self.secondary_oam.copy_from_slice(state.get_bytes("ppu.secondary_oam"));
+        self.oam_latch = state.get_u8("ppu.oam_latch");
+        self.evaluation.n = state.get_u8("ppu.evaluation.n");
+        self.evaluation.m = state.get_u8("ppu.evaluation.m");
+        self.evaluation.found = state.get_u8("ppu.evaluation.found");
+        self.evaluation.done = state.get_bool("ppu.evaluation.done");
+        self.evaluation.sprite0_in_range = state.get_bool("ppu.evaluation.sprite0_in_range");
+        for (sprite, bytes) in self.sprites.iter_mut().zip(state.get_bytes("ppu.sprites").chunks(4)) {
+            *sprite = Sprite { x: bytes[0], attributes: bytes[1],
+                               pattern_lower: bytes[2], pattern_upper: bytes[3] };
+        }
+    }
+
+    // Adds what a machine state of the version lacks to make it one of the
+    // next version. States from before the versions were kept are upgraded
+    // from version 0 whatever they have, so only missing sections are added.
+    pub fn upgrade_state(state: &mut Snapshot, version: u32) {
+        match version {
+            0 => {
+                state.add_missing("ppu.dot_count", &0u64.to_le_bytes());
+            }
+            1 => {
+                state.add_missing("ppu.sprite0_hit_pending", &[0]);
+                state.add_missing("ppu.warming_up", &[0]);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn component_state(&self) -> ComponentState {
+        let mut state = Snapshot::new();
+        self.save_state(&mut state);
+        ComponentState::new(STATE_VERSION, &state)
+    }
+
+    pub fn restore_component_state(&mut self, state: &ComponentState) -> Result<(), String> {
+        let mut layout = Snapshot::new();
+        self.save_state(&mut layout);
+        self.load_state(&state.to_snapshot(STATE_VERSION, &layout)?);
+        Ok(())
+    }
+
+    pub fn get_scroll_state_string(&self) -> String {
+        format!("v:{:04X} t:{:04X} x:{} w:{}",
+                self.reg.v, self.reg.t, self.reg.x, if self.reg.w { 1 } else { 0 })
+    }
+
+    pub fn set_left_clip_mode(&mut self, mode: LeftClipMode) {
+        self.left_clip_mode = mode;
+    }
+
+    // Off by default, as few games depend on it. Enabling it starts the
+    // warm-up as if the console had just been switched on.
+    pub fn set_warm_up(&mut self, enabled: bool) {
+        self.warm_up = enabled;
+        self.warming_up = enabled;
+    }
+
+    pub fn set_layers_shown(&mut self, background: bool, sprites: bool) {
+        self.background_layer_shown = background;
+        self.sprite_layer_shown = sprites;
+    }
+
+    pub fn layers_shown(&self) -> (bool, bool) {
+        (self.background_layer_shown, self.sprite_layer_shown)
+    }
+
+    fn get_background_pixel(&self, ignore_clipping: bool) -> u8 {
         if !self.background_enabled ||
-            (self.cycle_count < 8 && !self.background_leftmost_enabled) {
+            (self.cycle_count < 8 && !self.background_leftmost_enabled && !ignore_clipping) {
             return 0;
         }
 
@@ -239,59 +636,25 @@ impl Ppu {
                 (bg_pattern_upper << 1) | (bg_pattern_lower << 0);
     }
 
-    fn get_sprite_pixel(&self, cartridge: &mut cartridge::Cartridge)
-                        -> (u8, SpritePriority, bool) {
-        if self.sprites_enabled && (self.cycle_count >= 8 || self.sprites_leftmost_enabled) {
+    fn get_sprite_pixel(&self, ignore_clipping: bool) -> (u8, SpritePriority, bool) {
+        if self.sprites_enabled &&
+                (self.cycle_count >= 8 || self.sprites_leftmost_enabled || ignore_clipping) {
             let x = self.cycle_count;
-            let y = self.scan_line;
-            for i in 0..8 {
-                let sprite_y = self.secondary_oam[i*4] as i16;
-                let sprite_x = self.secondary_oam[i*4 + 3] as u16;
-                if sprite_x <= x && x < sprite_x + 8 && sprite_y < 0xEF {
-                    let mut tile_x = x - sprite_x;
-                    let mut tile_y = (y - 1 - sprite_y) as u16;
-
-                    let tile_index = self.secondary_oam[i*4 + 1] as u16;
-                    let palette_bits = 4 + (self.secondary_oam[i*4 + 2] & 0x3);
-                    let priority = if self.secondary_oam[i*4 + 2] & 0x20 != 0 {
-                        SpritePriority::Back
-                    }
-                    else {
-                        SpritePriority::Front
-                    };
-                    let flip_horiz = self.secondary_oam[i*4 + 2] & 0x40 != 0;
-                    let flip_vert = self.secondary_oam[i*4 + 2] & 0x80 != 0;
-
-                    if flip_horiz {
-                        tile_x = 7 - tile_x;
-                    }
-                    if flip_vert {
-                        tile_y = 7 - tile_y;
-                    }
-
-                    let pattern_address_lower =
-                        self.sprite_pattern_table_addr | (tile_index << 4) | tile_y;
-                    let pattern_address_upper = pattern_address_lower | 0x0008;
-
-                    if pattern_address_lower > 0x4000 {
-                        println!("spta {:04X}, ti {}, ty {} sy {}, y {}",
-                                 self.sprite_pattern_table_addr, tile_index, tile_y,
-                                 sprite_y, y);
-                    }
-
-                    let bitmap_row_lower =
-                        self.read_mem_ppu(pattern_address_lower, cartridge);
-                    let bitmap_row_upper =
-                        self.read_mem_ppu(pattern_address_upper, cartridge);
-
-                    let pattern_bit_lower = bitmap_row_lower & (0x80 >> tile_x) != 0;
-                    let pattern_bit_upper = bitmap_row_upper & (0x80 >> tile_x) != 0;
-                    let pattern_bits = (if pattern_bit_upper {2} else {0}) +
-                        (if pattern_bit_lower {1} else {0});
-
-                    let index = (palette_bits << 2) | pattern_bits;
-
+            for (i, sprite) in self.sprites.iter().enumerate() {
+                let sprite_x = sprite.x as u16;
+                if sprite_x <= x && x < sprite_x + 8 {
+                    let bit = 0x80 >> (x - sprite_x);
+                    let pattern_bits = (if sprite.pattern_upper & bit != 0 {2} else {0}) +
+                        (if sprite.pattern_lower & bit != 0 {1} else {0});
                     if pattern_bits != 0 {
+                        let palette_bits = 4 + (sprite.attributes & 0x3);
+                        let priority = if sprite.attributes & 0x20 != 0 {
+                            SpritePriority::Back
+                        }
+                        else {
+                            SpritePriority::Front
+                        };
+                        let index = (palette_bits << 2) | pattern_bits;
                         return (index, priority, i == 0 && self.sprite0_enabled);
                     }
                 }
@@ -301,12 +664,25 @@ impl Ppu {
     }
 
     fn draw_pixel(&mut self, cartridge: &mut cartridge::Cartridge) {
-        let background_index = self.get_background_pixel();
-        let (sprite_index, prio, sprite0) = self.get_sprite_pixel(cartridge);
+        let clipped = self.cycle_count < 8 &&
+            (!self.background_leftmost_enabled || !self.sprites_leftmost_enabled);
+        let show_clipped = clipped && self.left_clip_mode != LeftClipMode::Clip;
+        let background_index = self.get_background_pixel(show_clipped);
+        let (sprite_index, prio, sprite0) = self.get_sprite_pixel(show_clipped);
+        // Never at x = 255, nor in the leftmost 8 pixels when either layer is
+        // clipped there
+        if sprite_index & 0x3 != 0 && background_index & 0x3 != 0 && sprite0 &&
+                self.cycle_count != 255 && !clipped {
+            self.sprite0_hit_pending = true;
+        }
+        // The top and bottom 8 lines are hidden, like on most TVs, but
+        // sprite 0 hits there all the same
+        if self.scan_line < 8 || self.scan_line >= 232 {
+            return;
+        }
+        let background_index = if self.background_layer_shown { background_index } else { 0 };
+        let sprite_index = if self.sprite_layer_shown { sprite_index } else { 0 };
         let index = if sprite_index & 0x3 != 0 && background_index & 0x3 != 0 {
-            if sprite0 && self.cycle_count != 255 {
-                self.sprite0_hit = true;
-            }
             if prio == SpritePriority::Front {
                 sprite_index
             }
@@ -322,31 +698,73 @@ impl Ppu {
         };
 
         let palette_address = 0x3F00 + (index as u16);
-        let color_index = self.read_mem_ppu(palette_address, cartridge) as usize;
+        let color_index = self.read_mem_ppu(palette_address, cartridge);
+        let mut color = self.get_color(color_index);
+        if clipped {
+            match self.left_clip_mode {
+                LeftClipMode::Dim => {
+                    for component in color.iter_mut() {
+                        *component /= 2;
+                    }
+                }
+                LeftClipMode::Black => { color = [0, 0, 0]; }
+                LeftClipMode::Clip | LeftClipMode::Show => {}
+            }
+        }
+        self.output_pixel(color);
+    }
 
-        let red = self.colors[color_index * 3 + 0];
-        let green = self.colors[color_index * 3 + 1];
-        let blue = self.colors[color_index * 3 + 2];
-        self.renderer.set_draw_color(Color::RGB(red, green, blue));
+    // With rendering disabled the backdrop color is shown, unless v points into
+    // the palette, in which case the color at that address is shown instead
+    fn draw_backdrop_pixel(&mut self) {
+        let address = if self.reg.v & 0x3F00 == 0x3F00 { self.reg.v } else { 0x3F00 };
+        let color_index = self.palette_ram[Ppu::get_palette_index(address)];
+        let color = self.get_color(color_index);
+        self.output_pixel(color);
+    }
 
-        let x = self.cycle_count as i32;
-        let y = self.scan_line as i32;
-        self.renderer.draw_point(Point::new(x, y)).unwrap();
+    fn output_pixel(&mut self, color: [u8; 3]) {
+        let offset = (self.scan_line as usize * screen::WIDTH as usize +
+                      self.cycle_count as usize) * 3;
+        self.picture[offset..offset + 3].clone_from_slice(&color);
+    }
+
+    // Applies greyscale and color emphasis from $2001 to a palette entry
+    fn get_color(&self, color_index: u8) -> [u8; 3] {
+        let color_index = if self.greyscale { color_index & 0x30 } else { color_index & 0x3F };
+        let offset = color_index as usize * 3;
+        let mut color = [self.colors[offset], self.colors[offset + 1], self.colors[offset + 2]];
+        // The RGB PPU has no emphasis
+        if self.emphasis != 0 && self.variant != PpuVariant::Rgb {
+            // The PAL PPUs have the red and green emphasis bits swapped
+            let (red, green) = match self.variant {
+                PpuVariant::Ntsc | PpuVariant::Rgb => (0x1, 0x2),
+                PpuVariant::Pal | PpuVariant::Dendy => (0x2, 0x1),
+            };
+            let emphasized = [self.emphasis & red != 0, self.emphasis & green != 0,
+                              self.emphasis & 0x4 != 0];
+            for (component, emphasized) in color.iter_mut().zip(emphasized.iter()) {
+                if !emphasized {
+                    *component = (*component as u16 * 13 / 16) as u8;
+                }
+            }
+        }
+        color
     }
 
     fn load_bg_tile(&mut self, cartridge: &mut cartridge::Cartridge) {
         // pattern
         let tile_address = 0x2000 | (self.reg.v & 0x0FFF);
-        let tile = self.read_mem_ppu(tile_address, cartridge) as u16;
+        let tile = self.fetch_mem_ppu(tile_address, cartridge) as u16;
 
         let fine_y = self.reg.v >> 12;
         let pattern_address_lower = self.bg_pattern_table_addr | (tile << 4) | fine_y;
         let pattern_address_upper = pattern_address_lower + 8;
 
         let bitmap_row_lower =
-            self.read_mem_ppu(pattern_address_lower, cartridge) as u16;
+            self.fetch_mem_ppu(pattern_address_lower, cartridge) as u16;
         let bitmap_row_upper =
-            self.read_mem_ppu(pattern_address_upper, cartridge) as u16;
+            self.fetch_mem_ppu(pattern_address_upper, cartridge) as u16;
 
         self.reg.bg_pattern_lower |= bitmap_row_lower;
         self.reg.bg_pattern_upper |= bitmap_row_upper;
@@ -354,7 +772,7 @@ impl Ppu {
         // attribute
         let attribute_address = 0x23C0 | (self.reg.v & 0x0C00)
             | ((self.reg.v >> 4) & 0x38) | ((self.reg.v >> 2) & 0x07);
-        let attribute = self.read_mem_ppu(attribute_address, cartridge);
+        let attribute = self.fetch_mem_ppu(attribute_address, cartridge);
 
         let attr_x = self.reg.v & 0x0002 != 0;
         let attr_y = self.reg.v & 0x0040 != 0;
@@ -406,16 +824,47 @@ impl Ppu {
         }
     }
 
-    pub fn step_cycle(&mut self, count: u16, cartridge: &mut cartridge::Cartridge) -> bool {
-        for _ in 0..count*3 {
-            if self.background_enabled || self.sprites_enabled {
-                if self.scan_line == -1 {
-                    if self.cycle_count >= 280 && self.cycle_count <= 304 {
-                        // copy vertical bits
-                        self.reg.v = copy_bits(self.reg.v, self.reg.t, 0x7BE0);
-                    }
+    pub fn step_cycle(&mut self, count: u16, cartridge: &mut cartridge::Cartridge) {
+        let dots = match self.variant {
+            PpuVariant::Ntsc | PpuVariant::Dendy | PpuVariant::Rgb => count * 3,
+            PpuVariant::Pal => {
+                // 3.2 dots per CPU cycle
+                let sixteenths = count * 16 + self.dot_remainder;
+                self.dot_remainder = sixteenths % 5;
+                sixteenths / 5
+            }
+        };
+        for _ in 0..dots {
+            self.dot_count += 1;
+            if let Some((address, delay)) = self.v_update {
+                self.v_update = if delay > 1 { Some((address, delay - 1)) } else { None };
+                if delay == 1 {
+                    self.reg.v = address;
                 }
-                else if self.scan_line < 240 {
+            }
+            if self.sprite0_hit_pending {
+                self.sprite0_hit = true;
+                self.sprite0_hit_pending = false;
+            }
+            if self.cycle_count == 1 {
+                if self.scan_line == self.vblank_scan_line() {
+                    self.frame_buffer.copy_from_slice(&self.picture);
+                    self.vblank = !self.vblank_suppressed;
+                    self.vblank_suppressed = false;
+                    self.decay_io_latch();
+                }
+                else if self.scan_line == -1 {
+                    self.vblank = false;
+                    self.warming_up = false;
+                    self.sprite0_hit = false;
+                    self.sprite_overflow = false;
+                }
+            }
+            if self.background_enabled || self.sprites_enabled {
+                // The pre-render line updates v like the visible lines, so
+                // that the first two tiles of line 0 are fetched from the
+                // scroll position in t
+                if self.scan_line < 240 {
                     if self.cycle_count == 256 {
                         self.increment_v_vertical();
                     }
@@ -423,18 +872,19 @@ impl Ppu {
                         // copy horizontal bits
                         self.reg.v = copy_bits(self.reg.v, self.reg.t, 0x041F);
                     }
+                    else if self.scan_line == -1 && self.cycle_count >= 280 && self.cycle_count <= 304 {
+                        // copy vertical bits
+                        self.reg.v = copy_bits(self.reg.v, self.reg.t, 0x7BE0);
+                    }
                     if (self.cycle_count > 0 && self.cycle_count <= 256) ||
                             (self.cycle_count == 328 || self.cycle_count == 336) {
                         if self.cycle_count % 8 == 0 {
-                            if self.scan_line == -1 && self.cycle_count >= 328 ||
-                                    self.scan_line >= 0 && self.scan_line < 240 {
-                                self.load_bg_tile(cartridge);
-                                self.increment_v_horizontal();
-                            }
+                            self.load_bg_tile(cartridge);
+                            self.increment_v_horizontal();
                         }
                     }
                 }
-                if self.scan_line >= 8 && self.scan_line < 232 && self.cycle_count < 256 {
+                if self.scan_line >= 0 && self.scan_line < 240 && self.cycle_count < 256 {
                     self.draw_pixel(cartridge);
                 }
                 if self.cycle_count < 336 {
@@ -449,98 +899,235 @@ impl Ppu {
                         self.reg.bg_attribute_upper |= 0x01;
                     }
                 }
-                if self.cycle_count >= 257 && self.cycle_count <= 320 {
-                    self.oam_addr = 0;
+                if self.scan_line < 240 {
+                    if self.scan_line >= 0 && self.cycle_count >= 1 && self.cycle_count <= 256 {
+                        self.evaluate_sprites();
+                    }
+                    if self.cycle_count >= 257 && self.cycle_count <= 320 {
+                        self.oam_addr = 0;
+                        if (self.cycle_count - 257) & 7 == 0 {
+                            self.fetch_sprite(cartridge, (self.cycle_count - 257) as usize / 8);
+                        }
+                    }
                 }
             }
+            else if self.scan_line >= 8 && self.scan_line < 232 && self.cycle_count < 256 {
+                self.draw_backdrop_pixel();
+            }
             self.cycle_count += 1;
+            // With rendering enabled, the last dot of the pre-render line is
+            // skipped on every other frame (2C02 only)
+            if self.scan_line == -1 && self.cycle_count == 340 && self.odd_frame &&
+                    (self.background_enabled || self.sprites_enabled) &&
+                    self.variant == PpuVariant::Ntsc {
+                self.cycle_count = 341;
+            }
             if self.cycle_count >= 341 {
                 self.cycle_count -= 341;
-                if self.scan_line < 240 {
-                    self.prepare_sprites();
+                if self.scan_line == -1 {
+                    self.odd_frame = !self.odd_frame;
                 }
                 self.scan_line += 1;
-                if self.scan_line == 241 {
-                    self.vblank = true;
-                }
-                if self.scan_line >= 261 {
+                if self.scan_line >= self.pre_render_scan_line() {
                     self.scan_line = -1;
-                    self.vblank = false;
-                    self.sprite0_hit = false;
                 }
             }
         }
+    }
 
-        let nmi_line = !(self.vblank && self.gen_nmi_at_vblank);
-        nmi_line
+    // The NMI output (active low). It follows the vblank flag and the NMI
+    // enable bit, so enabling NMI during vblank asserts it right away. It is
+    // held back for the first two dots of vblank (dots 1 and 2), where a
+    // $2002 read still clears the flag in time to suppress the NMI.
+    pub fn nmi_line(&self) -> bool {
+        let vblank_starting = self.scan_line == self.vblank_scan_line() && self.cycle_count < 4;
+        !(self.vblank && self.gen_nmi_at_vblank && !vblank_starting)
     }
 
-    fn prepare_sprites(&mut self) {
-        for i in 0..32 {
-            self.secondary_oam[i] = 0xFF;
+    // Dots 1-64 clear the secondary OAM and dots 65-256 fill it with the
+    // sprites on the next scan line, reading from OAM on odd dots and writing
+    // to the secondary OAM on even dots
+    fn evaluate_sprites(&mut self) {
+        let dot = self.cycle_count;
+        if dot <= 64 {
+            self.oam_latch = 0xFF;
+            if dot & 1 == 0 {
+                self.secondary_oam[dot as usize / 2 - 1] = 0xFF;
+            }
+            return;
+        }
+        if dot == 65 {
+            self.evaluation = SpriteEvaluation::default();
         }
-        if self.scan_line == -1 {
+        let e = &self.evaluation;
+        if dot & 1 != 0 {
+            self.oam_latch = self.oam[e.n as usize * 4 + e.m as usize];
             return;
         }
-        self.sprite0_enabled = false;
-        let mut offset = 0;
-        let mut offset_2nd = 0;
-        while offset < 256 && offset_2nd < 32 {
-            let y = self.oam[offset] as i16;
-            if self.scan_line >= y && self.scan_line < y + 8 {
-                self.secondary_oam[offset_2nd..offset_2nd + 4].
-                    clone_from_slice(&self.oam[offset..offset + 4]);
-                offset_2nd += 4;
-                if offset == 0 {
-                    self.sprite0_enabled = true;
+
+        let value = self.oam_latch;
+        let in_range = self.scan_line >= value as i16 &&
+            self.scan_line < value as i16 + self.sprite_height as i16;
+        let e = &mut self.evaluation;
+        if e.done {
+            // The remaining dots only read the Y coordinates
+            e.n = (e.n + 1) % 64;
+        }
+        else if e.found < 8 {
+            self.secondary_oam[e.found as usize * 4 + e.m as usize] = value;
+            if e.m == 0 && !in_range {
+                e.next_sprite();
+            }
+            else {
+                if e.n == 0 {
+                    e.sprite0_in_range = true;
+                }
+                e.m += 1;
+                if e.m == 4 {
+                    e.m = 0;
+                    e.found += 1;
+                    e.next_sprite();
                 }
             }
-            offset += 4;
         }
+        else if in_range {
+            self.sprite_overflow = true;
+            e.done = true;
+        }
+        else {
+            // The hardware increments both the sprite and the byte here, so
+            // after the eighth sprite it compares tile numbers, attributes
+            // and X coordinates as Y coordinates
+            e.m = (e.m + 1) % 4;
+            e.next_sprite();
+        }
+    }
+
+    // Dots 257-320 fetch the eight sprites found for the next scan line, one
+    // every eight dots. The pre-render line finds none, as no evaluation was
+    // done for line 0. The patterns of tile $FF are fetched for the slots
+    // left empty, which mappers watching A12 see.
+    fn fetch_sprite(&mut self, cartridge: &mut cartridge::Cartridge, slot: usize) {
+        if slot == 0 {
+            self.sprite0_enabled = self.evaluation.sprite0_in_range && self.scan_line >= 0;
+        }
+        if slot >= self.evaluation.found as usize || self.scan_line < 0 {
+            let pattern_address = self.sprite_pattern_table_addr | 0x0FF0;
+            self.fetch_mem_ppu(pattern_address, cartridge);
+            self.fetch_mem_ppu(pattern_address | 0x0008, cartridge);
+            self.sprites[slot] = Sprite::default();
+            return;
+        }
+        let entry = &self.secondary_oam[slot * 4..slot * 4 + 4];
+        let (y, tile_index, attributes, x) = (entry[0], entry[1] as u16, entry[2], entry[3]);
+        let mut tile_y = (self.scan_line - y as i16) as u16;
+        if attributes & 0x80 != 0 {
+            tile_y = 7 - tile_y;
+        }
+        let pattern_address_lower = self.sprite_pattern_table_addr | (tile_index << 4) | tile_y;
+        let pattern_address_upper = pattern_address_lower | 0x0008;
+        let mut pattern_lower = self.fetch_mem_ppu(pattern_address_lower, cartridge);
+        let mut pattern_upper = self.fetch_mem_ppu(pattern_address_upper, cartridge);
+        if attributes & 0x40 != 0 {
+            pattern_lower = pattern_lower.reverse_bits();
+            pattern_upper = pattern_upper.reverse_bits();
+        }
+        self.sprites[slot] = Sprite { x, attributes, pattern_lower, pattern_upper };
+    }
+
+    // Replaces the colors with a palette in the common .pal format, 64 RGB triplets
+    pub fn set_palette(&mut self, colors: &[u8]) {
+        self.colors = colors[..64 * 3].to_vec();
     }
 
     pub fn read_mem(&mut self, cartridge: &mut cartridge::Cartridge, cpu_address: u16) -> u8 {
-        match cpu_address {
-            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 => { // Write-only registers, return 0
-                0
-            }
+        // The value and the bits of it that the register drives. The other
+        // bits come from the I/O latch.
+        let (value, driven) = match cpu_address {
             0x2002 => {
                 let mut value = if self.vblank {0x80} else {0x00};
                 value |= if self.sprite0_hit {0x40} else {0x00};
+                value |= if self.sprite_overflow {0x20} else {0x00};
                 if self.mem_read_mut_enabled {
                     self.vblank = false;
                     self.reg.w = false;
+                    // Reading one dot before vblank starts returns the flag
+                    // as clear, and it then stays clear for the whole frame
+                    if self.scan_line == self.vblank_scan_line() && self.cycle_count == 1 {
+                        self.vblank_suppressed = true;
+                    }
                 }
-                value |= self.last_written_value & 0b0001_1111;
-                value
+                (value, 0xE0)
             }
             0x2004 => {
-                if self.vblank {
-                    self.oam[self.oam_addr as usize]
+                // During rendering the value on the OAM bus is read, which
+                // follows the sprite evaluation
+                let rendering = (self.background_enabled || self.sprites_enabled) &&
+                    self.scan_line >= 0 && self.scan_line < 240;
+                if rendering {
+                    (self.oam_latch, 0xFF)
                 }
                 else {
-                    0
+                    (self.oam[self.oam_addr as usize], 0xFF)
                 }
             }
-            0x2007 => {
-                if self.mem_read_mut_enabled {
-                    let addr = self.reg.v;
-                    self.reg.v += self.vram_addr_increment;
-                    let return_value = self.reg.vram_read_buffer;
-                    self.reg.vram_read_buffer = self.read_mem_ppu(addr, cartridge);
-                    return_value
+            0x2007 if self.mem_read_mut_enabled => {
+                self.finish_v_update();
+                let addr = self.reg.v & 0x3FFF;
+                self.increment_v_after_access();
+                if addr >= 0x3F00 {
+                    // Palette reads are not buffered, but the buffer is filled
+                    // with the name table byte "under" the palette. The upper
+                    // two bits are open bus.
+                    self.reg.vram_read_buffer = self.read_mem_ppu(addr - 0x1000, cartridge);
+                    let value = self.read_mem_ppu(addr, cartridge);
+                    let value = if self.greyscale { value & 0x30 } else { value };
+                    (value, 0x3F)
                 }
                 else {
-                    0
+                    let return_value = self.reg.vram_read_buffer;
+                    self.reg.vram_read_buffer = self.fetch_mem_ppu(addr, cartridge);
+                    (return_value, 0xFF)
                 }
             }
-            _ => panic!("Unimplemented read address: {:04X}", cpu_address)
+            // Write-only registers, and $2007 when the read must not change
+            // the state
+            _ => (0, 0x00),
+        };
+        let value = (value & driven) | (self.io_latch & !driven);
+        if self.mem_read_mut_enabled {
+            self.refresh_io_latch(value, driven);
+        }
+        value
+    }
+
+    // The I/O latch holds the last value on the data bus between the CPU and
+    // the PPU. Each bit that is not refreshed by a write or read decays to 0
+    // after about 600 ms.
+    fn refresh_io_latch(&mut self, value: u8, mask: u8) {
+        self.io_latch = (self.io_latch & !mask) | (value & mask);
+        for (bit, age) in self.io_latch_age.iter_mut().enumerate() {
+            if mask & (1 << bit) != 0 {
+                *age = 0;
+            }
+        }
+    }
+
+    fn decay_io_latch(&mut self) {
+        for (bit, age) in self.io_latch_age.iter_mut().enumerate() {
+            *age = age.saturating_add(1);
+            if *age >= IO_LATCH_DECAY_FRAMES {
+                self.io_latch &= !(1 << bit);
+            }
         }
     }
 
     pub fn write_mem(&mut self, cpu_address: u16, value: u8,
                      cartridge: &mut cartridge::Cartridge) {
-        self.last_written_value = value;
+        self.refresh_io_latch(value, 0xFF);
+        if self.warming_up && matches!(cpu_address, 0x2000 | 0x2001 | 0x2005 | 0x2006) {
+            return;
+        }
         match cpu_address {
             0x2000 => {
                 self.vram_addr_increment = if (value & 0x04) == 0 { 1 } else { 32 };
@@ -554,6 +1141,8 @@ impl Ppu {
                 }
             }
             0x2001 => {
+                self.greyscale = value & 0x01 != 0;
+                self.emphasis = value >> 5;
                 self.background_leftmost_enabled = value & 0x02 != 0;
                 self.sprites_leftmost_enabled = value & 0x04 != 0;
                 self.background_enabled = value & 0x08 != 0;
@@ -586,24 +1175,76 @@ impl Ppu {
                 }
                 else {
                     self.reg.t = copy_bits(self.reg.t, value as u16, 0x00FF);
-                    self.reg.v = self.reg.t;
+                    self.v_update = Some((self.reg.t, V_UPDATE_DELAY));
                 }
                 self.reg.w = !self.reg.w;
             }
             0x2007 => {
-                let addr = self.reg.v;
+                self.finish_v_update();
+                let addr = self.reg.v & 0x3FFF;
                 self.write_mem_ppu(addr, value, cartridge);
-                self.reg.v += self.vram_addr_increment;
+                cartridge.notify_ppu_fetch(addr, self.dot_count);
+                self.increment_v_after_access();
             }
             _ => panic!("Unimplemented write address: {:04X}", cpu_address)
         }
+        if cpu_address == 0x2000 || cpu_address == 0x2005 || cpu_address == 0x2006 {
+            log::trace!(target: "ppu", "SL:{} CYC:{} ${:04X}={:02X} {}", self.scan_line, self.cycle_count,
+                        cpu_address, value, self.get_scroll_state_string());
+        }
+    }
+
+    // The CPU can't reach $2007 before a $2006 write has reached v, but code
+    // driving the PPU directly can
+    fn finish_v_update(&mut self) {
+        if let Some((address, _)) = self.v_update.take() {
+            self.reg.v = address;
+        }
+    }
+
+    // While rendering, accessing $2007 increments both coarse X and Y instead
+    // of adding the configured increment
+    fn increment_v_after_access(&mut self) {
+        let rendering = (self.background_enabled || self.sprites_enabled) &&
+            self.scan_line < 240;
+        if rendering {
+            self.increment_v_horizontal();
+            self.increment_v_vertical();
+        }
+        else {
+            self.reg.v = (self.reg.v + self.vram_addr_increment) & 0x7FFF;
+        }
+    }
+
+    // OAM DMA writes through $2004, starting at the current OAM address
+    pub fn write_oam_dma(&mut self, value: u8) {
+        self.oam[self.oam_addr as usize] = value;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    // Reads and writes of the PPU address space and OAM for the debug views,
+    // which the cartridge does not notice
+    pub fn peek_vram(&self, ppu_address: u16, cartridge: &cartridge::Cartridge) -> u8 {
+        self.read_mem_ppu(ppu_address & 0x3FFF, cartridge)
+    }
+
+    pub fn poke_vram(&mut self, ppu_address: u16, value: u8, cartridge: &mut cartridge::Cartridge) {
+        self.write_mem_ppu(ppu_address & 0x3FFF, value, cartridge);
+    }
+
+    pub fn peek_oam(&self, index: u8) -> u8 {
+        self.oam[index as usize]
+    }
+
+    pub fn poke_oam(&mut self, index: u8, value: u8) {
+        self.oam[index as usize] = value;
     }
 
-    pub fn perform_dma(&mut self, cartridge: &mut cartridge::Cartridge,
-                       memory: &[u8], start_addr: u16) {
-        let end_addr = start_addr + 256;
-        self.oam.clone_from_slice(&memory[start_addr as usize .. end_addr as usize]);
-        self.step_cycle(513, cartridge);
+    // $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
+    fn get_palette_index(ppu_address: u16) -> usize {
+        let index = ppu_address & 0x001F;
+        let index = if index & 0x0013 == 0x0010 { index - 0x10 } else { index };
+        index as usize
     }
 
     fn read_mem_ppu(&self, ppu_address: u16, cartridge: &cartridge::Cartridge) -> u8 {
@@ -611,37 +1252,539 @@ impl Ppu {
             cartridge.read_mem_ppu(ppu_address, &self.vram)
         }
         else if ppu_address < 0x4000 {
-            let palette_address = ppu_address & 0xFF1F;
-            let palette_address = if (palette_address & 0xFFF3) == 0x3F10 {
-                (palette_address - 0x10) - 0x3F00
-            }
-            else {
-                palette_address - 0x3F00
-            };
-            self.palette_ram[palette_address as usize]
+            self.palette_ram[Ppu::get_palette_index(ppu_address)]
         }
         else {
             panic!("unexpected address: {:04X}", ppu_address);
         }
     }
 
+    // A read that the cartridge sees, unlike the reads for the debug views
+    fn fetch_mem_ppu(&self, ppu_address: u16, cartridge: &mut cartridge::Cartridge) -> u8 {
+        let value = self.read_mem_ppu(ppu_address, cartridge);
+        cartridge.notify_ppu_fetch(ppu_address, self.dot_count);
+        value
+    }
+
     fn write_mem_ppu(&mut self, ppu_address: u16, value: u8,
                      cartridge: &mut cartridge::Cartridge) {
         if ppu_address < 0x3F00 {
             cartridge.write_mem_ppu(ppu_address, value, &mut self.vram);
         }
         else if ppu_address < 0x4000 {
-            let palette_address = ppu_address & 0xFF1F;
-            let palette_address = if (palette_address & 0xFFF3) == 0x3F10 {
-                (palette_address - 0x10) - 0x3F00
-            }
-            else {
-                palette_address - 0x3F00
-            };
-            self.palette_ram[palette_address as usize] = value;
+            // Palette RAM is only 6 bits wide
+            self.palette_ram[Ppu::get_palette_index(ppu_address)] = value & 0x3F;
         }
         else {
             //panic!("unexpected address: {:04X}", ppu_address);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Including the update of v, which is done by the time the CPU can
+    // access the PPU again
+    fn set_address(ppu: &mut Ppu, cart: &mut cartridge::Cartridge, address: u16) {
+        ppu.write_mem(0x2006, (address >> 8) as u8, cart);
+        ppu.write_mem(0x2006, address as u8, cart);
+        ppu.finish_v_update();
+    }
+
+    fn new_ppu(cart: &mut cartridge::Cartridge) -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.write_mem(0x2001, 0x00, cart);
+        ppu.set_scan_line(241);
+        ppu
+    }
+
+    #[test]
+    fn component_state_round_trips_through_toml() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        set_address(&mut ppu, &mut cart, 0x2123);
+        ppu.write_mem(0x2007, 0x45, &mut cart);
+        ppu.write_mem(0x2004, 0x67, &mut cart);
+        ppu.step_cycle(10, &mut cart);
+        let state = ppu.component_state();
+        let text = toml::to_string(&state).unwrap();
+        let state: ComponentState = toml::from_str(&text).unwrap();
+
+        let mut restored = Ppu::new();
+        restored.restore_component_state(&state).unwrap();
+        assert_eq!(restored.component_state(), ppu.component_state());
+        assert_eq!(restored.state(), ppu.state());
+        assert_eq!(restored.peek_vram(0x2123, &cart), 0x45);
+
+        let mut old = state.clone();
+        old.version = STATE_VERSION + 1;
+        assert!(restored.restore_component_state(&old).is_err());
+        let mut broken = state;
+        broken.sections.remove("ppu.oam");
+        assert!(restored.restore_component_state(&broken).is_err());
+    }
+
+    #[test]
+    fn palette_backdrop_entries_are_mirrored() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        set_address(&mut ppu, &mut cart, 0x3F10);
+        ppu.write_mem(0x2007, 0x21, &mut cart);
+        set_address(&mut ppu, &mut cart, 0x3F14);
+        ppu.write_mem(0x2007, 0x22, &mut cart);
+        set_address(&mut ppu, &mut cart, 0x3F01);
+        ppu.write_mem(0x2007, 0x23, &mut cart);
+
+        set_address(&mut ppu, &mut cart, 0x3F00);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2007), 0x21);
+        set_address(&mut ppu, &mut cart, 0x3F04);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2007), 0x22);
+        // Only the backdrop entries of the sprite palettes are mirrored
+        set_address(&mut ppu, &mut cart, 0x3F11);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2007), 0x00);
+        // $3F20-$3FFF mirrors $3F00-$3F1F
+        set_address(&mut ppu, &mut cart, 0x3F21);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2007), 0x23);
+    }
+
+    #[test]
+    fn palette_read_is_not_buffered() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        set_address(&mut ppu, &mut cart, 0x2F05);
+        ppu.write_mem(0x2007, 0x55, &mut cart);
+        set_address(&mut ppu, &mut cart, 0x3F05);
+        ppu.write_mem(0x2007, 0x12, &mut cart);
+
+        set_address(&mut ppu, &mut cart, 0x3F05);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2007), 0x12);
+        // The buffer now holds the name table byte under the palette
+        set_address(&mut ppu, &mut cart, 0x2000);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2007), 0x55);
+    }
+
+    #[test]
+    fn palette_entries_are_six_bits_with_open_bus() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        set_address(&mut ppu, &mut cart, 0x3F02);
+        ppu.write_mem(0x2007, 0xFF, &mut cart);
+
+        set_address(&mut ppu, &mut cart, 0x3F02);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2007), 0x3F);
+        ppu.write_mem(0x2006, 0xFF, &mut cart);
+        ppu.write_mem(0x2006, 0x02, &mut cart);
+        ppu.write_mem(0x2003, 0xC0, &mut cart);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2007), 0xFF);
+    }
+
+    #[test]
+    fn palette_read_applies_greyscale() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        set_address(&mut ppu, &mut cart, 0x3F03);
+        ppu.write_mem(0x2007, 0x2A, &mut cart);
+        ppu.write_mem(0x2001, 0x01, &mut cart);
+
+        set_address(&mut ppu, &mut cart, 0x3F03);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2007), 0x20);
+        // Emphasis does not affect the value read back
+        ppu.write_mem(0x2001, 0xE0, &mut cart);
+        set_address(&mut ppu, &mut cart, 0x3F03);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2007), 0x2A);
+    }
+
+    #[test]
+    fn io_latch_is_read_back_and_decays() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        ppu.write_mem(0x2003, 0x5A, &mut cart);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2000), 0x5A);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2005), 0x5A);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2002), 0x1A);
+
+        // Reading $2002 refreshes bits 5-7 only, so the other bits decay
+        // first
+        for _ in 0..IO_LATCH_DECAY_FRAMES - 1 {
+            ppu.set_scan_line(241);
+            ppu.cycle_count = 0;
+            ppu.step_cycle(1, &mut cart);
+        }
+        assert_eq!(ppu.read_mem(&mut cart, 0x2002), 0x9A);
+        ppu.set_scan_line(241);
+        ppu.cycle_count = 0;
+        ppu.step_cycle(1, &mut cart);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2006), 0x80);
+    }
+
+    #[test]
+    fn scroll_writes_update_t_and_x() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        ppu.write_mem(0x2000, 0x03, &mut cart);
+        assert_eq!(ppu.get_scroll_state_string(), "v:0000 t:0C00 x:0 w:0");
+        ppu.write_mem(0x2005, 0x7D, &mut cart);
+        assert_eq!(ppu.get_scroll_state_string(), "v:0000 t:0C0F x:5 w:1");
+        ppu.write_mem(0x2005, 0x5E, &mut cart);
+        assert_eq!(ppu.get_scroll_state_string(), "v:0000 t:6D6F x:5 w:0");
+        // Reading $2002 resets the write toggle
+        ppu.write_mem(0x2005, 0x00, &mut cart);
+        ppu.read_mem(&mut cart, 0x2002);
+        ppu.write_mem(0x2005, 0x00, &mut cart);
+        assert_eq!(ppu.get_scroll_state_string(), "v:0000 t:6D60 x:0 w:1");
+    }
+
+    #[test]
+    fn address_writes_update_t_and_v() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        ppu.write_mem(0x2006, 0xFD, &mut cart);
+        // The first write clears bit 14 of t
+        assert_eq!(ppu.get_scroll_state_string(), "v:0000 t:3D00 x:0 w:1");
+        ppu.write_mem(0x2006, 0x42, &mut cart);
+        // v follows 3 dots (one CPU cycle) later
+        assert_eq!(ppu.get_scroll_state_string(), "v:0000 t:3D42 x:0 w:0");
+        ppu.step_cycle(1, &mut cart);
+        assert_eq!(ppu.get_scroll_state_string(), "v:3D42 t:3D42 x:0 w:0");
+    }
+
+    #[test]
+    fn split_screen_write_sequence() {
+        // The $2006/$2005/$2005/$2006 sequence used for mid-frame scroll
+        // changes, here setting name table 1, X = 0x5A and Y = 0x93
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        ppu.write_mem(0x2001, 0x18, &mut cart);
+        ppu.set_scan_line(100);
+        ppu.cycle_count = 260;
+        ppu.write_mem(0x2006, 0x04, &mut cart);
+        ppu.write_mem(0x2005, 0x93, &mut cart);
+        ppu.write_mem(0x2005, 0x5A, &mut cart);
+        ppu.write_mem(0x2006, ((0x93 & 0xF8) << 2 | (0x5A >> 3)) as u8, &mut cart);
+        ppu.step_cycle(1, &mut cart);
+        assert_eq!(ppu.get_scroll_state_string(), "v:364B t:364B x:2 w:0");
+
+        // The horizontal bits are copied from t again at dot 257
+        ppu.write_mem(0x2005, 0xF8, &mut cart);
+        ppu.write_mem(0x2005, 0x00, &mut cart);
+        ppu.set_scan_line(101);
+        ppu.cycle_count = 250;
+        ppu.step_cycle(3, &mut cart);
+        assert_eq!(ppu.reg.v & 0x041F, ppu.reg.t & 0x041F);
+        assert_eq!(ppu.reg.v & 0x041F, 0x041F);
+        assert_eq!(ppu.reg.x, 0);
+    }
+
+    #[test]
+    fn emphasis_dims_other_components() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        assert_eq!(ppu.get_color(0x30), [236, 238, 236]);
+        ppu.write_mem(0x2001, 0x20, &mut cart);
+        assert_eq!(ppu.get_color(0x30), [236, 193, 191]);
+        ppu.set_region(Region::Pal);
+        assert_eq!(ppu.get_color(0x30), [191, 238, 191]);
+    }
+
+    #[test]
+    fn rgb_ppu_has_own_colors_and_no_emphasis() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        ppu.set_variant(PpuVariant::Rgb);
+        assert_eq!(ppu.get_color(0x00), [109, 109, 109]);
+        assert_eq!(ppu.get_color(0x16), [255, 0, 0]);
+        ppu.write_mem(0x2001, 0xE0, &mut cart);
+        assert_eq!(ppu.get_color(0x16), [255, 0, 0]);
+        ppu.set_variant(PpuVariant::Ntsc);
+        assert_eq!(ppu.get_color(0x30), [236, 238, 236]);
+    }
+
+    #[test]
+    fn dendy_vblank_starts_at_line_291() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        ppu.set_variant(PpuVariant::Dendy);
+        ppu.write_mem(0x2000, 0x80, &mut cart);
+        ppu.set_scan_line(241);
+        ppu.cycle_count = 0;
+        ppu.step_cycle(2, &mut cart);
+        assert!(!ppu.vblank);
+        ppu.set_scan_line(291);
+        ppu.cycle_count = 0;
+        ppu.step_cycle(2, &mut cart);
+        assert!(ppu.vblank);
+        assert!(!ppu.nmi_line());
+
+        // 312 lines of 341 dots, without skipped dots
+        ppu.set_scan_line(-1);
+        ppu.cycle_count = 0;
+        ppu.write_mem(0x2001, 0x18, &mut cart);
+        for _ in 0..8 {
+            ppu.step_cycle(35464 / 8, &mut cart);
+        }
+        assert_eq!((ppu.scan_line, ppu.cycle_count), (-1, 0));
+    }
+
+    #[test]
+    fn backdrop_shows_palette_entry_at_v() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        set_address(&mut ppu, &mut cart, 0x3F00);
+        ppu.write_mem(0x2007, 0x0F, &mut cart);
+        ppu.write_mem(0x2007, 0x30, &mut cart);
+
+        set_address(&mut ppu, &mut cart, 0x3F01);
+        ppu.set_scan_line(10);
+        ppu.cycle_count = 0;
+        ppu.step_cycle(1, &mut cart);
+        let offset = 10 * screen::WIDTH as usize * 3;
+        assert_eq!(&ppu.picture[offset..offset + 3], &[236, 238, 236]);
+
+        set_address(&mut ppu, &mut cart, 0x2000);
+        ppu.step_cycle(1, &mut cart);
+        let offset = offset + 3 * 3;
+        assert_eq!(&ppu.picture[offset..offset + 3], &[0, 0, 0]);
+    }
+
+    #[test]
+    fn frame_buffer_keeps_the_last_completed_frame() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        set_address(&mut ppu, &mut cart, 0x3F00);
+        ppu.write_mem(0x2007, 0x30, &mut cart);
+        set_address(&mut ppu, &mut cart, 0x2000);
+        ppu.set_scan_line(10);
+        ppu.cycle_count = 0;
+        ppu.step_cycle(1, &mut cart);
+        let offset = 10 * screen::WIDTH as usize * 3;
+        assert_eq!(&ppu.frame_buffer()[offset..offset + 3], &[0, 0, 0]);
+
+        ppu.set_scan_line(241);
+        ppu.cycle_count = 0;
+        ppu.step_cycle(1, &mut cart);
+        assert_eq!(&ppu.frame_buffer()[offset..offset + 3], &[236, 238, 236]);
+    }
+
+    #[test]
+    fn status_read_at_vblank_start_suppresses_nmi() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        ppu.write_mem(0x2000, 0x80, &mut cart);
+
+        // One dot before: the flag reads as clear and is never set
+        ppu.set_scan_line(241);
+        ppu.cycle_count = 1;
+        assert_eq!(ppu.read_mem(&mut cart, 0x2002) & 0x80, 0);
+        ppu.step_cycle(1, &mut cart);
+        assert!(!ppu.vblank);
+        assert!(ppu.nmi_line());
+
+        // On the first dots: the flag reads as set, but there is no NMI
+        ppu.set_scan_line(241);
+        ppu.cycle_count = 0;
+        ppu.step_cycle(1, &mut cart);
+        assert!(ppu.nmi_line());
+        assert_eq!(ppu.read_mem(&mut cart, 0x2002) & 0x80, 0x80);
+        ppu.step_cycle(1, &mut cart);
+        assert!(ppu.nmi_line());
+
+        // Later in vblank, enabling NMI asserts it immediately
+        ppu.set_scan_line(241);
+        ppu.cycle_count = 0;
+        ppu.step_cycle(2, &mut cart);
+        assert!(!ppu.nmi_line());
+        ppu.write_mem(0x2000, 0x00, &mut cart);
+        assert!(ppu.nmi_line());
+        ppu.write_mem(0x2000, 0x80, &mut cart);
+        assert!(!ppu.nmi_line());
+    }
+
+    #[test]
+    fn writes_are_ignored_while_warming_up() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = Ppu::new();
+        ppu.set_warm_up(true);
+        ppu.write_mem(0x2000, 0x80, &mut cart);
+        ppu.write_mem(0x2006, 0x21, &mut cart);
+        assert!(!ppu.gen_nmi_at_vblank && !ppu.reg.w);
+        ppu.write_mem(0x2003, 0x10, &mut cart);
+        assert_eq!(ppu.oam_addr, 0x10);
+
+        // Until the end of the first vblank
+        for _ in 0..296 {
+            ppu.step_cycle(100, &mut cart);
+        }
+        ppu.write_mem(0x2000, 0x80, &mut cart);
+        assert!(!ppu.gen_nmi_at_vblank);
+        ppu.step_cycle(100, &mut cart);
+        ppu.write_mem(0x2000, 0x80, &mut cart);
+        assert!(ppu.gen_nmi_at_vblank);
+
+        // And again after a reset
+        ppu.reset();
+        ppu.write_mem(0x2000, 0x80, &mut cart);
+        assert!(!ppu.gen_nmi_at_vblank);
+    }
+
+    #[test]
+    fn pre_render_line_timing() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        ppu.write_mem(0x2001, 0x18, &mut cart);
+
+        // The flags are cleared at dot 1
+        ppu.set_scan_line(-1);
+        ppu.cycle_count = 0;
+        ppu.vblank = true;
+        ppu.sprite0_hit = true;
+        ppu.sprite_overflow = true;
+        ppu.step_cycle(1, &mut cart);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2002) & 0xE0, 0);
+
+        // The last dot is skipped on odd frames only
+        ppu.cycle_count = 338;
+        ppu.step_cycle(1, &mut cart);
+        assert_eq!((ppu.scan_line, ppu.cycle_count), (0, 0));
+        assert!(ppu.odd_frame);
+        ppu.set_scan_line(-1);
+        ppu.cycle_count = 338;
+        ppu.step_cycle(1, &mut cart);
+        assert_eq!((ppu.scan_line, ppu.cycle_count), (0, 1));
+        assert!(!ppu.odd_frame);
+    }
+
+    #[test]
+    fn sprites_are_evaluated_and_fetched_during_the_line() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        ppu.write_mem(0x2001, 0x18, &mut cart);
+        ppu.oam = [0xF0; 256];
+        ppu.oam[0..4].copy_from_slice(&[10, 1, 0x02, 30]);
+        ppu.oam[20..24].copy_from_slice(&[12, 2, 0x41, 40]);
+        ppu.set_scan_line(12);
+        ppu.cycle_count = 0;
+
+        // Cleared during dots 1-64, and OAM reads as $FF meanwhile
+        ppu.step_cycle(21, &mut cart);
+        assert_eq!(ppu.secondary_oam, [0xFF; 32]);
+        assert_eq!(ppu.read_mem(&mut cart, 0x2004), 0xFF);
+
+        ppu.step_cycle(65, &mut cart);
+        assert_eq!(ppu.cycle_count, 258);
+        assert_eq!(ppu.secondary_oam[0..8], [10, 1, 0x02, 30, 12, 2, 0x41, 40]);
+        assert_eq!(ppu.evaluation.found, 2);
+        assert!(ppu.evaluation.sprite0_in_range);
+        assert!(!ppu.sprite_overflow);
+
+        ppu.step_cycle(22, &mut cart);
+        assert_eq!(ppu.scan_line, 12);
+        assert!(ppu.sprite0_enabled);
+        assert_eq!((ppu.sprites[0].x, ppu.sprites[0].attributes), (30, 0x02));
+        assert_eq!((ppu.sprites[1].x, ppu.sprites[1].attributes), (40, 0x41));
+        assert_eq!(ppu.sprites[2].pattern_lower | ppu.sprites[2].pattern_upper, 0);
+    }
+
+    #[test]
+    fn hidden_sprites_still_hit_sprite_0() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        // Tile 1 solid, everywhere in the name table, white and sprites red
+        set_address(&mut ppu, &mut cart, 0x0010);
+        for _ in 0..16 {
+            ppu.write_mem(0x2007, 0xFF, &mut cart);
+        }
+        set_address(&mut ppu, &mut cart, 0x2000);
+        for _ in 0..0x400 {
+            ppu.write_mem(0x2007, 0x01, &mut cart);
+        }
+        set_address(&mut ppu, &mut cart, 0x3F03);
+        ppu.write_mem(0x2007, 0x30, &mut cart);
+        set_address(&mut ppu, &mut cart, 0x3F13);
+        ppu.write_mem(0x2007, 0x16, &mut cart);
+        set_address(&mut ppu, &mut cart, 0x0000);
+        ppu.oam = [0xF0; 256];
+        ppu.oam[0..4].copy_from_slice(&[19, 1, 0x00, 100]);
+
+        ppu.set_layers_shown(true, false);
+        ppu.write_mem(0x2001, 0x1E, &mut cart);
+        ppu.set_scan_line(-1);
+        ppu.cycle_count = 0;
+        ppu.step_cycle(26 * 341 / 3, &mut cart);
+        assert!(ppu.sprite0_hit);
+        let offset = (20 * screen::WIDTH as usize + 104) * 3;
+        assert_eq!(&ppu.picture[offset..offset + 3], &ppu.get_color(0x30)[..]);
+    }
+
+    // Sprite 0 over a solid background on line 2, run from the start of line 1
+    fn sprite_0_hit(sprite_x: u8, mask: u8, cpu_cycles: u16) -> bool {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        set_address(&mut ppu, &mut cart, 0x0010);
+        for _ in 0..16 {
+            ppu.write_mem(0x2007, 0xFF, &mut cart);
+        }
+        set_address(&mut ppu, &mut cart, 0x2000);
+        for _ in 0..0x400 {
+            ppu.write_mem(0x2007, 0x01, &mut cart);
+        }
+        set_address(&mut ppu, &mut cart, 0x0000);
+        ppu.oam = [0xF0; 256];
+        ppu.oam[0..4].copy_from_slice(&[1, 1, 0x00, sprite_x]);
+        ppu.write_mem(0x2001, mask, &mut cart);
+        ppu.set_scan_line(1);
+        ppu.cycle_count = 0;
+        ppu.step_cycle(cpu_cycles, &mut cart);
+        ppu.sprite0_hit
+    }
+
+    #[test]
+    fn sprite_0_hits_on_the_dot_after_its_pixel() {
+        // 147 CPU cycles run line 1 and dots 0-99 of line 2, where pixel x
+        // is output on dot x + 1
+        assert!(sprite_0_hit(98, 0x1E, 147));
+        assert!(!sprite_0_hit(99, 0x1E, 147));
+        assert!(sprite_0_hit(99, 0x1E, 148));
+        // Never at x = 255
+        assert!(!sprite_0_hit(255, 0x1E, 147 + 114));
+        assert!(sprite_0_hit(254, 0x1E, 147 + 114));
+        // Nor in the leftmost pixels when either layer is clipped there
+        assert!(sprite_0_hit(0, 0x1E, 147));
+        assert!(!sprite_0_hit(0, 0x1C, 147));
+        assert!(!sprite_0_hit(0, 0x1A, 147));
+        assert!(sprite_0_hit(1, 0x1A, 147));
+    }
+
+    #[test]
+    fn sprite_overflow_has_the_hardware_bug() {
+        let mut cart = cartridge::Cartridge::new_blank();
+        let mut ppu = new_ppu(&mut cart);
+        ppu.write_mem(0x2001, 0x18, &mut cart);
+        let mut evaluate = |ppu: &mut Ppu, oam: [u8; 256]| {
+            ppu.oam = oam;
+            ppu.sprite_overflow = false;
+            ppu.set_scan_line(20);
+            ppu.cycle_count = 0;
+            ppu.step_cycle(86, &mut cart);
+            ppu.sprite_overflow
+        };
+        let mut oam = [0xF0; 256];
+        for i in 0..8 {
+            oam[i * 4] = 20;
+        }
+
+        // After eight sprites, a ninth one is found by its Y coordinate only
+        // if the sprites before it were also in range
+        oam[32] = 20;
+        assert!(evaluate(&mut ppu, oam));
+
+        // Otherwise the following sprite is checked by its tile number
+        oam[32] = 0xF0;
+        oam[36] = 20;
+        assert!(!evaluate(&mut ppu, oam));
+        oam[36] = 0xF0;
+        oam[37] = 20;
+        assert!(evaluate(&mut ppu, oam));
+    }
+}
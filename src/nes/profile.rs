@@ -0,0 +1,23 @@
+use std::time::{Duration, Instant};
+
+// Time spent stepping the PPU and the APU, for the benchmark. The rest of the
+// running time goes to the CPU, the memory accesses and the cartridge.
+#[derive(Default)]
+pub struct Profile {
+    pub ppu: Duration,
+    pub apu: Duration,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    // Adds the time from start to apu_end to the APU and the time from
+    // ppu_start to now to the PPU. The cartridge runs between apu_end and
+    // ppu_start and is counted in neither.
+    pub fn record(&mut self, start: Instant, apu_end: Instant, ppu_start: Instant) {
+        self.apu += apu_end - start;
+        self.ppu += ppu_start.elapsed();
+    }
+}
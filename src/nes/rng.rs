@@ -0,0 +1,57 @@
+use crate::nes::state::Snapshot;
+
+// Pseudo-random numbers (xorshift64*) for everything in the emulation that
+// the hardware leaves undetermined, such as the contents of RAM at power-on.
+// The generator is part of the machine state, so a given seed always gives
+// the same emulation.
+#[derive(Debug,Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // Spread the bits of small seeds; the state must not be zero
+        let state = (seed ^ 0x9E37_79B9_7F4A_7C15).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        Rng {
+            state: if state == 0 { 1 } else { state },
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32) as u32
+    }
+
+    pub fn fill(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            *byte = self.next_u32() as u8;
+        }
+    }
+
+    pub fn save_state(&self, state: &mut Snapshot) {
+        state.add_u64("rng.state", self.state);
+    }
+
+    pub fn load_state(&mut self, state: &Snapshot) {
+        self.state = state.get_u64("rng.state");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_gives_same_numbers() {
+        let mut first = [0; 16];
+        let mut second = [0; 16];
+        Rng::new(1).fill(&mut first);
+        Rng::new(1).fill(&mut second);
+        assert_eq!(first, second);
+        Rng::new(2).fill(&mut second);
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,89 @@
+use crate::nes::apu::CHANNEL_NAMES;
+use crate::nes::osd;
+use crate::nes::screen::{HEIGHT, WIDTH};
+
+// Draws the output of each sound channel before the mixing over the last
+// frame, like an oscilloscope, one channel above the other. A meter on the
+// right shows the loudest level of the frame. Muted channels are grey.
+
+const LANE_HEIGHT: u32 = HEIGHT / 5;
+const WAVE_WIDTH: u32 = WIDTH - 24;
+const METER_X: u32 = WIDTH - 16;
+const METER_WIDTH: u32 = 8;
+
+// The highest level of each channel
+const MAX_LEVELS: [u8; 5] = [15, 15, 15, 15, 127];
+const COLORS: [[u8; 3]; 5] = [
+    [0xF0, 0x60, 0x60], [0xF0, 0xB0, 0x40], [0x60, 0xD0, 0x60], [0x60, 0xA0, 0xF0],
+    [0xC0, 0x70, 0xF0],
+];
+const MUTED_COLOR: [u8; 3] = [0x60, 0x60, 0x60];
+const GRID_COLOR: [u8; 3] = [0x30, 0x30, 0x30];
+
+fn fill(picture: &mut [u8], x: u32, y: u32, width: u32, height: u32, color: [u8; 3]) {
+    for y in y..(y + height).min(HEIGHT) {
+        for x in x..(x + width).min(WIDTH) {
+            let offset = ((y * WIDTH + x) * 3) as usize;
+            picture[offset..offset + 3].copy_from_slice(&color);
+        }
+    }
+}
+
+// The levels are sampled evenly over the frame, each with a level per channel
+pub fn render(levels: &[[u8; 5]], enabled: [bool; 5]) -> Vec<u8> {
+    let mut picture = vec![0; (WIDTH * HEIGHT * 3) as usize];
+    for channel in 0..5 {
+        let top = channel as u32 * LANE_HEIGHT;
+        let color = if enabled[channel] { COLORS[channel] } else { MUTED_COLOR };
+        let wave_height = LANE_HEIGHT - 10;
+        let y_of = |level: u8| {
+            top + 8 + wave_height - level as u32 * wave_height / MAX_LEVELS[channel] as u32
+        };
+        fill(&mut picture, 0, top + 8 + wave_height, WAVE_WIDTH, 1, GRID_COLOR);
+        if !levels.is_empty() {
+            // A vertical line from the previous point keeps steep edges visible
+            let mut previous_y = None;
+            for x in 0..WAVE_WIDTH {
+                let level = levels[x as usize * levels.len() / WAVE_WIDTH as usize][channel];
+                let y = y_of(level.min(MAX_LEVELS[channel]));
+                let (from, to) = match previous_y {
+                    Some(p) if p < y => (p, y),
+                    Some(p) => (y, p),
+                    None => (y, y),
+                };
+                fill(&mut picture, x, from, 1, to - from + 1, color);
+                previous_y = Some(y);
+            }
+            let peak = levels.iter().map(|l| l[channel]).max().unwrap_or(0).min(MAX_LEVELS[channel]);
+            let meter_height = peak as u32 * wave_height / MAX_LEVELS[channel] as u32;
+            fill(&mut picture, METER_X, y_of(peak), METER_WIDTH, meter_height, color);
+        }
+        osd::draw_text(&mut picture, 2, top + 1, CHANNEL_NAMES[channel]);
+    }
+    picture
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(picture: &[u8], x: u32, y: u32) -> [u8; 3] {
+        let offset = ((y * WIDTH + x) * 3) as usize;
+        [picture[offset], picture[offset + 1], picture[offset + 2]]
+    }
+
+    #[test]
+    fn waveforms_and_meters_are_drawn_in_their_lanes() {
+        // Pulse 1 at full level in the second half, the triangle muted
+        let levels: Vec<[u8; 5]> = (0..100).map(|i| [if i < 50 { 0 } else { 15 }, 0, 7, 0, 0]).collect();
+        let picture = render(&levels, [true, true, false, true, true]);
+        let bottom = 8 + LANE_HEIGHT - 10;
+        assert_eq!(pixel(&picture, 10, bottom), COLORS[0]);
+        assert_eq!(pixel(&picture, WAVE_WIDTH - 10, 8), COLORS[0]);
+        // The edge in the middle
+        assert_eq!(pixel(&picture, WAVE_WIDTH / 2, 20), COLORS[0]);
+        assert_eq!(pixel(&picture, METER_X, bottom - 1), COLORS[0]);
+        assert_eq!(pixel(&picture, 10, 2 * LANE_HEIGHT + bottom - 7 * (LANE_HEIGHT - 10) / 15), MUTED_COLOR);
+        assert_eq!(pixel(&picture, METER_X, LANE_HEIGHT + bottom - 1), [0; 3]);
+    }
+}
@@ -0,0 +1,505 @@
+// The window of Machine::new. The size of the picture, the filters and the
+// layout are also known without SDL, for the options and configuration.
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "sdl")]
+use sdl2::{pixels::PixelFormatEnum, rect::Rect, VideoSubsystem};
+#[cfg(feature = "sdl")]
+use sdl2::render::{BlendMode, Texture, WindowCanvas};
+#[cfg(feature = "sdl")]
+use sdl2::video::{FullscreenType, WindowPos};
+#[cfg(feature = "sdl")]
+use std::time::Instant;
+
+#[cfg(feature = "sdl")]
+use crate::nes::osd;
+
+pub const WIDTH: u32 = 256;
+pub const HEIGHT: u32 = 240;
+
+// How the picture is scaled up to the window. The scan lines and the aperture
+// grille are drawn over the sharp picture, at least 2 times as large.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum VideoFilter {
+    Nearest,
+    Bilinear,
+    Scanlines,
+    ApertureGrille,
+}
+
+impl VideoFilter {
+    const ALL: [VideoFilter; 4] = [VideoFilter::Nearest, VideoFilter::Bilinear,
+                                   VideoFilter::Scanlines, VideoFilter::ApertureGrille];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            VideoFilter::Nearest => "nearest",
+            VideoFilter::Bilinear => "bilinear",
+            VideoFilter::Scanlines => "scanlines",
+            VideoFilter::ApertureGrille => "aperture",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<VideoFilter> {
+        VideoFilter::ALL.iter().copied().find(|f| f.name() == name)
+    }
+
+    #[cfg(feature = "sdl")]
+    fn next(self) -> VideoFilter {
+        let index = VideoFilter::ALL.iter().position(|f| *f == self).unwrap();
+        VideoFilter::ALL[(index + 1) % VideoFilter::ALL.len()]
+    }
+}
+
+// Textures are otherwise only freed with the window
+#[cfg(feature = "sdl")]
+fn destroy_texture(texture: Texture) {
+    // Safe as long as the canvas that created it still exists, which all
+    // textures here are kept together with
+    unsafe { texture.destroy(); }
+}
+
+// What the scaled picture is multiplied with, per pixel of the window. A scan
+// line darkens the lower rows of each row of the picture, and the aperture
+// grille dims two of the components in turn in each column.
+#[cfg(feature = "sdl")]
+fn filter_mask(filter: VideoFilter, width: u32, height: u32, scale: u32) -> Option<Vec<u8>> {
+    const SCANLINE: u8 = 0x90;
+    const GRILLE: u8 = 0xB0;
+    if scale < 2 {
+        return None;
+    }
+    let mut mask = vec![0xFF; (width * height * 3) as usize];
+    match filter {
+        VideoFilter::Scanlines => {
+            for y in (0..height).filter(|y| y % scale >= scale.div_ceil(2)) {
+                let row = (y * width * 3) as usize;
+                mask[row..row + width as usize * 3].iter_mut().for_each(|c| *c = SCANLINE);
+            }
+        }
+        VideoFilter::ApertureGrille => {
+            for (i, pixel) in mask.chunks_mut(3).enumerate() {
+                let lit = i % width as usize % 3;
+                for (component, c) in pixel.iter_mut().enumerate() {
+                    if component != lit {
+                        *c = GRILLE;
+                    }
+                }
+            }
+        }
+        VideoFilter::Nearest | VideoFilter::Bilinear => { return None; }
+    }
+    Some(mask)
+}
+
+
+#[cfg(feature = "sdl")]
+struct DebugWindow {
+    canvas: WindowCanvas,
+    texture: Texture,
+    width: u32,
+}
+
+#[cfg(feature = "sdl")]
+impl DebugWindow {
+    // Shows a picture of the given size, placed where the geometry says if
+    // it is known
+    fn new(video_subsystem: &VideoSubsystem, title: &str, width: u32, height: u32,
+           geometry: Option<WindowGeometry>) -> DebugWindow {
+        // Only the picture of the game may wait for the display to refresh
+        sdl2::hint::set("SDL_RENDER_VSYNC", "0");
+        let window = video_subsystem.window(title, 2 * WIDTH, 2 * HEIGHT)
+            .position_centered()
+            .resizable()
+            .build()
+            .unwrap();
+        let mut canvas = window.into_canvas().build().unwrap();
+        let texture = canvas.create_texture_streaming(PixelFormatEnum::RGB24, width, height)
+            .unwrap();
+        if let Some(geometry) = geometry {
+            geometry.apply_geometry(&mut canvas);
+        }
+        DebugWindow { canvas, texture, width }
+    }
+
+    fn set_shown(&mut self, shown: bool) {
+        if shown {
+            self.canvas.window_mut().show();
+        }
+        else {
+            self.canvas.window_mut().hide();
+        }
+    }
+
+    fn present(&mut self, picture: &[u8]) {
+        self.texture.update(None, picture, self.width as usize * 3).unwrap();
+        self.canvas.copy(&self.texture, None, None).unwrap();
+        self.canvas.present();
+    }
+}
+
+// Position and size of a window
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct WindowGeometry {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[cfg(feature = "sdl")]
+impl WindowGeometry {
+    fn of(canvas: &WindowCanvas) -> WindowGeometry {
+        let (x, y) = canvas.window().position();
+        let (width, height) = canvas.window().size();
+        WindowGeometry { x, y, width, height }
+    }
+
+    fn apply_position(&self, canvas: &mut WindowCanvas) {
+        canvas.window_mut().set_position(WindowPos::Positioned(self.x),
+                                         WindowPos::Positioned(self.y));
+    }
+
+    fn apply_geometry(&self, canvas: &mut WindowCanvas) {
+        self.apply_position(canvas);
+        canvas.window_mut().set_size(self.width, self.height).unwrap();
+    }
+}
+
+// Where the windows were, and whether the debug windows were shown, so the
+// same layout can be restored the next time
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Layout {
+    pub debug_windows: bool,
+    pub main: Option<WindowGeometry>,
+    pub name_table: Option<WindowGeometry>,
+    pub scope: Option<WindowGeometry>,
+}
+
+#[cfg(feature = "sdl")]
+pub struct Screen {
+    video_subsystem: VideoSubsystem,
+    canvas: WindowCanvas,
+    texture: Texture,
+    // Created the first time the debug windows are shown
+    name_table_window: Option<DebugWindow>,
+    name_table_geometry: Option<WindowGeometry>,
+    scope_window: Option<DebugWindow>,
+    scope_geometry: Option<WindowGeometry>,
+    debug_windows_shown: bool,
+    scale: u32,
+    aspect_correction: bool,
+    filter: VideoFilter,
+    // The filter mask for the size of the picture in the window
+    mask: Option<(Rect, Texture)>,
+    frame_blend: u32,
+    previous_frame: Vec<u8>,
+    blended_frame: Vec<u8>,
+    // Shown on top of the picture until the given time
+    messages: osd::Messages,
+    // Drawn on the next picture only
+    overlay: osd::Overlay,
+    message_frame: Vec<u8>,
+}
+
+// Mixes the previous frame into the current one, strength percent of it
+#[cfg(feature = "sdl")]
+fn blend_frames(current: &[u8], previous: &[u8], strength: u32, output: &mut [u8]) {
+    for ((out, cur), prev) in output.iter_mut().zip(current).zip(previous) {
+        *out = ((*cur as u32 * (100 - strength) + *prev as u32 * strength) / 100) as u8;
+    }
+}
+
+#[cfg(feature = "sdl")]
+impl Screen {
+    pub fn new(sdl_context: &mut sdl2::Sdl, show_name_table: bool) -> Screen {
+        let video_subsystem = sdl_context.video().unwrap();
+
+        // Nearest-neighbor scaling keeps the pixels sharp
+        sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", "0");
+
+        const DEFAULT_SCALE: u32 = 2;
+
+        let window = video_subsystem.window("nesemu", WIDTH * DEFAULT_SCALE, HEIGHT * DEFAULT_SCALE)
+            .position_centered()
+            .build()
+            .unwrap();
+
+        let canvas = window.into_canvas().build().unwrap();
+        let texture = canvas.create_texture_streaming(PixelFormatEnum::RGB24, WIDTH, HEIGHT)
+            .unwrap();
+
+        let mut screen = Screen {
+            video_subsystem,
+            canvas,
+            texture,
+            name_table_window: None,
+            name_table_geometry: None,
+            scope_window: None,
+            scope_geometry: None,
+            debug_windows_shown: false,
+            scale: DEFAULT_SCALE,
+            aspect_correction: false,
+            filter: VideoFilter::Nearest,
+            mask: None,
+            frame_blend: 0,
+            previous_frame: Vec::new(),
+            blended_frame: Vec::new(),
+            messages: osd::Messages::new(),
+            overlay: osd::Overlay::new(),
+            message_frame: Vec::new(),
+        };
+        if show_name_table {
+            screen.toggle_debug_windows();
+        }
+        screen
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.canvas.window_mut().set_title(title).unwrap();
+    }
+
+    pub fn set_scale(&mut self, scale: u32) {
+        self.scale = scale.max(1);
+        self.resize_window();
+    }
+
+    // Stretches the picture horizontally to the 8:7 pixel aspect ratio of a TV
+    pub fn set_aspect_correction(&mut self, enabled: bool) {
+        self.aspect_correction = enabled;
+        self.resize_window();
+    }
+
+    pub fn set_filter(&mut self, filter: VideoFilter) {
+        if (filter == VideoFilter::Bilinear) != (self.filter == VideoFilter::Bilinear) {
+            // The scaling is chosen when the texture is created
+            let quality = if filter == VideoFilter::Bilinear { "1" } else { "0" };
+            sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", quality);
+            let texture = self.canvas.create_texture_streaming(PixelFormatEnum::RGB24, WIDTH, HEIGHT)
+                .unwrap();
+            sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", "0");
+            destroy_texture(std::mem::replace(&mut self.texture, texture));
+        }
+        self.filter = filter;
+        if let Some((_, mask)) = self.mask.take() {
+            destroy_texture(mask);
+        }
+    }
+
+    pub fn cycle_filter(&mut self) {
+        self.set_filter(self.filter.next());
+        self.show_message(&format!("Filter: {}", self.filter.name()));
+    }
+
+    // Shows each frame mixed with the previous one, so that sprites that
+    // flicker every other frame look transparent like on a TV. The strength
+    // is the percentage of the previous frame, 0 turns it off.
+    pub fn set_frame_blend(&mut self, strength: u32) {
+        self.frame_blend = strength.min(100);
+        self.previous_frame.clear();
+    }
+
+    // Shows a short message at the bottom of the picture for a while
+    pub fn show_message(&mut self, text: &str) {
+        self.messages.show(text, Instant::now());
+    }
+
+    // Draws text over the next picture, in the font of the messages
+    pub fn draw_text(&mut self, x: u32, y: u32, text: &str) {
+        self.overlay.add_text(x, y, text);
+    }
+
+    // Draws the outline of a rectangle over the next picture
+    pub fn draw_box(&mut self, x: u32, y: u32, width: u32, height: u32, color: [u8; 3]) {
+        self.overlay.add_box(x, y, width, height, color);
+    }
+
+    // Draws an RGB picture of the width over the next picture
+    pub fn draw_image(&mut self, x: u32, y: u32, width: u32, pixels: &[u8]) {
+        self.overlay.add_image(x, y, width, pixels);
+    }
+
+    pub fn toggle_fullscreen(&mut self) {
+        let window = self.canvas.window_mut();
+        let new_state = if window.fullscreen_state() == FullscreenType::Off {
+            FullscreenType::Desktop
+        }
+        else {
+            FullscreenType::Off
+        };
+        window.set_fullscreen(new_state).unwrap();
+    }
+
+    pub fn shows_name_table(&self) -> bool {
+        self.debug_windows_shown
+    }
+
+    // Shows or hides all debug windows at once
+    pub fn toggle_debug_windows(&mut self) {
+        self.debug_windows_shown = !self.debug_windows_shown;
+        if self.debug_windows_shown && self.name_table_window.is_none() {
+            self.name_table_window = Some(DebugWindow::new(&self.video_subsystem, "nametable",
+                                                           2 * WIDTH, 2 * HEIGHT,
+                                                           self.name_table_geometry));
+            self.scope_window = Some(DebugWindow::new(&self.video_subsystem, "oscilloscope",
+                                                      WIDTH, HEIGHT, self.scope_geometry));
+        }
+        for window in self.name_table_window.iter_mut().chain(self.scope_window.iter_mut()) {
+            window.set_shown(self.debug_windows_shown);
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        Layout {
+            debug_windows: self.debug_windows_shown,
+            main: Some(WindowGeometry::of(&self.canvas)),
+            name_table: self.name_table_window.as_ref().map(|w| WindowGeometry::of(&w.canvas))
+                .or(self.name_table_geometry),
+            scope: self.scope_window.as_ref().map(|w| WindowGeometry::of(&w.canvas))
+                .or(self.scope_geometry),
+        }
+    }
+
+    // Moves the windows to where they were in the layout. The size of the
+    // main window follows the scale, while the debug windows get their size
+    // back.
+    pub fn set_layout(&mut self, layout: &Layout) {
+        if let Some(geometry) = layout.main {
+            geometry.apply_position(&mut self.canvas);
+        }
+        self.name_table_geometry = layout.name_table;
+        if let (Some(window), Some(geometry)) = (self.name_table_window.as_mut(), layout.name_table) {
+            geometry.apply_geometry(&mut window.canvas);
+        }
+        self.scope_geometry = layout.scope;
+        if let (Some(window), Some(geometry)) = (self.scope_window.as_mut(), layout.scope) {
+            geometry.apply_geometry(&mut window.canvas);
+        }
+        if layout.debug_windows != self.debug_windows_shown {
+            self.toggle_debug_windows();
+        }
+    }
+
+    fn scaled_width(&self, scale: u32) -> u32 {
+        if self.aspect_correction {
+            WIDTH * scale * 8 / 7
+        }
+        else {
+            WIDTH * scale
+        }
+    }
+
+    fn resize_window(&mut self) {
+        let width = self.scaled_width(self.scale);
+        let height = HEIGHT * self.scale;
+        let window = self.canvas.window_mut();
+        if window.fullscreen_state() == FullscreenType::Off {
+            window.set_size(width, height).unwrap();
+        }
+    }
+
+    // Largest integer multiple of the picture that fits the output, centered
+    fn get_target_rect(&self) -> Rect {
+        let (output_width, output_height) = self.canvas.output_size().unwrap();
+        let mut scale = 1;
+        while self.scaled_width(scale + 1) <= output_width && HEIGHT * (scale + 1) <= output_height {
+            scale += 1;
+        }
+        let width = self.scaled_width(scale);
+        let height = HEIGHT * scale;
+        Rect::new((output_width as i32 - width as i32) / 2,
+                  (output_height as i32 - height as i32) / 2,
+                  width, height)
+    }
+
+    fn create_mask(&self, target: Rect) -> Option<(Rect, Texture)> {
+        let scale = target.height() / HEIGHT;
+        let mask = filter_mask(self.filter, target.width(), target.height(), scale)?;
+        let mut texture = self.canvas.create_texture_static(PixelFormatEnum::RGB24,
+                                                            target.width(), target.height())
+            .unwrap();
+        texture.update(None, &mask, target.width() as usize * 3).unwrap();
+        texture.set_blend_mode(BlendMode::Mod);
+        Some((target, texture))
+    }
+
+    pub fn present(&mut self, frame: &[u8], name_table: Option<&[u8]>, scope: Option<&[u8]>) {
+        let blend = self.frame_blend > 0 && self.previous_frame.len() == frame.len();
+        if blend {
+            self.blended_frame.resize(frame.len(), 0);
+            blend_frames(frame, &self.previous_frame, self.frame_blend, &mut self.blended_frame);
+        }
+        let picture = if blend { &self.blended_frame } else { frame };
+        let messages_shown = self.messages.update(Instant::now());
+        if messages_shown || !self.overlay.is_empty() {
+            self.message_frame.clear();
+            self.message_frame.extend_from_slice(picture);
+            self.overlay.draw(&mut self.message_frame);
+            self.messages.draw(&mut self.message_frame);
+            self.overlay.clear();
+            self.texture.update(None, &self.message_frame, WIDTH as usize * 3).unwrap();
+        }
+        else {
+            self.texture.update(None, picture, WIDTH as usize * 3).unwrap();
+        }
+        if self.frame_blend > 0 {
+            self.previous_frame.clear();
+            self.previous_frame.extend_from_slice(frame);
+        }
+        let target = self.get_target_rect();
+        self.canvas.clear();
+        self.canvas.copy(&self.texture, None, Some(target)).unwrap();
+        if self.mask.as_ref().map(|(rect, _)| *rect) != Some(target) {
+            if let Some((_, mask)) = self.mask.take() {
+                destroy_texture(mask);
+            }
+            self.mask = self.create_mask(target);
+        }
+        if let Some((_, ref mask)) = self.mask {
+            self.canvas.copy(mask, None, Some(target)).unwrap();
+        }
+        self.canvas.present();
+
+        if !self.debug_windows_shown {
+            return;
+        }
+        if let (Some(window), Some(name_table)) = (self.name_table_window.as_mut(), name_table) {
+            window.present(name_table);
+        }
+        if let (Some(window), Some(scope)) = (self.scope_window.as_mut(), scope) {
+            window.present(scope);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sdl"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blending_mixes_in_the_previous_frame() {
+        let mut output = [0; 3];
+        blend_frames(&[200, 0, 100], &[0, 200, 100], 50, &mut output);
+        assert_eq!(output, [100, 100, 100]);
+        blend_frames(&[200, 0, 100], &[0, 200, 100], 25, &mut output);
+        assert_eq!(output, [150, 50, 100]);
+    }
+
+    #[test]
+    fn filter_masks_darken_rows_and_columns() {
+        let at = |mask: &[u8], x: u32, y: u32| {
+            let offset = ((y * 6 + x) * 3) as usize;
+            [mask[offset], mask[offset + 1], mask[offset + 2]]
+        };
+        let mask = filter_mask(VideoFilter::Scanlines, 6, 6, 3).unwrap();
+        assert_eq!((0..6).map(|y| at(&mask, 0, y)[0]).collect::<Vec<_>>(),
+                   [0xFF, 0xFF, 0x90, 0xFF, 0xFF, 0x90]);
+        let mask = filter_mask(VideoFilter::ApertureGrille, 6, 6, 3).unwrap();
+        assert_eq!(at(&mask, 1, 4), [0xB0, 0xFF, 0xB0]);
+        assert_eq!(at(&mask, 5, 0), [0xB0, 0xB0, 0xFF]);
+        assert!(filter_mask(VideoFilter::Scanlines, 6, 6, 1).is_none());
+        assert!(filter_mask(VideoFilter::Bilinear, 6, 6, 3).is_none());
+        assert_eq!(VideoFilter::ApertureGrille.next(), VideoFilter::Nearest);
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+// Machine state as a list of named sections, each holding the raw bytes of a
+// register or memory block (multi-byte values are little endian). The names
+// make it possible to compare two states and tell what differs.
+#[derive(Clone)]
+pub struct Snapshot {
+    sections: Vec<(String, Vec<u8>)>,
+    // The version of the sections of each chunk
+    versions: Vec<(Chunk, u32)>,
+}
+
+// The parts of the machine whose sections are kept together in a chunk,
+// each with an ID and a version of its own. The version of a part is raised
+// whenever its sections change, with a step that upgrades a state of the
+// previous version, so that older states still load.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Chunk {
+    Info,
+    Cpu,
+    Ppu,
+    Apu,
+    // The cartridge
+    Mapper,
+    // The RAM, the buses, the controllers and the random numbers
+    Machine,
+}
+
+pub const CHUNKS: [Chunk; 6] = [Chunk::Info, Chunk::Cpu, Chunk::Ppu, Chunk::Apu, Chunk::Mapper, Chunk::Machine];
+
+impl Chunk {
+    fn id(self) -> &'static [u8; 4] {
+        match self {
+            Chunk::Info => b"INFO",
+            Chunk::Cpu => b"CPU ",
+            Chunk::Ppu => b"PPU ",
+            Chunk::Apu => b"APU ",
+            Chunk::Mapper => b"MAPR",
+            Chunk::Machine => b"MACH",
+        }
+    }
+
+    // The chunk of a section, by the start of its name
+    pub fn of_section(name: &str) -> Chunk {
+        match name.split('.').next() {
+            Some("info") => Chunk::Info,
+            Some("cpu") => Chunk::Cpu,
+            Some("ppu") => Chunk::Ppu,
+            Some("apu") => Chunk::Apu,
+            Some("mapper") | Some("cartridge") | Some("rom") => Chunk::Mapper,
+            _ => Chunk::Machine,
+        }
+    }
+}
+
+// The magic is followed by the chunks, each its ID, version and size in
+// bytes followed by its sections. Chunks with other IDs are skipped.
+const MAGIC: &[u8] = b"NESC";
+// States from before the chunks are only the sections, without versions
+const OLD_MAGIC: &[u8] = b"NESS";
+
+fn truncated() -> String {
+    "Truncated machine state".to_string()
+}
+
+fn format_value(data: &[u8]) -> String {
+    let digits: Vec<_> = data.iter().rev().map(|b| format!("{:02X}", b)).collect();
+    digits.concat()
+}
+
+// Ranges of differing bytes, with gaps of less than 16 equal bytes merged
+fn changed_ranges(old: &[u8], new: &[u8]) -> Vec<(usize, usize, usize)> {
+    let mut ranges: Vec<(usize, usize, usize)> = Vec::new();
+    for i in 0..old.len().min(new.len()) {
+        if old[i] == new[i] {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(range) if i - range.1 < 16 => {
+                range.1 = i;
+                range.2 += 1;
+            }
+            _ => { ranges.push((i, i, 1)); }
+        }
+    }
+    ranges
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Snapshot::new()
+    }
+}
+
+impl Snapshot {
+    pub fn new() -> Snapshot {
+        Snapshot {
+            sections: Vec::new(),
+            versions: Vec::new(),
+        }
+    }
+
+    // Called by each part of the machine when it adds its sections
+    pub fn set_version(&mut self, chunk: Chunk, version: u32) {
+        self.versions.retain(|(c, _)| *c != chunk);
+        self.versions.push((chunk, version));
+    }
+
+    // None for a chunk of a state from before the chunks
+    pub fn version(&self, chunk: Chunk) -> Option<u32> {
+        self.versions.iter().find(|(c, _)| *c == chunk).map(|(_, version)| *version)
+    }
+
+    // For upgrades, which add what a state of an earlier version lacks
+    pub fn add_missing(&mut self, name: &str, data: &[u8]) {
+        if self.get(name).is_none() {
+            self.add_bytes(name, data);
+        }
+    }
+
+    pub fn add_bytes(&mut self, name: &str, data: &[u8]) {
+        self.sections.push((name.to_string(), data.to_vec()));
+    }
+
+    pub fn add_u8(&mut self, name: &str, value: u8) {
+        self.add_bytes(name, &[value]);
+    }
+
+    pub fn add_bool(&mut self, name: &str, value: bool) {
+        self.add_u8(name, value as u8);
+    }
+
+    pub fn add_u16(&mut self, name: &str, value: u16) {
+        self.add_bytes(name, &value.to_le_bytes());
+    }
+
+    pub fn add_u64(&mut self, name: &str, value: u64) {
+        self.add_bytes(name, &value.to_le_bytes());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.sections.iter().find(|(n, _)| n == name).map(|(_, data)| data.as_slice())
+    }
+
+    // The getters below restore a state whose layout has been checked with
+    // has_layout_of, and panic on a missing section
+    pub fn get_bytes(&self, name: &str) -> &[u8] {
+        self.get(name).unwrap_or_else(|| panic!("Missing {} in machine state", name))
+    }
+
+    pub fn get_u8(&self, name: &str) -> u8 {
+        self.get_bytes(name)[0]
+    }
+
+    pub fn get_bool(&self, name: &str) -> bool {
+        self.get_u8(name) != 0
+    }
+
+    pub fn get_u16(&self, name: &str) -> u16 {
+        let data = self.get_bytes(name);
+        u16::from_le_bytes([data[0], data[1]])
+    }
+
+    pub fn get_u64(&self, name: &str) -> u64 {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.get_bytes(name));
+        u64::from_le_bytes(value)
+    }
+
+    // True if both states have the same sections with the same sizes, so
+    // that this one can be restored where the other was taken
+    pub fn has_layout_of(&self, other: &Snapshot) -> bool {
+        self.sections.len() == other.sections.len() &&
+            self.sections.iter().zip(&other.sections)
+                .all(|((name, data), (other_name, other_data))| {
+                    name == other_name && data.len() == other_data.len()
+                })
+    }
+
+    // The sections in the same order as in the layout, a state taken now, or
+    // an error if they don't match it
+    pub fn in_layout_of(&self, layout: &Snapshot) -> Result<Snapshot, String> {
+        let mut snapshot = Snapshot { sections: Vec::new(), versions: layout.versions.clone() };
+        for (name, data) in &layout.sections {
+            match self.get(name) {
+                Some(saved) if saved.len() == data.len() => { snapshot.add_bytes(name, saved); }
+                Some(_) => { return Err(format!("Wrong size of {} in the state", name)); }
+                None => { return Err(format!("Missing {} in the state", name)); }
+            }
+        }
+        if self.sections.len() != layout.sections.len() {
+            return Err("Unknown sections in the state".to_string());
+        }
+        Ok(snapshot)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        for chunk in CHUNKS.iter().copied() {
+            let mut sections = Vec::new();
+            for (name, data) in self.sections.iter().filter(|(name, _)| Chunk::of_section(name) == chunk) {
+                sections.push(name.len() as u8);
+                sections.extend_from_slice(name.as_bytes());
+                sections.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                sections.extend_from_slice(data);
+            }
+            if sections.is_empty() {
+                continue;
+            }
+            bytes.extend_from_slice(chunk.id());
+            bytes.extend_from_slice(&self.version(chunk).unwrap_or(0).to_le_bytes());
+            bytes.extend_from_slice(&(sections.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&sections);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, String> {
+        let mut snapshot = Snapshot::new();
+        if bytes.starts_with(OLD_MAGIC) {
+            snapshot.read_sections(&bytes[OLD_MAGIC.len()..])?;
+            return Ok(snapshot);
+        }
+        if !bytes.starts_with(MAGIC) {
+            return Err("Not a machine state".to_string());
+        }
+        let mut pos = MAGIC.len();
+        while pos < bytes.len() {
+            let header = bytes.get(pos..pos + 12).ok_or_else(truncated)?;
+            let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            let len = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize;
+            pos += 12;
+            let sections = bytes.get(pos..pos + len).ok_or_else(truncated)?;
+            pos += len;
+            if let Some(chunk) = CHUNKS.iter().copied().find(|chunk| chunk.id() == &header[..4]) {
+                snapshot.read_sections(sections)?;
+                snapshot.set_version(chunk, version);
+            }
+        }
+        Ok(snapshot)
+    }
+
+    fn read_sections(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let name_len = bytes[pos] as usize;
+            let name = bytes.get(pos + 1..pos + 1 + name_len).ok_or_else(truncated)?;
+            pos += 1 + name_len;
+            let len = bytes.get(pos..pos + 4).ok_or_else(truncated)?;
+            let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            pos += 4;
+            let data = bytes.get(pos..pos + len).ok_or_else(truncated)?;
+            pos += len;
+            self.add_bytes(&String::from_utf8_lossy(name), data);
+        }
+        Ok(())
+    }
+
+    // Human-readable differences from this state to other, one per line
+    pub fn diff(&self, other: &Snapshot) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (name, old) in &self.sections {
+            let new = match other.get(name) {
+                Some(new) => new,
+                None => {
+                    lines.push(format!("{}: only in first state", name));
+                    continue;
+                }
+            };
+            if old.as_slice() == new {
+                continue;
+            }
+            if old.len() != new.len() {
+                lines.push(format!("{}: size changed {} -> {} bytes", name, old.len(), new.len()));
+            }
+            else if old.len() <= 8 {
+                lines.push(format!("{}: {} -> {}", name, format_value(old), format_value(new)));
+            }
+            else {
+                for (start, end, count) in changed_ranges(old, new) {
+                    lines.push(format!("{}: ${:04X}-${:04X} changed ({} bytes)",
+                                       name, start, end, count));
+                }
+            }
+        }
+        for (name, _) in &other.sections {
+            if self.get(name).is_none() {
+                lines.push(format!("{}: only in second state", name));
+            }
+        }
+        lines
+    }
+}
+
+// The thumbnail is the picture shrunk to a quarter
+pub const THUMBNAIL_WIDTH: u32 = 64;
+pub const THUMBNAIL_HEIGHT: u32 = 60;
+
+const INFO_PREFIX: &str = "info.";
+const INFO_VERSION: u32 = 1;
+
+// What a state file tells about the state without loading it, to list the
+// states of a game. It is kept in sections named info.*, in the first
+// chunk, which files from before it came about don't have.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StateInfo {
+    // Seconds since 1970
+    pub saved_at: u64,
+    // Of the PRG and CHR data of the game
+    pub crc32: u32,
+    // How long the game had been played in all
+    pub playtime_seconds: u64,
+    // THUMBNAIL_WIDTH x THUMBNAIL_HEIGHT pixels with 3 bytes (RGB) each
+    pub thumbnail: Vec<u8>,
+}
+
+impl StateInfo {
+    fn from_sections(info: &Snapshot) -> Option<StateInfo> {
+        let u64_of = |name: &str| info.get(name).filter(|data| data.len() == 8)
+            .map(|_| info.get_u64(name));
+        let crc32 = info.get("info.crc32").filter(|data| data.len() == 4)?;
+        let thumbnail = info.get("info.thumbnail")
+            .filter(|data| data.len() == (THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT * 3) as usize)?;
+        Some(StateInfo {
+            saved_at: u64_of("info.saved_at")?,
+            crc32: u32::from_le_bytes([crc32[0], crc32[1], crc32[2], crc32[3]]),
+            playtime_seconds: u64_of("info.playtime_seconds")?,
+            thumbnail: thumbnail.to_vec(),
+        })
+    }
+}
+
+// Each pixel of the thumbnail is the average of 4x4 pixels of the picture,
+// 256x240 pixels with 3 bytes (RGB) each
+pub fn thumbnail(picture: &[u8]) -> Vec<u8> {
+    let mut thumbnail = Vec::with_capacity((THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT * 3) as usize);
+    for y in 0..THUMBNAIL_HEIGHT as usize {
+        for x in 0..THUMBNAIL_WIDTH as usize {
+            for color in 0..3 {
+                let sum: u32 = (0..16).map(|i| {
+                    let offset = ((y * 4 + i / 4) * THUMBNAIL_WIDTH as usize * 4 + x * 4 + i % 4) * 3;
+                    picture[offset + color] as u32
+                }).sum();
+                thumbnail.push((sum / 16) as u8);
+            }
+        }
+    }
+    thumbnail
+}
+
+// The state with its info first, as written to a state file
+pub fn state_file_bytes(info: &StateInfo, state: &Snapshot) -> Vec<u8> {
+    let mut file = state.clone();
+    file.sections.retain(|(name, _)| !name.starts_with(INFO_PREFIX));
+    file.set_version(Chunk::Info, INFO_VERSION);
+    file.add_u64("info.saved_at", info.saved_at);
+    file.add_bytes("info.crc32", &info.crc32.to_le_bytes());
+    file.add_u64("info.playtime_seconds", info.playtime_seconds);
+    file.add_bytes("info.thumbnail", &info.thumbnail);
+    file.to_bytes()
+}
+
+// The info of a state file, if it has any, and the state of the machine
+pub fn parse_state_file(bytes: &[u8]) -> Result<(Option<StateInfo>, Snapshot), String> {
+    let mut state = Snapshot::from_bytes(bytes)?;
+    let info = StateInfo::from_sections(&state);
+    state.sections.retain(|(name, _)| !name.starts_with(INFO_PREFIX));
+    state.versions.retain(|(chunk, _)| *chunk != Chunk::Info);
+    Ok((info, state))
+}
+
+// The state of a single component, such as the PPU or the APU, with the
+// sections it saves in a Snapshot, in a form that serde can write. Each
+// component has a version that is raised whenever its sections change, and
+// a state of another version is refused.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ComponentState {
+    pub version: u32,
+    pub sections: BTreeMap<String, Vec<u8>>,
+}
+
+impl ComponentState {
+    pub fn new(version: u32, snapshot: &Snapshot) -> ComponentState {
+        ComponentState { version, sections: snapshot.sections.iter().cloned().collect() }
+    }
+
+    // The sections in the order of layout, a snapshot of the same component
+    // taken now, or an error if they don't match it
+    pub fn to_snapshot(&self, version: u32, layout: &Snapshot) -> Result<Snapshot, String> {
+        if self.version != version {
+            return Err(format!("State version {} is not the supported version {}",
+                               self.version, version));
+        }
+        let saved = Snapshot { sections: self.sections.clone().into_iter().collect(), versions: Vec::new() };
+        saved.in_layout_of(layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut state = Snapshot::new();
+        state.add_u16("cpu.pc", 0xC123);
+        state.add_bytes("ram", &[1, 2, 3]);
+        state.set_version(Chunk::Cpu, 3);
+        // A chunk that only a later version knows is skipped
+        let mut bytes = state.to_bytes();
+        bytes.extend_from_slice(b"NEW \x01\x00\x00\x00\x02\x00\x00\x00ab");
+        let state = Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(state.get("cpu.pc"), Some(&[0x23, 0xC1][..]));
+        assert_eq!(state.get("ram"), Some(&[1, 2, 3][..]));
+        assert_eq!(state.version(Chunk::Cpu), Some(3));
+        assert_eq!(state.version(Chunk::Ppu), None);
+        assert_eq!(state.to_bytes().len(), bytes.len() - 14);
+        assert!(Snapshot::from_bytes(&state.to_bytes()[..10]).is_err());
+    }
+
+    #[test]
+    fn getters_and_layout() {
+        let mut state = Snapshot::new();
+        state.add_u16("cpu.pc", 0xC123);
+        state.add_bool("cpu.nmi_triggered", true);
+        state.add_u64("cycle_count", 1 << 40);
+        assert_eq!(state.get_u16("cpu.pc"), 0xC123);
+        assert!(state.get_bool("cpu.nmi_triggered"));
+        assert_eq!(state.get_u64("cycle_count"), 1 << 40);
+
+        let mut other = Snapshot::new();
+        other.add_u16("cpu.pc", 0);
+        other.add_bool("cpu.nmi_triggered", false);
+        other.add_u64("cycle_count", 0);
+        assert!(state.has_layout_of(&other));
+        other.add_u8("cpu.a", 0);
+        assert!(!state.has_layout_of(&other));
+    }
+
+    #[test]
+    fn state_files_have_the_info_first() {
+        let mut picture = vec![0; 256 * 240 * 3];
+        // The top left 4x4 pixels average to 0x40 red
+        for i in 0..4 {
+            picture[(i * 256 + i) * 3] = 0xFF;
+        }
+        picture[(239 * 256 + 255) * 3 + 2] = 0x10;
+        let info = StateInfo { saved_at: 1700000000, crc32: 0x158B0388, playtime_seconds: 3900,
+                               thumbnail: thumbnail(&picture) };
+        assert_eq!(&info.thumbnail[..4], [0x3F, 0, 0, 0]);
+        assert_eq!(info.thumbnail[info.thumbnail.len() - 1], 1);
+
+        let mut state = Snapshot::new();
+        state.add_u16("cpu.pc", 0xC123);
+        let (read_info, read_state) = parse_state_file(&state_file_bytes(&info, &state)).unwrap();
+        assert_eq!(read_info, Some(info));
+        assert!(read_state.has_layout_of(&state));
+
+        // Older files have only the state
+        let (read_info, read_state) = parse_state_file(&state.to_bytes()).unwrap();
+        assert!(read_info.is_none() && read_state.has_layout_of(&state));
+    }
+
+    #[test]
+    fn diff_lists_changes() {
+        let mut ram = vec![0; 0x800];
+        let mut first = Snapshot::new();
+        first.add_u16("cpu.pc", 0xC000);
+        first.add_u8("cpu.a", 0x10);
+        first.add_bytes("ram", &ram);
+        first.add_u8("mapper.bank", 1);
+
+        ram[0x10] = 1;
+        ram[0x1A] = 1;
+        ram[0x300] = 1;
+        let mut second = Snapshot::new();
+        second.add_u16("cpu.pc", 0xC003);
+        second.add_u8("cpu.a", 0x10);
+        second.add_bytes("ram", &ram);
+        second.add_u8("mapper.prg_bank", 2);
+
+        assert_eq!(first.diff(&second), vec![
+            "cpu.pc: C000 -> C003",
+            "ram: $0010-$001A changed (2 bytes)",
+            "ram: $0300-$0300 changed (1 bytes)",
+            "mapper.bank: only in first state",
+            "mapper.prg_bank: only in second state",
+        ]);
+    }
+}
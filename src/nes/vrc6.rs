@@ -0,0 +1,343 @@
+use crate::nes::apu::ExpansionAudio;
+use crate::nes::state::Snapshot;
+
+// The VRC IRQ counter. It counts up once per scan line (approximated by a
+// prescaler that is decremented by 3 every CPU cycle) or once per CPU cycle,
+// and triggers the IRQ when it overflows.
+#[derive(Debug,Clone)]
+pub struct IrqCounter {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enabled_after_ack: bool,
+    cycle_mode: bool,
+    pub irq_pending: bool,
+}
+
+impl IrqCounter {
+    pub fn new() -> IrqCounter {
+        IrqCounter {
+            latch: 0,
+            counter: 0,
+            prescaler: 341,
+            enabled: false,
+            enabled_after_ack: false,
+            cycle_mode: false,
+            irq_pending: false,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut Snapshot, prefix: &str) {
+        state.add_u8(&format!("{}.latch", prefix), self.latch);
+        state.add_u8(&format!("{}.counter", prefix), self.counter);
+        state.add_u16(&format!("{}.prescaler", prefix), self.prescaler as u16);
+        state.add_bool(&format!("{}.enabled", prefix), self.enabled);
+        state.add_bool(&format!("{}.enabled_after_ack", prefix), self.enabled_after_ack);
+        state.add_bool(&format!("{}.cycle_mode", prefix), self.cycle_mode);
+        state.add_bool(&format!("{}.irq_pending", prefix), self.irq_pending);
+    }
+
+    pub fn load_state(&mut self, state: &Snapshot, prefix: &str) {
+        self.latch = state.get_u8(&format!("{}.latch", prefix));
+        self.counter = state.get_u8(&format!("{}.counter", prefix));
+        self.prescaler = state.get_u16(&format!("{}.prescaler", prefix)) as i16;
+        self.enabled = state.get_bool(&format!("{}.enabled", prefix));
+        self.enabled_after_ack = state.get_bool(&format!("{}.enabled_after_ack", prefix));
+        self.cycle_mode = state.get_bool(&format!("{}.cycle_mode", prefix));
+        self.irq_pending = state.get_bool(&format!("{}.irq_pending", prefix));
+    }
+
+    pub fn set_latch(&mut self, value: u8) {
+        self.latch = value;
+    }
+
+    // VRC4 writes the latch four bits at a time
+    pub fn set_latch_nibble(&mut self, high: bool, value: u8) {
+        self.latch = if high {
+            (self.latch & 0x0F) | (value << 4)
+        }
+        else {
+            (self.latch & 0xF0) | (value & 0x0F)
+        };
+    }
+
+    pub fn set_control(&mut self, value: u8) {
+        self.enabled_after_ack = value & 0x01 != 0;
+        self.enabled = value & 0x02 != 0;
+        self.cycle_mode = value & 0x04 != 0;
+        self.irq_pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.irq_pending = false;
+        self.enabled = self.enabled_after_ack;
+    }
+
+    pub fn step_cycle(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock_counter();
+        }
+        else {
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += 341;
+                self.clock_counter();
+            }
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.irq_pending = true;
+        }
+        else {
+            self.counter += 1;
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+struct Vrc6Pulse {
+    volume: u8,
+    duty_cycle: u8,
+    constant: bool,
+    enabled: bool,
+    timer_max: u16,
+    timer: u16,
+    duty_step: u8,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Vrc6Pulse {
+        Vrc6Pulse {
+            volume: 0,
+            duty_cycle: 0,
+            constant: false,
+            enabled: false,
+            timer_max: 0,
+            timer: 0,
+            duty_step: 15,
+        }
+    }
+
+    fn save_state(&self, state: &mut Snapshot, prefix: &str) {
+        state.add_u8(&format!("{}.volume", prefix), self.volume);
+        state.add_u8(&format!("{}.duty_cycle", prefix), self.duty_cycle);
+        state.add_bool(&format!("{}.constant", prefix), self.constant);
+        state.add_bool(&format!("{}.enabled", prefix), self.enabled);
+        state.add_u16(&format!("{}.timer_max", prefix), self.timer_max);
+        state.add_u16(&format!("{}.timer", prefix), self.timer);
+        state.add_u8(&format!("{}.duty_step", prefix), self.duty_step);
+    }
+
+    fn load_state(&mut self, state: &Snapshot, prefix: &str) {
+        self.volume = state.get_u8(&format!("{}.volume", prefix));
+        self.duty_cycle = state.get_u8(&format!("{}.duty_cycle", prefix));
+        self.constant = state.get_bool(&format!("{}.constant", prefix));
+        self.enabled = state.get_bool(&format!("{}.enabled", prefix));
+        self.timer_max = state.get_u16(&format!("{}.timer_max", prefix));
+        self.timer = state.get_u16(&format!("{}.timer", prefix));
+        self.duty_step = state.get_u8(&format!("{}.duty_step", prefix));
+    }
+
+    fn write_reg(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.constant = value & 0x80 != 0;
+                self.duty_cycle = (value >> 4) & 0x07;
+                self.volume = value & 0x0F;
+            }
+            1 => {
+                self.timer_max = (self.timer_max & 0x0F00) | value as u16;
+            }
+            2 => {
+                self.timer_max = (self.timer_max & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.duty_step = 15;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn update_level(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        else {
+            self.timer = self.timer_max;
+            self.duty_step = if self.duty_step == 0 { 15 } else { self.duty_step - 1 };
+        }
+    }
+
+    fn output_level(&self) -> u8 {
+        if self.enabled && (self.constant || self.duty_step <= self.duty_cycle) {
+            self.volume
+        }
+        else {
+            0
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+struct Vrc6Sawtooth {
+    rate: u8,
+    enabled: bool,
+    timer_max: u16,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Sawtooth {
+    fn new() -> Vrc6Sawtooth {
+        Vrc6Sawtooth {
+            rate: 0,
+            enabled: false,
+            timer_max: 0,
+            timer: 0,
+            step: 0,
+            accumulator: 0,
+        }
+    }
+
+    fn save_state(&self, state: &mut Snapshot, prefix: &str) {
+        state.add_u8(&format!("{}.rate", prefix), self.rate);
+        state.add_bool(&format!("{}.enabled", prefix), self.enabled);
+        state.add_u16(&format!("{}.timer_max", prefix), self.timer_max);
+        state.add_u16(&format!("{}.timer", prefix), self.timer);
+        state.add_u8(&format!("{}.step", prefix), self.step);
+        state.add_u8(&format!("{}.accumulator", prefix), self.accumulator);
+    }
+
+    fn load_state(&mut self, state: &Snapshot, prefix: &str) {
+        self.rate = state.get_u8(&format!("{}.rate", prefix));
+        self.enabled = state.get_bool(&format!("{}.enabled", prefix));
+        self.timer_max = state.get_u16(&format!("{}.timer_max", prefix));
+        self.timer = state.get_u16(&format!("{}.timer", prefix));
+        self.step = state.get_u8(&format!("{}.step", prefix));
+        self.accumulator = state.get_u8(&format!("{}.accumulator", prefix));
+    }
+
+    fn write_reg(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.rate = value & 0x3F;
+            }
+            1 => {
+                self.timer_max = (self.timer_max & 0x0F00) | value as u16;
+            }
+            2 => {
+                self.timer_max = (self.timer_max & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // The accumulator is increased by the rate on every other of 14 steps
+    fn update_level(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_max;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        }
+        else if self.step & 0x1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output_level(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+// The two pulse channels and the sawtooth channel of the VRC6
+#[derive(Debug,Clone)]
+pub struct Vrc6Audio {
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    sawtooth: Vrc6Sawtooth,
+    halted: bool,
+}
+
+impl Vrc6Audio {
+    pub fn new() -> Vrc6Audio {
+        Vrc6Audio {
+            pulse1: Vrc6Pulse::new(),
+            pulse2: Vrc6Pulse::new(),
+            sawtooth: Vrc6Sawtooth::new(),
+            halted: false,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut Snapshot, prefix: &str) {
+        self.pulse1.save_state(state, &format!("{}.pulse1", prefix));
+        self.pulse2.save_state(state, &format!("{}.pulse2", prefix));
+        self.sawtooth.save_state(state, &format!("{}.sawtooth", prefix));
+        state.add_bool(&format!("{}.halted", prefix), self.halted);
+    }
+
+    pub fn load_state(&mut self, state: &Snapshot, prefix: &str) {
+        self.pulse1.load_state(state, &format!("{}.pulse1", prefix));
+        self.pulse2.load_state(state, &format!("{}.pulse2", prefix));
+        self.sawtooth.load_state(state, &format!("{}.sawtooth", prefix));
+        self.halted = state.get_bool(&format!("{}.halted", prefix));
+    }
+
+    // Registers $9000-$9003, $A000-$A002 and $B000-$B002 with the address
+    // lines already normalized to the VRC6a layout
+    pub fn write_mem(&mut self, address: u16, value: u8) {
+        let reg = address & 0x0003;
+        match address & 0xF000 {
+            0x9000 if reg == 3 => { self.halted = value & 0x01 != 0; }
+            0x9000 => { self.pulse1.write_reg(reg, value); }
+            0xA000 => { self.pulse2.write_reg(reg, value); }
+            0xB000 => { self.sawtooth.write_reg(reg, value); }
+            _ => {}
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc6Audio {
+    fn step_cycle(&mut self) {
+        if !self.halted {
+            self.pulse1.update_level();
+            self.pulse2.update_level();
+            self.sawtooth.update_level();
+        }
+    }
+
+    // A pulse channel at full volume is about as loud as an APU pulse channel
+    fn output_level(&self) -> f32 {
+        let level = self.pulse1.output_level() as f32 + self.pulse2.output_level() as f32 +
+            self.sawtooth.output_level() as f32;
+        level * 0.00752
+    }
+}
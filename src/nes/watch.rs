@@ -0,0 +1,59 @@
+// Records the CPU reads and writes of chosen addresses, for the frontend to
+// act on between instructions, e.g. to run a script when the game writes to
+// an address. Nothing is recorded for addresses that are not watched.
+
+const READ: u8 = 0x01;
+const WRITE: u8 = 0x02;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MemoryAccess {
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+pub struct MemoryWatch {
+    // READ and WRITE flags for each address
+    watched: Vec<u8>,
+    accesses: Vec<MemoryAccess>,
+}
+
+impl MemoryWatch {
+    pub fn new() -> MemoryWatch {
+        MemoryWatch {
+            watched: vec![0; 0x10000],
+            accesses: Vec::new(),
+        }
+    }
+
+    pub fn watch(&mut self, address: u16, reads: bool, writes: bool) {
+        let flags = &mut self.watched[address as usize];
+        if reads {
+            *flags |= READ;
+        }
+        if writes {
+            *flags |= WRITE;
+        }
+    }
+
+    pub fn record_read(&mut self, address: u16, value: u8) {
+        if self.watched[address as usize] & READ != 0 {
+            self.accesses.push(MemoryAccess { address, value, write: false });
+        }
+    }
+
+    pub fn record_write(&mut self, address: u16, value: u8) {
+        if self.watched[address as usize] & WRITE != 0 {
+            self.accesses.push(MemoryAccess { address, value, write: true });
+        }
+    }
+
+    pub fn has_accesses(&self) -> bool {
+        !self.accesses.is_empty()
+    }
+
+    // The accesses since the last call, in the order they happened
+    pub fn take_accesses(&mut self) -> Vec<MemoryAccess> {
+        std::mem::take(&mut self.accesses)
+    }
+}
@@ -0,0 +1,120 @@
+use std::io::Read;
+
+use flate2::read::DeflateDecoder;
+
+// Just enough of the zip format to take a ROM out of an archive: the central
+// directory is searched for the first .nes file, which may be stored or
+// deflated.
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const CENTRAL_DIRECTORY_ENTRY: u32 = 0x0201_4b50;
+const LOCAL_FILE_HEADER: u32 = 0x0403_4b50;
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| "The zip file is truncated".to_string())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "The zip file is truncated".to_string())
+}
+
+// The contents of the first file in the archive whose name ends with .nes
+pub fn extract_nes_file(data: &[u8]) -> Result<Vec<u8>, String> {
+    // The end record is last, followed by a comment of at most 64KB
+    let end = (0..data.len().saturating_sub(21)).rev()
+        .find(|offset| u32_at(data, *offset) == Ok(END_OF_CENTRAL_DIRECTORY))
+        .ok_or("Not a zip file")?;
+    let entry_count = u16_at(data, end + 10)?;
+    let mut offset = u32_at(data, end + 16)? as usize;
+    for _ in 0..entry_count {
+        if u32_at(data, offset)? != CENTRAL_DIRECTORY_ENTRY {
+            return Err("The zip file has an invalid directory".to_string());
+        }
+        let method = u16_at(data, offset + 10)?;
+        let compressed_size = u32_at(data, offset + 20)? as usize;
+        let size = u32_at(data, offset + 24)? as usize;
+        let name_length = u16_at(data, offset + 28)? as usize;
+        let extra_length = u16_at(data, offset + 30)? as usize;
+        let comment_length = u16_at(data, offset + 32)? as usize;
+        let header_offset = u32_at(data, offset + 42)? as usize;
+        let name = data.get(offset + 46..offset + 46 + name_length)
+            .ok_or("The zip file is truncated")?;
+        offset += 46 + name_length + extra_length + comment_length;
+
+        if !String::from_utf8_lossy(name).to_lowercase().ends_with(".nes") {
+            continue;
+        }
+        if u32_at(data, header_offset)? != LOCAL_FILE_HEADER {
+            return Err("The zip file has an invalid file header".to_string());
+        }
+        let start = header_offset + 30 +
+            u16_at(data, header_offset + 26)? as usize + u16_at(data, header_offset + 28)? as usize;
+        let compressed = data.get(start..start + compressed_size)
+            .ok_or("The zip file is truncated")?;
+        return match method {
+            0 => Ok(compressed.to_vec()),
+            8 => {
+                let mut contents = Vec::with_capacity(size);
+                DeflateDecoder::new(compressed).read_to_end(&mut contents)
+                    .map_err(|e| format!("Unable to decompress the ROM: {}", e))?;
+                Ok(contents)
+            }
+            _ => Err(format!("Unsupported zip compression method {}", method)),
+        };
+    }
+    Err("No .nes file in the zip file".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An archive with the files stored uncompressed
+    fn stored_zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut directory = Vec::new();
+        for (name, contents) in files {
+            let header_offset = data.len() as u32;
+            data.extend_from_slice(&LOCAL_FILE_HEADER.to_le_bytes());
+            data.extend_from_slice(&[0; 14]);
+            data.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            data.extend_from_slice(&[0; 2]);
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(contents);
+
+            directory.extend_from_slice(&CENTRAL_DIRECTORY_ENTRY.to_le_bytes());
+            directory.extend_from_slice(&[0; 16]);
+            directory.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&[0; 12]);
+            directory.extend_from_slice(&header_offset.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let directory_offset = data.len() as u32;
+        data.extend_from_slice(&directory);
+        data.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        data.extend_from_slice(&directory_offset.to_le_bytes());
+        data.extend_from_slice(&[0; 2]);
+        data
+    }
+
+    #[test]
+    fn extracts_the_first_nes_file() {
+        let zip = stored_zip(&[("readme.txt", b"text"), ("game.NES", b"rom"), ("b.nes", b"other")]);
+        assert_eq!(extract_nes_file(&zip), Ok(b"rom".to_vec()));
+        let zip = stored_zip(&[("readme.txt", b"text")]);
+        assert!(extract_nes_file(&zip).is_err());
+        assert!(extract_nes_file(b"NES\x1a").is_err());
+    }
+}
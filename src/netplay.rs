@@ -0,0 +1,290 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use nesemu::nes;
+
+// Two player netplay over TCP. Both sides run the whole game and only send
+// their controller input to each other: the host plays player 1 and the other
+// side player 2. The input read on a frame is used input_delay frames later,
+// so that it has usually reached the other side by the time it is needed and
+// the game does not have to wait for the network. To start from the same
+// state the host sends its machine state when the other side connects, and
+// the sides compare hashes of their states now and then to notice when they
+// have drifted apart.
+
+const MAGIC: &[u8; 6] = b"NESNET";
+const VERSION: u8 = 1;
+
+const MESSAGE_INPUT: u8 = 1;
+const MESSAGE_HASH: u8 = 2;
+
+const HASH_INTERVAL_FRAMES: u32 = 60;
+
+// Machine states are far smaller, so a larger size is not from nesemu
+const MAX_STATE_SIZE: u32 = 4 * 1024 * 1024;
+
+pub const DEFAULT_INPUT_DELAY: u32 = 2;
+pub const MAX_INPUT_DELAY: u32 = 30;
+
+pub struct Session {
+    stream: TcpStream,
+    // 1 on the host, 2 on the other side
+    player: usize,
+    input_delay: u32,
+    frame: u32,
+    // The buttons of both sides for the coming frames, as bits with A in
+    // bit 0, starting with the current frame
+    local_inputs: VecDeque<u8>,
+    remote_inputs: VecDeque<u8>,
+    // Hashes of one side waiting for the hash of the other side for the
+    // same frame
+    local_hashes: VecDeque<(u32, u32)>,
+    remote_hashes: VecDeque<(u32, u32)>,
+}
+
+fn to_bits(buttons: [bool; 8]) -> u8 {
+    buttons.iter().rev().fold(0, |bits, pressed| (bits << 1) | *pressed as u8)
+}
+
+fn from_bits(bits: u8) -> [bool; 8] {
+    let mut buttons = [false; 8];
+    for (i, button) in buttons.iter_mut().enumerate() {
+        *button = bits & (1 << i) != 0;
+    }
+    buttons
+}
+
+fn disconnected(e: std::io::Error) -> String {
+    format!("The other player disconnected: {}", e)
+}
+
+fn read_u32(stream: &mut TcpStream) -> Result<u32, String> {
+    let mut bytes = [0; 4];
+    stream.read_exact(&mut bytes).map_err(disconnected)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+// Both sides send the magic, the protocol version and the CRC32 of their ROM
+fn handshake(stream: &mut TcpStream, rom_crc32: u32) -> Result<(), String> {
+    let mut hello = MAGIC.to_vec();
+    hello.push(VERSION);
+    hello.extend_from_slice(&rom_crc32.to_le_bytes());
+    stream.write_all(&hello).map_err(disconnected)?;
+
+    let mut reply = [0; 11];
+    stream.read_exact(&mut reply).map_err(disconnected)?;
+    if &reply[..6] != MAGIC {
+        return Err("The other side is not nesemu".to_string());
+    }
+    if reply[6] != VERSION {
+        return Err(format!("The other player uses netplay version {}, this is version {}",
+                           reply[6], VERSION));
+    }
+    if reply[7..] != rom_crc32.to_le_bytes() {
+        return Err("The other player is playing a different ROM".to_string());
+    }
+    Ok(())
+}
+
+impl Session {
+    fn new(stream: TcpStream, player: usize, input_delay: u32) -> Session {
+        Session {
+            stream,
+            player,
+            input_delay,
+            frame: 0,
+            // Nothing is pressed during the first frames
+            local_inputs: vec![0; input_delay as usize].into(),
+            remote_inputs: vec![0; input_delay as usize].into(),
+            local_hashes: VecDeque::new(),
+            remote_hashes: VecDeque::new(),
+        }
+    }
+
+    // Waits for the other player to connect and sends the current state of
+    // the machine, which the game continues from on both sides
+    pub fn host(listener: &TcpListener, input_delay: u32, cpu: &nes::cpu::Cpu,
+                machine: &nes::Machine) -> Result<Session, String> {
+        let (mut stream, address) = listener.accept().map_err(|e| e.to_string())?;
+        println!("Player 2 connected from {}", address);
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        handshake(&mut stream, machine.rom_crc32())?;
+
+        let state = nes::snapshot(cpu, machine).to_bytes();
+        let mut message = input_delay.to_le_bytes().to_vec();
+        message.extend_from_slice(&(state.len() as u32).to_le_bytes());
+        message.extend_from_slice(&state);
+        stream.write_all(&message).map_err(disconnected)?;
+        Ok(Session::new(stream, 1, input_delay))
+    }
+
+    // Connects to a host and continues from its state, with the input delay
+    // of the host
+    pub fn connect(address: &str, cpu: &mut nes::cpu::Cpu, machine: &mut nes::Machine)
+                   -> Result<Session, String> {
+        let mut stream = TcpStream::connect(address)
+            .map_err(|e| format!("Unable to connect to {}: {}", address, e))?;
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        handshake(&mut stream, machine.rom_crc32())?;
+
+        let input_delay = read_u32(&mut stream)?;
+        if input_delay > MAX_INPUT_DELAY {
+            return Err(format!("The input delay of the host is too large: {} frames", input_delay));
+        }
+        let state_size = read_u32(&mut stream)?;
+        if state_size > MAX_STATE_SIZE {
+            return Err(format!("The state of the host is too large: {} bytes", state_size));
+        }
+        let mut state = vec![0; state_size as usize];
+        stream.read_exact(&mut state).map_err(disconnected)?;
+        nes::load_snapshot(cpu, machine, &nes::state::Snapshot::from_bytes(&state)?)
+            .map_err(|e| format!("Unable to use the state of the host: {}", e))?;
+        Ok(Session::new(stream, 2, input_delay))
+    }
+
+    pub fn player(&self) -> usize {
+        self.player
+    }
+
+    fn send(&mut self, message_type: u8, frame: u32, value: &[u8]) -> Result<(), String> {
+        let mut message = vec![message_type];
+        message.extend_from_slice(&frame.to_le_bytes());
+        message.extend_from_slice(value);
+        self.stream.write_all(&message).map_err(disconnected)
+    }
+
+    // Reads one message from the other side
+    fn receive(&mut self) -> Result<(), String> {
+        let mut message_type = [0];
+        self.stream.read_exact(&mut message_type).map_err(disconnected)?;
+        let frame = read_u32(&mut self.stream)?;
+        match message_type[0] {
+            MESSAGE_INPUT => {
+                let mut buttons = [0];
+                self.stream.read_exact(&mut buttons).map_err(disconnected)?;
+                let expected = self.frame + self.remote_inputs.len() as u32;
+                if frame != expected {
+                    return Err(format!("Received the input of frame {} instead of frame {}",
+                                       frame, expected));
+                }
+                self.remote_inputs.push_back(buttons[0]);
+                Ok(())
+            }
+            MESSAGE_HASH => {
+                let hash = read_u32(&mut self.stream)?;
+                self.remote_hashes.push_back((frame, hash));
+                self.compare_hashes()
+            }
+            _ => Err(format!("Unknown netplay message {}", message_type[0])),
+        }
+    }
+
+    fn compare_hashes(&mut self) -> Result<(), String> {
+        while let (Some(local), Some(remote)) = (self.local_hashes.front(), self.remote_hashes.front()) {
+            if local != remote {
+                return Err(format!("Desync detected at frame {}", local.0));
+            }
+            self.local_hashes.pop_front();
+            self.remote_hashes.pop_front();
+        }
+        Ok(())
+    }
+
+    // Sends the buttons held on this side and returns the buttons of players
+    // 1 and 2 for the coming frame, waiting for the other side if needed
+    pub fn exchange_input(&mut self, buttons: [bool; 8]) -> Result<[[bool; 8]; 2], String> {
+        let bits = to_bits(buttons);
+        self.local_inputs.push_back(bits);
+        self.send(MESSAGE_INPUT, self.frame + self.input_delay, &[bits])?;
+        while self.remote_inputs.is_empty() {
+            self.receive()?;
+        }
+        let local = from_bits(self.local_inputs.pop_front().unwrap());
+        let remote = from_bits(self.remote_inputs.pop_front().unwrap());
+        self.frame += 1;
+        Ok(if self.player == 1 { [local, remote] } else { [remote, local] })
+    }
+
+    // Called after each frame to compare the state with the other side now
+    // and then
+    pub fn check_state(&mut self, cpu: &nes::cpu::Cpu, machine: &nes::Machine) -> Result<(), String> {
+        if !self.frame.is_multiple_of(HASH_INTERVAL_FRAMES) {
+            return Ok(());
+        }
+        let hash = nes::state_hash(cpu, machine);
+        self.local_hashes.push_back((self.frame, hash));
+        self.send(MESSAGE_HASH, self.frame, &hash.to_le_bytes())?;
+        self.compare_hashes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start_machine() -> (nes::Machine, nes::cpu::Cpu) {
+        let (cpu, mut machine) = nes::cartridge::start_controller_reading_machine();
+        machine.controller.plug_in(2);
+        (machine, cpu)
+    }
+
+    // Plays the frames with the buttons pressed on this side changing every
+    // frame, and returns the buttons of both players for each frame
+    fn play(session: &mut Session, cpu: &mut nes::cpu::Cpu, machine: &mut nes::Machine,
+            frames: u32) -> Vec<[[bool; 8]; 2]> {
+        let mut inputs = Vec::new();
+        for frame in 0..frames {
+            let pressed = from_bits((frame * session.player() as u32) as u8);
+            let buttons = session.exchange_input(pressed).unwrap();
+            machine.controller.set_player_buttons(1, buttons[0]);
+            machine.controller.set_player_buttons(2, buttons[1]);
+            nes::run_frame(cpu, machine);
+            session.check_state(cpu, machine).unwrap();
+            inputs.push(buttons);
+        }
+        inputs
+    }
+
+    #[test]
+    fn both_sides_play_the_same_game() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let client = std::thread::spawn(move || {
+            let (mut machine, mut cpu) = start_machine();
+            let mut session = Session::connect(&address, &mut cpu, &mut machine).unwrap();
+            let inputs = play(&mut session, &mut cpu, &mut machine, 130);
+            // The session is kept open until the host has read everything
+            (inputs, nes::state_hash(&cpu, &machine), session)
+        });
+
+        let (mut machine, mut cpu) = start_machine();
+        // The host has played a while before the other player joins
+        nes::run_frame(&mut cpu, &mut machine);
+        let mut session = Session::host(&listener, 3, &cpu, &machine).unwrap();
+        let inputs = play(&mut session, &mut cpu, &mut machine, 130);
+        let (client_inputs, client_hash, _) = client.join().unwrap();
+
+        assert_eq!(inputs, client_inputs);
+        assert_eq!(inputs[2], [[false; 8]; 2]);
+        assert_eq!(inputs[4], [from_bits(1), from_bits(2)]);
+        assert_eq!(inputs[10], [from_bits(7), from_bits(14)]);
+        assert_eq!(nes::state_hash(&cpu, &machine), client_hash);
+        assert_ne!(machine.peek_mem(0x0002), 0);
+    }
+
+    #[test]
+    fn a_too_large_input_delay_is_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let client = std::thread::spawn(move || {
+            let (mut machine, mut cpu) = start_machine();
+            Session::connect(&address, &mut cpu, &mut machine).err()
+        });
+
+        let (machine, cpu) = start_machine();
+        let _session = Session::host(&listener, MAX_INPUT_DELAY + 1, &cpu, &machine).unwrap();
+        assert_eq!(client.join().unwrap().as_deref(),
+                   Some("The input delay of the host is too large: 31 frames"));
+    }
+}
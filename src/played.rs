@@ -0,0 +1,187 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+// The games that have been played, the last played first, with how long they
+// have been played in all. They are kept in played.toml next to the default
+// configuration file, listed by nesemu list and shown first by the ROM
+// browser.
+
+// Games further back are forgotten
+const MAX_GAMES: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Game {
+    pub path: PathBuf,
+    // Of the PRG and CHR data, in hexadecimal like in the configuration
+    pub crc32: String,
+    // Seconds since 1970
+    pub last_played: u64,
+    pub playtime_seconds: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Played {
+    #[serde(default, rename = "game")]
+    games: Vec<Game>,
+}
+
+impl Played {
+    // A missing file gives no games
+    pub fn load(path: &Path) -> Result<Played, String> {
+        if !path.exists() {
+            return Ok(Played::default());
+        }
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("Invalid list of played games in {}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = toml::to_string(self).map_err(|e| e.to_string())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("Unable to create {}: {}", dir.display(), e))?;
+        }
+        fs::write(path, text).map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+    }
+
+    // Puts the game first, keeping the time it has been played, which is
+    // returned
+    pub fn start(&mut self, rom: &Path, crc32: u32, now: u64) -> u64 {
+        let position = self.games.iter().position(|game| game.path == rom);
+        let playtime_seconds = position.map_or(0, |i| self.games.remove(i).playtime_seconds);
+        let game = Game { path: rom.to_path_buf(), crc32: format!("{:08X}", crc32), last_played: now, playtime_seconds };
+        self.games.insert(0, game);
+        self.games.truncate(MAX_GAMES);
+        playtime_seconds
+    }
+
+    pub fn add_playtime(&mut self, rom: &Path, seconds: u64) {
+        if let Some(game) = self.games.iter_mut().find(|game| game.path == rom) {
+            game.playtime_seconds += seconds;
+        }
+    }
+}
+
+pub fn path() -> Option<PathBuf> {
+    config::Config::default_path().map(|path| path.with_file_name("played.toml"))
+}
+
+// The played games whose ROMs are still there, the last played first
+pub fn recent(count: usize) -> Vec<Game> {
+    let played = match path().map(|path| Played::load(&path)) {
+        Some(Ok(played)) => played,
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            return Vec::new();
+        }
+        None => { return Vec::new(); }
+    };
+    played.games.into_iter().filter(|game| game.path.is_file()).take(count).collect()
+}
+
+fn update(f: impl FnOnce(&mut Played)) {
+    let path = match path() {
+        Some(path) => path,
+        None => { return; }
+    };
+    let result = Played::load(&path).and_then(|mut played| {
+        f(&mut played);
+        played.save(&path)
+    });
+    if let Err(e) = result {
+        eprintln!("{}", e);
+    }
+}
+
+// The same game is found by any path to it
+fn full_path(rom: &Path) -> PathBuf {
+    fs::canonicalize(rom).unwrap_or_else(|_| rom.to_path_buf())
+}
+
+// Returns how long the game has been played before
+pub fn start(rom: &Path, crc32: u32) -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let mut playtime_seconds = 0;
+    update(|played| { playtime_seconds = played.start(&full_path(rom), crc32, now); });
+    playtime_seconds
+}
+
+pub fn add_playtime(rom: &Path, seconds: u64) {
+    update(|played| played.add_playtime(&full_path(rom), seconds));
+}
+
+// Hours and minutes, e.g. 1:05
+pub fn format_playtime(seconds: u64) -> String {
+    format!("{}:{:02}", seconds / 3600, seconds / 60 % 60)
+}
+
+// In UTC, e.g. 2024-05-01 18:30
+pub fn format_time(seconds: u64) -> String {
+    // From the days since 1970 to the date, as in
+    // http://howardhinnant.github.io/date_algorithms.html
+    let days = seconds / 86400 + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Counted from March
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let (year, month) = if month < 10 { (0, month + 3) } else { (1, month - 9) };
+    format!("{}-{:02}-{:02} {:02}:{:02}", era * 400 + year_of_era + year, month, day,
+            seconds / 3600 % 24, seconds / 60 % 60)
+}
+
+// Prints the played games for nesemu list
+pub fn print_list() {
+    let games = path().map(|path| Played::load(&path)).transpose()
+        .unwrap_or_else(|e| crate::exit_with_error(&e))
+        .map_or_else(Vec::new, |played| played.games);
+    if games.is_empty() {
+        println!("No games have been played");
+        return;
+    }
+    println!("{:<17}  {:>8}  {:<8}  ROM", "Last played (UTC)", "Playtime", "CRC32");
+    for game in games {
+        println!("{:<17}  {:>8}  {:<8}  {}", format_time(game.last_played),
+                 format_playtime(game.playtime_seconds), game.crc32, game.path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn games_are_kept_with_their_playtime() {
+        let path = env::temp_dir().join(format!("nesemu-played-{}", std::process::id()))
+            .join("played.toml");
+        let mut played = Played::load(&path).unwrap();
+        played.start(Path::new("/roms/a.nes"), 0x158B0388, 1000);
+        played.add_playtime(Path::new("/roms/a.nes"), 3900);
+        played.start(Path::new("/roms/b.nes"), 0x12345678, 2000);
+        assert_eq!(played.start(Path::new("/roms/a.nes"), 0x158B0388, 3000), 3900);
+        played.add_playtime(Path::new("/roms/a.nes"), 100);
+        played.add_playtime(Path::new("/roms/c.nes"), 100);
+        played.save(&path).unwrap();
+
+        let played = Played::load(&path).unwrap();
+        assert_eq!(played.games, [
+            Game { path: PathBuf::from("/roms/a.nes"), crc32: "158B0388".to_string(),
+                   last_played: 3000, playtime_seconds: 4000 },
+            Game { path: PathBuf::from("/roms/b.nes"), crc32: "12345678".to_string(),
+                   last_played: 2000, playtime_seconds: 0 },
+        ]);
+        assert_eq!(format_playtime(4000), "1:06");
+        assert_eq!(format_time(0), "1970-01-01 00:00");
+        assert_eq!(format_time(951782400), "2000-02-29 00:00");
+        assert_eq!(format_time(1700000000), "2023-11-14 22:13");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
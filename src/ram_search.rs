@@ -0,0 +1,132 @@
+use nesemu::nes;
+
+// Finds where a game keeps a number, like the lives or the health, by
+// comparing the memory over time: start a search, change the number in the
+// game, keep the addresses whose value changed the same way and repeat until
+// few are left.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Filter {
+    Equal(u8),
+    NotEqual(u8),
+    Increased,
+    Decreased,
+    Changed,
+    Unchanged,
+}
+
+impl Filter {
+    // E.g. "dec", or "eq 3"
+    pub fn parse(words: &[&str]) -> Result<Filter, String> {
+        let value = || words.get(1).and_then(|v| parse_value(v))
+            .ok_or(format!("{} requires a value from 0 to 255", words[0]));
+        match words.first().copied() {
+            Some("eq") => Ok(Filter::Equal(value()?)),
+            Some("ne") => Ok(Filter::NotEqual(value()?)),
+            Some("inc") => Ok(Filter::Increased),
+            Some("dec") => Ok(Filter::Decreased),
+            Some("changed") => Ok(Filter::Changed),
+            Some("same") => Ok(Filter::Unchanged),
+            _ => Err("The search filters are eq <n>, ne <n>, inc, dec, changed and same".to_string()),
+        }
+    }
+
+    fn matches(self, previous: u8, current: u8) -> bool {
+        match self {
+            Filter::Equal(value) => current == value,
+            Filter::NotEqual(value) => current != value,
+            Filter::Increased => current > previous,
+            Filter::Decreased => current < previous,
+            Filter::Changed => current != previous,
+            Filter::Unchanged => current == previous,
+        }
+    }
+}
+
+// Decimal, or hexadecimal with $ or 0x
+pub fn parse_value(text: &str) -> Option<u8> {
+    match text.strip_prefix('$').or_else(|| text.strip_prefix("0x")) {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Candidate {
+    pub address: u16,
+    pub previous: u8,
+    pub current: u8,
+}
+
+pub struct RamSearch {
+    candidates: Vec<Candidate>,
+}
+
+// The work RAM, and the RAM on the cartridge if it has any
+fn searched_addresses(machine: &mut nes::Machine) -> Vec<u16> {
+    let mut addresses: Vec<u16> = (0x0000..0x0800).collect();
+    if machine.has_prg_ram() {
+        addresses.extend(0x6000..0x8000);
+    }
+    addresses
+}
+
+impl RamSearch {
+    // Starts with every address as a candidate
+    pub fn new(machine: &mut nes::Machine) -> RamSearch {
+        let candidates = searched_addresses(machine).into_iter().map(|address| {
+            let value = machine.peek_mem(address);
+            Candidate { address, previous: value, current: value }
+        }).collect();
+        RamSearch { candidates }
+    }
+
+    // Keeps the candidates whose value now compared to the last time matches
+    // the filter
+    pub fn filter(&mut self, filter: Filter, machine: &mut nes::Machine) {
+        for candidate in &mut self.candidates {
+            candidate.previous = candidate.current;
+            candidate.current = machine.peek_mem(candidate.address);
+        }
+        self.candidates.retain(|c| filter.matches(c.previous, c.current));
+    }
+
+    pub fn candidates(&self) -> &[Candidate] {
+        &self.candidates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_narrow_down_the_candidates() {
+        let (_, mut machine) = nes::cartridge::start_controller_reading_machine();
+        machine.poke_mem(0x0075, 3);
+        machine.poke_mem(0x6010, 3);
+        let mut search = RamSearch::new(&mut machine);
+        assert_eq!(search.candidates().len(), 0x800 + 0x2000);
+
+        machine.poke_mem(0x0075, 2);
+        machine.poke_mem(0x6010, 2);
+        machine.poke_mem(0x0100, 1);
+        search.filter(Filter::Decreased, &mut machine);
+        assert_eq!(search.candidates().len(), 2);
+
+        machine.poke_mem(0x6010, 7);
+        search.filter(Filter::parse(&["eq", "2"]).unwrap(), &mut machine);
+        assert_eq!(search.candidates(), [Candidate { address: 0x0075, previous: 2, current: 2 }]);
+    }
+
+    #[test]
+    fn values_and_filters_are_parsed() {
+        assert_eq!(parse_value("$1F"), Some(0x1F));
+        assert_eq!(parse_value("0x10"), Some(0x10));
+        assert_eq!(parse_value("99"), Some(99));
+        assert_eq!(parse_value("256"), None);
+        assert_eq!(Filter::parse(&["ne", "$FF"]), Ok(Filter::NotEqual(0xFF)));
+        assert!(Filter::parse(&["eq"]).is_err());
+        assert!(Filter::parse(&["bigger"]).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use nesemu::nes;
+use nesemu::nes::screen::{HEIGHT, WIDTH};
+
+// Remote control with --remote, so that test scripts and practice tools can
+// drive a running nesemu. Clients connect to a TCP port on localhost or, on
+// Unix, to a Unix socket, and send requests as JSON objects, one per line,
+// e.g. {"command": "read", "address": 117}. Each request gets one line back,
+// {"ok": true, ...} or {"ok": false, "error": "..."}. The requests are run
+// between frames, like the console commands; a step is answered when its
+// frames have run.
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase", deny_unknown_fields)]
+enum Request {
+    Pause,
+    Resume,
+    // Runs the frames, then pauses
+    Step { frames: u32 },
+    Read { address: u16, length: Option<usize>, space: Option<String> },
+    Write { address: u16, values: Vec<u8>, space: Option<String> },
+    // Holds the buttons down until release, or for the frames
+    Press { buttons: Vec<String>, player: Option<usize>, frames: Option<u32> },
+    Release,
+    // Saves the picture as a PPM file
+    Screenshot { path: PathBuf },
+    Status,
+}
+
+type Reply = mpsc::Sender<String>;
+
+fn response(result: Result<Value, String>) -> String {
+    let mut value = match result {
+        Ok(value) => value,
+        Err(e) => json!({ "ok": false, "error": e }),
+    };
+    if let Some(object) = value.as_object_mut() {
+        object.entry("ok").or_insert(json!(true));
+    }
+    value.to_string()
+}
+
+// Reads the requests of a client and answers them in order. Requests that
+// are not valid JSON are answered here.
+fn serve<S: Read + Write>(stream: S, requests: mpsc::Sender<(Request, Reply)>) {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    while reader.read_line(&mut line).is_ok_and(|n| n > 0) {
+        let answer = match serde_json::from_str::<Request>(line.trim()) {
+            Ok(request) => {
+                let (reply, answer) = mpsc::channel();
+                if requests.send((request, reply)).is_err() {
+                    return;
+                }
+                match answer.recv() {
+                    Ok(answer) => answer,
+                    Err(_) => return,
+                }
+            }
+            Err(e) => response(Err(format!("Invalid request: {}", e))),
+        };
+        if writeln!(reader.get_mut(), "{}", answer).is_err() {
+            return;
+        }
+        line.clear();
+    }
+}
+
+fn accept<S, I>(incoming: I, requests: mpsc::Sender<(Request, Reply)>)
+    where S: Read + Write + Send + 'static, I: Iterator<Item = std::io::Result<S>>
+{
+    for stream in incoming.flatten() {
+        let requests = requests.clone();
+        thread::spawn(move || serve(stream, requests));
+    }
+}
+
+fn parse_space(space: &Option<String>) -> Result<nes::MemorySpace, String> {
+    match space {
+        Some(name) => nes::MemorySpace::from_name(name).ok_or(format!("Unknown memory space: {}", name)),
+        None => Ok(nes::MemorySpace::Cpu),
+    }
+}
+
+fn ppm_file(picture: &[u8]) -> Vec<u8> {
+    let mut data = format!("P6\n{} {}\n255\n", WIDTH, HEIGHT).into_bytes();
+    data.extend_from_slice(picture);
+    data
+}
+
+pub struct Remote {
+    requests: mpsc::Receiver<(Request, Reply)>,
+    paused: bool,
+    // The frames left of a step, and where to answer when they have run
+    step: Option<(u32, Reply)>,
+    // The frames left of a press, if it has a length
+    press_frames: Option<u32>,
+    frame_count: u64,
+}
+
+impl Remote {
+    // A port number for TCP, otherwise the path of a Unix socket
+    pub fn start(address: &str) -> Result<Remote, String> {
+        let (sender, requests) = mpsc::channel();
+        if let Ok(port) = address.parse::<u16>() {
+            let listener = TcpListener::bind(("127.0.0.1", port))
+                .map_err(|e| format!("Unable to listen on port {}: {}", port, e))?;
+            thread::spawn(move || accept(listener.incoming(), sender));
+        }
+        else {
+            Remote::listen_unix(address, sender)?;
+        }
+        println!("Remote control on {}", address);
+        Ok(Remote::new(requests))
+    }
+
+    #[cfg(unix)]
+    fn listen_unix(path: &str, sender: mpsc::Sender<(Request, Reply)>) -> Result<(), String> {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::UnixListener;
+
+        // Left behind by an earlier run
+        if fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            fs::remove_file(path).map_err(|e| format!("Unable to remove {}: {}", path, e))?;
+        }
+        let listener = UnixListener::bind(path).map_err(|e| format!("Unable to listen on {}: {}", path, e))?;
+        thread::spawn(move || accept(listener.incoming(), sender));
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn listen_unix(path: &str, _sender: mpsc::Sender<(Request, Reply)>) -> Result<(), String> {
+        Err(format!("--remote requires a port number here, not {}", path))
+    }
+
+    fn new(requests: mpsc::Receiver<(Request, Reply)>) -> Remote {
+        Remote {
+            requests,
+            paused: false,
+            step: None,
+            press_frames: None,
+            frame_count: 0,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Runs the requests that have arrived since the last call
+    pub fn run_requests(&mut self, machine: &mut nes::Machine) {
+        while let Ok((request, reply)) = self.requests.try_recv() {
+            if let Request::Step { frames } = request {
+                self.start_step(frames, reply, machine);
+                continue;
+            }
+            let _ = reply.send(response(self.run_request(request, machine)));
+        }
+    }
+
+    fn start_step(&mut self, frames: u32, reply: Reply, machine: &mut nes::Machine) {
+        if frames == 0 {
+            let _ = reply.send(response(Ok(json!({ "frame": self.frame_count }))));
+            return;
+        }
+        if let Some((_, earlier)) = self.step.take() {
+            let _ = earlier.send(response(Err("Replaced by another step".to_string())));
+        }
+        self.step = Some((frames, reply));
+        self.set_paused(false, machine);
+    }
+
+    fn set_paused(&mut self, paused: bool, machine: &mut nes::Machine) {
+        self.paused = paused;
+        machine.set_status("paused", paused);
+    }
+
+    fn run_request(&mut self, request: Request, machine: &mut nes::Machine) -> Result<Value, String> {
+        match request {
+            Request::Pause => {
+                self.set_paused(true, machine);
+            }
+            Request::Resume => {
+                if let Some((_, reply)) = self.step.take() {
+                    let _ = reply.send(response(Err("Resumed before the step ended".to_string())));
+                }
+                self.set_paused(false, machine);
+            }
+            // Answered when its frames have run, see run_requests
+            Request::Step { .. } => unreachable!(),
+            Request::Read { address, length, space } => {
+                let space = parse_space(&space)?;
+                let length = length.unwrap_or(1).min(space.size());
+                let values: Vec<u8> = (0..length)
+                    .map(|i| machine.peek(space, address.wrapping_add(i as u16)))
+                    .collect();
+                return Ok(json!({ "values": values }));
+            }
+            Request::Write { address, values, space } => {
+                let space = parse_space(&space)?;
+                for (i, value) in values.iter().enumerate() {
+                    machine.poke(space, address.wrapping_add(i as u16), *value);
+                }
+            }
+            Request::Press { buttons, player, frames } => {
+                let player = player.unwrap_or(1);
+                if !(1..=nes::PLAYERS).contains(&player) {
+                    return Err(format!("Invalid player: {}", player));
+                }
+                let indexes = buttons.iter()
+                    .map(|name| {
+                        nes::BUTTON_NAMES.iter().position(|b| b == name)
+                            .ok_or(format!("Unknown controller button: {}", name))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                for index in indexes {
+                    machine.controller.override_button(player, index, true);
+                }
+                self.press_frames = frames;
+            }
+            Request::Release => {
+                machine.controller.clear_overrides();
+                self.press_frames = None;
+            }
+            Request::Screenshot { path } => {
+                fs::write(&path, ppm_file(machine.frame_buffer()))
+                    .map_err(|e| format!("Unable to write {}: {}", path.display(), e))?;
+            }
+            Request::Status => {
+                return Ok(json!({ "paused": self.paused, "frame": self.frame_count }));
+            }
+        }
+        Ok(json!({}))
+    }
+
+    // Counts down the step and the press after each frame that has run
+    pub fn end_frame(&mut self, machine: &mut nes::Machine) {
+        self.frame_count += 1;
+        if let Some(frames) = self.press_frames.as_mut() {
+            *frames = frames.saturating_sub(1);
+            if *frames == 0 {
+                machine.controller.clear_overrides();
+                self.press_frames = None;
+            }
+        }
+        if let Some((frames, reply)) = self.step.as_mut() {
+            *frames -= 1;
+            if *frames == 0 {
+                let _ = reply.send(response(Ok(json!({ "frame": self.frame_count }))));
+                self.step = None;
+                self.set_paused(true, machine);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A, B, select and start of player 1, as the game reads them
+    fn read_buttons(machine: &mut nes::Machine) -> Vec<u8> {
+        machine.controller.write_mem(0x4016, 1);
+        machine.controller.write_mem(0x4016, 0);
+        (0..4).map(|_| machine.controller.read_mem(0x4016) & 1).collect()
+    }
+
+    fn request(line: &str) -> Request {
+        serde_json::from_str(line).unwrap()
+    }
+
+    #[test]
+    fn requests_are_parsed_from_json() {
+        assert_eq!(request(r#"{"command": "step", "frames": 3}"#), Request::Step { frames: 3 });
+        assert_eq!(request(r#"{"command": "read", "address": 117}"#),
+                   Request::Read { address: 117, length: None, space: None });
+        assert!(serde_json::from_str::<Request>(r#"{"command": "jump"}"#).is_err());
+        assert!(serde_json::from_str::<Request>(r#"{"command": "step"}"#).is_err());
+        assert_eq!(response(Ok(json!({ "frame": 2 }))), r#"{"frame":2,"ok":true}"#);
+        assert_eq!(response(Err("No".to_string())), r#"{"error":"No","ok":false}"#);
+    }
+
+    #[test]
+    fn memory_buttons_and_steps() {
+        let (_, mut machine) = nes::cartridge::start_controller_reading_machine();
+        let (sender, requests) = mpsc::channel();
+        let mut remote = Remote::new(requests);
+
+        let write = request(r#"{"command": "write", "address": 768, "values": [1, 2, 255]}"#);
+        remote.run_request(write, &mut machine).unwrap();
+        let read = request(r#"{"command": "read", "address": 769, "length": 2}"#);
+        assert_eq!(remote.run_request(read, &mut machine).unwrap(), json!({ "values": [2, 255] }));
+        let read = request(r#"{"command": "read", "address": 0, "space": "vram"}"#);
+        assert!(remote.run_request(read, &mut machine).is_err());
+
+        let press = request(r#"{"command": "press", "buttons": ["start"], "frames": 2}"#);
+        remote.run_request(press, &mut machine).unwrap();
+        assert_eq!(read_buttons(&mut machine), [0, 0, 0, 1]);
+        let press = request(r#"{"command": "press", "buttons": ["turbo"]}"#);
+        assert!(remote.run_request(press, &mut machine).is_err());
+
+        let (reply, answer) = mpsc::channel();
+        sender.send((request(r#"{"command": "step", "frames": 2}"#), reply)).unwrap();
+        remote.run_requests(&mut machine);
+        remote.end_frame(&mut machine);
+        assert!(answer.try_recv().is_err());
+        assert!(!remote.is_paused());
+        remote.end_frame(&mut machine);
+        assert_eq!(answer.try_recv().unwrap(), r#"{"frame":2,"ok":true}"#);
+        assert!(remote.is_paused());
+        // The press has ended too
+        assert_eq!(read_buttons(&mut machine), [0, 0, 0, 0]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn requests_are_answered_a_line_each() {
+        let (_, mut machine) = nes::cartridge::start_controller_reading_machine();
+        let (sender, requests) = mpsc::channel();
+        let mut remote = Remote::new(requests);
+        let (client, server) = std::os::unix::net::UnixStream::pair().unwrap();
+        thread::spawn(move || serve(server, sender));
+
+        // The requests are run between the reads, like the main loop does
+        // between frames
+        client.set_read_timeout(Some(std::time::Duration::from_millis(1))).unwrap();
+        let mut client = BufReader::new(client);
+        client.get_mut().write_all(b"{\"command\": \"pause\"}\n").unwrap();
+        writeln!(client.get_mut(), "not json").unwrap();
+        client.get_mut().write_all(b"{\"command\": \"status\"}\n").unwrap();
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        while lines.len() < 3 {
+            remote.run_requests(&mut machine);
+            match client.read_line(&mut line) {
+                Ok(_) => {
+                    lines.push(line.trim().to_string());
+                    line.clear();
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock ||
+                          e.kind() == std::io::ErrorKind::TimedOut => {}
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert_eq!(lines[0], r#"{"ok":true}"#);
+        assert!(lines[1].starts_with(r#"{"error":"Invalid request"#));
+        assert_eq!(lines[2], r#"{"frame":0,"ok":true,"paused":true}"#);
+    }
+}
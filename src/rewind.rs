@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+
+use nesemu::nes;
+
+// Stepping back a frame at a time while paused. The state of the machine is
+// kept every SNAPSHOT_INTERVAL frames, for the last MAX_SNAPSHOTS of them,
+// together with the buttons of every frame since the oldest state. A step
+// back loads the last state from before the frame and runs the frames from
+// there to it again, silently and with the same buttons.
+
+const SNAPSHOT_INTERVAL: u64 = 30;
+
+// A minute at 60 frames per second
+const MAX_SNAPSHOTS: usize = 120;
+
+pub struct Rewind {
+    // The frames run since the start
+    frame: u64,
+    // The states before the frames with these numbers
+    snapshots: VecDeque<(u64, nes::state::Snapshot)>,
+    // The buttons of each player on every frame from the oldest state on
+    inputs: VecDeque<[[bool; 8]; nes::PLAYERS]>,
+}
+
+impl Rewind {
+    pub fn new() -> Rewind {
+        Rewind { frame: 0, snapshots: VecDeque::new(), inputs: VecDeque::new() }
+    }
+
+    // Forgets the frames that have been run, e.g. after a reset or when a
+    // state has been loaded, which the frames after it would not repeat
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.inputs.clear();
+    }
+
+    // Called before each frame is run
+    pub fn start_frame(&mut self, cpu: &nes::cpu::Cpu, machine: &nes::Machine) {
+        if self.snapshots.back().is_none_or(|(frame, _)| self.frame - frame >= SNAPSHOT_INTERVAL) {
+            self.snapshots.push_back((self.frame, nes::snapshot(cpu, machine)));
+            if self.snapshots.len() > MAX_SNAPSHOTS {
+                self.snapshots.pop_front();
+                let oldest = self.snapshots[0].0;
+                let dropped = self.inputs.len() - (self.frame - oldest) as usize;
+                self.inputs.drain(..dropped);
+            }
+        }
+        let controller = &machine.controller;
+        self.inputs.push_back([1, 2, 3, 4].map(|player| controller.buttons(player)));
+        self.frame += 1;
+    }
+
+    // Returns the machine to where it was a frame ago
+    pub fn step_back(&mut self, cpu: &mut nes::cpu::Cpu, machine: &mut nes::Machine) -> Result<(), String> {
+        let target = self.frame.checked_sub(1)
+            .filter(|target| self.snapshots.front().is_some_and(|(frame, _)| frame <= target))
+            .ok_or("No earlier frames are kept")?;
+        let oldest = self.snapshots[0].0;
+        while self.snapshots.back().is_some_and(|(frame, _)| *frame > target) {
+            self.snapshots.pop_back();
+        }
+        let (start, state) = self.snapshots.back().unwrap();
+        nes::load_snapshot(cpu, machine, state)?;
+        machine.apu.set_silent(true);
+        machine.controller.clear_overrides();
+        for frame in *start..target {
+            let buttons = self.inputs[(frame - oldest) as usize];
+            for (player, buttons) in buttons.iter().enumerate() {
+                machine.controller.set_player_buttons(player + 1, *buttons);
+            }
+            if nes::try_run_frame(cpu, machine).is_err() {
+                break;
+            }
+        }
+        machine.apu.set_silent(false);
+        machine.controller.enable_keyboard();
+        self.inputs.truncate((target - oldest) as usize);
+        self.frame = target;
+        // The state of the target is kept again when it is left
+        if self.snapshots.back().is_some_and(|(frame, _)| *frame == target) {
+            self.snapshots.pop_back();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_back_to_the_same_state() {
+        let (mut cpu, mut machine) = nes::cartridge::start_controller_reading_machine();
+        let mut rewind = Rewind::new();
+        assert!(rewind.step_back(&mut cpu, &mut machine).is_err());
+        let mut hashes = Vec::new();
+        for frame in 0..35 {
+            machine.controller.set_player_buttons(1, [frame % 3 == 0; 8]);
+            hashes.push(nes::state_hash(&cpu, &machine));
+            rewind.start_frame(&cpu, &machine);
+            nes::run_frame(&mut cpu, &mut machine);
+        }
+        for frame in (32..35).rev().chain([31, 30, 29].iter().copied()) {
+            while rewind.frame > frame {
+                rewind.step_back(&mut cpu, &mut machine).unwrap();
+            }
+            assert_eq!(nes::state_hash(&cpu, &machine), hashes[frame as usize], "frame {}", frame);
+        }
+        // Forward again from there
+        rewind.start_frame(&cpu, &machine);
+        nes::run_frame(&mut cpu, &mut machine);
+        rewind.step_back(&mut cpu, &mut machine).unwrap();
+        assert_eq!(nes::state_hash(&cpu, &machine), hashes[29]);
+
+        rewind.clear();
+        assert!(rewind.step_back(&mut cpu, &mut machine).is_err());
+    }
+}
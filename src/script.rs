@@ -0,0 +1,248 @@
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, FuncArgs, AST, INT};
+
+use nesemu::nes;
+
+// Rhai scripts that run along with the game, for bots, practice hacks and
+// drawing over the picture, like the Lua scripts of FCEUX. The script is run
+// once when it is loaded and registers functions to be called before and
+// after each frame and when an instruction reads or writes an address. See
+// the README for the functions a script can use.
+
+// What the functions given to the script work on
+struct Context {
+    // The machine is lent to the script while it runs, by swapping it with
+    // this one
+    machine: nes::Machine,
+    frame: INT,
+    frame_start: Vec<FnPtr>,
+    frame_end: Vec<FnPtr>,
+    reads: Vec<(u16, FnPtr)>,
+    writes: Vec<(u16, FnPtr)>,
+}
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    context: Rc<RefCell<Context>>,
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+// Players are numbered from 1 and the buttons named as in BUTTON_NAMES
+fn button_index(player: INT, button: &str) -> ScriptResult<(usize, usize)> {
+    if !(1..=nes::PLAYERS as INT).contains(&player) {
+        return Err(format!("Invalid player: {}", player).into());
+    }
+    let index = nes::BUTTON_NAMES.iter().position(|b| *b == button)
+        .ok_or_else(|| format!("Unknown controller button: {}", button))?;
+    Ok((player as usize, index))
+}
+
+fn register_functions(engine: &mut Engine, context: &Rc<RefCell<Context>>) {
+    let c = context.clone();
+    engine.register_fn("read", move |address: INT| -> INT {
+        c.borrow_mut().machine.peek_mem(address as u16) as INT
+    });
+    let c = context.clone();
+    engine.register_fn("write", move |address: INT, value: INT| {
+        c.borrow_mut().machine.poke_mem(address as u16, value as u8);
+    });
+    let c = context.clone();
+    engine.register_fn("frame", move || -> INT { c.borrow().frame });
+
+    let c = context.clone();
+    engine.register_fn("pressed", move |player: INT, button: &str| -> ScriptResult<bool> {
+        let (player, index) = button_index(player, button)?;
+        Ok(c.borrow().machine.controller.keyboard_buttons(player)[index])
+    });
+    let c = context.clone();
+    engine.register_fn("press", move |player: INT, button: &str| -> ScriptResult<()> {
+        let (player, index) = button_index(player, button)?;
+        c.borrow_mut().machine.controller.override_button(player, index, true);
+        Ok(())
+    });
+    let c = context.clone();
+    engine.register_fn("release", move |player: INT, button: &str| -> ScriptResult<()> {
+        let (player, index) = button_index(player, button)?;
+        c.borrow_mut().machine.controller.override_button(player, index, false);
+        Ok(())
+    });
+
+    let c = context.clone();
+    engine.register_fn("draw_text", move |x: INT, y: INT, text: &str| {
+        if let Some(screen) = c.borrow_mut().machine.screen.as_mut() {
+            screen.draw_text(x.max(0) as u32, y.max(0) as u32, text);
+        }
+    });
+    let c = context.clone();
+    engine.register_fn("draw_box", move |x: INT, y: INT, width: INT, height: INT, color: INT| {
+        if let Some(screen) = c.borrow_mut().machine.screen.as_mut() {
+            let color = [(color >> 16) as u8, (color >> 8) as u8, color as u8];
+            screen.draw_box(x.max(0) as u32, y.max(0) as u32, width.max(0) as u32,
+                            height.max(0) as u32, color);
+        }
+    });
+
+    let c = context.clone();
+    engine.register_fn("on_frame_start", move |hook: FnPtr| {
+        c.borrow_mut().frame_start.push(hook);
+    });
+    let c = context.clone();
+    engine.register_fn("on_frame_end", move |hook: FnPtr| {
+        c.borrow_mut().frame_end.push(hook);
+    });
+    let c = context.clone();
+    engine.register_fn("on_read", move |address: INT, hook: FnPtr| {
+        let mut context = c.borrow_mut();
+        context.machine.watch_memory(address as u16, true, false);
+        context.reads.push((address as u16, hook));
+    });
+    let c = context.clone();
+    engine.register_fn("on_write", move |address: INT, hook: FnPtr| {
+        let mut context = c.borrow_mut();
+        context.machine.watch_memory(address as u16, false, true);
+        context.writes.push((address as u16, hook));
+    });
+}
+
+impl Script {
+    pub fn load(path: &Path, machine: &mut nes::Machine) -> Result<Script, String> {
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        Script::from_source(&source, machine).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn from_source(source: &str, machine: &mut nes::Machine) -> Result<Script, String> {
+        let context = Rc::new(RefCell::new(Context {
+            machine: nes::Machine::new_headless(),
+            frame: 0,
+            frame_start: Vec::new(),
+            frame_end: Vec::new(),
+            reads: Vec::new(),
+            writes: Vec::new(),
+        }));
+        let mut engine = Engine::new();
+        register_functions(&mut engine, &context);
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        let script = Script { engine, ast, context };
+        script.with_machine(machine, || script.engine.run_ast(&script.ast))
+            .map_err(|e| e.to_string())?;
+        Ok(script)
+    }
+
+    // Runs f with the machine lent to the functions the script calls
+    fn with_machine<T>(&self, machine: &mut nes::Machine, f: impl FnOnce() -> T) -> T {
+        std::mem::swap(machine, &mut self.context.borrow_mut().machine);
+        let result = f();
+        std::mem::swap(machine, &mut self.context.borrow_mut().machine);
+        result
+    }
+
+    fn call_hooks(&self, machine: &mut nes::Machine, hooks: &[FnPtr], args: impl FuncArgs + Clone)
+                  -> Result<(), String> {
+        if hooks.is_empty() {
+            return Ok(());
+        }
+        self.with_machine(machine, || {
+            hooks.iter().try_for_each(|hook| {
+                hook.call::<Dynamic>(&self.engine, &self.ast, args.clone()).map(|_| ())
+            })
+        }).map_err(|e| e.to_string())
+    }
+
+    fn call_access_hooks(&self, machine: &mut nes::Machine, access: nes::MemoryAccess)
+                         -> Result<(), String> {
+        let hooks: Vec<FnPtr> = {
+            let context = self.context.borrow();
+            let watches = if access.write { &context.writes } else { &context.reads };
+            watches.iter().filter(|(a, _)| *a == access.address).map(|(_, hook)| hook.clone()).collect()
+        };
+        self.call_hooks(machine, &hooks, (access.address as INT, access.value as INT))
+    }
+
+    // Runs a frame with the hooks of the script. The buttons pressed or
+    // released by the script are used until the end of the frame; those set
+    // at the end of a frame are used in the next. If the CPU stops on an
+    // error, the frame ends there without the frame end hooks; see
+    // Cpu::error.
+    pub fn run_frame(&mut self, cpu: &mut nes::cpu::Cpu, machine: &mut nes::Machine)
+                     -> Result<(), String> {
+        let hooks = self.context.borrow().frame_start.clone();
+        self.call_hooks(machine, &hooks, ())?;
+        let watching = {
+            let context = self.context.borrow();
+            !context.reads.is_empty() || !context.writes.is_empty()
+        };
+        let stopped = if watching {
+            // The hooks are called right after the instruction that made the
+            // access
+            loop {
+                let frame_done = match nes::try_run_instruction(cpu, machine) {
+                    Ok(frame_done) => frame_done,
+                    Err(_) => { break true; }
+                };
+                if machine.has_memory_accesses() {
+                    for access in machine.take_memory_accesses() {
+                        self.call_access_hooks(machine, access)?;
+                    }
+                }
+                if frame_done {
+                    break false;
+                }
+            }
+        }
+        else {
+            nes::try_run_frame(cpu, machine).is_err()
+        };
+        machine.controller.clear_overrides();
+        if stopped {
+            return Ok(());
+        }
+        self.context.borrow_mut().frame += 1;
+        let hooks = self.context.borrow().frame_end.clone();
+        self.call_hooks(machine, &hooks, ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hooks_see_memory_and_press_buttons() {
+        let (mut cpu, mut machine) = nes::cartridge::start_controller_reading_machine();
+        let source = r#"
+            let frames = 0;
+            let writes = 0;
+            on_frame_start(|| { if frame() >= 1 && frame() <= 5 { press(1, "a"); } });
+            on_frame_end(|| { frames += 1; write(0x0010, read(0x0002)); });
+            on_write(0x0003, |address, value| { writes += 1; });
+            on_frame_end(|| { write(0x0011, writes); });
+        "#;
+        let mut script = Script::from_source(source, &mut machine).unwrap();
+        for _ in 0..10 {
+            script.run_frame(&mut cpu, &mut machine).unwrap();
+        }
+        // A was pressed during 5 frames and read once per frame, from the
+        // first NMI in the second frame
+        assert_eq!(machine.peek_mem(0x0002), 5);
+        assert_eq!(machine.peek_mem(0x0010), 5);
+        // INC writes the old value back before the new one
+        assert_eq!(machine.peek_mem(0x0011), machine.peek_mem(0x0003) * 2);
+        assert_ne!(machine.peek_mem(0x0003), 0);
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        let (mut cpu, mut machine) = nes::cartridge::start_controller_reading_machine();
+        assert!(Script::from_source("let x = ;", &mut machine).is_err());
+        let mut script = Script::from_source(r#"on_frame_end(|| press(5, "a"));"#, &mut machine)
+            .unwrap();
+        assert!(script.run_frame(&mut cpu, &mut machine).unwrap_err().contains("Invalid player"));
+    }
+}
@@ -0,0 +1,241 @@
+use crate::nes;
+
+// A quick check of the CPU that needs no test ROMs: every implemented opcode
+// is timed against the cycle counts of the 6502, and a set of instructions
+// is checked for the resulting A register and flags. Each instruction runs in
+// a machine of its own, after a short program that sets up the registers and
+// memory, in both the standard and the cycle accurate mode.
+
+// Cycles of each opcode, without the extra cycles for crossing pages and
+// taking branches
+const CYCLES: [u8; 256] = [
+    7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+    2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5,
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+    2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4,
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+];
+
+// Reads with an indexed address that take one more cycle when the index
+// moves the address to the next page
+const PAGE_CROSS_OP_CODES: [u8; 32] = [
+    0x11, 0x19, 0x1C, 0x1D, 0x31, 0x39, 0x3C, 0x3D, 0x51, 0x59, 0x5C, 0x5D,
+    0x71, 0x79, 0x7C, 0x7D, 0xB1, 0xB3, 0xB9, 0xBB, 0xBC, 0xBD, 0xBE, 0xBF,
+    0xD1, 0xD9, 0xDC, 0xDD, 0xF1, 0xF9, 0xFC, 0xFD,
+];
+
+// Instruction, A, X, P and the byte at $0210 before, then A and P after. P
+// always has bit 5 set and bit 4 clear after being pulled from the stack.
+type ResultCase = ([u8; 3], u8, u8, u8, u8, u8, u8);
+const RESULT_CASES: [ResultCase; 32] = [
+    ([0x69, 0x50, 0x00], 0x50, 0x00, 0x00, 0x00, 0xA0, 0xE0), // ADC #$50, overflow
+    ([0x69, 0x01, 0x00], 0xFF, 0x00, 0x01, 0x00, 0x01, 0x21), // ADC #$01 with carry
+    ([0x69, 0x80, 0x00], 0x80, 0x00, 0x00, 0x00, 0x00, 0x63), // ADC #$80, zero and overflow
+    ([0x69, 0x09, 0x00], 0x01, 0x00, 0x08, 0x00, 0x0A, 0x28), // ADC #$09, no decimal mode
+    ([0xE9, 0x01, 0x00], 0x00, 0x00, 0x01, 0x00, 0xFF, 0xA0), // SBC #$01, borrow
+    ([0xE9, 0x01, 0x00], 0x80, 0x00, 0x01, 0x00, 0x7F, 0x61), // SBC #$01, overflow
+    ([0xC9, 0x10, 0x00], 0x10, 0x00, 0x00, 0x00, 0x10, 0x23), // CMP #$10, equal
+    ([0xC9, 0x20, 0x00], 0x10, 0x00, 0x00, 0x00, 0x10, 0xA0), // CMP #$20, less
+    ([0xE0, 0x05, 0x00], 0x00, 0x05, 0x00, 0x00, 0x00, 0x23), // CPX #$05
+    ([0x29, 0x0F, 0x00], 0xF0, 0x00, 0x00, 0x00, 0x00, 0x22), // AND #$0F
+    ([0x09, 0x80, 0x00], 0x01, 0x00, 0x00, 0x00, 0x81, 0xA0), // ORA #$80
+    ([0x49, 0xFF, 0x00], 0xFF, 0x00, 0x00, 0x00, 0x00, 0x22), // EOR #$FF
+    ([0x2C, 0x10, 0x02], 0x01, 0x00, 0x00, 0xC0, 0x01, 0xE2), // BIT $0210
+    ([0x0A, 0x00, 0x00], 0x81, 0x00, 0x00, 0x00, 0x02, 0x21), // ASL A
+    ([0x4A, 0x00, 0x00], 0x01, 0x00, 0x00, 0x00, 0x00, 0x23), // LSR A
+    ([0x2A, 0x00, 0x00], 0x80, 0x00, 0x01, 0x00, 0x01, 0x21), // ROL A
+    ([0x6A, 0x00, 0x00], 0x01, 0x00, 0x01, 0x00, 0x80, 0xA1), // ROR A
+    ([0xE8, 0x00, 0x00], 0x00, 0xFF, 0x00, 0x00, 0x00, 0x22), // INX
+    ([0xCA, 0x00, 0x00], 0x00, 0x00, 0x00, 0x00, 0x00, 0xA0), // DEX
+    ([0xAA, 0x00, 0x00], 0x00, 0x00, 0x80, 0x00, 0x00, 0x22), // TAX
+    ([0xA9, 0x80, 0x00], 0x00, 0x00, 0x02, 0x00, 0x80, 0xA0), // LDA #$80
+    ([0xEE, 0x10, 0x02], 0x00, 0x00, 0x00, 0xFF, 0x00, 0x22), // INC $0210
+    ([0xCE, 0x10, 0x02], 0x00, 0x00, 0x00, 0x01, 0x00, 0x22), // DEC $0210
+    ([0x18, 0x00, 0x00], 0x00, 0x00, 0xFF, 0x00, 0x00, 0xEE), // CLC
+    ([0x38, 0x00, 0x00], 0x00, 0x00, 0x00, 0x00, 0x00, 0x21), // SEC
+    ([0xB8, 0x00, 0x00], 0x00, 0x00, 0xFF, 0x00, 0x00, 0xAF), // CLV
+    ([0xF8, 0x00, 0x00], 0x00, 0x00, 0x00, 0x00, 0x00, 0x28), // SED
+    ([0xAF, 0x10, 0x02], 0x00, 0x00, 0x00, 0x80, 0x80, 0xA0), // *LAX $0210
+    ([0xCF, 0x10, 0x02], 0x10, 0x00, 0x00, 0x11, 0x10, 0x23), // *DCP $0210
+    ([0xEF, 0x10, 0x02], 0x05, 0x00, 0x01, 0x00, 0x04, 0x21), // *ISB $0210
+    ([0x0F, 0x10, 0x02], 0x00, 0x00, 0x00, 0x81, 0x02, 0x21), // *SLO $0210
+    ([0x6F, 0x10, 0x02], 0x01, 0x00, 0x00, 0x02, 0x02, 0x20), // *RRA $0210
+];
+
+struct Setup {
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    mem: u8,
+}
+
+struct Outcome {
+    description: String,
+    a: u8,
+    p: u8,
+    cycles: u64,
+}
+
+// Sets up the registers and memory: a pointer at $10 to $0210, the byte at
+// $0210 and then X, Y, P and A
+fn setup_program(setup: &Setup) -> Vec<u8> {
+    vec![
+        0xA9, 0x10, 0x85, 0x10,
+        0xA9, 0x02, 0x85, 0x11,
+        0xA9, setup.mem, 0x8D, 0x10, 0x02,
+        0xA2, setup.x,
+        0xA0, setup.y,
+        0xA9, setup.p, 0x48,
+        0xA9, setup.a, 0x28,
+    ]
+}
+const SETUP_INSTRUCTIONS: usize = 12;
+
+fn test_address() -> u16 {
+    0x8000 + setup_program(&Setup { a: 0, x: 0, y: 0, p: 0, mem: 0 }).len() as u16
+}
+
+fn run_instruction(code: &[u8], setup: &Setup, cycle_accurate: bool) -> Outcome {
+    let mut program = setup_program(setup);
+    program.extend_from_slice(code);
+    let mut machine = nes::Machine::new_headless();
+    let mut cpu = nes::cpu::Cpu::new();
+    let rom = nes::cartridge::nrom_image(&program);
+    machine.load_cartridge(nes::cartridge::Cartridge::from_bytes(&rom).unwrap());
+    cpu.reset(&mut machine);
+    cpu.set_cycle_accurate(cycle_accurate);
+    for _ in 0..SETUP_INSTRUCTIONS {
+        cpu.execute(&mut machine);
+    }
+
+    let address = test_address() as usize;
+    let description = cpu.disassemble(&mut machine, address, address).remove(0);
+    let cycle_count = |cpu: &nes::cpu::Cpu, machine: &nes::Machine| {
+        let state = nes::snapshot(cpu, machine);
+        let bytes = state.get("cycle_count").unwrap();
+        let mut value = [0; 8];
+        value.copy_from_slice(bytes);
+        u64::from_le_bytes(value)
+    };
+    let start = cycle_count(&cpu, &machine);
+    cpu.execute(&mut machine);
+    let state = nes::snapshot(&cpu, &machine);
+    Outcome {
+        description: description.trim_end().to_string(),
+        a: state.get("cpu.a").unwrap()[0],
+        p: state.get("cpu.status").unwrap()[0],
+        cycles: cycle_count(&cpu, &machine) - start,
+    }
+}
+
+fn is_branch(op_code: u8) -> bool {
+    op_code & 0x1F == 0x10
+}
+
+// Branches test N, V, C or Z, and are taken when the flag is set or clear
+// depending on bit 5 of the opcode
+fn is_branch_taken(op_code: u8, p: u8) -> bool {
+    let flag = [0x80, 0x40, 0x01, 0x02][(op_code >> 6) as usize];
+    (p & flag != 0) == (op_code & 0x20 != 0)
+}
+
+fn expected_cycles(op_code: u8, setup: &Setup, operand: u8) -> u64 {
+    let mut cycles = CYCLES[op_code as usize] as u64;
+    if setup.x == 0xFF && PAGE_CROSS_OP_CODES.contains(&op_code) {
+        cycles += 1;
+    }
+    if is_branch(op_code) && is_branch_taken(op_code, setup.p) {
+        let next = test_address() + 2;
+        let target = next.wrapping_add(operand as i8 as u16);
+        cycles += if target & 0xFF00 != next & 0xFF00 { 2 } else { 1 };
+    }
+    cycles
+}
+
+// Returns the failures
+fn check_timing(cycle_accurate: bool) -> Vec<String> {
+    let mut failures = Vec::new();
+    let cpu = nes::cpu::Cpu::new();
+    for op_code in 0..=0xFF {
+        if !cpu.is_implemented(op_code) {
+            continue;
+        }
+        // With X = Y = $FF the indexed addresses cross pages. Branches are
+        // also run backwards across a page.
+        let operands: &[u8] = if is_branch(op_code) { &[0x10, 0x80] } else { &[0x10] };
+        for &operand in operands {
+            for &value in &[0x00, 0xFF] {
+                let setup = Setup { a: 0, x: value, y: value, p: value, mem: 0 };
+                let outcome = run_instruction(&[op_code, operand, 0x02], &setup, cycle_accurate);
+                let expected = expected_cycles(op_code, &setup, operand);
+                if outcome.cycles != expected {
+                    failures.push(format!("{} (X=Y=P={:02X}): {} cycles, expected {}",
+                                          outcome.description, value, outcome.cycles, expected));
+                }
+            }
+        }
+    }
+    failures
+}
+
+fn check_results(cycle_accurate: bool) -> Vec<String> {
+    let mut failures = Vec::new();
+    let cpu = nes::cpu::Cpu::new();
+    for &(code, a, x, p, mem, expected_a, expected_p) in RESULT_CASES.iter() {
+        if !cpu.is_implemented(code[0]) {
+            continue;
+        }
+        let setup = Setup { a, x, y: 0, p, mem };
+        let outcome = run_instruction(&code, &setup, cycle_accurate);
+        if outcome.a != expected_a || outcome.p != expected_p {
+            failures.push(format!("{} (A={:02X} X={:02X} P={:02X} M={:02X}): \
+                                   A={:02X} P={:02X}, expected A={:02X} P={:02X}",
+                                  outcome.description, a, x, p, mem,
+                                  outcome.a, outcome.p, expected_a, expected_p));
+        }
+    }
+    failures
+}
+
+// Prints the failures and returns the exit code
+fn failures(cycle_accurate: bool) -> Vec<String> {
+    check_timing(cycle_accurate).into_iter().chain(check_results(cycle_accurate)).collect()
+}
+
+pub fn run() -> i32 {
+    let mut failure_count = 0;
+    for &(name, cycle_accurate) in &[("standard", false), ("cycle accurate", true)] {
+        let failures = failures(cycle_accurate);
+        println!("CPU in {} mode: {}", name,
+                 if failures.is_empty() { "passed".to_string() }
+                 else { format!("{} failures", failures.len()) });
+        for failure in &failures {
+            println!("    {}", failure);
+        }
+        failure_count += failures.len();
+    }
+    if failure_count == 0 { 0 } else { 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_passes() {
+        assert_eq!(failures(false), Vec::<String>::new());
+        assert_eq!(failures(true), Vec::<String>::new());
+    }
+}
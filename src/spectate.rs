@@ -0,0 +1,265 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Mod};
+
+use nesemu::nes;
+use nesemu::nes::screen::{HEIGHT, WIDTH};
+
+// Spectating over TCP: with --stream any number of spectators can connect
+// with nesemu watch and see and hear the game, but not play it. Unlike
+// netplay the spectators do not run the game themselves, so they need no ROM.
+// Each frame is sent as one zlib compressed message with the picture XORed
+// with the previous picture sent, which is mostly zeros, followed by the
+// sound as 16 bit samples. A spectator that can not keep up misses frames,
+// so that the game never waits for the network.
+
+const MAGIC: &[u8; 7] = b"NESVIEW";
+const VERSION: u8 = 1;
+
+const PICTURE_SIZE: usize = (WIDTH * HEIGHT * 3) as usize;
+
+// Frames waiting to be sent to a spectator, after which frames are dropped
+const QUEUE_FRAMES: usize = 30;
+
+// Sound a spectator keeps queued, after which the sound of frames is dropped
+// so that it does not fall further and further behind the picture
+const MAX_QUEUED_AUDIO_MS: usize = 200;
+
+// Of a message both before and after it is decompressed, far more than a
+// frame takes
+const MAX_MESSAGE_SIZE: usize = 4 << 20;
+
+pub struct Frame {
+    // 256x240 RGB
+    pub picture: Vec<u8>,
+    // Mono, at the sample rate of the game
+    pub samples: Vec<f32>,
+}
+
+// The game side
+pub struct Broadcast {
+    spectators: Arc<Mutex<Vec<SyncSender<Arc<Frame>>>>>,
+}
+
+impl Broadcast {
+    // Takes spectators from the listener, each sent to by its own thread
+    pub fn start(listener: TcpListener, sample_rate: u32) -> Broadcast {
+        let spectators = Arc::new(Mutex::new(Vec::new()));
+        let accepted = spectators.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (sender, frames) = mpsc::sync_channel(QUEUE_FRAMES);
+                accepted.lock().unwrap().push(sender);
+                thread::spawn(move || send_frames(stream, sample_rate, frames));
+            }
+        });
+        Broadcast { spectators }
+    }
+
+    pub fn send_frame(&self, picture: &[u8], samples: Vec<f32>) {
+        let mut spectators = self.spectators.lock().unwrap();
+        if spectators.is_empty() {
+            return;
+        }
+        let frame = Arc::new(Frame { picture: picture.to_vec(), samples });
+        spectators.retain(|spectator| {
+            !matches!(spectator.try_send(frame.clone()), Err(TrySendError::Disconnected(_)))
+        });
+    }
+}
+
+// Sends the sample rate, then the frames until the spectator disconnects
+fn send_frames(mut stream: TcpStream, sample_rate: u32, frames: Receiver<Arc<Frame>>) {
+    let address = stream.peer_addr().map_or("?".to_string(), |a| a.to_string());
+    println!("Spectator {} connected", address);
+    stream.set_nodelay(true).ok();
+    let mut hello = MAGIC.to_vec();
+    hello.push(VERSION);
+    hello.extend_from_slice(&sample_rate.to_le_bytes());
+    let mut result = stream.write_all(&hello);
+    let mut previous = vec![0; PICTURE_SIZE];
+    for frame in frames.iter() {
+        if result.is_err() {
+            break;
+        }
+        let message = encode(&frame, &mut previous);
+        result = stream.write_all(&(message.len() as u32).to_le_bytes())
+            .and_then(|_| stream.write_all(&message));
+    }
+    println!("Spectator {} disconnected", address);
+}
+
+// The previous picture becomes that of the frame
+fn encode(frame: &Frame, previous: &mut [u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(PICTURE_SIZE + frame.samples.len() * 2);
+    message.extend(frame.picture.iter().zip(previous.iter()).map(|(new, old)| new ^ old));
+    for sample in &frame.samples {
+        message.extend_from_slice(&((sample.clamp(-1.0, 1.0) * 32767.0) as i16).to_le_bytes());
+    }
+    previous.copy_from_slice(&frame.picture);
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(&message).unwrap();
+    encoder.finish().unwrap()
+}
+
+// Applies the message to the previous picture and returns the samples
+fn decode(message: &[u8], picture: &mut [u8]) -> Result<Vec<f32>, String> {
+    let mut contents = Vec::new();
+    ZlibDecoder::new(message).take(MAX_MESSAGE_SIZE as u64 + 1).read_to_end(&mut contents)
+        .map_err(|e| format!("Invalid frame: {}", e))?;
+    if contents.len() > MAX_MESSAGE_SIZE {
+        return Err("Invalid frame, too large".to_string());
+    }
+    if contents.len() < PICTURE_SIZE || !(contents.len() - PICTURE_SIZE).is_multiple_of(2) {
+        return Err("Invalid frame".to_string());
+    }
+    let (delta, samples) = contents.split_at(PICTURE_SIZE);
+    for (pixel, change) in picture.iter_mut().zip(delta) {
+        *pixel ^= change;
+    }
+    Ok(samples.chunks(2)
+        .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32767.0)
+        .collect())
+}
+
+// The spectator side. Returns the sample rate of the game and the frames,
+// received on a thread of their own until the stream ends with an error.
+pub fn connect(address: &str) -> Result<(u32, Receiver<Result<Frame, String>>), String> {
+    let mut stream = TcpStream::connect(address)
+        .map_err(|e| format!("Unable to connect to {}: {}", address, e))?;
+    let mut hello = [0; 12];
+    stream.read_exact(&mut hello).map_err(|e| format!("Unable to connect to {}: {}", address, e))?;
+    if &hello[..7] != MAGIC {
+        return Err(format!("{} is not streaming a game", address));
+    }
+    if hello[7] != VERSION {
+        return Err(format!("{} streams with version {}, this is version {}", address, hello[7], VERSION));
+    }
+    let sample_rate = u32::from_le_bytes([hello[8], hello[9], hello[10], hello[11]]);
+    let (sender, frames) = mpsc::channel();
+    thread::spawn(move || receive_frames(stream, sender));
+    Ok((sample_rate, frames))
+}
+
+fn receive_frames(mut stream: TcpStream, frames: Sender<Result<Frame, String>>) {
+    let mut picture = vec![0; PICTURE_SIZE];
+    loop {
+        let frame = receive_frame(&mut stream, &mut picture)
+            .map(|samples| Frame { picture: picture.clone(), samples });
+        let ended = frame.is_err();
+        if frames.send(frame).is_err() || ended {
+            return;
+        }
+    }
+}
+
+fn receive_frame(stream: &mut TcpStream, picture: &mut [u8]) -> Result<Vec<f32>, String> {
+    let ended = |e: std::io::Error| format!("The stream ended: {}", e);
+    let mut size = [0; 4];
+    stream.read_exact(&mut size).map_err(ended)?;
+    let size = u32::from_le_bytes(size) as usize;
+    if size > MAX_MESSAGE_SIZE {
+        return Err("Invalid frame".to_string());
+    }
+    let mut message = vec![0; size];
+    stream.read_exact(&mut message).map_err(ended)?;
+    decode(&message, picture)
+}
+
+// Shows the game streamed from the address in a window until it is closed
+// or the stream ends
+pub fn watch(address: &str) -> Result<(), String> {
+    let (sample_rate, frames) = connect(address)?;
+    let output = nes::AudioOutput { sample_rate, ..nes::AudioOutput::default() };
+    output.check()?;
+    let mut sdl_context = sdl2::init()?;
+    let mut screen = nes::screen::Screen::new(&mut sdl_context, false);
+    screen.set_title(&format!("nesemu - watching {}", address));
+    let mut audio = nes::open_sdl_audio(&mut sdl_context, output);
+    let mut event_pump = sdl_context.event_pump()?;
+    let max_queued_samples = sample_rate as usize * MAX_QUEUED_AUDIO_MS / 1000;
+    loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    return Ok(());
+                }
+                Event::KeyDown { keycode: Some(Keycode::Return), keymod, .. }
+                        if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) => {
+                    screen.toggle_fullscreen();
+                }
+                _ => {}
+            }
+        }
+        // Only the last of the frames that have arrived is shown, but all
+        // their sound is played
+        let first = match frames.recv_timeout(Duration::from_millis(10)) {
+            Ok(frame) => frame,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        let mut picture = None;
+        for frame in std::iter::once(first).chain(frames.try_iter()) {
+            let frame = frame?;
+            if audio.queued() < max_queued_samples {
+                audio.queue(&frame.samples);
+            }
+            picture = Some(frame.picture);
+        }
+        if let Some(picture) = picture {
+            screen.present(&picture, None, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_reach_the_spectators() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let broadcast = Broadcast::start(listener, 44100);
+        // Nobody watches this one
+        broadcast.send_frame(&vec![1; PICTURE_SIZE], vec![0.5]);
+        let (sample_rate, frames) = connect(&address).unwrap();
+        assert_eq!(sample_rate, 44100);
+
+        let mut picture = vec![0; PICTURE_SIZE];
+        picture[0] = 0xFF;
+        broadcast.send_frame(&picture, vec![0.5, -1.0]);
+        picture[1] = 0x80;
+        broadcast.send_frame(&picture, Vec::new());
+        let first = frames.recv().unwrap().unwrap();
+        assert_eq!(&first.picture[..3], [0xFF, 0, 0]);
+        assert_eq!(first.samples.len(), 2);
+        assert!((first.samples[0] - 0.5).abs() < 0.001 && first.samples[1] == -1.0);
+        let second = frames.recv().unwrap().unwrap();
+        assert_eq!(second.picture, picture);
+        assert!(second.samples.is_empty());
+    }
+
+    #[test]
+    fn invalid_frames_are_errors() {
+        let mut picture = vec![0; PICTURE_SIZE];
+        assert!(decode(b"junk", &mut picture).is_err());
+        let frame = Frame { picture: vec![0; 100], samples: Vec::new() };
+        let mut previous = vec![0; 100];
+        assert!(decode(&encode(&frame, &mut previous), &mut picture).is_err());
+        let frame = Frame { picture: vec![0; MAX_MESSAGE_SIZE + 2], samples: Vec::new() };
+        let mut previous = vec![0; MAX_MESSAGE_SIZE + 2];
+        let message = encode(&frame, &mut previous);
+        assert!(message.len() < MAX_MESSAGE_SIZE);
+        assert!(decode(&message, &mut picture).is_err());
+    }
+}